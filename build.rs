@@ -0,0 +1,10 @@
+// Generates the gRPC server of `proto/updater/v1/updater.proto`; see
+// `src/grpc.rs`. protox compiles the schema in Rust, so no protoc is needed.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto");
+    let descriptors = protox::compile(["updater/v1/updater.proto"], ["proto"])?;
+    tonic_prost_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)?;
+    Ok(())
+}
@@ -0,0 +1,324 @@
+//! `arm`: uploads a release artifact to the App Release Manager.
+//!
+//! Reads the artifact and its updater `.sig`, prints the SHA-256 the server
+//! will deduplicate on, and uploads through [`updater::client`]. With
+//! `--dry-run` everything is validated and printed but nothing is sent, and
+//! no token is needed.
+//!
+//! `arm check-config` runs the server's [`updater::selfcheck`] against the
+//! configuration in the environment, to vet a deployment before starting it.
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use chrono::{DateTime, Utc};
+use updater::api_version;
+use updater::artifacts::sha256_hex;
+use updater::client::{Client, Upload, UploadOutcome};
+use updater::config::Config;
+use updater::schema::{CheckStatus, DEFAULT_CHANNEL, is_valid_channel};
+use updater::selfcheck;
+
+const USAGE: &str = "\
+Usage: arm upload [options] <artifact>
+       arm check-config
+
+Commands:
+  upload               Upload a release artifact
+  check-config         Check the server configuration in the environment
+                       (DATABASE_URL, GITHUB_TOKEN, ...) and exit non-zero
+                       when a check fails
+
+Upload options:
+  --server <url>       Release server (default: $UPDATER_URL)
+  --token <token>      Organization API token (default: $UPDATER_TOKEN)
+  --app <name>         Application name, e.g. classprime
+  --version <version>  Version being released, in the app's version scheme
+  --target <os>        Target OS, e.g. darwin or windows
+  --arch <arch>        Architecture, e.g. aarch64 or x86_64
+  --channel <name>     Release channel (default: stable)
+  --signature <file>   Updater signature (default: <artifact>.sig)
+  --notes <text>       Release notes
+  --notes-file <file>  Read release notes from a file
+  --pub-date <time>    RFC3339 publish date (default: now)
+  --dry-run            Validate and print, but don't upload
+  -h, --help           Show this help";
+
+#[derive(Debug)]
+enum Command {
+    Upload(Box<Args>),
+    CheckConfig,
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    server: Option<String>,
+    token: Option<String>,
+    app: Option<String>,
+    version: Option<String>,
+    target: Option<String>,
+    arch: Option<String>,
+    channel: Option<String>,
+    signature: Option<PathBuf>,
+    notes: Option<String>,
+    notes_file: Option<PathBuf>,
+    pub_date: Option<String>,
+    dry_run: bool,
+    artifact: Option<PathBuf>,
+}
+
+/// Returns `None` when help was asked for.
+fn parse_args(mut argv: impl Iterator<Item = String>) -> Result<Option<Command>, String> {
+    match argv.next().as_deref() {
+        None | Some("-h" | "--help" | "help") => return Ok(None),
+        Some("upload") => {}
+        Some("check-config") => {
+            return match argv.next().as_deref() {
+                None => Ok(Some(Command::CheckConfig)),
+                Some("-h" | "--help") => Ok(None),
+                Some(other) => Err(format!("check-config takes no arguments, got '{}'", other)),
+            };
+        }
+        Some(other) => return Err(format!("unknown command '{}'", other)),
+    }
+
+    let mut args = Args::default();
+    while let Some(arg) = argv.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(None);
+        }
+        if arg == "--dry-run" {
+            args.dry_run = true;
+            continue;
+        }
+        let Some(flag) = arg.strip_prefix("--") else {
+            if args.artifact.replace(PathBuf::from(&arg)).is_some() {
+                return Err("only one artifact can be uploaded at a time".to_string());
+            }
+            continue;
+        };
+
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => {
+                let value = argv
+                    .next()
+                    .ok_or_else(|| format!("--{} needs a value", flag))?;
+                (flag.to_string(), value)
+            }
+        };
+        let slot = match name.as_str() {
+            "server" => &mut args.server,
+            "token" => &mut args.token,
+            "app" => &mut args.app,
+            "version" => &mut args.version,
+            "target" => &mut args.target,
+            "arch" => &mut args.arch,
+            "channel" => &mut args.channel,
+            "notes" => &mut args.notes,
+            "pub-date" => &mut args.pub_date,
+            "signature" => {
+                args.signature = Some(PathBuf::from(value));
+                continue;
+            }
+            "notes-file" => {
+                args.notes_file = Some(PathBuf::from(value));
+                continue;
+            }
+            _ => return Err(format!("unknown option '--{}'", name)),
+        };
+        *slot = Some(value);
+    }
+    Ok(Some(Command::Upload(Box::new(args))))
+}
+
+fn required(value: Option<String>, flag: &str) -> Result<String, String> {
+    match value {
+        Some(v) if !v.trim().is_empty() => Ok(v.trim().to_string()),
+        _ => Err(format!("--{} is required", flag)),
+    }
+}
+
+async fn read_text(path: &Path, what: &str) -> Result<String, String> {
+    tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("cannot read {} {}: {}", what, path.display(), e))
+}
+
+/// Validates everything the server would, so a dry run catches mistakes
+/// before CI gets as far as publishing. The version is left to the server,
+/// as only it knows the app's version scheme.
+async fn prepare(args: Args) -> Result<Upload, String> {
+    let artifact = args.artifact.ok_or("missing <artifact>")?;
+    let app_name = required(args.app, "app")?;
+    let version = required(args.version, "version")?;
+    let target = required(args.target, "target")?;
+    let arch = required(args.arch, "arch")?;
+
+    let channel = args.channel.unwrap_or_else(|| DEFAULT_CHANNEL.to_string());
+    if !is_valid_channel(&channel) {
+        return Err(format!(
+            "--channel '{}' must be a lowercase slug (letters, digits, '-')",
+            channel
+        ));
+    }
+
+    let pub_date = match args.pub_date {
+        Some(raw) => Some(
+            DateTime::parse_from_rfc3339(&raw)
+                .map_err(|e| format!("--pub-date '{}' is not RFC3339: {}", raw, e))?
+                .with_timezone(&Utc),
+        ),
+        None => None,
+    };
+
+    let notes = match (args.notes, args.notes_file) {
+        (Some(_), Some(_)) => return Err("use either --notes or --notes-file".to_string()),
+        (Some(notes), None) => notes,
+        (None, Some(path)) => read_text(&path, "notes file").await?,
+        (None, None) => String::new(),
+    };
+
+    let signature_path = args.signature.unwrap_or_else(|| {
+        let mut path = artifact.clone().into_os_string();
+        path.push(".sig");
+        PathBuf::from(path)
+    });
+    let signature = read_text(&signature_path, "signature").await?;
+    let signature = signature.trim().to_string();
+    if signature.is_empty() {
+        return Err(format!("signature {} is empty", signature_path.display()));
+    }
+
+    let file = tokio::fs::read(&artifact)
+        .await
+        .map_err(|e| format!("cannot read artifact {}: {}", artifact.display(), e))?;
+    if file.is_empty() {
+        return Err(format!("artifact {} is empty", artifact.display()));
+    }
+    let file_name = artifact
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .ok_or_else(|| format!("{} is not a file", artifact.display()))?;
+
+    println!("artifact:  {} ({} bytes)", artifact.display(), file.len());
+    println!("sha256:    {}", sha256_hex(&file));
+    println!("signature: {}", signature_path.display());
+    println!(
+        "release:   {} {} ({}/{}) on {}",
+        app_name, version, target, arch, channel
+    );
+
+    Ok(Upload {
+        app_name,
+        version,
+        target,
+        arch,
+        notes,
+        signature,
+        channel: Some(channel),
+        pub_date,
+        file_name,
+        file,
+    })
+}
+
+fn from_env(value: Option<String>, var: &str) -> Option<String> {
+    value
+        .or_else(|| std::env::var(var).ok())
+        .filter(|s| !s.trim().is_empty())
+}
+
+async fn run(mut args: Args) -> Result<(), String> {
+    let server = from_env(args.server.take(), "UPDATER_URL");
+    let token = from_env(args.token.take(), "UPDATER_TOKEN");
+    let dry_run = args.dry_run;
+    let upload = prepare(args).await?;
+    let server = server.ok_or("--server is required (or set UPDATER_URL)")?;
+    let server = server.trim_end_matches('/');
+    let client = Client::new(server).map_err(|e| format!("--server '{}': {}", server, e))?;
+
+    let channel = upload.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
+    let latest = format!(
+        "{}{}/download/latest/{}/{}/{}?channel={}",
+        server,
+        api_version::PREFIX,
+        upload.app_name,
+        upload.target,
+        upload.arch,
+        channel
+    );
+
+    if dry_run {
+        println!("dry run:   nothing uploaded to {}", server);
+        return Ok(());
+    }
+
+    let token = token.ok_or("--token is required (or set UPDATER_TOKEN)")?;
+    let outcome = client
+        .with_token(token)
+        .upload(&upload)
+        .await
+        .map_err(|e| format!("upload failed: {}", e))?;
+    match outcome {
+        UploadOutcome::Published(url) => println!("download:  {}", url),
+        UploadOutcome::Staged(staged) => {
+            let checks: Vec<&str> = staged.checks.iter().map(|c| c.name.as_str()).collect();
+            println!(
+                "staged:    #{}, published once these pass: {}",
+                staged.id,
+                checks.join(", ")
+            );
+        }
+    }
+    println!("latest:    {}", latest);
+    Ok(())
+}
+
+async fn check_config() -> ExitCode {
+    let report = selfcheck::run_standalone(&Config::from_env()).await;
+    let width = report
+        .checks
+        .iter()
+        .map(|c| c.name.len())
+        .max()
+        .unwrap_or(0);
+    for check in &report.checks {
+        let status = match check.status {
+            CheckStatus::Pass => "pass",
+            CheckStatus::Fail => "FAIL",
+            CheckStatus::Skip => "skip",
+        };
+        println!("{}  {:width$}  {}", status, check.name, check.detail);
+    }
+    if report.passed {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let command = match parse_args(std::env::args().skip(1)) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match command {
+        None => {
+            println!("{}", USAGE);
+            ExitCode::SUCCESS
+        }
+        Some(Command::Upload(args)) => match run(*args).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+        Some(Command::CheckConfig) => check_config().await,
+    }
+}
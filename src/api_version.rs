@@ -0,0 +1,99 @@
+//! API versioning.
+//!
+//! Every endpoint is served under `/v1`. The original unprefixed routes stay
+//! mounted as aliases of the current version so installed apps keep
+//! updating, but their responses carry `Deprecation` and a `Link` to the
+//! versioned path.
+//!
+//! The path decides the version. Clients may also send `X-API-Version`;
+//! a value the path doesn't serve is rejected instead of being silently
+//! answered in another shape. Every API response reports the version that
+//! produced it in the same header.
+
+use axum::{
+    extract::{OriginalUri, Request},
+    http::{HeaderName, HeaderValue, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use utoipa::openapi::{OpenApi, Paths};
+
+use crate::error::AppError;
+
+pub const VERSION_HEADER: HeaderName = HeaderName::from_static("x-api-version");
+
+/// Version served by the `/v1` routes and the unprefixed aliases.
+pub const CURRENT: &str = "1";
+
+pub const PREFIX: &str = "/v1";
+
+/// The response rejecting a request for a version this route doesn't serve.
+fn unsupported_version(request: &Request) -> Option<Response> {
+    let requested = request.headers().get(&VERSION_HEADER)?;
+    let requested = requested.to_str().unwrap_or_default().trim();
+    let requested = requested.trim_start_matches(['v', 'V']);
+    if requested == CURRENT {
+        return None;
+    }
+    Some(
+        (
+            [(VERSION_HEADER, HeaderValue::from_static(CURRENT))],
+            AppError::bad_request(format!(
+                "API version '{}' is not served here; supported versions: {}",
+                requested, CURRENT
+            )),
+        )
+            .into_response(),
+    )
+}
+
+/// Middleware for the `/v1` routes.
+pub async fn versioned(request: Request, next: Next) -> Response {
+    if let Some(response) = unsupported_version(&request) {
+        return response;
+    }
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .insert(VERSION_HEADER, HeaderValue::from_static(CURRENT));
+    response
+}
+
+/// Middleware for the deprecated unprefixed aliases.
+pub async fn deprecated_alias(
+    OriginalUri(uri): OriginalUri,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(response) = unsupported_version(&request) {
+        return response;
+    }
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(VERSION_HEADER, HeaderValue::from_static(CURRENT));
+    headers.insert(
+        HeaderName::from_static("deprecation"),
+        HeaderValue::from_static("true"),
+    );
+    let successor = format!("<{}{}>; rel=\"successor-version\"", PREFIX, uri.path());
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.append(header::LINK, link);
+    }
+    response
+}
+
+/// Documents the versioned paths: everything but `/` moves under `/v1`.
+pub fn prefix_paths(mut doc: OpenApi) -> OpenApi {
+    let mut paths = Paths::new();
+    for (path, item) in std::mem::take(&mut doc.paths.paths) {
+        let path = if path == "/" {
+            path
+        } else {
+            format!("{}{}", PREFIX, path)
+        };
+        paths.paths.insert(path, item);
+    }
+    paths.extensions = doc.paths.extensions.take();
+    doc.paths = paths;
+    doc
+}
@@ -0,0 +1,443 @@
+//! Cold-storage archival of old releases.
+//!
+//! With an archive bucket configured (`ARCHIVE_S3_URL`, `ARCHIVE_S3_REGION`,
+//! `ARCHIVE_S3_ACCESS_KEY_ID` and `ARCHIVE_S3_SECRET_ACCESS_KEY`), an app can
+//! set an archive policy. Every `ARCHIVE_INTERVAL_SECS`, releases older than
+//! the newest `keep_versions` unyanked versions of their target, arch and
+//! channel have their artifact moved from GitHub into the bucket, stored as
+//! `ARCHIVE_STORAGE_CLASS` (`GLACIER_IR` by default). Their URL then points
+//! into the private bucket and they're marked `archived`, which clients are
+//! never offered, like yanked releases. Support can still download one
+//! through `GET /releases/{id}/archive`, which redirects to a short-lived
+//! presigned URL; with the `GLACIER` and `DEEP_ARCHIVE` classes the object
+//! has to be restored in S3 first.
+//!
+//! Artifacts are shared by identical uploads within an organization, so one
+//! that a kept release, a component or any other upload also uses stays
+//! where it is. Components
+//! and delta patches aren't archived.
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+use axum::{
+    Extension,
+    extract::{Path, State},
+    response::{IntoResponse, Json, Redirect, Response},
+};
+use percent_encoding::percent_decode_str;
+use sqlx::{Pool, Sqlite};
+
+use crate::artifacts;
+use crate::auth::{self, Principal};
+use crate::cache;
+use crate::config::Config;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::github::GitHub;
+use crate::http_client::HttpClient;
+use crate::s3;
+use crate::schema::{APP_COLUMNS, App, AppState, ArchivePolicyRequest, Artifact, VersionScheme};
+use crate::variants::scoped_release;
+use crate::versioning::{self, AppVersion};
+
+/// Artifacts can be large, so transfers get longer than other calls.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How long a presigned archive download works.
+const DOWNLOAD_LINK_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// App, target, arch and channel of archived releases, whose latest-release
+/// cache entries are dropped.
+type Stream = (String, String, String, String);
+
+pub struct Archive {
+    bucket: s3::Bucket,
+    storage_class: String,
+    client: HttpClient,
+}
+
+impl Archive {
+    pub fn from_config(config: &Config) -> std::io::Result<Option<Self>> {
+        let (Some(url), Some(access_key_id), Some(secret_access_key)) = (
+            &config.archive_s3_url,
+            &config.archive_s3_access_key_id,
+            &config.archive_s3_secret_access_key,
+        ) else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            bucket: s3::Bucket {
+                url: url.trim_end_matches('/').to_string(),
+                region: config.archive_s3_region.clone(),
+                credentials: s3::Credentials {
+                    access_key_id: access_key_id.clone(),
+                    secret_access_key: secret_access_key.clone(),
+                    session_token: None,
+                },
+            },
+            storage_class: config.archive_storage_class.clone(),
+            client: HttpClient::new(REQUEST_TIMEOUT)?,
+        }))
+    }
+
+    /// Deletes an orphaned artifact that lives in the archive.
+    pub async fn delete_orphaned(&self, artifact: &Artifact) {
+        let Some(key) = self.bucket.key_of(&artifact.url) else {
+            return;
+        };
+        println!(
+            "Archived artifact {} no longer referenced, deleting {}",
+            artifact.sha256, key
+        );
+        if let Err(e) = self.bucket.delete(&self.client, &key).await {
+            println!("Cannot delete archived artifact {}: {}", artifact.sha256, e);
+        }
+    }
+
+    /// Whether `artifact` has been moved into the archive.
+    pub fn holds(&self, artifact: &Artifact) -> bool {
+        artifact.github_asset_id.is_none() && self.bucket.key_of(&artifact.url).is_some()
+    }
+}
+
+pub fn spawn(state: AppState) {
+    let Some(archive) = state.archive.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(state.config.archive_interval).await;
+            if let Err(e) = run(&state, &archive).await {
+                println!("Archive pass failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Applies every app's archive policy once.
+pub async fn run(state: &AppState, archive: &Archive) -> Result<(), sqlx::Error> {
+    let apps: Vec<(String, i64, String)> = sqlx::query_as(
+        "SELECT name, archive_keep_versions, version_scheme FROM apps WHERE archive_keep_versions IS NOT NULL ORDER BY name",
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    for (app_name, keep_versions, scheme) in apps {
+        let scheme = VersionScheme::parse(&scheme).unwrap_or_default();
+        for (sha256, release_ids) in
+            candidates(&state.pool, &app_name, keep_versions, scheme).await?
+        {
+            if let Err(e) = archive_artifact(state, archive, &app_name, &sha256, &release_ids).await
+            {
+                println!("Failed to archive artifact {}: {}", sha256, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The artifacts of `app_name` that only releases past the kept versions
+/// use, with those releases' IDs.
+async fn candidates(
+    pool: &Pool<Sqlite>,
+    app_name: &str,
+    keep_versions: i64,
+    scheme: VersionScheme,
+) -> Result<Vec<(String, Vec<i64>)>, sqlx::Error> {
+    type Row = (
+        i64,
+        String,
+        String,
+        String,
+        String,
+        Option<String>,
+        bool,
+        bool,
+    );
+    let releases: Vec<Row> = sqlx::query_as(
+        "SELECT id, target, arch, channel, version, sha256, yanked, archived FROM releases WHERE app_name = ?",
+    )
+    .bind(app_name)
+    .fetch_all(pool)
+    .await?;
+
+    // The oldest version each target, arch and channel keeps; streams
+    // with fewer versions than that keep everything.
+    let mut streams: HashMap<(&str, &str, &str), BTreeSet<AppVersion>> = HashMap::new();
+    for (_, target, arch, channel, version, _, yanked, _) in &releases {
+        if let (false, Ok(version)) = (yanked, versioning::parse(scheme, version)) {
+            streams
+                .entry((target, arch, channel))
+                .or_default()
+                .insert(version);
+        }
+    }
+    let oldest_kept: HashMap<(&str, &str, &str), AppVersion> = streams
+        .into_iter()
+        .filter_map(|(stream, versions)| {
+            Some((
+                stream,
+                versions
+                    .into_iter()
+                    .rev()
+                    .nth(keep_versions.max(1) as usize - 1)?,
+            ))
+        })
+        .collect();
+
+    let mut old: HashMap<&str, Vec<i64>> = HashMap::new();
+    for (id, target, arch, channel, version, sha256, _, archived) in &releases {
+        let (false, Some(sha256)) = (archived, sha256) else {
+            continue;
+        };
+        let Some(oldest) = oldest_kept.get(&(target.as_str(), arch.as_str(), channel.as_str()))
+        else {
+            continue;
+        };
+        if versioning::parse(scheme, version).is_ok_and(|v| v < *oldest) {
+            old.entry(sha256).or_default().push(*id);
+        }
+    }
+
+    let mut candidates = Vec::new();
+    for (sha256, mut ids) in old {
+        ids.sort();
+        if users(pool, app_name, sha256).await? == ids
+            && only_releases_use(pool, app_name, sha256).await?
+        {
+            candidates.push((sha256.to_string(), ids));
+        }
+    }
+    candidates.sort();
+    Ok(candidates)
+}
+
+/// The apps of the organization owning the app bound to `?1`.
+const ORG_APPS: &str =
+    "SELECT name FROM apps WHERE org_id = (SELECT org_id FROM apps WHERE name = ?1)";
+
+/// The unarchived releases, of any app of `app_name`'s organization, that
+/// use an artifact.
+async fn users(
+    conn: impl sqlx::SqliteExecutor<'_>,
+    app_name: &str,
+    sha256: &str,
+) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar(&format!(
+        "SELECT id FROM releases WHERE sha256 = ?2 AND archived = 0 AND app_name IN ({}) ORDER BY id",
+        ORG_APPS
+    ))
+    .bind(app_name)
+    .bind(sha256)
+    .fetch_all(conn)
+    .await
+}
+
+/// Whether releases hold every reference to an artifact. Components,
+/// variants, bundles and deltas share artifacts too, and moving one would
+/// break their URLs. So would moving an asset that another organization
+/// still shares from before artifacts were kept apart.
+async fn only_releases_use(
+    pool: &Pool<Sqlite>,
+    app_name: &str,
+    sha256: &str,
+) -> Result<bool, sqlx::Error> {
+    let only: Option<bool> = sqlx::query_scalar(&format!(
+        "SELECT (SELECT count(*) FROM releases WHERE sha256 = ?2 AND app_name IN ({0})) = ref_count
+             AND NOT EXISTS (SELECT 1 FROM artifacts o WHERE o.url = a.url AND o.org_id != a.org_id)
+         FROM artifacts a WHERE a.sha256 = ?2 AND a.org_id = (SELECT org_id FROM apps WHERE name = ?1)",
+        ORG_APPS
+    ))
+    .bind(app_name)
+    .bind(sha256)
+    .fetch_optional(pool)
+    .await?;
+    Ok(only.unwrap_or(false))
+}
+
+/// Moves an artifact from GitHub into the archive and repoints the
+/// releases using it, which must still be exactly `release_ids`.
+async fn archive_artifact(
+    state: &AppState,
+    archive: &Archive,
+    app_name: &str,
+    sha256: &str,
+    release_ids: &[i64],
+) -> Result<(), String> {
+    let artifact = artifacts::find(&state.pool, app_name, sha256)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| "There is no such artifact".to_string())?;
+    let Some(asset_id) = artifact.github_asset_id else {
+        return Err("The artifact is not stored on GitHub".to_string());
+    };
+
+    let github = GitHub::from_config(&state.config)?;
+    let data = github.download_asset(asset_id as u64).await?;
+    if artifacts::sha256_hex(&data) != sha256 {
+        return Err("The downloaded bytes don't match the artifact's SHA-256".to_string());
+    }
+    let file_name = artifact.url.rsplit('/').next().unwrap_or_default();
+    let key = format!(
+        "{}/{}/{}",
+        artifact.org_id,
+        sha256,
+        percent_decode_str(file_name).decode_utf8_lossy()
+    );
+    let url = archive
+        .bucket
+        .put(
+            &archive.client,
+            &key,
+            &[("x-amz-storage-class", &archive.storage_class)],
+            data,
+        )
+        .await?;
+
+    let moved: Result<Option<Vec<Stream>>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        if users(&mut *tx, app_name, sha256).await? != release_ids {
+            return Ok(None);
+        }
+        let streams = sqlx::query_as(&format!(
+            "UPDATE releases SET url = ?3, archived = 1 WHERE sha256 = ?2 AND archived = 0 AND app_name IN ({}) RETURNING app_name, target, arch, channel",
+            ORG_APPS
+        ))
+        .bind(app_name)
+        .bind(sha256)
+        .bind(&url)
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query("UPDATE artifacts SET url = ?, github_asset_id = NULL WHERE org_id = ? AND sha256 = ?")
+            .bind(&url)
+            .bind(artifact.org_id)
+            .bind(sha256)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(streams))
+    }
+    .await;
+    let streams = match moved {
+        Ok(Some(streams)) => streams,
+        Ok(None) => {
+            let _ = archive.bucket.delete(&archive.client, &key).await;
+            return Err("A release started using the artifact while it was archived".to_string());
+        }
+        Err(e) => {
+            let _ = archive.bucket.delete(&archive.client, &key).await;
+            return Err(e.to_string());
+        }
+    };
+
+    for (app_name, target, arch, channel) in &streams {
+        state
+            .cache
+            .invalidate(&cache::latest_key(app_name, target, arch, channel))
+            .await;
+    }
+    if let Err(e) = github
+        .delete_asset(&state.github_lookups, asset_id as u64)
+        .await
+    {
+        println!("{}", e);
+    }
+    println!(
+        "Archived artifact {} of releases {:?} to {}",
+        sha256, release_ids, url
+    );
+    Ok(())
+}
+
+/// Set an app's archive policy
+///
+/// Releases older than the newest `keep_versions` versions of their target,
+/// arch and channel are moved to the archive bucket and no longer offered.
+#[utoipa::path(
+    put,
+    path = "/apps/{name}/archive-policy",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    request_body = ArchivePolicyRequest,
+    responses(
+        (status = 200, description = "Policy saved", body = App),
+        (status = 400, description = "keep_versions below 1", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody),
+        (status = 409, description = "No archive bucket is configured", body = ErrorBody)
+    )
+)]
+pub async fn set_archive_policy(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<ArchivePolicyRequest>,
+) -> AppResult<Json<App>> {
+    if request.keep_versions.is_some_and(|keep| keep < 1) {
+        return Err(AppError::bad_request("keep_versions must be at least 1"));
+    }
+    if request.keep_versions.is_some() && state.archive.is_none() {
+        return Err(AppError::conflict(
+            "No archive bucket is configured; set ARCHIVE_S3_URL and its credentials",
+        ));
+    }
+    auth::claim_app(&state.pool, principal, &name).await?;
+
+    let app = sqlx::query_as::<_, App>(&format!(
+        "UPDATE apps SET archive_keep_versions = ? WHERE name = ? RETURNING {}",
+        APP_COLUMNS
+    ))
+    .bind(request.keep_versions)
+    .bind(&name)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to save the archive policy", e))?;
+
+    match app.archive_keep_versions {
+        Some(keep) => println!(
+            "{} now keeps {} versions out of the archive",
+            app.name, keep
+        ),
+        None => println!("{} is no longer archived", app.name),
+    }
+    Ok(Json(app))
+}
+
+/// Download an archived release
+///
+/// Redirects to a presigned URL of the artifact in the archive bucket,
+/// valid for 15 minutes.
+#[utoipa::path(
+    get,
+    path = "/releases/{id}/archive",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    responses(
+        (status = 307, description = "Redirect to the archived artifact"),
+        (status = 404, description = "Release not found", body = ErrorBody),
+        (status = 409, description = "The release is not archived", body = ErrorBody)
+    )
+)]
+pub async fn download_archived(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Response> {
+    let release = scoped_release(&state, principal, id).await?;
+    let key = state
+        .archive
+        .as_ref()
+        .filter(|_| release.archived)
+        .and_then(|archive| Some((archive, archive.bucket.key_of(&release.url)?)));
+    let Some((archive, key)) = key else {
+        return Err(AppError::conflict(
+            "The release is not in the archive bucket",
+        ));
+    };
+    let url = archive
+        .bucket
+        .presign_get(&key, DOWNLOAD_LINK_TTL)
+        .map_err(|e| AppError::internal("Failed to sign the archive download", e))?;
+    println!("Handing out an archive download of release {}", release.id);
+    Ok(Redirect::temporary(&url).into_response())
+}
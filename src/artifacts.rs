@@ -0,0 +1,100 @@
+//! Content-addressed artifact bookkeeping.
+//!
+//! Stored binaries are keyed by their organization and SHA-256, so identical
+//! bytes uploaded by two organizations are stored twice and neither's
+//! download URL or deletion depends on the other. Within an organization,
+//! every upload pointing at an artifact holds one reference; the stored
+//! asset is only deleted once the last one goes away.
+//!
+//! Lookups and reference counts take the app an upload belongs to and work
+//! on the artifacts of its organization.
+
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite, SqliteConnection};
+
+use crate::schema::{ARTIFACT_COLUMNS, Artifact};
+
+/// The organization owning the app bound to `?1`.
+const APP_ORG: &str = "(SELECT org_id FROM apps WHERE name = ?1)";
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+pub async fn find(
+    pool: &Pool<Sqlite>,
+    app_name: &str,
+    sha256: &str,
+) -> Result<Option<Artifact>, sqlx::Error> {
+    sqlx::query_as::<_, Artifact>(&format!(
+        "SELECT {} FROM artifacts WHERE org_id = {} AND sha256 = ?2",
+        ARTIFACT_COLUMNS, APP_ORG
+    ))
+    .bind(app_name)
+    .bind(sha256)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Records one more reference to the artifact, registering it on first use.
+/// The stored location is updated, for bytes published again after they
+/// were archived.
+pub async fn retain(
+    conn: &mut SqliteConnection,
+    app_name: &str,
+    sha256: &str,
+    url: &str,
+    size: i64,
+    github_asset_id: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "INSERT INTO artifacts ({}) VALUES ({}, ?2, ?3, ?4, ?5, 1)
+         ON CONFLICT(org_id, sha256) DO UPDATE SET ref_count = ref_count + 1, url = excluded.url, github_asset_id = excluded.github_asset_id",
+        ARTIFACT_COLUMNS, APP_ORG
+    ))
+    .bind(app_name)
+    .bind(sha256)
+    .bind(url)
+    .bind(size)
+    .bind(github_asset_id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Drops one reference. Returns the artifact when that was the last one, in
+/// which case its row is gone and the caller should delete the stored bytes.
+/// Organizations that shared one asset before artifacts were kept apart
+/// still point at the same URL, which is then left for the others.
+pub async fn release(
+    conn: &mut SqliteConnection,
+    app_name: &str,
+    sha256: &str,
+) -> Result<Option<Artifact>, sqlx::Error> {
+    let artifact = sqlx::query_as::<_, Artifact>(&format!(
+        "UPDATE artifacts SET ref_count = ref_count - 1 WHERE org_id = {} AND sha256 = ?2
+         RETURNING {}",
+        APP_ORG, ARTIFACT_COLUMNS
+    ))
+    .bind(app_name)
+    .bind(sha256)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    match artifact {
+        Some(a) if a.ref_count <= 0 => {
+            sqlx::query("DELETE FROM artifacts WHERE org_id = ? AND sha256 = ?")
+                .bind(a.org_id)
+                .bind(sha256)
+                .execute(&mut *conn)
+                .await?;
+            let shared: bool =
+                sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM artifacts WHERE url = ?)")
+                    .bind(&a.url)
+                    .fetch_one(conn)
+                    .await?;
+            Ok((!shared).then_some(a))
+        }
+        _ => Ok(None),
+    }
+}
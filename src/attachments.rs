@@ -0,0 +1,350 @@
+//! Release-note attachments.
+//!
+//! Screenshots and other small images can be uploaded for a release and
+//! referenced from its Markdown notes by the URL this server serves them
+//! at, `/attachments/{release_id}/{name}`, so an in-app changelog shows them
+//! without an external image host. The bytes are stored like any other
+//! artifact; the served URL redirects there.
+
+use axum::{
+    Extension,
+    extract::{Multipart, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Json, Redirect, Response},
+};
+use chrono::Utc;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+
+use crate::api_version;
+use crate::artifacts;
+use crate::auth::Principal;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::quotas;
+use crate::routes::{delete_orphaned, release_tag, store_artifact};
+use crate::schema::{
+    AppState, Artifact, AttachmentUploadForm, RELEASE_ATTACHMENT_COLUMNS, ReleaseAttachment,
+};
+use crate::variants::scoped_release;
+
+/// Largest attachment accepted, in bytes.
+const MAX_ATTACHMENT_SIZE: usize = 2 * 1024 * 1024;
+
+/// Most attachments a release can have.
+const MAX_ATTACHMENTS: i64 = 32;
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// The image type of `data`, from its signature rather than what the
+/// uploader claims. SVG isn't accepted, as it can carry scripts.
+fn image_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+fn served(state: &AppState, mut attachment: ReleaseAttachment) -> ReleaseAttachment {
+    attachment.url = format!(
+        "{}{}/attachments/{}/{}",
+        state.config.public_url,
+        api_version::PREFIX,
+        attachment.release_id,
+        attachment.name
+    );
+    attachment
+}
+
+async fn load_attachments(
+    pool: &Pool<Sqlite>,
+    release_id: i64,
+) -> Result<Vec<ReleaseAttachment>, sqlx::Error> {
+    sqlx::query_as::<_, ReleaseAttachment>(&format!(
+        "SELECT {} FROM release_attachments WHERE release_id = ? ORDER BY name",
+        RELEASE_ATTACHMENT_COLUMNS
+    ))
+    .bind(release_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Removes every attachment of a release, returning their artifacts for the
+/// caller to release.
+pub async fn remove_attachments(
+    conn: &mut SqliteConnection,
+    release_id: i64,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar("DELETE FROM release_attachments WHERE release_id = ? RETURNING sha256")
+        .bind(release_id)
+        .fetch_all(conn)
+        .await
+}
+
+/// Attach an image to a release
+///
+/// Replaces the attachment if the release already has one by that name.
+/// PNG, JPEG, GIF and WebP images up to 2 MiB are accepted.
+#[utoipa::path(
+    post,
+    path = "/releases/{id}/attachments/{name}",
+    params(
+        ("id" = i64, Path, description = "Release ID"),
+        ("name" = String, Path, description = "Attachment name: letters, digits, '_', '-' and '.'")
+    ),
+    request_body(content = AttachmentUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "Attachment stored", body = ReleaseAttachment),
+        (status = 400, description = "Invalid name, no file, or the file isn't a supported image", body = ErrorBody),
+        (status = 404, description = "Release not found", body = ErrorBody),
+        (status = 409, description = "The release already has the most attachments allowed", body = ErrorBody),
+        (status = 413, description = "The image is too large or doesn't fit the storage quota", body = ErrorBody),
+        (status = 429, description = "The daily upload quota is used up", body = ErrorBody)
+    )
+)]
+pub async fn upload_attachment(
+    Path((id, name)): Path<(i64, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    mut multipart: Multipart,
+) -> AppResult<(StatusCode, Json<ReleaseAttachment>)> {
+    if !is_valid_name(&name) {
+        return Err(AppError::bad_request(
+            "Attachment names must be 1-64 letters, digits, '_', '-' or '.', not starting with '.'",
+        ));
+    }
+    let mut file_data: Vec<u8> = Vec::new();
+    while let Some(res) = multipart.next_field().await.transpose() {
+        let field =
+            res.map_err(|e| AppError::bad_request(format!("Malformed multipart body: {}", e)))?;
+        if field.name() == Some("file") {
+            file_data = field
+                .bytes()
+                .await
+                .map_err(|e| AppError::bad_request(format!("Failed to read file: {}", e)))?
+                .to_vec();
+        }
+    }
+    if file_data.is_empty() {
+        return Err(AppError::bad_request("No file uploaded or file is empty"));
+    }
+    if file_data.len() > MAX_ATTACHMENT_SIZE {
+        return Err(AppError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            format!(
+                "Attachments can be at most {} KiB",
+                MAX_ATTACHMENT_SIZE / 1024
+            ),
+        ));
+    }
+    let content_type = image_type(&file_data).ok_or_else(|| {
+        AppError::bad_request("Attachments must be PNG, JPEG, GIF or WebP images")
+    })?;
+    let release = scoped_release(&state, principal, id).await?;
+
+    let count: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM release_attachments WHERE release_id = ? AND name != ?",
+    )
+    .bind(release.id)
+    .bind(&name)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to count attachments", e))?;
+    if count >= MAX_ATTACHMENTS {
+        return Err(AppError::conflict(format!(
+            "A release can have at most {} attachments",
+            MAX_ATTACHMENTS
+        )));
+    }
+
+    let sha256 = artifacts::sha256_hex(&file_data);
+    let size = file_data.len() as i64;
+    quotas::check_upload(&state, &release.app_name, &sha256, size, false).await?;
+    // Stored next to the release's own artifact, under a name that changes
+    // with the content so a replacement doesn't collide with the old asset.
+    let (sha256, size, storage_url, github_asset_id) = store_artifact(
+        &state,
+        &release.app_name,
+        sha256.clone(),
+        &release_tag(&release.app_name, &release.version),
+        "",
+        &format!("{}-{}", &sha256[..12], name),
+        file_data,
+    )
+    .await?;
+
+    let saved: Result<Result<(ReleaseAttachment, Option<Artifact>), AppError>, sqlx::Error> =
+        async {
+            let mut tx = state.pool.begin().await?;
+            if let Some(refused) = quotas::record_upload(
+                &mut tx,
+                &state.config,
+                &release.app_name,
+                &sha256,
+                size,
+                false,
+            )
+            .await?
+            {
+                return Ok(Err(refused));
+            }
+            artifacts::retain(
+                &mut tx,
+                &release.app_name,
+                &sha256,
+                &storage_url,
+                size,
+                github_asset_id,
+            )
+            .await?;
+            let replaced: Option<String> = sqlx::query_scalar(
+            "DELETE FROM release_attachments WHERE release_id = ? AND name = ? RETURNING sha256",
+        )
+        .bind(release.id)
+        .bind(&name)
+        .fetch_optional(&mut *tx)
+        .await?;
+            let attachment = sqlx::query_as::<_, ReleaseAttachment>(&format!(
+                "INSERT INTO release_attachments ({0}) VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING {0}",
+                RELEASE_ATTACHMENT_COLUMNS
+            ))
+            .bind(release.id)
+            .bind(&name)
+            .bind(content_type)
+            .bind(&storage_url)
+            .bind(&sha256)
+            .bind(size)
+            .bind(Utc::now())
+            .fetch_one(&mut *tx)
+            .await?;
+            let orphaned = match replaced {
+                Some(old) => artifacts::release(&mut tx, &release.app_name, &old).await?,
+                None => None,
+            };
+            tx.commit().await?;
+            Ok(Ok((attachment, orphaned)))
+        }
+        .await;
+    let (attachment, orphaned) =
+        saved.map_err(|e| AppError::internal("Failed to save attachment", e))??;
+    delete_orphaned(&state, orphaned).await;
+
+    println!("Attached {} to release {}", attachment.name, release.id);
+    Ok((StatusCode::CREATED, Json(served(&state, attachment))))
+}
+
+/// List a release's attachments
+#[utoipa::path(
+    get,
+    path = "/releases/{id}/attachments",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    responses(
+        (status = 200, description = "Attachments of the release", body = Vec<ReleaseAttachment>),
+        (status = 404, description = "Release not found", body = ErrorBody)
+    )
+)]
+pub async fn list_attachments(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<ReleaseAttachment>>> {
+    let release = scoped_release(&state, principal, id).await?;
+    let attachments = load_attachments(&state.read_pool, release.id)
+        .await
+        .map_err(|e| AppError::internal("Failed to load attachments", e))?;
+    Ok(Json(
+        attachments.into_iter().map(|a| served(&state, a)).collect(),
+    ))
+}
+
+/// Remove an attachment from a release
+#[utoipa::path(
+    delete,
+    path = "/releases/{id}/attachments/{name}",
+    params(
+        ("id" = i64, Path, description = "Release ID"),
+        ("name" = String, Path, description = "Attachment name")
+    ),
+    responses(
+        (status = 204, description = "Attachment removed"),
+        (status = 404, description = "Release or attachment not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_attachment(
+    Path((id, name)): Path<(i64, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let release = scoped_release(&state, principal, id).await?;
+    let removed: Result<Option<Option<Artifact>>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        let Some(sha256) = sqlx::query_scalar::<_, String>(
+            "DELETE FROM release_attachments WHERE release_id = ? AND name = ? RETURNING sha256",
+        )
+        .bind(release.id)
+        .bind(&name)
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let orphaned = artifacts::release(&mut tx, &release.app_name, &sha256).await?;
+        tx.commit().await?;
+        Ok(Some(orphaned))
+    }
+    .await;
+    let orphaned = removed
+        .map_err(|e| AppError::internal("Failed to remove attachment", e))?
+        .ok_or_else(|| AppError::not_found("Attachment not found"))?;
+    delete_orphaned(&state, orphaned).await;
+
+    println!("Removed attachment {} of release {}", name, release.id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Get a release-note attachment
+///
+/// The URL release notes reference an attachment by; redirects to the
+/// stored image.
+#[utoipa::path(
+    get,
+    path = "/attachments/{release_id}/{name}",
+    params(
+        ("release_id" = i64, Path, description = "Release ID"),
+        ("name" = String, Path, description = "Attachment name")
+    ),
+    responses(
+        (status = 307, description = "Redirect to the stored image"),
+        (status = 404, description = "Attachment not found", body = ErrorBody)
+    )
+)]
+pub async fn get_attachment(
+    Path((release_id, name)): Path<(i64, String)>,
+    State(state): State<AppState>,
+) -> AppResult<Response> {
+    let storage_url: String = sqlx::query_scalar(
+        "SELECT storage_url FROM release_attachments WHERE release_id = ? AND name = ?",
+    )
+    .bind(release_id)
+    .bind(&name)
+    .fetch_optional(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to look up attachment", e))?
+    .ok_or_else(|| AppError::not_found("Attachment not found"))?;
+    Ok(Redirect::temporary(&storage_url).into_response())
+}
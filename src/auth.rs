@@ -0,0 +1,295 @@
+//! Authentication and tenant scoping for the admin API.
+//!
+//! Every admin route needs `Authorization: Bearer <token>`. Tokens are
+//! issued per organization and only reach that organization's apps,
+//! releases, webhooks, subscriptions and stats. `ADMIN_TOKEN` is the
+//! operator token: it manages organizations and sees all of them.
+//!
+//! App names stay global because installed apps check for updates at
+//! `/{app_name}/...` without credentials. An app belongs to the organization
+//! that first uploads, subscribes or hooks into it (see [`claim_app`]);
+//! other organizations can't use the name after that.
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+
+use crate::error::{AppError, AppResult};
+use crate::schema::AppState;
+
+/// Owner of everything that predates organizations, and of apps the
+/// operator token creates.
+pub const DEFAULT_ORG_ID: i64 = 1;
+
+/// Issued tokens start with this, so they are easy to spot in leaked logs.
+const TOKEN_PREFIX: &str = "arm_";
+
+/// `last_used_at` is only rewritten when it is older than this, so busy
+/// tokens don't turn every admin read into a write.
+const LAST_USED_RESOLUTION: chrono::Duration = chrono::Duration::minutes(1);
+
+/// Who an admin request acts for: added to the request extensions by
+/// [`require_token`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Principal {
+    Operator,
+    Org(i64),
+}
+
+impl Principal {
+    /// Organization the caller is limited to; `None` for the operator.
+    pub fn org_id(self) -> Option<i64> {
+        match self {
+            Principal::Operator => None,
+            Principal::Org(id) => Some(id),
+        }
+    }
+
+    pub fn can_see(self, org_id: i64) -> bool {
+        self.org_id().is_none_or(|id| id == org_id)
+    }
+
+    /// Organization a write acts for: the caller's own, or the `requested`
+    /// one (default organization if none) when the operator makes it.
+    pub fn target_org(self, requested: Option<i64>) -> AppResult<i64> {
+        match (self, requested) {
+            (Principal::Operator, requested) => Ok(requested.unwrap_or(DEFAULT_ORG_ID)),
+            (Principal::Org(id), Some(requested)) if requested != id => Err(AppError::forbidden(
+                "Tokens can only act for their own organization",
+            )),
+            (Principal::Org(id), _) => Ok(id),
+        }
+    }
+
+    pub fn require_operator(self) -> AppResult<()> {
+        match self {
+            Principal::Operator => Ok(()),
+            Principal::Org(_) => Err(AppError::forbidden("Requires the operator token")),
+        }
+    }
+}
+
+/// SQL condition limiting a row with an `app_name` column to the apps of the
+/// organization bound at `?{param}`. Binding `NULL` (the operator) matches
+/// everything.
+pub fn app_scope(param: u8) -> String {
+    format!(
+        "(?{0} IS NULL OR app_name IN (SELECT name FROM apps WHERE org_id = ?{0}))",
+        param
+    )
+}
+
+/// Like [`app_scope`], for rows with an `org_id` column.
+pub fn org_scope(param: u8) -> String {
+    format!("(?{0} IS NULL OR org_id = ?{0})", param)
+}
+
+/// 32 random bytes, hex-encoded.
+pub fn random_token() -> AppResult<String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| AppError::internal("Failed to generate a token", e))?;
+    Ok(hex::encode(bytes))
+}
+
+/// A fresh API token secret.
+pub fn new_api_token() -> AppResult<String> {
+    Ok(format!("{}{}", TOKEN_PREFIX, random_token()?))
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Registers `app_name` to the caller's organization if nobody owns it yet,
+/// and fails if another organization does.
+pub async fn claim_app(pool: &Pool<Sqlite>, principal: Principal, app_name: &str) -> AppResult<()> {
+    let claimant = principal.org_id().unwrap_or(DEFAULT_ORG_ID);
+    sqlx::query("INSERT INTO apps (name, org_id, created_at) VALUES (?, ?, ?) ON CONFLICT (name) DO NOTHING")
+        .bind(app_name)
+        .bind(claimant)
+        .bind(Utc::now())
+        .execute(pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to register app", e))?;
+
+    let owner: i64 = sqlx::query_scalar("SELECT org_id FROM apps WHERE name = ?")
+        .bind(app_name)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to register app", e))?;
+    if !principal.can_see(owner) {
+        return Err(AppError::forbidden(format!(
+            "App '{}' belongs to another organization",
+            app_name
+        )));
+    }
+    Ok(())
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+/// The [`Principal`] of the bearer token in `headers`.
+pub async fn authenticate(state: &AppState, headers: &HeaderMap) -> AppResult<Principal> {
+    let token =
+        bearer_token(headers).ok_or_else(|| AppError::unauthorized("Missing bearer token"))?;
+    let hash = hash_token(token);
+    // Comparing digests keeps the comparison time independent of how much
+    // of the operator token a guess gets right.
+    if state
+        .config
+        .admin_token
+        .as_deref()
+        .is_some_and(|admin| hash_token(admin) == hash)
+    {
+        return Ok(Principal::Operator);
+    }
+
+    // The primary, not the read pool: a revoked token must stop working
+    // at once, not when the replica catches up.
+    let row: Option<(i64, i64, Option<DateTime<Utc>>)> =
+        sqlx::query_as("SELECT id, org_id, last_used_at FROM api_tokens WHERE token_hash = ?")
+            .bind(&hash)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(|e| AppError::internal("Failed to check token", e))?;
+    let Some((id, org_id, last_used_at)) = row else {
+        return Err(AppError::unauthorized("Invalid or revoked token"));
+    };
+
+    let now = Utc::now();
+    if last_used_at.is_none_or(|t| now - t > LAST_USED_RESOLUTION)
+        && let Err(e) = sqlx::query("UPDATE api_tokens SET last_used_at = ? WHERE id = ?")
+            .bind(now)
+            .bind(id)
+            .execute(&state.pool)
+            .await
+    {
+        println!("Failed to record use of token {}: {}", id, e);
+    }
+    Ok(Principal::Org(org_id))
+}
+
+/// Middleware for the admin routes: rejects requests without a valid token
+/// before their bodies are read, and hands the [`Principal`] to handlers.
+pub async fn require_token(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match authenticate(&state, request.headers()).await {
+        Ok(principal) => {
+            request.extensions_mut().insert(principal);
+            next.run(request).await
+        }
+        Err(e) if e.status() == StatusCode::UNAUTHORIZED => {
+            ([(header::WWW_AUTHENTICATE, "Bearer")], e).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    async fn add_org(pool: &Pool<Sqlite>, id: i64) {
+        sqlx::query("INSERT INTO organizations (id, slug, name, created_at) VALUES (?, ?, ?, ?)")
+            .bind(id)
+            .bind(format!("org-{}", id))
+            .bind(format!("Org {}", id))
+            .bind(Utc::now())
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn owner(pool: &Pool<Sqlite>, app_name: &str) -> i64 {
+        sqlx::query_scalar("SELECT org_id FROM apps WHERE name = ?")
+            .bind(app_name)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn claim_app_keeps_apps_with_their_first_organization() {
+        let pool = db::memory_pool().await;
+        add_org(&pool, 2).await;
+
+        claim_app(&pool, Principal::Org(2), "acme").await.unwrap();
+        assert_eq!(owner(&pool, "acme").await, 2);
+        claim_app(&pool, Principal::Org(2), "acme").await.unwrap();
+
+        let refused = claim_app(&pool, Principal::Org(1), "acme")
+            .await
+            .unwrap_err();
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+        assert_eq!(owner(&pool, "acme").await, 2);
+
+        claim_app(&pool, Principal::Operator, "acme").await.unwrap();
+        assert_eq!(owner(&pool, "acme").await, 2);
+        claim_app(&pool, Principal::Operator, "fresh")
+            .await
+            .unwrap();
+        assert_eq!(owner(&pool, "fresh").await, DEFAULT_ORG_ID);
+    }
+
+    #[tokio::test]
+    async fn app_scope_limits_rows_to_the_organization() {
+        let pool = db::memory_pool().await;
+        add_org(&pool, 2).await;
+        claim_app(&pool, Principal::Org(1), "classprime")
+            .await
+            .unwrap();
+        claim_app(&pool, Principal::Org(2), "acme").await.unwrap();
+
+        let sql = format!(
+            "SELECT app_name FROM (SELECT name AS app_name FROM apps) WHERE {} ORDER BY app_name",
+            app_scope(1)
+        );
+        let visible = |org_id: Option<i64>| {
+            let (pool, sql) = (&pool, &sql);
+            async move {
+                sqlx::query_scalar::<_, String>(sql)
+                    .bind(org_id)
+                    .fetch_all(pool)
+                    .await
+                    .unwrap()
+            }
+        };
+        assert_eq!(visible(Some(1)).await, ["classprime"]);
+        assert_eq!(visible(Some(2)).await, ["acme"]);
+        assert!(visible(Some(3)).await.is_empty());
+        assert_eq!(visible(None).await, ["acme", "classprime"]);
+    }
+
+    #[test]
+    fn tokens_only_act_for_their_own_organization() {
+        assert_eq!(
+            Principal::Operator.target_org(None).unwrap(),
+            DEFAULT_ORG_ID
+        );
+        assert_eq!(Principal::Operator.target_org(Some(3)).unwrap(), 3);
+        assert_eq!(Principal::Org(2).target_org(None).unwrap(), 2);
+        assert_eq!(Principal::Org(2).target_org(Some(2)).unwrap(), 2);
+        let refused = Principal::Org(2).target_org(Some(3)).unwrap_err();
+        assert_eq!(refused.status(), StatusCode::FORBIDDEN);
+
+        assert!(Principal::Operator.can_see(2));
+        assert!(Principal::Org(2).can_see(2));
+        assert!(!Principal::Org(2).can_see(1));
+    }
+}
@@ -0,0 +1,445 @@
+//! Authenticode signing of Windows artifacts.
+//!
+//! With `AUTHENTICODE_COMMAND` or `AUTHENTICODE_SERVICE_URL` set, every
+//! Windows upload passes a signing stage before it's stored, on any channel,
+//! so a test build signed by nobody can't be promoted to stable later:
+//!
+//! - `.exe` and `.msi` files that aren't signed yet are signed, either by
+//!   running the command, such as `osslsigncode sign -pkcs12 cert.p12
+//!   -readpass pass.txt -t http://timestamp.digicert.com -in {input} -out
+//!   {output}`, or by POSTing the file to the service, which answers with the
+//!   signed file.
+//! - Zipped installers can't be signed in place, so each `.exe` and `.msi`
+//!   inside must already be signed.
+//! - Other Windows artifacts are refused.
+//!
+//! Signing changes the bytes an updater signature covers, so CI that signs
+//! for the updater sends the installer to `POST /authenticode` first and signs
+//! what comes back; the upload then finds it signed and keeps it as it is.
+//! That endpoint signs with the server's certificate, so it takes the app
+//! the installer belongs to and only signs for the organization owning it,
+//! as an upload of it would.
+
+use std::io::Read;
+use std::time::Duration;
+
+use axum::{
+    Extension,
+    extract::{Multipart, State},
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use flate2::read::DeflateDecoder;
+
+use crate::auth::{self, Principal};
+use crate::config::Config;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::http_client::HttpClient;
+use crate::schema::{AppState, AuthenticodeForm};
+
+/// Largest installer unpacked from a zip to check its signature.
+const MAX_ENTRY_BYTES: u64 = 4 << 30;
+
+const MSI_MAGIC: &[u8] = &[0xd0, 0xcf, 0x11, 0xe0, 0xa1, 0xb1, 0x1a, 0xe1];
+
+enum Backend {
+    /// Program and arguments, with `{input}` and `{output}` placeholders.
+    Command(Vec<String>),
+    Service {
+        url: String,
+        token: Option<String>,
+    },
+}
+
+pub struct Signer {
+    backend: Backend,
+    timeout: Duration,
+    client: HttpClient,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Exe,
+    Msi,
+    Zip,
+    Other,
+}
+
+fn kind(file_name: &str) -> Kind {
+    let lower = file_name.to_ascii_lowercase();
+    if lower.ends_with(".exe") {
+        Kind::Exe
+    } else if lower.ends_with(".msi") {
+        Kind::Msi
+    } else if lower.ends_with(".zip") {
+        Kind::Zip
+    } else {
+        Kind::Other
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Whether a PE file has a certificate table; `None` if it isn't a PE file.
+fn pe_is_signed(data: &[u8]) -> Option<bool> {
+    if data.get(..2)? != b"MZ" {
+        return None;
+    }
+    let pe = u32_at(data, 0x3c)? as usize;
+    if data.get(pe..pe + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let optional = pe + 24;
+    let directories = match u16_at(data, optional)? {
+        0x10b => optional + 96,
+        0x20b => optional + 112,
+        _ => return None,
+    };
+    // The certificate table is data directory 4.
+    if u32_at(data, directories - 4)? < 5 {
+        return Some(false);
+    }
+    Some(u32_at(data, directories + 4 * 8 + 4)? != 0)
+}
+
+/// Whether an MSI has a `\x05DigitalSignature` stream; `None` if it isn't
+/// an MSI.
+fn msi_is_signed(data: &[u8]) -> Option<bool> {
+    if !data.starts_with(MSI_MAGIC) {
+        return None;
+    }
+    let name: Vec<u8> = "\u{5}DigitalSignature"
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    Some(data.windows(name.len()).any(|w| w == name.as_slice()))
+}
+
+fn is_signed(kind: Kind, data: &[u8]) -> Option<bool> {
+    match kind {
+        Kind::Exe => pe_is_signed(data),
+        Kind::Msi => msi_is_signed(data),
+        _ => None,
+    }
+}
+
+/// Installers in a zip that aren't signed.
+fn unsigned_in_zip(data: &[u8]) -> Result<Vec<String>, String> {
+    const EOCD_SIGNATURE: u32 = 0x06054b50;
+    let search_from = data.len().saturating_sub(22 + u16::MAX as usize);
+    let eocd = (search_from..data.len().saturating_sub(21))
+        .rev()
+        .find(|&i| u32_at(data, i) == Some(EOCD_SIGNATURE))
+        .ok_or("not a zip archive")?;
+    let malformed = || "malformed zip archive".to_string();
+    let entries = u16_at(data, eocd + 10).ok_or_else(malformed)?;
+    let mut offset = u32_at(data, eocd + 16).ok_or_else(malformed)? as usize;
+    if entries == u16::MAX || offset == u32::MAX as usize {
+        return Err("zip64 archives are not supported".to_string());
+    }
+
+    let mut unsigned = Vec::new();
+    for _ in 0..entries {
+        if u32_at(data, offset) != Some(0x02014b50) {
+            return Err(malformed());
+        }
+        let method = u16_at(data, offset + 10).ok_or_else(malformed)?;
+        let compressed = u32_at(data, offset + 20).ok_or_else(malformed)? as usize;
+        let name_len = u16_at(data, offset + 28).ok_or_else(malformed)? as usize;
+        let extra_len = u16_at(data, offset + 30).ok_or_else(malformed)? as usize;
+        let comment_len = u16_at(data, offset + 32).ok_or_else(malformed)? as usize;
+        let local = u32_at(data, offset + 42).ok_or_else(malformed)? as usize;
+        let name = data
+            .get(offset + 46..offset + 46 + name_len)
+            .ok_or_else(malformed)?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        offset += 46 + name_len + extra_len + comment_len;
+
+        let entry_kind = kind(&name);
+        if !matches!(entry_kind, Kind::Exe | Kind::Msi) {
+            continue;
+        }
+        if u32_at(data, local) != Some(0x04034b50) {
+            return Err(malformed());
+        }
+        let start = local
+            + 30
+            + u16_at(data, local + 26).ok_or_else(malformed)? as usize
+            + u16_at(data, local + 28).ok_or_else(malformed)? as usize;
+        let raw = data.get(start..start + compressed).ok_or_else(malformed)?;
+        let contents = match method {
+            0 => raw.to_vec(),
+            8 => {
+                let mut out = Vec::new();
+                DeflateDecoder::new(raw)
+                    .take(MAX_ENTRY_BYTES)
+                    .read_to_end(&mut out)
+                    .map_err(|e| format!("failed to unpack {}: {}", name, e))?;
+                out
+            }
+            _ => return Err(format!("{} uses an unsupported compression method", name)),
+        };
+        if is_signed(entry_kind, &contents) != Some(true) {
+            unsigned.push(name);
+        }
+    }
+    Ok(unsigned)
+}
+
+impl Signer {
+    pub fn from_config(config: &Config) -> std::io::Result<Option<Self>> {
+        let backend = match (
+            &config.authenticode_command,
+            &config.authenticode_service_url,
+        ) {
+            (Some(command), _) => {
+                let args: Vec<String> = command.split_whitespace().map(String::from).collect();
+                if args.is_empty() {
+                    return Ok(None);
+                }
+                Backend::Command(args)
+            }
+            (None, Some(url)) => Backend::Service {
+                url: url.clone(),
+                token: config.authenticode_service_token.clone(),
+            },
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Self {
+            backend,
+            timeout: config.authenticode_timeout,
+            client: HttpClient::new(config.authenticode_timeout)?,
+        }))
+    }
+
+    async fn sign(&self, file_name: &str, kind: Kind, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        let signed = match &self.backend {
+            Backend::Command(args) => self.run_command(args, kind, data).await?,
+            Backend::Service { url, token } => {
+                let authorization = token.as_ref().map(|t| format!("Bearer {}", t));
+                let mut headers = vec![("X-File-Name", file_name)];
+                if let Some(authorization) = &authorization {
+                    headers.push(("Authorization", authorization.as_str()));
+                }
+                let limit = data.len() * 2 + (16 << 20);
+                let (status, body) = self.client.post_bytes(url, &headers, data, limit).await?;
+                if !status.is_success() {
+                    return Err(format!("signing service answered {}", status));
+                }
+                body.to_vec()
+            }
+        };
+        match is_signed(kind, &signed) {
+            Some(true) => Ok(signed),
+            _ => Err("the signer returned a file without a signature".to_string()),
+        }
+    }
+
+    async fn run_command(
+        &self,
+        args: &[String],
+        kind: Kind,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, String> {
+        let extension = if kind == Kind::Msi { "msi" } else { "exe" };
+        let token = auth::random_token().map_err(|e| e.to_string())?;
+        let dir = std::env::temp_dir().join(format!("updater-sign-{}", token));
+        tokio::fs::create_dir(&dir)
+            .await
+            .map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+        let input = dir.join(format!("input.{}", extension));
+        let output = dir.join(format!("output.{}", extension));
+
+        let result = async {
+            tokio::fs::write(&input, &data)
+                .await
+                .map_err(|e| format!("failed to write {}: {}", input.display(), e))?;
+            let args: Vec<String> = args
+                .iter()
+                .map(|a| {
+                    a.replace("{input}", &input.to_string_lossy())
+                        .replace("{output}", &output.to_string_lossy())
+                })
+                .collect();
+            let run = tokio::process::Command::new(&args[0])
+                .args(&args[1..])
+                .kill_on_drop(true)
+                .output();
+            let finished = tokio::time::timeout(self.timeout, run)
+                .await
+                .map_err(|_| format!("{} timed out", args[0]))?
+                .map_err(|e| format!("failed to run {}: {}", args[0], e))?;
+            if !finished.status.success() {
+                let stderr = String::from_utf8_lossy(&finished.stderr);
+                let mut message = format!("{} exited with {}", args[0], finished.status);
+                if !stderr.trim().is_empty() {
+                    message = format!(
+                        "{}: {}",
+                        message,
+                        stderr.trim().chars().take(500).collect::<String>()
+                    );
+                }
+                return Err(message);
+            }
+            tokio::fs::read(&output)
+                .await
+                .map_err(|e| format!("failed to read {}: {}", output.display(), e))
+        }
+        .await;
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        result
+    }
+}
+
+fn signing_failed(file_name: &str, e: String) -> AppError {
+    println!("Authenticode signing of {} failed: {}", file_name, e);
+    AppError::new(
+        StatusCode::BAD_GATEWAY,
+        "signing_failed",
+        format!("Signing {} failed: {}", file_name, e),
+    )
+}
+
+/// Passes a Windows artifact through the signing stage, if one is
+/// configured; other targets keep their bytes.
+pub async fn prepare(
+    state: &AppState,
+    target: &str,
+    file_name: &str,
+    data: Vec<u8>,
+) -> AppResult<Vec<u8>> {
+    let Some(signer) = &state.signer else {
+        return Ok(data);
+    };
+    if target != "windows" {
+        return Ok(data);
+    }
+    match kind(file_name) {
+        Kind::Exe | Kind::Msi => sign_if_unsigned(signer, file_name, data).await,
+        Kind::Zip => {
+            let unsigned = unsigned_in_zip(&data)
+                .map_err(|e| AppError::bad_request(format!("Cannot check {}: {}", file_name, e)))?;
+            if !unsigned.is_empty() {
+                return Err(AppError::bad_request(format!(
+                    "{} in {} must be Authenticode-signed before zipping, e.g. through POST /authenticode",
+                    unsigned.join(", "),
+                    file_name
+                )));
+            }
+            Ok(data)
+        }
+        Kind::Other => Err(AppError::bad_request(format!(
+            "{} can't be Authenticode-signed; Windows artifacts must be .exe, .msi or a zip of them",
+            file_name
+        ))),
+    }
+}
+
+async fn sign_if_unsigned(signer: &Signer, file_name: &str, data: Vec<u8>) -> AppResult<Vec<u8>> {
+    let kind = kind(file_name);
+    match is_signed(kind, &data) {
+        Some(true) => Ok(data),
+        Some(false) => {
+            let signed = signer
+                .sign(file_name, kind, data)
+                .await
+                .map_err(|e| signing_failed(file_name, e))?;
+            println!("Authenticode-signed {}", file_name);
+            Ok(signed)
+        }
+        None => Err(AppError::bad_request(format!(
+            "{} is not a Windows executable or installer",
+            file_name
+        ))),
+    }
+}
+
+/// Authenticode-sign an installer
+///
+/// Returns the `.exe` or `.msi` as a Windows upload of `app_name` would
+/// store it, for CI to sign for the updater before uploading. Signed files
+/// come back unchanged.
+#[utoipa::path(
+    post,
+    path = "/authenticode",
+    request_body(content = AuthenticodeForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "The signed file", content_type = "application/octet-stream"),
+        (status = 400, description = "No app name or file, or not an .exe or .msi", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody),
+        (status = 404, description = "Signing isn't configured", body = ErrorBody),
+        (status = 502, description = "The signing command or service failed", body = ErrorBody)
+    )
+)]
+pub async fn sign_artifact(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    mut multipart: Multipart,
+) -> AppResult<Response> {
+    let Some(signer) = &state.signer else {
+        return Err(AppError::not_found("Authenticode signing isn't configured"));
+    };
+    let mut app_name = String::new();
+    let mut file_name = String::new();
+    let mut file_data = Vec::new();
+    while let Some(res) = multipart.next_field().await.transpose() {
+        let field =
+            res.map_err(|e| AppError::bad_request(format!("Malformed multipart body: {}", e)))?;
+        match field.name().unwrap_or_default() {
+            "app_name" => app_name = field.text().await.unwrap_or_default(),
+            "file" => {
+                file_name = field.file_name().unwrap_or("installer").to_string();
+                file_data = field
+                    .bytes()
+                    .await
+                    .map_err(|e| AppError::bad_request(format!("Failed to read file: {}", e)))?
+                    .to_vec();
+            }
+            _ => (),
+        }
+    }
+    let app_name = app_name.trim();
+    if app_name.is_empty() {
+        return Err(AppError::bad_request("app_name is required"));
+    }
+    if file_data.is_empty() {
+        return Err(AppError::bad_request("No file uploaded or file is empty"));
+    }
+    if !matches!(kind(&file_name), Kind::Exe | Kind::Msi) {
+        return Err(AppError::bad_request(
+            "Only .exe and .msi files can be signed",
+        ));
+    }
+
+    auth::claim_app(&state.pool, principal, app_name).await?;
+
+    let signed = sign_if_unsigned(signer, &file_name, file_data).await?;
+    println!(
+        "Served Authenticode signing of {} for {}",
+        file_name, app_name
+    );
+    let disposition = HeaderValue::try_from(format!("attachment; filename=\"{}\"", file_name))
+        .map_err(|_| AppError::bad_request("File names must be plain text"))?;
+    Ok((
+        StatusCode::OK,
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        signed,
+    )
+        .into_response())
+}
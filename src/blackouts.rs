@@ -0,0 +1,238 @@
+//! Blackout windows.
+//!
+//! Schools don't want software changing mid-exams. While one of an app's
+//! blackout windows is open, update checks answer "no update" unless the
+//! release on offer is flagged critical. Downloads and `/latest` are not
+//! affected, so admins can still install by hand.
+
+use axum::{
+    Extension,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use sqlx::{Pool, Sqlite};
+
+use crate::auth::{self, Principal, app_scope};
+use crate::cache;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::schema::{
+    AppState, BLACKOUT_COLUMNS, Blackout, BlackoutFilter, BlackoutRequest, CriticalRequest,
+    RELEASE_COLUMNS, Release,
+};
+
+/// Reason of the blackout window open for `app_name` at `now`, if any.
+pub async fn active_blackout(
+    pool: &Pool<Sqlite>,
+    app_name: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT reason FROM blackouts WHERE app_name = ?1 AND starts_at <= ?2 AND ends_at > ?2 ORDER BY ends_at DESC LIMIT 1",
+    )
+    .bind(app_name)
+    .bind(now)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Parses a UTC offset such as `+02:00`; `UTC` and `Z` mean `+00:00`.
+fn parse_timezone(timezone: &str) -> AppResult<FixedOffset> {
+    match timezone {
+        "UTC" | "utc" | "Z" => Ok(FixedOffset::east_opt(0).expect("zero offset")),
+        _ => timezone.parse::<FixedOffset>().map_err(|_| {
+            AppError::bad_request(format!(
+                "timezone '{}' must be a UTC offset such as +02:00",
+                timezone
+            ))
+        }),
+    }
+}
+
+/// Turns a window given in local times at `timezone` (UTC if absent) into
+/// UTC instants, along with the offset it was given in.
+pub fn resolve_window(
+    starts_at: NaiveDateTime,
+    ends_at: NaiveDateTime,
+    timezone: Option<&str>,
+) -> AppResult<(DateTime<Utc>, DateTime<Utc>, FixedOffset)> {
+    let offset = parse_timezone(timezone.unwrap_or("UTC").trim())?;
+    // Fixed offsets map every local time to exactly one instant.
+    let to_utc = |local: NaiveDateTime| {
+        offset
+            .from_local_datetime(&local)
+            .single()
+            .map(|t| t.with_timezone(&Utc))
+    };
+    let (Some(starts_at), Some(ends_at)) = (to_utc(starts_at), to_utc(ends_at)) else {
+        return Err(AppError::bad_request(
+            "starts_at or ends_at is out of range",
+        ));
+    };
+    if ends_at <= starts_at {
+        return Err(AppError::bad_request("ends_at must be after starts_at"));
+    }
+    Ok((starts_at, ends_at, offset))
+}
+
+/// List blackout windows
+#[utoipa::path(
+    get,
+    path = "/blackouts",
+    params(BlackoutFilter),
+    responses(
+        (status = 200, description = "Current and upcoming windows, soonest first", body = Vec<Blackout>)
+    )
+)]
+pub async fn list_blackouts(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<BlackoutFilter>,
+) -> AppResult<Json<Vec<Blackout>>> {
+    let blackouts = sqlx::query_as::<_, Blackout>(&format!(
+        "SELECT {} FROM blackouts WHERE (?1 IS NULL OR app_name = ?1) AND (?2 OR ends_at > ?3) AND {} ORDER BY starts_at, id",
+        BLACKOUT_COLUMNS,
+        app_scope(4)
+    ))
+    .bind(&filter.app_name)
+    .bind(filter.include_past)
+    .bind(Utc::now())
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load blackout windows", e))?;
+
+    Ok(Json(blackouts))
+}
+
+/// Schedule a blackout window
+#[utoipa::path(
+    post,
+    path = "/blackouts",
+    request_body = BlackoutRequest,
+    responses(
+        (status = 201, description = "Window scheduled", body = Blackout),
+        (status = 400, description = "Invalid time range or timezone", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody)
+    )
+)]
+pub async fn create_blackout(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<BlackoutRequest>,
+) -> AppResult<(StatusCode, Json<Blackout>)> {
+    let app_name = request.app_name.trim();
+    if app_name.is_empty() {
+        return Err(AppError::bad_request("app_name is required"));
+    }
+    let (starts_at, ends_at, offset) = resolve_window(
+        request.starts_at,
+        request.ends_at,
+        request.timezone.as_deref(),
+    )?;
+
+    auth::claim_app(&state.pool, principal, app_name).await?;
+
+    let blackout = sqlx::query_as::<_, Blackout>(&format!(
+        "INSERT INTO blackouts (app_name, starts_at, ends_at, timezone, reason, created_at) VALUES (?, ?, ?, ?, ?, ?) RETURNING {}",
+        BLACKOUT_COLUMNS
+    ))
+    .bind(app_name)
+    .bind(starts_at)
+    .bind(ends_at)
+    .bind(offset.to_string())
+    .bind(request.reason.trim())
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to schedule blackout window", e))?;
+
+    println!(
+        "Scheduled blackout {} for {} from {} to {}",
+        blackout.id, blackout.app_name, blackout.starts_at, blackout.ends_at
+    );
+    Ok((StatusCode::CREATED, Json(blackout)))
+}
+
+/// Remove a blackout window
+#[utoipa::path(
+    delete,
+    path = "/blackouts/{id}",
+    params(
+        ("id" = i64, Path, description = "Blackout window ID")
+    ),
+    responses(
+        (status = 204, description = "Window removed"),
+        (status = 404, description = "Blackout window not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_blackout(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let deleted = sqlx::query(&format!(
+        "DELETE FROM blackouts WHERE id = ?1 AND {}",
+        app_scope(2)
+    ))
+    .bind(id)
+    .bind(principal.org_id())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to remove blackout window", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("Blackout window not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Flag a release as critical
+#[utoipa::path(
+    put,
+    path = "/releases/{id}/critical",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    request_body = CriticalRequest,
+    responses(
+        (status = 200, description = "Flag updated", body = Release),
+        (status = 404, description = "Release not found", body = ErrorBody)
+    )
+)]
+pub async fn set_release_critical(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<CriticalRequest>,
+) -> AppResult<Json<Release>> {
+    let release = sqlx::query_as::<_, Release>(&format!(
+        "UPDATE releases SET critical = ?1 WHERE id = ?2 AND {} RETURNING {}",
+        app_scope(3),
+        RELEASE_COLUMNS
+    ))
+    .bind(request.critical)
+    .bind(id)
+    .bind(principal.org_id())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to update release", e))?
+    .ok_or_else(|| AppError::not_found("Release not found"))?;
+
+    state
+        .cache
+        .invalidate(&cache::latest_key(
+            &release.app_name,
+            &release.target,
+            &release.arch,
+            &release.channel,
+        ))
+        .await;
+
+    println!(
+        "Release {} is {}critical",
+        release.id,
+        if release.critical { "" } else { "no longer " }
+    );
+    Ok(Json(release))
+}
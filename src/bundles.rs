@@ -0,0 +1,508 @@
+//! Auxiliary asset bundles.
+//!
+//! Large assets that change less often than the app, such as ffmpeg builds,
+//! ML models or content packs, are uploaded as bundles: named, versioned
+//! artifacts of an app, stored and deduplicated like release artifacts. A
+//! bundle version is either built for one target/arch or runs everywhere.
+//! Releases declare which bundles they need with a semver requirement per
+//! bundle, and clients resolve the bundle versions matching their app
+//! version instead of shipping the assets in every installer.
+
+use axum::{
+    Extension,
+    extract::{Multipart, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::Utc;
+use semver::{Version, VersionReq};
+
+use crate::artifacts;
+use crate::auth::{self, Principal, app_scope};
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::freezes;
+use crate::http_cache;
+use crate::licenses;
+use crate::quotas;
+use crate::routes::{delete_orphaned, release_tag, store_artifact};
+use crate::schema::{
+    AppState, Artifact, BUNDLE_COLUMNS, Bundle, BundleRequirement, BundleRequirementsRequest,
+    BundleUploadForm, DEFAULT_CHANNEL, LatestQuery, OverrideQuery, ResolvedBundle,
+};
+use crate::variants::scoped_release;
+
+/// Most bundles one release can depend on.
+const MAX_REQUIREMENTS: usize = 32;
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+fn invalid_name() -> AppError {
+    AppError::bad_request("Bundle names must be 1-64 letters, digits, '_', '-' or '.'")
+}
+
+/// Upload a bundle version
+#[utoipa::path(
+    post,
+    path = "/apps/{name}/bundles/{bundle}",
+    params(
+        ("name" = String, Path, description = "Application name"),
+        ("bundle" = String, Path, description = "Bundle name: letters, digits, '_', '-' and '.'"),
+        OverrideQuery
+    ),
+    request_body(content = BundleUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "Bundle version stored", body = Bundle),
+        (status = 400, description = "Invalid name or version, or no file", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization, or only the operator may override the freeze", body = ErrorBody),
+        (status = 409, description = "This version was already uploaded for the platform, or publishing is frozen", body = ErrorBody),
+        (status = 413, description = "The upload doesn't fit the storage quota", body = ErrorBody),
+        (status = 429, description = "The daily upload quota is used up", body = ErrorBody)
+    )
+)]
+pub async fn upload_bundle(
+    Path((app_name, name)): Path<(String, String)>,
+    Query(freeze): Query<OverrideQuery>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    mut multipart: Multipart,
+) -> AppResult<(StatusCode, Json<Bundle>)> {
+    if !is_valid_name(&name) {
+        return Err(invalid_name());
+    }
+    let mut version = String::new();
+    let mut target = String::new();
+    let mut arch = String::new();
+    let mut notes = String::new();
+    let mut file_name = String::new();
+    let mut file_data: Vec<u8> = Vec::new();
+    while let Some(res) = multipart.next_field().await.transpose() {
+        let field =
+            res.map_err(|e| AppError::bad_request(format!("Malformed multipart body: {}", e)))?;
+        match field.name().unwrap_or_default() {
+            "version" => version = field.text().await.unwrap_or_default(),
+            "target" => target = field.text().await.unwrap_or_default(),
+            "arch" => arch = field.text().await.unwrap_or_default(),
+            "notes" => notes = field.text().await.unwrap_or_default(),
+            "file" => {
+                file_name = field.file_name().unwrap_or("bundle").to_string();
+                file_data = field
+                    .bytes()
+                    .await
+                    .map_err(|e| AppError::bad_request(format!("Failed to read file: {}", e)))?
+                    .to_vec();
+            }
+            _ => (),
+        }
+    }
+    if file_data.is_empty() {
+        return Err(AppError::bad_request("No file uploaded or file is empty"));
+    }
+    let version = version.trim();
+    Version::parse(version).map_err(|e| {
+        AppError::bad_request(format!(
+            "version '{}' is not a semver version: {}",
+            version, e
+        ))
+    })?;
+    let (target, arch) = (target.trim(), arch.trim());
+    auth::claim_app(&state.pool, principal, &app_name).await?;
+    freezes::check_publish(&state.pool, principal, &app_name, &freeze).await?;
+
+    // Checked again when saving; this only spares GitHub a doomed upload.
+    let exists: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM bundles WHERE app_name = ? AND name = ? AND version = ? AND target = ? AND arch = ?)",
+    )
+    .bind(&app_name)
+    .bind(&name)
+    .bind(version)
+    .bind(target)
+    .bind(arch)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to look up the bundle", e))?;
+    let duplicate = || {
+        AppError::conflict(format!(
+            "{} {} was already uploaded for this platform",
+            name, version
+        ))
+    };
+    if exists {
+        return Err(duplicate());
+    }
+
+    let sha256 = artifacts::sha256_hex(&file_data);
+    let size = file_data.len() as i64;
+    quotas::check_upload(&state, &app_name, &sha256, size, false).await?;
+    // Bundles get GitHub releases of their own, tagged `{app}-{bundle}-v{version}`.
+    let (sha256, size, url, github_asset_id) = store_artifact(
+        &state,
+        &app_name,
+        sha256,
+        &release_tag(&format!("{}-{}", app_name, name), version),
+        &notes,
+        &file_name,
+        file_data,
+    )
+    .await?;
+
+    let saved: Result<Result<Option<Bundle>, AppError>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        if let Some(refused) =
+            quotas::record_upload(&mut tx, &state.config, &app_name, &sha256, size, false).await?
+        {
+            return Ok(Err(refused));
+        }
+        let Some(bundle) = sqlx::query_as::<_, Bundle>(&format!(
+            "INSERT INTO bundles ({0}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT DO NOTHING RETURNING {0}",
+            BUNDLE_COLUMNS
+        ))
+        .bind(&app_name)
+        .bind(&name)
+        .bind(version)
+        .bind(target)
+        .bind(arch)
+        .bind(&url)
+        .bind(&sha256)
+        .bind(size)
+        .bind(notes.trim())
+        .bind(Utc::now())
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(Ok(None));
+        };
+        artifacts::retain(&mut tx, &app_name, &sha256, &url, size, github_asset_id).await?;
+        tx.commit().await?;
+        Ok(Ok(Some(bundle)))
+    }
+    .await;
+    let bundle = saved
+        .map_err(|e| AppError::internal("Failed to save bundle", e))??
+        .ok_or_else(duplicate)?;
+
+    println!(
+        "Uploaded bundle {} {} of {} ({})",
+        bundle.name,
+        bundle.version,
+        bundle.app_name,
+        platform_label(&bundle)
+    );
+    Ok((StatusCode::CREATED, Json(bundle)))
+}
+
+fn platform_label(bundle: &Bundle) -> String {
+    match (bundle.target.as_str(), bundle.arch.as_str()) {
+        ("", "") => "any platform".to_string(),
+        (t, a) => format!(
+            "{}/{}",
+            if t.is_empty() { "*" } else { t },
+            if a.is_empty() { "*" } else { a }
+        ),
+    }
+}
+
+/// List an app's bundles
+#[utoipa::path(
+    get,
+    path = "/apps/{name}/bundles",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    responses(
+        (status = 200, description = "Every uploaded bundle version", body = Vec<Bundle>)
+    )
+)]
+pub async fn list_bundles(
+    Path(app_name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<Bundle>>> {
+    let bundles = sqlx::query_as::<_, Bundle>(&format!(
+        "SELECT {} FROM bundles WHERE app_name = ?1 AND {} ORDER BY name, created_at DESC",
+        BUNDLE_COLUMNS,
+        app_scope(2)
+    ))
+    .bind(&app_name)
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load bundles", e))?;
+
+    Ok(Json(bundles))
+}
+
+/// Delete a bundle version
+///
+/// Removes the version for every platform. Releases requiring it resolve
+/// to another matching version, or fail to resolve.
+#[utoipa::path(
+    delete,
+    path = "/apps/{name}/bundles/{bundle}/{version}",
+    params(
+        ("name" = String, Path, description = "Application name"),
+        ("bundle" = String, Path, description = "Bundle name"),
+        ("version" = String, Path, description = "Bundle version")
+    ),
+    responses(
+        (status = 204, description = "Bundle version deleted"),
+        (status = 404, description = "Bundle version not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_bundle(
+    Path((app_name, name, version)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let deleted: Result<Option<Vec<Artifact>>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        let hashes: Vec<String> = sqlx::query_scalar(&format!(
+            "DELETE FROM bundles WHERE app_name = ?1 AND name = ?2 AND version = ?3 AND {} RETURNING sha256",
+            app_scope(4)
+        ))
+        .bind(&app_name)
+        .bind(&name)
+        .bind(&version)
+        .bind(principal.org_id())
+        .fetch_all(&mut *tx)
+        .await?;
+        if hashes.is_empty() {
+            return Ok(None);
+        }
+        let mut orphaned = Vec::new();
+        for sha256 in &hashes {
+            orphaned.extend(artifacts::release(&mut tx, &app_name, sha256).await?);
+        }
+        tx.commit().await?;
+        Ok(Some(orphaned))
+    }
+    .await;
+    let orphaned = deleted
+        .map_err(|e| AppError::internal("Failed to delete bundle", e))?
+        .ok_or_else(|| AppError::not_found("Bundle version not found"))?;
+    for artifact in orphaned {
+        delete_orphaned(&state, Some(artifact)).await;
+    }
+
+    println!("Deleted bundle {} {} of {}", name, version, app_name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn requirements(
+    state: &AppState,
+    release_id: i64,
+) -> Result<Vec<BundleRequirement>, sqlx::Error> {
+    sqlx::query_as::<_, BundleRequirement>(
+        "SELECT bundle, version_req FROM release_bundles WHERE release_id = ? ORDER BY bundle",
+    )
+    .bind(release_id)
+    .fetch_all(&state.read_pool)
+    .await
+}
+
+/// Bundles a release depends on
+#[utoipa::path(
+    get,
+    path = "/releases/{id}/bundles",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    responses(
+        (status = 200, description = "The release's bundle requirements", body = Vec<BundleRequirement>),
+        (status = 404, description = "Release not found", body = ErrorBody)
+    )
+)]
+pub async fn get_release_bundles(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<BundleRequirement>>> {
+    let release = scoped_release(&state, principal, id).await?;
+    let requirements = requirements(&state, release.id)
+        .await
+        .map_err(|e| AppError::internal("Failed to load bundle requirements", e))?;
+    Ok(Json(requirements))
+}
+
+/// Set the bundles a release depends on
+#[utoipa::path(
+    put,
+    path = "/releases/{id}/bundles",
+    params(
+        ("id" = i64, Path, description = "Release ID"),
+        OverrideQuery
+    ),
+    request_body = BundleRequirementsRequest,
+    responses(
+        (status = 200, description = "Requirements saved", body = Vec<BundleRequirement>),
+        (status = 400, description = "Invalid bundle name or version requirement", body = ErrorBody),
+        (status = 403, description = "Only the operator may override the freeze", body = ErrorBody),
+        (status = 404, description = "Release not found", body = ErrorBody),
+        (status = 409, description = "Publishing is frozen", body = ErrorBody)
+    )
+)]
+pub async fn set_release_bundles(
+    Path(id): Path<i64>,
+    Query(freeze): Query<OverrideQuery>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<BundleRequirementsRequest>,
+) -> AppResult<Json<Vec<BundleRequirement>>> {
+    if request.bundles.len() > MAX_REQUIREMENTS {
+        return Err(AppError::bad_request(format!(
+            "A release can depend on at most {} bundles",
+            MAX_REQUIREMENTS
+        )));
+    }
+    let mut parsed = Vec::new();
+    for (name, req) in &request.bundles {
+        if !is_valid_name(name) {
+            return Err(invalid_name());
+        }
+        let req = VersionReq::parse(req.trim()).map_err(|e| {
+            AppError::bad_request(format!(
+                "'{}' for {} is not a semver requirement: {}",
+                req, name, e
+            ))
+        })?;
+        parsed.push((name, req.to_string()));
+    }
+    let release = scoped_release(&state, principal, id).await?;
+    freezes::check_publish(&state.pool, principal, &release.app_name, &freeze).await?;
+
+    let saved: Result<(), sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        sqlx::query("DELETE FROM release_bundles WHERE release_id = ?")
+            .bind(release.id)
+            .execute(&mut *tx)
+            .await?;
+        for (name, req) in &parsed {
+            sqlx::query(
+                "INSERT INTO release_bundles (release_id, bundle, version_req) VALUES (?, ?, ?)",
+            )
+            .bind(release.id)
+            .bind(name)
+            .bind(req)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+    .await;
+    saved.map_err(|e| AppError::internal("Failed to save bundle requirements", e))?;
+
+    println!(
+        "Release {} now depends on {} bundles",
+        release.id,
+        parsed.len()
+    );
+    let requirements = requirements(&state, release.id)
+        .await
+        .map_err(|e| AppError::internal("Failed to load bundle requirements", e))?;
+    Ok(Json(requirements))
+}
+
+/// Resolve the bundles of an app version
+///
+/// Picks, for every bundle the release of `app_version` depends on, the
+/// highest version meeting its requirement that runs on the platform. A
+/// build for the exact platform wins over a platform-independent one of
+/// the same version.
+#[utoipa::path(
+    get,
+    path = "/bundles/{app_name}/{target}/{arch}/{app_version}",
+    security(()),
+    params(
+        ("app_name" = String, Path, description = "Application name"),
+        ("target" = String, Path, description = "Target OS"),
+        ("arch" = String, Path, description = "Architecture (e.g., aarch64, x86_64)"),
+        ("app_version" = String, Path, description = "Installed version of the app"),
+        LatestQuery
+    ),
+    responses(
+        (status = 200, description = "Bundle versions to install", body = Vec<ResolvedBundle>),
+        (status = 402, description = "The license key has expired (`license_expired`)", body = ErrorBody),
+        (status = 403, description = "The app requires a license key and none or an invalid one was sent (`license_required`, `license_invalid`)", body = ErrorBody),
+        (status = 404, description = "No such release", body = ErrorBody),
+        (status = 409, description = "No uploaded version meets a requirement (`bundle_unresolved`)", body = ErrorBody)
+    )
+)]
+pub async fn resolve_bundles(
+    Path((app_name, target, arch, app_version)): Path<(String, String, String, String)>,
+    Query(query): Query<LatestQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let channel = query.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
+    let key = licenses::request_key(&headers, query.license_key.as_deref());
+    licenses::check_download(&state, key, (&app_name, &target, &arch, channel)).await?;
+    // Yanked releases still resolve: clients may be running them.
+    let release_id: i64 = sqlx::query_scalar(
+        "SELECT id FROM releases WHERE app_name = ? AND target = ? AND arch = ? AND version = ? AND channel = ? ORDER BY id DESC LIMIT 1",
+    )
+    .bind(&app_name)
+    .bind(&target)
+    .bind(&arch)
+    .bind(&app_version)
+    .bind(channel)
+    .fetch_optional(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to look up the release", e))?
+    .ok_or_else(|| AppError::not_found("Release not found"))?;
+    let requirements = requirements(&state, release_id)
+        .await
+        .map_err(|e| AppError::internal("Failed to load bundle requirements", e))?;
+
+    let mut resolved = Vec::new();
+    for requirement in requirements {
+        let candidates = sqlx::query_as::<_, Bundle>(&format!(
+            "SELECT {} FROM bundles WHERE app_name = ? AND name = ? AND target IN (?, '') AND arch IN (?, '')",
+            BUNDLE_COLUMNS
+        ))
+        .bind(&app_name)
+        .bind(&requirement.bundle)
+        .bind(&target)
+        .bind(&arch)
+        .fetch_all(&state.read_pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up bundles", e))?;
+        // Requirements were validated when they were set.
+        let req = VersionReq::parse(&requirement.version_req).unwrap_or(VersionReq::STAR);
+        let best = candidates
+            .into_iter()
+            .filter_map(|b| {
+                let v = Version::parse(&b.version).ok().filter(|v| req.matches(v))?;
+                let specific = !b.target.is_empty() as u8 + !b.arch.is_empty() as u8;
+                Some(((v, specific), b))
+            })
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, b)| b)
+            .ok_or_else(|| {
+                AppError::new(
+                    StatusCode::CONFLICT,
+                    "bundle_unresolved",
+                    format!(
+                        "No version of {} meeting {} is available for {}/{}",
+                        requirement.bundle, requirement.version_req, target, arch
+                    ),
+                )
+            })?;
+        resolved.push(ResolvedBundle {
+            name: best.name,
+            version: best.version,
+            version_req: requirement.version_req,
+            url: best.url,
+            sha256: best.sha256,
+            size: best.size,
+        });
+    }
+    Ok(http_cache::vary(
+        Json(resolved).into_response(),
+        licenses::LICENSE_KEY_HEADER,
+    ))
+}
@@ -0,0 +1,228 @@
+//! Cache for latest-release lookups.
+//!
+//! Every replica keeps a short-lived in-process copy of each lookup. When
+//! `REDIS_URL` is configured, Redis acts as the shared second tier and a
+//! pub/sub channel tells every replica to drop its local copy as soon as a
+//! release is published, so replicas never disagree for longer than a
+//! round-trip.
+//!
+//! Concurrent misses for the same key are coalesced so that only one of them
+//! reaches the database (see [`ReleaseCache::get_or_load`]). Reads normally
+//! go to the read pool, which may be a lagging replica; for a TTL after a
+//! key is invalidated they go to the primary instead, and a load that was
+//! already running when the key was invalidated isn't cached, so a publish
+//! is never hidden behind a stale copy.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OnceCell, RwLock};
+
+use crate::redis::RedisClient;
+use crate::schema::Release;
+
+const INVALIDATION_CHANNEL: &str = "updater:invalidate";
+
+/// A load shared by every request that missed on the same key. Resolves to
+/// `Ok(None)` when there is no such release, and to the error message when
+/// the load failed, which waiters return without caching.
+type InflightLoad = OnceCell<Result<Option<Release>, String>>;
+
+pub struct ReleaseCache {
+    ttl: Duration,
+    local: RwLock<HashMap<String, (Instant, Option<Release>)>>,
+    redis: Option<RedisClient>,
+    inflight: Mutex<HashMap<String, Arc<InflightLoad>>>,
+    /// How often each key was invalidated on this replica, and when last.
+    invalidations: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+pub fn latest_key(app_name: &str, target: &str, arch: &str, channel: &str) -> String {
+    format!(
+        "updater:latest:{}:{}:{}:{}",
+        app_name, target, arch, channel
+    )
+}
+
+impl ReleaseCache {
+    pub fn new(ttl: Duration, redis_url: Option<&str>) -> Self {
+        let redis = redis_url.and_then(|url| match RedisClient::new(url) {
+            Ok(client) => {
+                println!("Using Redis for the release cache");
+                Some(client)
+            }
+            Err(e) => {
+                println!("Invalid REDIS_URL, falling back to local cache: {}", e);
+                None
+            }
+        });
+
+        Self {
+            ttl,
+            local: RwLock::new(HashMap::new()),
+            redis,
+            inflight: Mutex::new(HashMap::new()),
+            invalidations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts listening for invalidations published by other replicas.
+    pub fn spawn_invalidation_listener(self: &Arc<Self>) {
+        let Some(redis) = &self.redis else {
+            return;
+        };
+        let mut rx = redis.subscribe(INVALIDATION_CHANNEL);
+        let cache = self.clone();
+        tokio::spawn(async move {
+            while let Some(key) = rx.recv().await {
+                cache.forget(&key).await;
+            }
+        });
+    }
+
+    /// Returns `Some(entry)` on a cache hit. The entry itself is `None` when
+    /// the lookup was cached as "no release exists".
+    pub async fn get(&self, key: &str) -> Option<Option<Release>> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+
+        if let Some((stored_at, entry)) = self.local.read().await.get(key)
+            && stored_at.elapsed() < self.ttl
+        {
+            return Some(entry.clone());
+        }
+
+        let redis = self.redis.as_ref()?;
+        match redis.get(key).await {
+            Ok(Some(bytes)) => {
+                let entry: Option<Release> = serde_json::from_slice(&bytes).ok()?;
+                self.local
+                    .write()
+                    .await
+                    .insert(key.to_string(), (Instant::now(), entry.clone()));
+                Some(entry)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                println!("Redis GET {} failed: {}", key, e);
+                None
+            }
+        }
+    }
+
+    fn generation(&self, key: &str) -> Option<(u64, Instant)> {
+        self.invalidations.lock().unwrap().get(key).copied()
+    }
+
+    /// Returns the cached entry for `key`, or runs `load` to fill it. When many
+    /// requests miss on the same key at once, only the first runs `load`; the
+    /// rest wait for its result. A failed load is not cached; its error goes
+    /// to every request that was waiting on it. `load` is told to read from
+    /// the primary when the key was invalidated less than a TTL ago.
+    pub async fn get_or_load<F, Fut, E>(
+        &self,
+        key: &str,
+        load: F,
+    ) -> Result<Option<Release>, String>
+    where
+        F: FnOnce(bool) -> Fut,
+        Fut: Future<Output = Result<Option<Release>, E>>,
+        E: std::fmt::Display,
+    {
+        if let Some(entry) = self.get(key).await {
+            return Ok(entry);
+        }
+
+        let cell = self
+            .inflight
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+
+        let result = cell
+            .get_or_init(|| async {
+                let generation = self.generation(key);
+                let primary = generation.is_some_and(|(_, at)| at.elapsed() < self.ttl);
+                match load(primary).await {
+                    Ok(entry) => {
+                        // Invalidated while loading: what was read may
+                        // predate the change.
+                        if self.generation(key) == generation {
+                            self.put(key, entry.clone()).await;
+                        }
+                        Ok(entry)
+                    }
+                    Err(e) => {
+                        println!("Failed to load {}: {}", key, e);
+                        Err(e.to_string())
+                    }
+                }
+            })
+            .await
+            .clone();
+
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.get(key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+            inflight.remove(key);
+        }
+
+        result
+    }
+
+    pub async fn put(&self, key: &str, entry: Option<Release>) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        if let Some(redis) = &self.redis {
+            match serde_json::to_string(&entry) {
+                Ok(json) => {
+                    if let Err(e) = redis.set_ex(key, &json, self.ttl).await {
+                        println!("Redis SET {} failed: {}", key, e);
+                    }
+                }
+                Err(e) => println!("Failed to serialize cache entry {}: {}", key, e),
+            }
+        }
+
+        self.local
+            .write()
+            .await
+            .insert(key.to_string(), (Instant::now(), entry));
+    }
+
+    /// Drops the local copy of `key`, and makes the next load of it start
+    /// afresh.
+    async fn forget(&self, key: &str) {
+        {
+            let mut invalidations = self.invalidations.lock().unwrap();
+            let (count, at) = invalidations
+                .entry(key.to_string())
+                .or_insert((0, Instant::now()));
+            *count += 1;
+            *at = Instant::now();
+        }
+        self.inflight.lock().unwrap().remove(key);
+        self.local.write().await.remove(key);
+    }
+
+    /// Drops `key` locally, in Redis, and on every other replica.
+    pub async fn invalidate(&self, key: &str) {
+        self.forget(key).await;
+
+        if let Some(redis) = &self.redis {
+            if let Err(e) = redis.del(key).await {
+                println!("Redis DEL {} failed: {}", key, e);
+            }
+            match redis.publish(INVALIDATION_CHANNEL, key).await {
+                Ok(receivers) => println!("Invalidated {} on {} replicas", key, receivers),
+                Err(e) => println!("Redis PUBLISH {} failed: {}", key, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! In-app messaging campaigns.
+//!
+//! A campaign is a message aimed at users of some versions of an app
+//! (a semver requirement such as `<1.2.0`), optionally on one platform and
+//! within a date window. Update checks carry the matching messages in their
+//! body; when there is no update, and so no body, the count goes in
+//! [`MESSAGES_HEADER`] and the messages themselves are at
+//! `/messages/{app_name}/{target}/{current_version}`.
+
+use axum::{
+    Extension,
+    extract::{Path, Query, State},
+    http::{HeaderName, StatusCode},
+    response::Json,
+};
+use chrono::{DateTime, Utc};
+use semver::{Version, VersionReq};
+use sqlx::{Pool, Sqlite};
+
+use crate::auth::{self, Principal, app_scope};
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::routes::validate_http_url;
+use crate::schema::{
+    AppState, CAMPAIGN_COLUMNS, Campaign, CampaignFilter, CampaignMessage, CampaignRequest,
+};
+
+/// Number of campaign messages waiting for a client told there is no update.
+pub const MESSAGES_HEADER: HeaderName = HeaderName::from_static("x-campaign-messages");
+
+const MAX_TITLE_LEN: usize = 100;
+const MAX_BODY_LEN: usize = 2000;
+
+/// Messages of the campaigns of `app_name` running at `now` that target
+/// `current` on `target`, oldest campaign first.
+pub async fn matching_messages(
+    pool: &Pool<Sqlite>,
+    app_name: &str,
+    target: &str,
+    current: &Version,
+    now: DateTime<Utc>,
+) -> Result<Vec<CampaignMessage>, sqlx::Error> {
+    let campaigns = sqlx::query_as::<_, Campaign>(&format!(
+        "SELECT {} FROM campaigns WHERE app_name = ?1 AND (target IS NULL OR target = ?2) AND (starts_at IS NULL OR starts_at <= ?3) AND (ends_at IS NULL OR ends_at > ?3) ORDER BY id",
+        CAMPAIGN_COLUMNS
+    ))
+    .bind(app_name)
+    .bind(target)
+    .bind(now)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(campaigns
+        .into_iter()
+        .filter(|c| {
+            // Requirements are validated when saved, so one that no longer
+            // parses matches nobody rather than everybody.
+            c.version_req
+                .as_deref()
+                .is_none_or(|req| VersionReq::parse(req).is_ok_and(|req| req.matches(current)))
+        })
+        .map(|c| CampaignMessage {
+            id: c.id,
+            title: c.title,
+            body: c.body,
+            link_url: c.link_url,
+        })
+        .collect())
+}
+
+/// A validated [`CampaignRequest`].
+struct CampaignFields<'a> {
+    app_name: &'a str,
+    title: &'a str,
+    body: &'a str,
+    link_url: Option<&'a str>,
+    version_req: Option<&'a str>,
+    target: Option<&'a str>,
+}
+
+fn optional(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty())
+}
+
+fn validate(request: &CampaignRequest) -> AppResult<CampaignFields<'_>> {
+    let app_name = request.app_name.trim();
+    if app_name.is_empty() {
+        return Err(AppError::bad_request("app_name is required"));
+    }
+    let title = request.title.trim();
+    if title.is_empty() || title.chars().count() > MAX_TITLE_LEN {
+        return Err(AppError::bad_request(format!(
+            "title must be 1-{} characters",
+            MAX_TITLE_LEN
+        )));
+    }
+    let body = request.body.trim();
+    if body.is_empty() || body.chars().count() > MAX_BODY_LEN {
+        return Err(AppError::bad_request(format!(
+            "body must be 1-{} characters",
+            MAX_BODY_LEN
+        )));
+    }
+    let link_url = optional(&request.link_url);
+    if let Some(url) = link_url {
+        validate_http_url(url)?;
+    }
+    let version_req = optional(&request.version_req);
+    if let Some(req) = version_req
+        && let Err(e) = VersionReq::parse(req)
+    {
+        return Err(AppError::bad_request(format!(
+            "version_req '{}' is not a semver requirement: {}",
+            req, e
+        )));
+    }
+    let target = optional(&request.target);
+    if let (Some(starts_at), Some(ends_at)) = (request.starts_at, request.ends_at)
+        && ends_at <= starts_at
+    {
+        return Err(AppError::bad_request("ends_at must be after starts_at"));
+    }
+    Ok(CampaignFields {
+        app_name,
+        title,
+        body,
+        link_url,
+        version_req,
+        target,
+    })
+}
+
+/// List campaigns
+#[utoipa::path(
+    get,
+    path = "/campaigns",
+    params(CampaignFilter),
+    responses(
+        (status = 200, description = "Running and upcoming campaigns", body = Vec<Campaign>)
+    )
+)]
+pub async fn list_campaigns(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<CampaignFilter>,
+) -> AppResult<Json<Vec<Campaign>>> {
+    let campaigns = sqlx::query_as::<_, Campaign>(&format!(
+        "SELECT {} FROM campaigns WHERE (?1 IS NULL OR app_name = ?1) AND (?2 OR ends_at IS NULL OR ends_at > ?3) AND {} ORDER BY app_name, id",
+        CAMPAIGN_COLUMNS,
+        app_scope(4)
+    ))
+    .bind(&filter.app_name)
+    .bind(filter.include_past)
+    .bind(Utc::now())
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load campaigns", e))?;
+
+    Ok(Json(campaigns))
+}
+
+/// Create a campaign
+#[utoipa::path(
+    post,
+    path = "/campaigns",
+    request_body = CampaignRequest,
+    responses(
+        (status = 201, description = "Campaign created", body = Campaign),
+        (status = 400, description = "Invalid text, link, version requirement or window", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody)
+    )
+)]
+pub async fn create_campaign(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<CampaignRequest>,
+) -> AppResult<(StatusCode, Json<Campaign>)> {
+    let fields = validate(&request)?;
+    auth::claim_app(&state.pool, principal, fields.app_name).await?;
+
+    let campaign = sqlx::query_as::<_, Campaign>(&format!(
+        "INSERT INTO campaigns (app_name, title, body, link_url, version_req, target, starts_at, ends_at, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING {}",
+        CAMPAIGN_COLUMNS
+    ))
+    .bind(fields.app_name)
+    .bind(fields.title)
+    .bind(fields.body)
+    .bind(fields.link_url)
+    .bind(fields.version_req)
+    .bind(fields.target)
+    .bind(request.starts_at)
+    .bind(request.ends_at)
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to create campaign", e))?;
+
+    println!(
+        "Created campaign {} for {} ({})",
+        campaign.id,
+        campaign.app_name,
+        campaign.version_req.as_deref().unwrap_or("every version")
+    );
+    Ok((StatusCode::CREATED, Json(campaign)))
+}
+
+/// Replace a campaign
+///
+/// The campaign stays with its app; `app_name` must name it.
+#[utoipa::path(
+    put,
+    path = "/campaigns/{id}",
+    params(
+        ("id" = i64, Path, description = "Campaign ID")
+    ),
+    request_body = CampaignRequest,
+    responses(
+        (status = 200, description = "Campaign updated", body = Campaign),
+        (status = 400, description = "Invalid text, link, version requirement or window", body = ErrorBody),
+        (status = 404, description = "Campaign not found for that app", body = ErrorBody)
+    )
+)]
+pub async fn update_campaign(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<CampaignRequest>,
+) -> AppResult<Json<Campaign>> {
+    let fields = validate(&request)?;
+
+    let campaign = sqlx::query_as::<_, Campaign>(&format!(
+        "UPDATE campaigns SET title = ?1, body = ?2, link_url = ?3, version_req = ?4, target = ?5, starts_at = ?6, ends_at = ?7 WHERE id = ?8 AND app_name = ?9 AND {} RETURNING {}",
+        app_scope(10),
+        CAMPAIGN_COLUMNS
+    ))
+    .bind(fields.title)
+    .bind(fields.body)
+    .bind(fields.link_url)
+    .bind(fields.version_req)
+    .bind(fields.target)
+    .bind(request.starts_at)
+    .bind(request.ends_at)
+    .bind(id)
+    .bind(fields.app_name)
+    .bind(principal.org_id())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to update campaign", e))?
+    .ok_or_else(|| AppError::not_found("Campaign not found"))?;
+
+    Ok(Json(campaign))
+}
+
+/// Delete a campaign
+#[utoipa::path(
+    delete,
+    path = "/campaigns/{id}",
+    params(
+        ("id" = i64, Path, description = "Campaign ID")
+    ),
+    responses(
+        (status = 204, description = "Campaign deleted; clients stop seeing it"),
+        (status = 404, description = "Campaign not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_campaign(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let deleted = sqlx::query(&format!(
+        "DELETE FROM campaigns WHERE id = ?1 AND {}",
+        app_scope(2)
+    ))
+    .bind(id)
+    .bind(principal.org_id())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to delete campaign", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("Campaign not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
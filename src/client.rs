@@ -0,0 +1,311 @@
+//! Typed async client for the release server.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), updater::client::ClientError> {
+//! let client = updater::client::Client::new("https://updates.example.com")?;
+//! if let Some(update) = client
+//!     .check_update("classprime", "darwin", "aarch64", "1.0.0", None)
+//!     .await?
+//! {
+//!     println!("{} is available at {}", update.version, update.url);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt;
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::http::{Method, Request, StatusCode, header};
+use chrono::{DateTime, Utc};
+use http_body_util::Full;
+use url::Url;
+
+use crate::api_version;
+use crate::error::ErrorBody;
+use crate::http_client::{HttpClient, HttpResponse};
+use crate::schema::{StagedRelease, UpdateResponse};
+
+/// Uploads can be large, so the default timeout is generous.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Responses bigger than this are cut off (and then fail to decode).
+const MAX_RESPONSE_BYTES: usize = 8 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The base URL or a path built from it is invalid.
+    InvalidUrl(url::ParseError),
+    /// The request could not be sent or the response not read.
+    Transport(String),
+    /// The server answered with an unexpected status. `code` and
+    /// `request_id` come from the JSON error body, when there is one.
+    Status {
+        status: StatusCode,
+        code: Option<String>,
+        message: String,
+        request_id: Option<String>,
+    },
+    /// The response body was not the expected JSON.
+    Decode(serde_json::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidUrl(e) => write!(f, "invalid URL: {}", e),
+            ClientError::Transport(e) => f.write_str(e),
+            ClientError::Status {
+                status,
+                message,
+                request_id,
+                ..
+            } => {
+                write!(f, "server responded with {}", status)?;
+                if !message.is_empty() {
+                    write!(f, ": {}", message)?;
+                }
+                if let Some(id) = request_id {
+                    write!(f, " (request {})", id)?;
+                }
+                Ok(())
+            }
+            ClientError::Decode(e) => write!(f, "unexpected response body: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// A release to publish with [`Client::upload`].
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub app_name: String,
+    pub version: String,
+    pub target: String,
+    pub arch: String,
+    pub notes: String,
+    /// Contents of the updater `.sig` file.
+    pub signature: String,
+    /// Defaults to `stable` on the server.
+    pub channel: Option<String>,
+    /// Defaults to the time of upload on the server.
+    pub pub_date: Option<DateTime<Utc>>,
+    /// Name the asset is stored under.
+    pub file_name: String,
+    pub file: Vec<u8>,
+}
+
+/// What the server did with an [`Upload`].
+#[derive(Debug, Clone)]
+pub enum UploadOutcome {
+    /// Published right away; the release's download URL.
+    Published(String),
+    /// Held back until the app's checks pass and, for macOS artifacts,
+    /// Apple notarizes it.
+    Staged(Box<StagedRelease>),
+}
+
+#[derive(Clone)]
+pub struct Client {
+    base_url: Url,
+    http: HttpClient,
+    token: Option<String>,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Result<Self, ClientError> {
+        Self::with_timeout(base_url, DEFAULT_TIMEOUT)
+    }
+
+    pub fn with_timeout(base_url: &str, timeout: Duration) -> Result<Self, ClientError> {
+        let base_url = Url::parse(base_url).map_err(ClientError::InvalidUrl)?;
+        if base_url.cannot_be_a_base() {
+            return Err(ClientError::InvalidUrl(
+                url::ParseError::RelativeUrlWithCannotBeABaseBase,
+            ));
+        }
+        let http = HttpClient::new(timeout).map_err(|e| ClientError::Transport(e.to_string()))?;
+        Ok(Self {
+            base_url,
+            http,
+            token: None,
+        })
+    }
+
+    /// Sends `token` as a bearer token, as the admin API (including
+    /// [`Client::upload`]) requires.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Returns the newest release on `channel` if it is newer than
+    /// `current_version`.
+    pub async fn check_update(
+        &self,
+        app_name: &str,
+        target: &str,
+        arch: &str,
+        current_version: &str,
+        channel: Option<&str>,
+    ) -> Result<Option<UpdateResponse>, ClientError> {
+        let url = self.url(&[app_name, target, arch, current_version], channel);
+        self.get_optional(url).await
+    }
+
+    /// Returns the newest release on `channel`, if there is one.
+    pub async fn latest(
+        &self,
+        app_name: &str,
+        target: &str,
+        arch: &str,
+        channel: Option<&str>,
+    ) -> Result<Option<UpdateResponse>, ClientError> {
+        let url = self.url(&["latest", app_name, target, arch], channel);
+        self.get_optional(url).await
+    }
+
+    /// Uploads a release, which the server either publishes or stages.
+    pub async fn upload(&self, upload: &Upload) -> Result<UploadOutcome, ClientError> {
+        let url = self.url(&["upload"], None);
+        let boundary = boundary();
+        let body = multipart_body(&boundary, upload);
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(url.as_str())
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| ClientError::Transport(e.to_string()))?;
+
+        let response = self.send(request).await?;
+        match response.status {
+            StatusCode::CREATED => serde_json::from_str(&response.body)
+                .map(UploadOutcome::Published)
+                .map_err(ClientError::Decode),
+            StatusCode::ACCEPTED => serde_json::from_str(&response.body)
+                .map(|staged| UploadOutcome::Staged(Box::new(staged)))
+                .map_err(ClientError::Decode),
+            _ => Err(status_error(response)),
+        }
+    }
+
+    fn url(&self, segments: &[&str], channel: Option<&str>) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("checked in Client::with_timeout")
+            .pop_if_empty()
+            .push(api_version::PREFIX.trim_start_matches('/'))
+            .extend(segments);
+        if let Some(channel) = channel {
+            url.query_pairs_mut().append_pair("channel", channel);
+        }
+        url
+    }
+
+    async fn get_optional(&self, url: Url) -> Result<Option<UpdateResponse>, ClientError> {
+        let request = Request::builder()
+            .uri(url.as_str())
+            .header(header::ACCEPT, "application/json")
+            .body(Full::new(Bytes::new()))
+            .map_err(|e| ClientError::Transport(e.to_string()))?;
+
+        let response = self.send(request).await?;
+        match response.status {
+            StatusCode::NO_CONTENT => Ok(None),
+            StatusCode::OK => serde_json::from_str(&response.body).map_err(ClientError::Decode),
+            _ => Err(status_error(response)),
+        }
+    }
+
+    async fn send(&self, mut request: Request<Full<Bytes>>) -> Result<HttpResponse, ClientError> {
+        if let Some(token) = &self.token {
+            let value = format!("Bearer {}", token).parse().map_err(|_| {
+                ClientError::Transport("token is not a valid header value".to_string())
+            })?;
+            request.headers_mut().insert(header::AUTHORIZATION, value);
+        }
+        self.http
+            .send(request, MAX_RESPONSE_BYTES)
+            .await
+            .map_err(ClientError::Transport)
+    }
+}
+
+fn status_error(response: HttpResponse) -> ClientError {
+    match serde_json::from_str::<ErrorBody>(&response.body) {
+        Ok(body) => ClientError::Status {
+            status: response.status,
+            code: Some(body.code),
+            message: body.message,
+            request_id: body.request_id,
+        },
+        Err(_) => ClientError::Status {
+            status: response.status,
+            code: None,
+            message: response.body.trim().to_string(),
+            request_id: None,
+        },
+    }
+}
+
+fn boundary() -> String {
+    let mut bytes = [0u8; 16];
+    let _ = getrandom::getrandom(&mut bytes);
+    format!("----updater-{}", hex::encode(bytes))
+}
+
+/// Quotes are escaped and line breaks dropped so a file name can't end the
+/// header early.
+fn quoted(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| *c != '\r' && *c != '\n')
+        .collect::<String>()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+}
+
+fn multipart_body(boundary: &str, upload: &Upload) -> Vec<u8> {
+    let pub_date = upload.pub_date.map(|d| d.to_rfc3339());
+    let mut fields = vec![
+        ("app_name", upload.app_name.as_str()),
+        ("version", upload.version.as_str()),
+        ("target", upload.target.as_str()),
+        ("arch", upload.arch.as_str()),
+        ("notes", upload.notes.as_str()),
+        ("signature", upload.signature.as_str()),
+    ];
+    if let Some(channel) = &upload.channel {
+        fields.push(("channel", channel));
+    }
+    if let Some(pub_date) = &pub_date {
+        fields.push(("pub_date", pub_date));
+    }
+
+    let mut body = Vec::with_capacity(upload.file.len() + 1024);
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            )
+            .as_bytes(),
+        );
+    }
+    body.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            boundary,
+            quoted(&upload.file_name)
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(&upload.file);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    body
+}
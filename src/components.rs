@@ -0,0 +1,328 @@
+//! Component-based updates.
+//!
+//! A release can be split into components, such as `core`, `renderer` and
+//! `content`, each with its own version and artifact. The release version
+//! still decides whether an update is offered; its answer then lists the
+//! components whose version differs from the client's manifest, so a client
+//! that only needs new content downloads only that. The full installer stays
+//! in the answer for clients that don't handle components.
+
+use std::collections::BTreeMap;
+
+use axum::{
+    Extension,
+    extract::{Multipart, Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::Utc;
+use semver::Version;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+
+use crate::artifacts;
+use crate::auth::Principal;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::freezes;
+use crate::quotas;
+use crate::routes::{delete_orphaned, release_tag, store_artifact};
+use crate::schema::{
+    AppState, Artifact, ComponentUpdate, ComponentUploadForm, OverrideQuery,
+    RELEASE_COMPONENT_COLUMNS, ReleaseComponent,
+};
+use crate::variants::scoped_release;
+
+/// Most components a manifest may list.
+const MAX_MANIFEST_ENTRIES: usize = 64;
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// Parses a client's `name=version,...` component manifest.
+pub fn parse_manifest(raw: &str) -> AppResult<BTreeMap<String, String>> {
+    let mut manifest = BTreeMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((name, version)) = entry.split_once('=') else {
+            return Err(AppError::bad_request(format!(
+                "components entry '{}' must be name=version",
+                entry
+            )));
+        };
+        manifest.insert(name.trim().to_string(), version.trim().to_string());
+    }
+    if manifest.len() > MAX_MANIFEST_ENTRIES {
+        return Err(AppError::bad_request(format!(
+            "components can list at most {} entries",
+            MAX_MANIFEST_ENTRIES
+        )));
+    }
+    Ok(manifest)
+}
+
+/// Components of `release_id` the client with `manifest` doesn't have at
+/// their version; all of them without a manifest.
+pub async fn changed_components(
+    pool: &Pool<Sqlite>,
+    release_id: i64,
+    manifest: Option<&BTreeMap<String, String>>,
+) -> Result<Vec<ComponentUpdate>, sqlx::Error> {
+    let components = load_components(pool, release_id).await?;
+    Ok(components
+        .into_iter()
+        .filter(|c| manifest.and_then(|m| m.get(&c.name)) != Some(&c.version))
+        .map(|c| ComponentUpdate {
+            name: c.name,
+            version: c.version,
+            url: c.url,
+            signature: c.signature,
+            sha256: c.sha256,
+            size: c.size,
+        })
+        .collect())
+}
+
+async fn load_components(
+    pool: &Pool<Sqlite>,
+    release_id: i64,
+) -> Result<Vec<ReleaseComponent>, sqlx::Error> {
+    sqlx::query_as::<_, ReleaseComponent>(&format!(
+        "SELECT {} FROM release_components WHERE release_id = ? ORDER BY name",
+        RELEASE_COMPONENT_COLUMNS
+    ))
+    .bind(release_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Removes every component of a release, returning their artifacts for the
+/// caller to release.
+pub async fn remove_components(
+    conn: &mut SqliteConnection,
+    release_id: i64,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar("DELETE FROM release_components WHERE release_id = ? RETURNING sha256")
+        .bind(release_id)
+        .fetch_all(conn)
+        .await
+}
+
+/// Upload a release component
+///
+/// Replaces the component if the release already has it.
+#[utoipa::path(
+    post,
+    path = "/releases/{id}/components/{name}",
+    params(
+        ("id" = i64, Path, description = "Release ID"),
+        ("name" = String, Path, description = "Component name: letters, digits, '_', '-' and '.'"),
+        OverrideQuery
+    ),
+    request_body(content = ComponentUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "Component stored", body = ReleaseComponent),
+        (status = 400, description = "Invalid name or version, or no file", body = ErrorBody),
+        (status = 403, description = "Only the operator may override the freeze", body = ErrorBody),
+        (status = 404, description = "Release not found", body = ErrorBody),
+        (status = 409, description = "Publishing is frozen", body = ErrorBody),
+        (status = 413, description = "The upload doesn't fit the storage quota", body = ErrorBody),
+        (status = 429, description = "The daily upload quota is used up", body = ErrorBody)
+    )
+)]
+pub async fn upload_component(
+    Path((id, name)): Path<(i64, String)>,
+    Query(freeze): Query<OverrideQuery>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    mut multipart: Multipart,
+) -> AppResult<(StatusCode, Json<ReleaseComponent>)> {
+    if !is_valid_name(&name) {
+        return Err(AppError::bad_request(
+            "Component names must be 1-64 letters, digits, '_', '-' or '.'",
+        ));
+    }
+    let mut version = String::new();
+    let mut signature = String::new();
+    let mut file_name = String::new();
+    let mut file_data: Vec<u8> = Vec::new();
+    while let Some(res) = multipart.next_field().await.transpose() {
+        let field =
+            res.map_err(|e| AppError::bad_request(format!("Malformed multipart body: {}", e)))?;
+        match field.name().unwrap_or_default() {
+            "version" => version = field.text().await.unwrap_or_default(),
+            "signature" => signature = field.text().await.unwrap_or_default(),
+            "file" => {
+                file_name = field.file_name().unwrap_or("component").to_string();
+                file_data = field
+                    .bytes()
+                    .await
+                    .map_err(|e| AppError::bad_request(format!("Failed to read file: {}", e)))?
+                    .to_vec();
+            }
+            _ => (),
+        }
+    }
+    if file_data.is_empty() {
+        return Err(AppError::bad_request("No file uploaded or file is empty"));
+    }
+    let version = version.trim();
+    Version::parse(version).map_err(|e| {
+        AppError::bad_request(format!(
+            "version '{}' is not a semver version: {}",
+            version, e
+        ))
+    })?;
+    let release = scoped_release(&state, principal, id).await?;
+    freezes::check_publish(&state.pool, principal, &release.app_name, &freeze).await?;
+
+    let sha256 = artifacts::sha256_hex(&file_data);
+    let size = file_data.len() as i64;
+    quotas::check_upload(&state, &release.app_name, &sha256, size, false).await?;
+    // Components are stored under `{app}-{component}-v{version}`, so one
+    // component version uploaded for several releases is stored once.
+    let (sha256, size, url, github_asset_id) = store_artifact(
+        &state,
+        &release.app_name,
+        sha256,
+        &release_tag(&format!("{}-{}", release.app_name, name), version),
+        "",
+        &file_name,
+        file_data,
+    )
+    .await?;
+
+    let saved: Result<Result<(ReleaseComponent, Option<Artifact>), AppError>, sqlx::Error> =
+        async {
+            let mut tx = state.pool.begin().await?;
+            if let Some(refused) = quotas::record_upload(
+                &mut tx,
+                &state.config,
+                &release.app_name,
+                &sha256,
+                size,
+                false,
+            )
+            .await?
+            {
+                return Ok(Err(refused));
+            }
+            artifacts::retain(
+                &mut tx,
+                &release.app_name,
+                &sha256,
+                &url,
+                size,
+                github_asset_id,
+            )
+            .await?;
+            let replaced: Option<String> = sqlx::query_scalar(
+                "DELETE FROM release_components WHERE release_id = ? AND name = ? RETURNING sha256",
+            )
+            .bind(release.id)
+            .bind(&name)
+            .fetch_optional(&mut *tx)
+            .await?;
+            let component = sqlx::query_as::<_, ReleaseComponent>(&format!(
+            "INSERT INTO release_components ({0}) VALUES (?, ?, ?, ?, ?, ?, ?, ?) RETURNING {0}",
+            RELEASE_COMPONENT_COLUMNS
+        ))
+        .bind(release.id)
+        .bind(&name)
+        .bind(version)
+        .bind(&url)
+        .bind(signature.trim())
+        .bind(&sha256)
+        .bind(size)
+        .bind(Utc::now())
+        .fetch_one(&mut *tx)
+        .await?;
+            let orphaned = match replaced {
+                Some(old) => artifacts::release(&mut tx, &release.app_name, &old).await?,
+                None => None,
+            };
+            tx.commit().await?;
+            Ok(Ok((component, orphaned)))
+        }
+        .await;
+    let (component, orphaned) =
+        saved.map_err(|e| AppError::internal("Failed to save component", e))??;
+    delete_orphaned(&state, orphaned).await;
+
+    println!(
+        "Release {} now ships component {} {}",
+        release.id, component.name, component.version
+    );
+    Ok((StatusCode::CREATED, Json(component)))
+}
+
+/// List a release's components
+#[utoipa::path(
+    get,
+    path = "/releases/{id}/components",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    responses(
+        (status = 200, description = "Components of the release", body = Vec<ReleaseComponent>),
+        (status = 404, description = "Release not found", body = ErrorBody)
+    )
+)]
+pub async fn list_components(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<ReleaseComponent>>> {
+    let release = scoped_release(&state, principal, id).await?;
+    let components = load_components(&state.read_pool, release.id)
+        .await
+        .map_err(|e| AppError::internal("Failed to load components", e))?;
+    Ok(Json(components))
+}
+
+/// Remove a component from a release
+#[utoipa::path(
+    delete,
+    path = "/releases/{id}/components/{name}",
+    params(
+        ("id" = i64, Path, description = "Release ID"),
+        ("name" = String, Path, description = "Component name")
+    ),
+    responses(
+        (status = 204, description = "Component removed"),
+        (status = 404, description = "Release or component not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_component(
+    Path((id, name)): Path<(i64, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let release = scoped_release(&state, principal, id).await?;
+    let removed: Result<Option<Option<Artifact>>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        let Some(sha256) = sqlx::query_scalar::<_, String>(
+            "DELETE FROM release_components WHERE release_id = ? AND name = ? RETURNING sha256",
+        )
+        .bind(release.id)
+        .bind(&name)
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let orphaned = artifacts::release(&mut tx, &release.app_name, &sha256).await?;
+        tx.commit().await?;
+        Ok(Some(orphaned))
+    }
+    .await;
+    let orphaned = removed
+        .map_err(|e| AppError::internal("Failed to remove component", e))?
+        .ok_or_else(|| AppError::not_found("Component not found"))?;
+    delete_orphaned(&state, orphaned).await;
+
+    println!("Removed component {} of release {}", name, release.id);
+    Ok(StatusCode::NO_CONTENT)
+}
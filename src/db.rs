@@ -0,0 +1,877 @@
+//! Connection pools and schema migrations (tracked with SQLite's
+//! `PRAGMA user_version`).
+
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{Pool, Sqlite, SqliteConnection};
+
+use crate::config::Config;
+
+fn connect_options(config: &Config, url: &str) -> Result<SqliteConnectOptions, sqlx::Error> {
+    let journal_mode =
+        SqliteJournalMode::from_str(&config.sqlite_journal_mode).unwrap_or_else(|_| {
+            println!(
+                "Unknown SQLITE_JOURNAL_MODE '{}', using WAL",
+                config.sqlite_journal_mode
+            );
+            SqliteJournalMode::Wal
+        });
+    let mut options = SqliteConnectOptions::from_str(url)?
+        .busy_timeout(config.sqlite_busy_timeout)
+        .journal_mode(journal_mode);
+    if journal_mode == SqliteJournalMode::Wal {
+        // Durable across application crashes; only an OS crash can lose the last commits.
+        options = options.synchronous(SqliteSynchronous::Normal);
+    }
+    Ok(options)
+}
+
+/// Opens the write pool. It holds exactly one connection, so concurrent
+/// writers queue inside the pool instead of racing for SQLite's write lock
+/// and failing with `database is locked`.
+pub async fn connect_primary(config: &Config) -> Result<Pool<Sqlite>, sqlx::Error> {
+    let options = connect_options(config, &config.database_url)?.create_if_missing(true);
+    SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await
+}
+
+/// Opens the read pool: the replica when one is configured, otherwise a
+/// separate read-only pool on the primary database.
+pub async fn connect_read(config: &Config) -> Result<Pool<Sqlite>, sqlx::Error> {
+    let url = match &config.database_read_url {
+        Some(url) => {
+            println!("Routing reads to the replica database");
+            url
+        }
+        None => &config.database_url,
+    };
+    let options = connect_options(config, url)?.read_only(true);
+    SqlitePoolOptions::new()
+        .max_connections(config.db_max_read_connections)
+        .connect_with(options)
+        .await
+}
+
+/// Opens the primary database read-only, without creating it, for
+/// `arm check-config`.
+pub async fn connect_existing(config: &Config) -> Result<Pool<Sqlite>, sqlx::Error> {
+    let options = connect_options(config, &config.database_url)?.read_only(true);
+    SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await
+}
+
+/// A migrated in-memory database, for unit tests.
+#[cfg(test)]
+pub async fn memory_pool() -> Pool<Sqlite> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("in-memory database");
+    migrate(&pool).await.expect("migrations");
+    pool
+}
+
+/// Bump together with a new arm in [`apply`].
+pub const SCHEMA_VERSION: i64 = 36;
+
+pub async fn migrate(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let mut version: i64 = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(pool)
+        .await?;
+
+    while version < SCHEMA_VERSION {
+        println!("Migrating database schema {} -> {}", version, version + 1);
+        let mut tx = pool.begin().await?;
+        apply(&mut tx, version).await?;
+        version += 1;
+        sqlx::query(&format!("PRAGMA user_version = {}", version))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+    }
+
+    Ok(())
+}
+
+async fn apply(conn: &mut SqliteConnection, from_version: i64) -> Result<(), sqlx::Error> {
+    match from_version {
+        0 => {
+            sqlx::query(
+                r#"
+                CREATE TABLE IF NOT EXISTS releases (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    app_name TEXT NOT NULL,
+                    target TEXT NOT NULL,
+                    arch TEXT NOT NULL,
+                    version TEXT NOT NULL,
+                    url TEXT NOT NULL,
+                    signature TEXT NOT NULL,
+                    pub_date TEXT NOT NULL,
+                    notes TEXT NOT NULL
+                )
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        1 => normalize_pub_dates(conn).await?,
+        2 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE artifacts (
+                    sha256 TEXT PRIMARY KEY,
+                    url TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    github_asset_id INTEGER,
+                    ref_count INTEGER NOT NULL DEFAULT 0
+                );
+                ALTER TABLE releases ADD COLUMN sha256 TEXT REFERENCES artifacts(sha256);
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        3 => {
+            sqlx::raw_sql(
+                r#"
+                ALTER TABLE releases ADD COLUMN channel TEXT NOT NULL DEFAULT 'stable';
+                ALTER TABLE releases ADD COLUMN yanked INTEGER NOT NULL DEFAULT 0;
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        4 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE webhooks (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    url TEXT NOT NULL,
+                    secret TEXT NOT NULL,
+                    events TEXT NOT NULL DEFAULT '',
+                    app_name TEXT,
+                    active INTEGER NOT NULL DEFAULT 1,
+                    created_at TEXT NOT NULL
+                );
+                CREATE TABLE webhook_deliveries (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    webhook_id INTEGER NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+                    event TEXT NOT NULL,
+                    payload TEXT NOT NULL,
+                    status TEXT NOT NULL DEFAULT 'pending',
+                    attempts INTEGER NOT NULL DEFAULT 0,
+                    next_attempt_at TEXT,
+                    last_status_code INTEGER,
+                    last_error TEXT,
+                    last_response TEXT,
+                    created_at TEXT NOT NULL,
+                    completed_at TEXT
+                );
+                CREATE INDEX webhook_deliveries_due ON webhook_deliveries (status, next_attempt_at);
+                CREATE INDEX webhook_deliveries_by_hook ON webhook_deliveries (webhook_id, id);
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        5 => {
+            sqlx::query(
+                r#"
+                CREATE TABLE email_subscriptions (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    email TEXT NOT NULL,
+                    app_name TEXT NOT NULL,
+                    channel TEXT NOT NULL DEFAULT 'stable',
+                    token TEXT NOT NULL UNIQUE,
+                    created_at TEXT NOT NULL,
+                    UNIQUE (email, app_name, channel)
+                )
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        6 => create_organizations(conn).await?,
+        7 => {
+            sqlx::raw_sql(
+                r#"
+                ALTER TABLE releases ADD COLUMN ring_schedule TEXT;
+                CREATE TABLE customer_rings (
+                    org_id INTEGER NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+                    customer_id TEXT NOT NULL,
+                    ring TEXT NOT NULL,
+                    updated_at TEXT NOT NULL,
+                    PRIMARY KEY (org_id, customer_id)
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        8 => {
+            sqlx::raw_sql(
+                r#"
+                ALTER TABLE releases ADD COLUMN critical INTEGER NOT NULL DEFAULT 0;
+                CREATE TABLE blackouts (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    app_name TEXT NOT NULL,
+                    starts_at TEXT NOT NULL,
+                    ends_at TEXT NOT NULL,
+                    timezone TEXT NOT NULL,
+                    reason TEXT NOT NULL,
+                    created_at TEXT NOT NULL
+                );
+                CREATE INDEX blackouts_by_app ON blackouts (app_name, ends_at);
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        9 => {
+            sqlx::query(
+                r#"
+                CREATE TABLE publish_freezes (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    org_id INTEGER NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+                    app_name TEXT,
+                    starts_at TEXT NOT NULL,
+                    ends_at TEXT NOT NULL,
+                    timezone TEXT NOT NULL,
+                    reason TEXT NOT NULL,
+                    created_at TEXT NOT NULL
+                )
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        10 => {
+            sqlx::query(
+                "ALTER TABLE organizations ADD COLUMN defer_days INTEGER NOT NULL DEFAULT 0",
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        11 => {
+            sqlx::raw_sql(
+                r#"
+                ALTER TABLE apps ADD COLUMN require_license INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE apps ADD COLUMN license_url TEXT;
+                CREATE TABLE licenses (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    app_name TEXT NOT NULL,
+                    key_hash TEXT NOT NULL,
+                    key_hint TEXT NOT NULL,
+                    customer_id TEXT,
+                    expires_at TEXT,
+                    created_at TEXT NOT NULL,
+                    UNIQUE (app_name, key_hash)
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        12 => {
+            sqlx::raw_sql(
+                r#"
+                ALTER TABLE apps ADD COLUMN entitlement_url TEXT;
+                ALTER TABLE apps ADD COLUMN entitlement_secret TEXT;
+                ALTER TABLE apps ADD COLUMN entitlement_fail_open INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE apps ADD COLUMN entitlement_cache_secs INTEGER NOT NULL DEFAULT 60;
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        13 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE devices (
+                    app_name TEXT NOT NULL,
+                    device_id TEXT NOT NULL,
+                    customer_id TEXT,
+                    group_name TEXT,
+                    target TEXT,
+                    arch TEXT,
+                    current_version TEXT,
+                    last_seen_at TEXT,
+                    registered_at TEXT NOT NULL,
+                    PRIMARY KEY (app_name, device_id)
+                );
+                CREATE TABLE device_targets (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    app_name TEXT NOT NULL,
+                    device_id TEXT,
+                    group_name TEXT,
+                    version TEXT NOT NULL,
+                    created_at TEXT NOT NULL
+                );
+                CREATE INDEX device_targets_by_app ON device_targets (app_name);
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        14 => {
+            sqlx::raw_sql(
+                r#"
+                ALTER TABLE apps ADD COLUMN display_name TEXT;
+                ALTER TABLE apps ADD COLUMN icon_url TEXT;
+                ALTER TABLE apps ADD COLUMN homepage_url TEXT;
+                ALTER TABLE apps ADD COLUMN support_url TEXT;
+                ALTER TABLE apps ADD COLUMN description TEXT;
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        15 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE campaigns (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    app_name TEXT NOT NULL,
+                    title TEXT NOT NULL,
+                    body TEXT NOT NULL,
+                    link_url TEXT,
+                    version_req TEXT,
+                    target TEXT,
+                    starts_at TEXT,
+                    ends_at TEXT,
+                    created_at TEXT NOT NULL
+                );
+                CREATE INDEX campaigns_by_app ON campaigns (app_name);
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        16 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE feature_flags (
+                    app_name TEXT NOT NULL,
+                    key TEXT NOT NULL,
+                    value TEXT NOT NULL,
+                    version_req TEXT,
+                    rollout_percent INTEGER NOT NULL DEFAULT 100,
+                    updated_at TEXT NOT NULL,
+                    PRIMARY KEY (app_name, key)
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        17 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE release_variants (
+                    release_id INTEGER PRIMARY KEY,
+                    url TEXT NOT NULL,
+                    signature TEXT NOT NULL,
+                    sha256 TEXT NOT NULL,
+                    split_percent INTEGER NOT NULL,
+                    created_at TEXT NOT NULL
+                );
+                CREATE TABLE install_reports (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    release_id INTEGER NOT NULL,
+                    variant TEXT NOT NULL,
+                    outcome TEXT NOT NULL,
+                    client_id TEXT,
+                    message TEXT,
+                    created_at TEXT NOT NULL,
+                    UNIQUE (release_id, client_id, outcome)
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        18 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE promotion_policies (
+                    app_name TEXT PRIMARY KEY,
+                    from_channel TEXT NOT NULL,
+                    to_channel TEXT NOT NULL,
+                    min_days INTEGER NOT NULL,
+                    min_reports INTEGER NOT NULL DEFAULT 0,
+                    max_failure_rate REAL NOT NULL,
+                    max_rollbacks INTEGER NOT NULL,
+                    enabled INTEGER NOT NULL DEFAULT 1,
+                    updated_at TEXT NOT NULL
+                );
+                CREATE TABLE release_promotions (
+                    release_id INTEGER PRIMARY KEY,
+                    app_name TEXT NOT NULL,
+                    version TEXT NOT NULL,
+                    state TEXT NOT NULL,
+                    from_channel TEXT NOT NULL,
+                    to_channel TEXT NOT NULL,
+                    reason TEXT NOT NULL,
+                    decided_at TEXT NOT NULL
+                );
+                CREATE INDEX release_promotions_by_app ON release_promotions (app_name, decided_at);
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        19 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE org_quotas (
+                    org_id INTEGER PRIMARY KEY REFERENCES organizations(id) ON DELETE CASCADE,
+                    max_releases INTEGER,
+                    max_storage_bytes INTEGER,
+                    max_uploads_per_day INTEGER,
+                    updated_at TEXT NOT NULL
+                );
+                CREATE TABLE app_quotas (
+                    app_name TEXT PRIMARY KEY,
+                    max_releases INTEGER,
+                    max_storage_bytes INTEGER,
+                    max_uploads_per_day INTEGER,
+                    updated_at TEXT NOT NULL
+                );
+                CREATE TABLE uploads (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    app_name TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    created_at TEXT NOT NULL
+                );
+                CREATE INDEX uploads_by_app ON uploads (app_name, created_at);
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        20 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE check_gates (
+                    app_name TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    trigger_url TEXT,
+                    timeout_mins INTEGER NOT NULL,
+                    created_at TEXT NOT NULL,
+                    PRIMARY KEY (app_name, name)
+                );
+                CREATE TABLE staged_releases (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    app_name TEXT NOT NULL,
+                    target TEXT NOT NULL,
+                    arch TEXT NOT NULL,
+                    version TEXT NOT NULL,
+                    url TEXT NOT NULL,
+                    signature TEXT NOT NULL,
+                    pub_date TEXT NOT NULL,
+                    notes TEXT NOT NULL,
+                    sha256 TEXT NOT NULL,
+                    channel TEXT NOT NULL,
+                    ring_schedule TEXT,
+                    critical INTEGER NOT NULL,
+                    status TEXT NOT NULL DEFAULT 'pending',
+                    release_id INTEGER,
+                    created_at TEXT NOT NULL,
+                    completed_at TEXT
+                );
+                CREATE INDEX staged_releases_by_app ON staged_releases (app_name, status);
+                CREATE TABLE check_runs (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    staged_release_id INTEGER NOT NULL REFERENCES staged_releases(id) ON DELETE CASCADE,
+                    name TEXT NOT NULL,
+                    token_hash TEXT NOT NULL UNIQUE,
+                    status TEXT NOT NULL DEFAULT 'pending',
+                    message TEXT,
+                    details_url TEXT,
+                    deadline TEXT NOT NULL,
+                    completed_at TEXT
+                );
+                CREATE INDEX check_runs_due ON check_runs (status, deadline);
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        21 => {
+            sqlx::raw_sql(
+                r#"
+                ALTER TABLE apps ADD COLUMN host_app TEXT;
+                CREATE INDEX apps_by_host ON apps (host_app);
+                CREATE TABLE plugin_host_requirements (
+                    app_name TEXT NOT NULL,
+                    version TEXT NOT NULL,
+                    host_version_req TEXT NOT NULL,
+                    PRIMARY KEY (app_name, version)
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        22 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE bundles (
+                    app_name TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    version TEXT NOT NULL,
+                    target TEXT NOT NULL DEFAULT '',
+                    arch TEXT NOT NULL DEFAULT '',
+                    url TEXT NOT NULL,
+                    sha256 TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    notes TEXT NOT NULL,
+                    created_at TEXT NOT NULL,
+                    PRIMARY KEY (app_name, name, version, target, arch)
+                );
+                CREATE TABLE release_bundles (
+                    release_id INTEGER NOT NULL,
+                    bundle TEXT NOT NULL,
+                    version_req TEXT NOT NULL,
+                    PRIMARY KEY (release_id, bundle)
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        23 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE release_components (
+                    release_id INTEGER NOT NULL,
+                    name TEXT NOT NULL,
+                    version TEXT NOT NULL,
+                    url TEXT NOT NULL,
+                    signature TEXT NOT NULL,
+                    sha256 TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    created_at TEXT NOT NULL,
+                    PRIMARY KEY (release_id, name)
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        24 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE web_bundles (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    app_name TEXT NOT NULL,
+                    version TEXT NOT NULL,
+                    channel TEXT NOT NULL,
+                    app_version_req TEXT,
+                    url TEXT NOT NULL,
+                    signature TEXT NOT NULL,
+                    sha256 TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    notes TEXT NOT NULL,
+                    rolled_back INTEGER NOT NULL DEFAULT 0,
+                    created_at TEXT NOT NULL,
+                    UNIQUE (app_name, channel, version)
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        25 => {
+            sqlx::raw_sql(
+                r#"
+                ALTER TABLE releases ADD COLUMN notarization_id TEXT;
+                ALTER TABLE staged_releases ADD COLUMN notarization_id TEXT;
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        26 => {
+            sqlx::raw_sql(
+                r#"
+                ALTER TABLE apps ADD COLUMN version_scheme TEXT NOT NULL DEFAULT 'semver';
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        27 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE release_rules (
+                    release_id INTEGER NOT NULL,
+                    position INTEGER NOT NULL,
+                    rule TEXT NOT NULL,
+                    PRIMARY KEY (release_id, position)
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        28 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE release_deltas (
+                    release_id INTEGER NOT NULL,
+                    from_version TEXT NOT NULL,
+                    url TEXT NOT NULL,
+                    signature TEXT NOT NULL,
+                    sha256 TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    created_at TEXT NOT NULL,
+                    PRIMARY KEY (release_id, from_version)
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        29 => {
+            sqlx::raw_sql(
+                r#"
+                ALTER TABLE releases ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE apps ADD COLUMN archive_keep_versions INTEGER;
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        30 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE release_attachments (
+                    release_id INTEGER NOT NULL,
+                    name TEXT NOT NULL,
+                    content_type TEXT NOT NULL,
+                    storage_url TEXT NOT NULL,
+                    sha256 TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    created_at TEXT NOT NULL,
+                    PRIMARY KEY (release_id, name)
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        31 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE digests (
+                    day TEXT PRIMARY KEY,
+                    sent_at TEXT NOT NULL
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        32 => {
+            sqlx::raw_sql("ALTER TABLE apps ADD COLUMN response_fields TEXT;")
+                .execute(&mut *conn)
+                .await?;
+        }
+        33 => {
+            sqlx::raw_sql("ALTER TABLE staged_releases ADD COLUMN host_version_req TEXT;")
+                .execute(&mut *conn)
+                .await?;
+        }
+        34 => {
+            sqlx::raw_sql(
+                r#"
+                CREATE TABLE customer_update_policies (
+                    org_id INTEGER NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+                    customer_id TEXT NOT NULL,
+                    defer_days INTEGER NOT NULL,
+                    updated_at TEXT NOT NULL,
+                    PRIMARY KEY (org_id, customer_id)
+                );
+                "#,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        35 => artifacts_by_org(conn).await?,
+        _ => unreachable!("no migration from schema version {}", from_version),
+    }
+    Ok(())
+}
+
+/// Introduces organizations. Everything that already exists moves into the
+/// `default` organization, so a single-tenant deployment keeps working with
+/// just the operator token.
+async fn create_organizations(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    sqlx::raw_sql(
+        r#"
+        CREATE TABLE organizations (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            slug TEXT NOT NULL UNIQUE,
+            name TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );
+        CREATE TABLE apps (
+            name TEXT PRIMARY KEY,
+            org_id INTEGER NOT NULL REFERENCES organizations(id),
+            created_at TEXT NOT NULL
+        );
+        CREATE INDEX apps_by_org ON apps (org_id);
+        CREATE TABLE api_tokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            org_id INTEGER NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+            name TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            created_at TEXT NOT NULL,
+            last_used_at TEXT
+        );
+        ALTER TABLE webhooks ADD COLUMN org_id INTEGER;
+        UPDATE webhooks SET org_id = 1;
+        "#,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let now = Utc::now();
+    sqlx::query("INSERT INTO organizations (id, slug, name, created_at) VALUES (1, 'default', 'Default', ?)")
+        .bind(now)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO apps (name, org_id, created_at)
+        SELECT app_name, 1, ? FROM releases
+        UNION SELECT app_name, 1, ? FROM email_subscriptions
+        UNION SELECT app_name, 1, ? FROM webhooks WHERE app_name IS NOT NULL
+        "#,
+    )
+    .bind(now)
+    .bind(now)
+    .bind(now)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Keys artifacts on their organization as well as their SHA-256, so
+/// identical bytes uploaded by two organizations no longer share a stored
+/// asset. Each organization using an artifact gets its own row, counting
+/// only its own references. `releases.sha256` can't reference the new key,
+/// so `releases` is rebuilt without that foreign key.
+async fn artifacts_by_org(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    sqlx::raw_sql(
+        r#"
+        CREATE TABLE artifacts_by_org (
+            org_id INTEGER NOT NULL REFERENCES organizations(id),
+            sha256 TEXT NOT NULL,
+            url TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            github_asset_id INTEGER,
+            ref_count INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (org_id, sha256)
+        );
+        INSERT INTO artifacts_by_org (org_id, sha256, url, size, github_asset_id, ref_count)
+        SELECT apps.org_id, a.sha256, a.url, a.size, a.github_asset_id, count(*)
+        FROM (
+            SELECT app_name, sha256 FROM releases WHERE sha256 IS NOT NULL
+            UNION ALL
+            SELECT r.app_name, v.sha256 FROM release_variants v JOIN releases r ON r.id = v.release_id
+            UNION ALL
+            SELECT r.app_name, c.sha256 FROM release_components c JOIN releases r ON r.id = c.release_id
+            UNION ALL
+            SELECT r.app_name, d.sha256 FROM release_deltas d JOIN releases r ON r.id = d.release_id
+            UNION ALL
+            SELECT r.app_name, t.sha256 FROM release_attachments t JOIN releases r ON r.id = t.release_id
+            UNION ALL
+            SELECT app_name, sha256 FROM staged_releases WHERE status != 'published'
+            UNION ALL
+            SELECT app_name, sha256 FROM bundles
+            UNION ALL
+            SELECT app_name, sha256 FROM web_bundles
+        ) refs
+        JOIN apps ON apps.name = refs.app_name
+        JOIN artifacts a ON a.sha256 = refs.sha256
+        GROUP BY apps.org_id, a.sha256;
+
+        CREATE TABLE releases_rebuilt (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            app_name TEXT NOT NULL,
+            target TEXT NOT NULL,
+            arch TEXT NOT NULL,
+            version TEXT NOT NULL,
+            url TEXT NOT NULL,
+            signature TEXT NOT NULL,
+            pub_date TEXT NOT NULL,
+            notes TEXT NOT NULL,
+            sha256 TEXT,
+            channel TEXT NOT NULL DEFAULT 'stable',
+            yanked INTEGER NOT NULL DEFAULT 0,
+            ring_schedule TEXT,
+            critical INTEGER NOT NULL DEFAULT 0,
+            notarization_id TEXT,
+            archived INTEGER NOT NULL DEFAULT 0
+        );
+        INSERT INTO releases_rebuilt (id, app_name, target, arch, version, url, signature, pub_date, notes, sha256, channel, yanked, ring_schedule, critical, notarization_id, archived)
+        SELECT id, app_name, target, arch, version, url, signature, pub_date, notes, sha256, channel, yanked, ring_schedule, critical, notarization_id, archived FROM releases;
+        DELETE FROM sqlite_sequence WHERE name = 'releases_rebuilt';
+        INSERT INTO sqlite_sequence (name, seq) SELECT 'releases_rebuilt', seq FROM sqlite_sequence WHERE name = 'releases';
+        DROP TABLE releases;
+        ALTER TABLE releases_rebuilt RENAME TO releases;
+
+        DROP TABLE artifacts;
+        ALTER TABLE artifacts_by_org RENAME TO artifacts;
+        "#,
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Rewrites every `pub_date` in the single UTC encoding sqlx uses for
+/// `DateTime<Utc>`, so ordering and range filters compare like with like.
+/// Older rows were free-form RFC3339 strings with arbitrary offsets.
+async fn normalize_pub_dates(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    let rows: Vec<(i64, String)> = sqlx::query_as("SELECT id, pub_date FROM releases")
+        .fetch_all(&mut *conn)
+        .await?;
+
+    for (id, raw) in rows {
+        let pub_date = parse_legacy_timestamp(&raw).unwrap_or_else(|| {
+            println!(
+                "Release {} has unparseable pub_date '{}', resetting to the epoch",
+                id, raw
+            );
+            DateTime::UNIX_EPOCH
+        });
+        sqlx::query("UPDATE releases SET pub_date = ? WHERE id = ?")
+            .bind(pub_date)
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+fn parse_legacy_timestamp(raw: &str) -> Option<DateTime<Utc>> {
+    let raw = raw.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(raw) {
+        return Some(dt.with_timezone(&Utc));
+    }
+    ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(raw, fmt).ok())
+        .map(|naive| naive.and_utc())
+}
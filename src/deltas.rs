@@ -0,0 +1,526 @@
+//! Delta updates.
+//!
+//! Besides its full artifact, a release can carry patches from earlier
+//! versions, uploaded per `from_version`. Clients that can apply them ask
+//! `/{app}/{target}/{arch}/{current_version}/deltas` for the cheapest chain
+//! of patches from their exact version to the latest release (or the one an
+//! update check offered them), next to the full download, and pick
+//! whichever is smaller or safer for them. Intermediate steps may go through
+//! any release on the channel that isn't yanked.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+
+use axum::{
+    Extension,
+    extract::{Multipart, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::Utc;
+use sqlx::{FromRow, Pool, Sqlite, SqliteConnection};
+
+use crate::artifacts;
+use crate::auth::Principal;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::http_cache;
+use crate::licenses;
+use crate::normalize;
+use crate::quotas;
+use crate::routes::{delete_orphaned, find_latest_release, release_tag, store_artifact};
+use crate::schema::{
+    AppState, Artifact, DEFAULT_CHANNEL, DeltaManifest, DeltaQuery, DeltaStep, DeltaUploadForm,
+    FullDownload, RELEASE_COLUMNS, RELEASE_DELTA_COLUMNS, Release, ReleaseDelta,
+    UpdateCheckContext,
+};
+use crate::variants::scoped_release;
+use crate::versioning::{self, AppVersion};
+
+/// A patch, with the version of the release it leads to.
+#[derive(Debug, FromRow)]
+struct Edge {
+    from_version: String,
+    to_version: String,
+    url: String,
+    signature: String,
+    sha256: String,
+    size: i64,
+}
+
+async fn load_deltas(
+    pool: &Pool<Sqlite>,
+    release_id: i64,
+) -> Result<Vec<ReleaseDelta>, sqlx::Error> {
+    sqlx::query_as::<_, ReleaseDelta>(&format!(
+        "SELECT {} FROM release_deltas WHERE release_id = ? ORDER BY from_version",
+        RELEASE_DELTA_COLUMNS
+    ))
+    .bind(release_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Removes every patch of a release, returning their artifacts for the
+/// caller to release.
+pub async fn remove_deltas(
+    conn: &mut SqliteConnection,
+    release_id: i64,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar("DELETE FROM release_deltas WHERE release_id = ? RETURNING sha256")
+        .bind(release_id)
+        .fetch_all(conn)
+        .await
+}
+
+/// The chain of patches from `from` to `to` that is smallest in total, by
+/// Dijkstra over the versions. Patches only ever move forward.
+fn cheapest_chain(
+    edges: Vec<(AppVersion, AppVersion, Edge)>,
+    from: &AppVersion,
+    to: &AppVersion,
+) -> Vec<DeltaStep> {
+    let mut outgoing: BTreeMap<&AppVersion, Vec<usize>> = BTreeMap::new();
+    for (index, (source, target, _)) in edges.iter().enumerate() {
+        if source < target && target <= to {
+            outgoing.entry(source).or_default().push(index);
+        }
+    }
+
+    let mut best: BTreeMap<&AppVersion, (i64, Option<usize>)> = BTreeMap::new();
+    let mut queue = BinaryHeap::new();
+    best.insert(from, (0, None));
+    queue.push(Reverse((0i64, from)));
+    while let Some(Reverse((cost, version))) = queue.pop() {
+        if version == to {
+            break;
+        }
+        if best.get(version).is_some_and(|(c, _)| *c < cost) {
+            continue;
+        }
+        for &index in outgoing.get(version).into_iter().flatten() {
+            let (_, target, edge) = &edges[index];
+            let next = cost + edge.size;
+            if best.get(target).is_none_or(|(c, _)| next < *c) {
+                best.insert(target, (next, Some(index)));
+                queue.push(Reverse((next, target)));
+            }
+        }
+    }
+
+    let mut chain = Vec::new();
+    let mut at = to;
+    while let Some((_, Some(index))) = best.get(at) {
+        let (source, _, edge) = &edges[*index];
+        chain.push(DeltaStep {
+            from_version: edge.from_version.clone(),
+            to_version: edge.to_version.clone(),
+            url: edge.url.clone(),
+            signature: edge.signature.clone(),
+            sha256: edge.sha256.clone(),
+            size: edge.size,
+        });
+        at = source;
+    }
+    if at != from {
+        return Vec::new();
+    }
+    chain.reverse();
+    chain
+}
+
+/// Available deltas
+///
+/// Lists the patches that take an installation of exactly `current_version`
+/// to the latest release, with the full download to compare them against.
+#[utoipa::path(
+    get,
+    path = "/{app_name}/{target}/{arch}/{current_version}/deltas",
+    security(()),
+    params(
+        ("app_name" = String, Path, description = "Application name"),
+        ("target" = String, Path, description = "Target OS"),
+        ("arch" = String, Path, description = "Architecture (e.g., aarch64, x86_64)"),
+        ("current_version" = String, Path, description = "Current version of the application"),
+        DeltaQuery
+    ),
+    responses(
+        (status = 200, description = "The patch chain, if any, and the full download", body = DeltaManifest),
+        (status = 204, description = "Already on the latest version, or past `to_version`"),
+        (status = 400, description = "Invalid version", body = ErrorBody),
+        (status = 402, description = "The license key has expired (`license_expired`)", body = ErrorBody),
+        (status = 403, description = "The app requires a license key and none or an invalid one was sent (`license_required`, `license_invalid`)", body = ErrorBody),
+        (status = 404, description = "No release to update to", body = ErrorBody)
+    )
+)]
+pub async fn get_delta_manifest(
+    Path((app_name, target, arch, current_version)): Path<(String, String, String, String)>,
+    Query(query): Query<DeltaQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let channel = query.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
+    let scheme = versioning::scheme_for(&state, &app_name).await?;
+    let current = versioning::parse_field(scheme, "current_version", &current_version)?;
+    let context = UpdateCheckContext {
+        app_name: app_name.clone(),
+        target: target.clone(),
+        arch: arch.clone(),
+        current_version: Some(current_version.clone()),
+        channel: channel.to_string(),
+        customer_id: None,
+    };
+    let key = licenses::request_key(&headers, query.license_key.as_deref());
+    licenses::check_license(&state, &context, key).await?;
+    let vary = |response: Response| http_cache::vary(response, licenses::LICENSE_KEY_HEADER);
+
+    let release = match query.to_version.as_deref().map(str::trim) {
+        Some(version) => sqlx::query_as::<_, Release>(&format!(
+            "SELECT {} FROM releases WHERE app_name = ? AND target = ? AND arch = ? AND channel = ? AND version = ? AND yanked = 0 AND archived = 0 ORDER BY id DESC LIMIT 1",
+            RELEASE_COLUMNS
+        ))
+        .bind(&app_name)
+        .bind(&target)
+        .bind(&arch)
+        .bind(channel)
+        .bind(version)
+        .fetch_optional(&state.read_pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up the release", e))?,
+        None => find_latest_release(&state, &app_name, &target, &arch, channel)
+            .await
+            .map_err(|e| AppError::internal("Failed to look up the latest release", e))?,
+    }
+    .ok_or_else(|| AppError::not_found("No release found"))?;
+    let to = versioning::parse(scheme, &release.version)
+        .map_err(|e| AppError::internal("Release has an invalid version", e))?;
+    if to <= current {
+        return Ok(vary(StatusCode::NO_CONTENT.into_response()));
+    }
+
+    let edges = sqlx::query_as::<_, Edge>(
+        "SELECT d.from_version, r.version AS to_version, d.url, d.signature, d.sha256, d.size FROM release_deltas d JOIN releases r ON r.id = d.release_id WHERE r.app_name = ? AND r.target = ? AND r.arch = ? AND r.channel = ? AND r.yanked = 0 AND r.archived = 0",
+    )
+    .bind(&app_name)
+    .bind(&target)
+    .bind(&arch)
+    .bind(channel)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to look up deltas", e))?;
+    let edges = edges
+        .into_iter()
+        .filter_map(|edge| {
+            let source = versioning::parse(scheme, &edge.from_version).ok()?;
+            let target = versioning::parse(scheme, &edge.to_version).ok()?;
+            Some((source, target, edge))
+        })
+        .collect();
+    let chain = cheapest_chain(edges, &current, &to);
+
+    let size = match &release.sha256 {
+        Some(sha256) => artifacts::find(&state.read_pool, &release.app_name, sha256)
+            .await
+            .map_err(|e| AppError::internal("Failed to look up artifact", e))?
+            .map(|a| a.size),
+        None => None,
+    };
+    let manifest = DeltaManifest {
+        current_version,
+        to_version: release.version,
+        full: FullDownload {
+            url: release.url,
+            signature: release.signature,
+            sha256: release.sha256,
+            size,
+        },
+        chain_size: chain.iter().map(|step| step.size).sum(),
+        chain,
+    };
+    Ok(vary(Json(manifest).into_response()))
+}
+
+/// Upload a delta
+///
+/// Replaces the release's patch from the same version, if any.
+#[utoipa::path(
+    post,
+    path = "/releases/{id}/deltas",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    request_body(content = DeltaUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "Patch stored", body = ReleaseDelta),
+        (status = 400, description = "Invalid or not older from_version, or no file", body = ErrorBody),
+        (status = 404, description = "Release not found", body = ErrorBody),
+        (status = 413, description = "The upload doesn't fit the storage quota", body = ErrorBody),
+        (status = 429, description = "The daily upload quota is used up", body = ErrorBody)
+    )
+)]
+pub async fn upload_delta(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    mut multipart: Multipart,
+) -> AppResult<(StatusCode, Json<ReleaseDelta>)> {
+    let mut from_version = String::new();
+    let mut signature = String::new();
+    let mut file_name = String::new();
+    let mut file_data: Vec<u8> = Vec::new();
+    while let Some(res) = multipart.next_field().await.transpose() {
+        let field =
+            res.map_err(|e| AppError::bad_request(format!("Malformed multipart body: {}", e)))?;
+        match field.name().unwrap_or_default() {
+            "from_version" => from_version = field.text().await.unwrap_or_default(),
+            "signature" => signature = field.text().await.unwrap_or_default(),
+            "file" => {
+                file_name = field.file_name().unwrap_or("delta.patch").to_string();
+                file_data = field
+                    .bytes()
+                    .await
+                    .map_err(|e| AppError::bad_request(format!("Failed to read file: {}", e)))?
+                    .to_vec();
+            }
+            _ => (),
+        }
+    }
+    if file_data.is_empty() {
+        return Err(AppError::bad_request("No file uploaded or file is empty"));
+    }
+    let release = scoped_release(&state, principal, id).await?;
+    let scheme = versioning::scheme_for(&state, &release.app_name).await?;
+    let from_version = from_version.trim();
+    let from = versioning::parse_field(scheme, "from_version", from_version)?;
+    if versioning::parse(scheme, &release.version).is_ok_and(|v| v <= from) {
+        return Err(AppError::bad_request(format!(
+            "from_version {} is not older than the release's {}",
+            from_version, release.version
+        )));
+    }
+
+    let sha256 = artifacts::sha256_hex(&file_data);
+    let size = file_data.len() as i64;
+    quotas::check_upload(&state, &release.app_name, &sha256, size, false).await?;
+    // Patches go with the release's artifacts, named after both versions.
+    let file_name = normalize::canonical_file_name(
+        &release.app_name,
+        &format!("{}_to_{}", from_version, release.version),
+        &release.target,
+        &release.arch,
+        &file_name,
+    );
+    let (sha256, size, url, github_asset_id) = store_artifact(
+        &state,
+        &release.app_name,
+        sha256,
+        &release_tag(&release.app_name, &release.version),
+        &release.notes,
+        &file_name,
+        file_data,
+    )
+    .await?;
+
+    let saved: Result<Result<(ReleaseDelta, Option<Artifact>), AppError>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        if let Some(refused) = quotas::record_upload(
+            &mut tx,
+            &state.config,
+            &release.app_name,
+            &sha256,
+            size,
+            false,
+        )
+        .await?
+        {
+            return Ok(Err(refused));
+        }
+        artifacts::retain(
+            &mut tx,
+            &release.app_name,
+            &sha256,
+            &url,
+            size,
+            github_asset_id,
+        )
+        .await?;
+        let replaced: Option<String> = sqlx::query_scalar(
+            "DELETE FROM release_deltas WHERE release_id = ? AND from_version = ? RETURNING sha256",
+        )
+        .bind(release.id)
+        .bind(from_version)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let delta = sqlx::query_as::<_, ReleaseDelta>(&format!(
+            "INSERT INTO release_deltas ({0}) VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING {0}",
+            RELEASE_DELTA_COLUMNS
+        ))
+        .bind(release.id)
+        .bind(from_version)
+        .bind(&url)
+        .bind(signature.trim())
+        .bind(&sha256)
+        .bind(size)
+        .bind(Utc::now())
+        .fetch_one(&mut *tx)
+        .await?;
+        let orphaned = match replaced {
+            Some(old) => artifacts::release(&mut tx, &release.app_name, &old).await?,
+            None => None,
+        };
+        tx.commit().await?;
+        Ok(Ok((delta, orphaned)))
+    }
+    .await;
+    let (delta, orphaned) = saved.map_err(|e| AppError::internal("Failed to save delta", e))??;
+    delete_orphaned(&state, orphaned).await;
+
+    println!(
+        "Release {} now has a {} byte patch from {}",
+        release.id, delta.size, delta.from_version
+    );
+    Ok((StatusCode::CREATED, Json(delta)))
+}
+
+/// List a release's deltas
+#[utoipa::path(
+    get,
+    path = "/releases/{id}/deltas",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    responses(
+        (status = 200, description = "Patches to the release", body = Vec<ReleaseDelta>),
+        (status = 404, description = "Release not found", body = ErrorBody)
+    )
+)]
+pub async fn list_deltas(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<ReleaseDelta>>> {
+    let release = scoped_release(&state, principal, id).await?;
+    let deltas = load_deltas(&state.read_pool, release.id)
+        .await
+        .map_err(|e| AppError::internal("Failed to load deltas", e))?;
+    Ok(Json(deltas))
+}
+
+/// Remove a delta from a release
+#[utoipa::path(
+    delete,
+    path = "/releases/{id}/deltas/{from_version}",
+    params(
+        ("id" = i64, Path, description = "Release ID"),
+        ("from_version" = String, Path, description = "Version the patch applies to")
+    ),
+    responses(
+        (status = 204, description = "Patch removed"),
+        (status = 404, description = "Release or patch not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_delta(
+    Path((id, from_version)): Path<(i64, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let release = scoped_release(&state, principal, id).await?;
+    let removed: Result<Option<Option<Artifact>>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        let Some(sha256) = sqlx::query_scalar::<_, String>(
+            "DELETE FROM release_deltas WHERE release_id = ? AND from_version = ? RETURNING sha256",
+        )
+        .bind(release.id)
+        .bind(&from_version)
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let orphaned = artifacts::release(&mut tx, &release.app_name, &sha256).await?;
+        tx.commit().await?;
+        Ok(Some(orphaned))
+    }
+    .await;
+    let orphaned = removed
+        .map_err(|e| AppError::internal("Failed to remove delta", e))?
+        .ok_or_else(|| AppError::not_found("Delta not found"))?;
+    delete_orphaned(&state, orphaned).await;
+
+    println!(
+        "Removed the patch from {} of release {}",
+        from_version, release.id
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(raw: &str) -> AppVersion {
+        AppVersion::Semver(semver::Version::parse(raw).unwrap())
+    }
+
+    fn edge(from: &str, to: &str, size: i64) -> (AppVersion, AppVersion, Edge) {
+        (
+            version(from),
+            version(to),
+            Edge {
+                from_version: from.to_string(),
+                to_version: to.to_string(),
+                url: format!("https://example.com/{}_to_{}.patch", from, to),
+                signature: String::new(),
+                sha256: String::new(),
+                size,
+            },
+        )
+    }
+
+    fn steps(chain: &[DeltaStep]) -> Vec<(&str, &str)> {
+        chain
+            .iter()
+            .map(|s| (s.from_version.as_str(), s.to_version.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn no_chain_without_a_path() {
+        let edges = vec![edge("1.0.0", "1.1.0", 10), edge("1.2.0", "1.3.0", 10)];
+        let chain = cheapest_chain(edges, &version("1.0.0"), &version("1.3.0"));
+        assert!(chain.is_empty());
+    }
+
+    #[test]
+    fn prefers_a_cheaper_chain_over_a_direct_patch() {
+        let edges = vec![
+            edge("1.0.0", "1.2.0", 100),
+            edge("1.0.0", "1.1.0", 20),
+            edge("1.1.0", "1.2.0", 30),
+        ];
+        let chain = cheapest_chain(edges, &version("1.0.0"), &version("1.2.0"));
+        assert_eq!(steps(&chain), [("1.0.0", "1.1.0"), ("1.1.0", "1.2.0")]);
+        assert_eq!(chain.iter().map(|s| s.size).sum::<i64>(), 50);
+    }
+
+    #[test]
+    fn prefers_a_direct_patch_when_it_is_cheaper() {
+        let edges = vec![
+            edge("1.0.0", "1.2.0", 40),
+            edge("1.0.0", "1.1.0", 20),
+            edge("1.1.0", "1.2.0", 30),
+        ];
+        let chain = cheapest_chain(edges, &version("1.0.0"), &version("1.2.0"));
+        assert_eq!(steps(&chain), [("1.0.0", "1.2.0")]);
+    }
+
+    #[test]
+    fn ignores_patches_past_the_target_or_backwards() {
+        let edges = vec![
+            edge("1.0.0", "1.3.0", 1),
+            edge("1.3.0", "1.2.0", 1),
+            edge("1.0.0", "1.2.0", 50),
+        ];
+        let chain = cheapest_chain(edges, &version("1.0.0"), &version("1.2.0"));
+        assert_eq!(steps(&chain), [("1.0.0", "1.2.0")]);
+    }
+}
@@ -0,0 +1,445 @@
+//! Managed devices.
+//!
+//! For the managed-laptop program, admins register the devices they hand
+//! out. Update checks that pass `device_id` then double as check-ins: the
+//! device's reported version, platform and last contact are recorded, which
+//! gives a fleet view over the updater traffic. Unregistered IDs are
+//! ignored, so anonymous clients can't fill the table.
+//!
+//! A device, or every device in a group, can also be targeted with a
+//! version. Targeted devices are offered that version instead of the
+//! channel's latest (rings and deferral don't apply) and nothing newer.
+//! Blackouts, licenses and entitlement hooks still do.
+
+use axum::{
+    Extension,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+
+use crate::auth::{self, Principal, app_scope};
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::schema::{
+    AppState, DEVICE_COLUMNS, DEVICE_TARGET_COLUMNS, Device, DeviceFilter, DeviceRequest,
+    DeviceTarget, DeviceTargetFilter, DeviceTargetRequest, RELEASE_COLUMNS, Release,
+};
+use crate::versioning;
+
+/// Check-ins that change nothing only rewrite `last_seen_at` when it is
+/// older than this, so polling devices don't turn every check into a write.
+const LAST_SEEN_RESOLUTION: chrono::Duration = chrono::Duration::minutes(5);
+
+fn validate_id(field: &str, value: &str) -> AppResult<()> {
+    if value.is_empty() || value.len() > 128 || value.chars().any(|c| c.is_control()) {
+        return Err(AppError::bad_request(format!(
+            "{} must be 1-128 printable characters",
+            field
+        )));
+    }
+    Ok(())
+}
+
+/// Optional text fields: blank means unset.
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// Records a check-in of `device_id` if it is registered, and returns the
+/// version it is targeted with, if any.
+pub async fn check_in(
+    state: &AppState,
+    app_name: &str,
+    device_id: &str,
+    (target, arch): (&str, &str),
+    current_version: &str,
+) -> AppResult<Option<String>> {
+    let now = Utc::now();
+    // Failing to record a check-in shouldn't fail the update check.
+    if let Err(e) = sqlx::query(
+        "UPDATE devices SET target = ?1, arch = ?2, current_version = ?3, last_seen_at = ?4 WHERE app_name = ?5 AND device_id = ?6 AND (target IS NOT ?1 OR arch IS NOT ?2 OR current_version IS NOT ?3 OR last_seen_at IS NULL OR last_seen_at < ?7)",
+    )
+    .bind(target)
+    .bind(arch)
+    .bind(current_version)
+    .bind(now)
+    .bind(app_name)
+    .bind(device_id)
+    .bind(now - LAST_SEEN_RESOLUTION)
+    .execute(&state.pool)
+    .await
+    {
+        println!(
+            "Failed to record check-in of device {} for {}: {}",
+            device_id, app_name, e
+        );
+    }
+
+    // A target naming the device wins over one naming its group.
+    sqlx::query_scalar(
+        "SELECT version FROM device_targets WHERE app_name = ?1 AND (device_id = ?2 OR group_name = (SELECT group_name FROM devices WHERE app_name = ?1 AND device_id = ?2)) ORDER BY device_id IS NULL, id DESC LIMIT 1",
+    )
+    .bind(app_name)
+    .bind(device_id)
+    .fetch_optional(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to look up the device's target", e))
+}
+
+/// The release of `version` a targeted device should install, preferring the
+/// channel it follows.
+pub async fn targeted_release(
+    pool: &Pool<Sqlite>,
+    (app_name, target, arch, channel): (&str, &str, &str, &str),
+    version: &str,
+) -> Result<Option<Release>, sqlx::Error> {
+    sqlx::query_as::<_, Release>(&format!(
+        "SELECT {} FROM releases WHERE app_name = ? AND target = ? AND arch = ? AND version = ? AND yanked = 0 AND archived = 0 ORDER BY channel = ? DESC, id DESC LIMIT 1",
+        RELEASE_COLUMNS
+    ))
+    .bind(app_name)
+    .bind(target)
+    .bind(arch)
+    .bind(version)
+    .bind(channel)
+    .fetch_optional(pool)
+    .await
+}
+
+/// List managed devices
+#[utoipa::path(
+    get,
+    path = "/devices",
+    params(DeviceFilter),
+    responses(
+        (status = 200, description = "Registered devices, most recently seen first", body = Vec<Device>)
+    )
+)]
+pub async fn list_devices(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<DeviceFilter>,
+) -> AppResult<Json<Vec<Device>>> {
+    let devices = sqlx::query_as::<_, Device>(&format!(
+        "SELECT {} FROM devices WHERE (?1 IS NULL OR app_name = ?1) AND (?2 IS NULL OR customer_id = ?2) AND (?3 IS NULL OR group_name = ?3) AND (?4 IS NULL OR current_version = ?4) AND {} ORDER BY last_seen_at IS NULL, last_seen_at DESC, app_name, device_id",
+        DEVICE_COLUMNS,
+        app_scope(5)
+    ))
+    .bind(&filter.app_name)
+    .bind(&filter.customer_id)
+    .bind(&filter.group_name)
+    .bind(&filter.current_version)
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load devices", e))?;
+
+    Ok(Json(devices))
+}
+
+/// Get a managed device
+#[utoipa::path(
+    get,
+    path = "/devices/{app_name}/{device_id}",
+    params(
+        ("app_name" = String, Path, description = "Application name"),
+        ("device_id" = String, Path, description = "Device identifier the app sends as `device_id`")
+    ),
+    responses(
+        (status = 200, description = "Device", body = Device),
+        (status = 404, description = "Device not found", body = ErrorBody)
+    )
+)]
+pub async fn get_device(
+    Path((app_name, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Device>> {
+    let device = sqlx::query_as::<_, Device>(&format!(
+        "SELECT {} FROM devices WHERE app_name = ?1 AND device_id = ?2 AND {}",
+        DEVICE_COLUMNS,
+        app_scope(3)
+    ))
+    .bind(&app_name)
+    .bind(&device_id)
+    .bind(principal.org_id())
+    .fetch_optional(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load device", e))?
+    .ok_or_else(|| AppError::not_found("Device not found"))?;
+
+    Ok(Json(device))
+}
+
+/// Register or update a managed device
+#[utoipa::path(
+    put,
+    path = "/devices/{app_name}/{device_id}",
+    params(
+        ("app_name" = String, Path, description = "Application name"),
+        ("device_id" = String, Path, description = "Device identifier the app sends as `device_id`")
+    ),
+    request_body = DeviceRequest,
+    responses(
+        (status = 200, description = "Device registered", body = Device),
+        (status = 400, description = "Invalid device ID", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody)
+    )
+)]
+pub async fn register_device(
+    Path((app_name, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<DeviceRequest>,
+) -> AppResult<Json<Device>> {
+    validate_id("device_id", &device_id)?;
+    let customer_id = non_empty(&request.customer_id);
+    if let Some(customer_id) = customer_id {
+        validate_id("customer_id", customer_id)?;
+    }
+    let group_name = non_empty(&request.group_name);
+    if let Some(group_name) = group_name {
+        validate_id("group_name", group_name)?;
+    }
+    auth::claim_app(&state.pool, principal, &app_name).await?;
+
+    // The platform a device reports on check-in is more current than what
+    // was entered at registration, so only overwrite it when given.
+    let device = sqlx::query_as::<_, Device>(&format!(
+        "INSERT INTO devices (app_name, device_id, customer_id, group_name, target, arch, registered_at) VALUES (?, ?, ?, ?, ?, ?, ?) ON CONFLICT (app_name, device_id) DO UPDATE SET customer_id = excluded.customer_id, group_name = excluded.group_name, target = COALESCE(excluded.target, devices.target), arch = COALESCE(excluded.arch, devices.arch) RETURNING {}",
+        DEVICE_COLUMNS
+    ))
+    .bind(&app_name)
+    .bind(&device_id)
+    .bind(customer_id)
+    .bind(group_name)
+    .bind(non_empty(&request.target))
+    .bind(non_empty(&request.arch))
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to register device", e))?;
+
+    println!(
+        "Registered device {} for {}",
+        device.device_id, device.app_name
+    );
+    Ok(Json(device))
+}
+
+/// Unregister a managed device
+#[utoipa::path(
+    delete,
+    path = "/devices/{app_name}/{device_id}",
+    params(
+        ("app_name" = String, Path, description = "Application name"),
+        ("device_id" = String, Path, description = "Device identifier")
+    ),
+    responses(
+        (status = 204, description = "Device and its own target removed"),
+        (status = 404, description = "Device not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_device(
+    Path((app_name, device_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|e| AppError::internal("Failed to unregister device", e))?;
+    let deleted = sqlx::query(&format!(
+        "DELETE FROM devices WHERE app_name = ?1 AND device_id = ?2 AND {}",
+        app_scope(3)
+    ))
+    .bind(&app_name)
+    .bind(&device_id)
+    .bind(principal.org_id())
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::internal("Failed to unregister device", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("Device not found"));
+    }
+    sqlx::query("DELETE FROM device_targets WHERE app_name = ? AND device_id = ?")
+        .bind(&app_name)
+        .bind(&device_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| AppError::internal("Failed to unregister device", e))?;
+    tx.commit()
+        .await
+        .map_err(|e| AppError::internal("Failed to unregister device", e))?;
+
+    println!("Unregistered device {} of {}", device_id, app_name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List device targets
+#[utoipa::path(
+    get,
+    path = "/device-targets",
+    params(DeviceTargetFilter),
+    responses(
+        (status = 200, description = "Device and group targets", body = Vec<DeviceTarget>)
+    )
+)]
+pub async fn list_device_targets(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<DeviceTargetFilter>,
+) -> AppResult<Json<Vec<DeviceTarget>>> {
+    let targets = sqlx::query_as::<_, DeviceTarget>(&format!(
+        "SELECT {} FROM device_targets WHERE (?1 IS NULL OR app_name = ?1) AND {} ORDER BY app_name, id",
+        DEVICE_TARGET_COLUMNS,
+        app_scope(2)
+    ))
+    .bind(&filter.app_name)
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load device targets", e))?;
+
+    Ok(Json(targets))
+}
+
+/// Target a device or group with a version
+///
+/// Replaces any earlier target of the same device or group.
+#[utoipa::path(
+    post,
+    path = "/device-targets",
+    request_body = DeviceTargetRequest,
+    responses(
+        (status = 201, description = "Target saved", body = DeviceTarget),
+        (status = 400, description = "Neither or both of device_id and group_name, or no such release", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody),
+        (status = 404, description = "Device not registered", body = ErrorBody)
+    )
+)]
+pub async fn create_device_target(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<DeviceTargetRequest>,
+) -> AppResult<(StatusCode, Json<DeviceTarget>)> {
+    let app_name = request.app_name.trim();
+    if app_name.is_empty() {
+        return Err(AppError::bad_request("app_name is required"));
+    }
+    let device_id = non_empty(&request.device_id);
+    let group_name = non_empty(&request.group_name);
+    if device_id.is_some() == group_name.is_some() {
+        return Err(AppError::bad_request(
+            "Give exactly one of device_id and group_name",
+        ));
+    }
+    let version = request.version.trim();
+    auth::claim_app(&state.pool, principal, app_name).await?;
+    let scheme = versioning::scheme_for(&state, app_name).await?;
+    versioning::parse_field(scheme, "version", version)?;
+
+    let released: Option<i64> = sqlx::query_scalar(
+        "SELECT id FROM releases WHERE app_name = ? AND version = ? AND yanked = 0 AND archived = 0 LIMIT 1",
+    )
+    .bind(app_name)
+    .bind(version)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to look up the release", e))?;
+    if released.is_none() {
+        return Err(AppError::bad_request(format!(
+            "{} has no release {}",
+            app_name, version
+        )));
+    }
+    if let Some(device_id) = device_id {
+        let registered: Option<String> = sqlx::query_scalar(
+            "SELECT device_id FROM devices WHERE app_name = ? AND device_id = ?",
+        )
+        .bind(app_name)
+        .bind(device_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to load device", e))?;
+        registered.ok_or_else(|| AppError::not_found("Device not found"))?;
+    }
+
+    let mut tx = state
+        .pool
+        .begin()
+        .await
+        .map_err(|e| AppError::internal("Failed to save device target", e))?;
+    sqlx::query(
+        "DELETE FROM device_targets WHERE app_name = ?1 AND (device_id = ?2 OR group_name = ?3)",
+    )
+    .bind(app_name)
+    .bind(device_id)
+    .bind(group_name)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::internal("Failed to save device target", e))?;
+    let target = sqlx::query_as::<_, DeviceTarget>(&format!(
+        "INSERT INTO device_targets (app_name, device_id, group_name, version, created_at) VALUES (?, ?, ?, ?, ?) RETURNING {}",
+        DEVICE_TARGET_COLUMNS
+    ))
+    .bind(app_name)
+    .bind(device_id)
+    .bind(group_name)
+    .bind(version)
+    .bind(Utc::now())
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| AppError::internal("Failed to save device target", e))?;
+    tx.commit()
+        .await
+        .map_err(|e| AppError::internal("Failed to save device target", e))?;
+
+    println!(
+        "Targeted {} {} of {} with {}",
+        if target.device_id.is_some() {
+            "device"
+        } else {
+            "group"
+        },
+        device_id.or(group_name).unwrap_or_default(),
+        target.app_name,
+        target.version
+    );
+    Ok((StatusCode::CREATED, Json(target)))
+}
+
+/// Remove a device target
+#[utoipa::path(
+    delete,
+    path = "/device-targets/{id}",
+    params(
+        ("id" = i64, Path, description = "Device target ID")
+    ),
+    responses(
+        (status = 204, description = "Target removed; its devices follow their channel again"),
+        (status = 404, description = "Device target not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_device_target(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let deleted = sqlx::query(&format!(
+        "DELETE FROM device_targets WHERE id = ?1 AND {}",
+        app_scope(2)
+    ))
+    .bind(id)
+    .bind(principal.org_id())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to remove device target", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("Device target not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
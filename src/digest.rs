@@ -0,0 +1,500 @@
+//! Daily digest for release managers.
+//!
+//! With `DIGEST_HOUR_UTC` set, a summary of the last 24 hours goes out at
+//! that hour to `SLACK_WEBHOOK_URL` and, over `SMTP_URL`, to every address
+//! in `DIGEST_EMAIL_TO`. It covers releases published, ring rollouts still
+//! under way and promotion decisions, install failure rates, download links
+//! of the newest releases that don't answer, and each organization's quota
+//! usage. The `digests` table makes sure only one replica sends each day's.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use axum::{
+    Extension,
+    body::Bytes,
+    extract::State,
+    http::{Method, Request, header},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use http_body_util::Full;
+use serde_json::json;
+use tokio::task::JoinSet;
+
+use crate::auth::Principal;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::http_client::HttpClient;
+use crate::notify::slack_escape;
+use crate::quotas;
+use crate::reports::OutcomeCounts;
+use crate::rings::{self, RINGS};
+use crate::schema::{
+    AppState, RELEASE_COLUMNS, RELEASE_PROMOTION_COLUMNS, Release, ReleasePromotion,
+};
+use crate::smtp::{self, Email, Mailer};
+
+/// Releases listed with their install failure rate, worst first.
+const MAX_FAILURE_LINES: usize = 10;
+
+/// Download links checked per digest.
+const MAX_LINK_CHECKS: i64 = 200;
+
+const LINK_CHECK_CONCURRENCY: usize = 8;
+
+/// One part of the digest: a heading and a line per finding.
+struct Section {
+    title: &'static str,
+    lines: Vec<String>,
+}
+
+pub struct Digest {
+    since: DateTime<Utc>,
+    sections: Vec<Section>,
+}
+
+fn megabytes(bytes: i64) -> String {
+    format!("{:.1} MB", bytes as f64 / 1_000_000.0)
+}
+
+/// `used` and, when there is one, the limit it counts against.
+fn of_limit(used: String, limit: Option<String>) -> String {
+    match limit {
+        Some(limit) => format!("{} of {}", used, limit),
+        None => used,
+    }
+}
+
+async fn published(state: &AppState, since: DateTime<Utc>) -> Result<Section, sqlx::Error> {
+    let releases = sqlx::query_as::<_, Release>(&format!(
+        "SELECT {} FROM releases WHERE pub_date > ? ORDER BY pub_date",
+        RELEASE_COLUMNS
+    ))
+    .bind(since)
+    .fetch_all(&state.read_pool)
+    .await?;
+
+    let mut versions: BTreeMap<(String, String, String), Vec<String>> = BTreeMap::new();
+    for r in releases {
+        versions
+            .entry((r.app_name, r.version, r.channel))
+            .or_default()
+            .push(format!("{}/{}", r.target, r.arch));
+    }
+    Ok(Section {
+        title: "Releases published",
+        lines: versions
+            .into_iter()
+            .map(|((app, version, channel), platforms)| {
+                format!(
+                    "{} {} on {}: {}",
+                    app,
+                    version,
+                    channel,
+                    platforms.join(", ")
+                )
+            })
+            .collect(),
+    })
+}
+
+async fn rollouts(state: &AppState, since: DateTime<Utc>) -> Result<Section, sqlx::Error> {
+    let now = Utc::now();
+    let scheduled = sqlx::query_as::<_, Release>(&format!(
+        "SELECT {} FROM releases WHERE ring_schedule IS NOT NULL AND yanked = 0 AND archived = 0 ORDER BY id",
+        RELEASE_COLUMNS
+    ))
+    .fetch_all(&state.read_pool)
+    .await?;
+
+    let mut lines = Vec::new();
+    for r in scheduled
+        .iter()
+        .filter(|r| !rings::is_available(r, rings::DEFAULT_RING, now))
+    {
+        let reached: Vec<&str> = RINGS
+            .into_iter()
+            .filter(|ring| rings::is_available(r, ring, now))
+            .collect();
+        let next = RINGS.into_iter().find_map(|ring| {
+            rings::available_at(r, ring)
+                .filter(|at| *at > now)
+                .map(|at| (ring, at))
+        });
+        let mut line = format!(
+            "{} {} {}/{} on {}: ",
+            r.app_name, r.version, r.target, r.arch, r.channel
+        );
+        if reached.is_empty() {
+            line.push_str("no ring yet");
+        } else {
+            line.push_str(&format!("reached {}", reached.join(", ")));
+        }
+        if let Some((ring, at)) = next {
+            line.push_str(&format!(
+                ", {} from {}",
+                ring,
+                at.format("%Y-%m-%d %H:%M UTC")
+            ));
+        }
+        lines.push(line);
+    }
+
+    let decisions = sqlx::query_as::<_, ReleasePromotion>(&format!(
+        "SELECT {} FROM release_promotions WHERE decided_at > ? ORDER BY decided_at",
+        RELEASE_PROMOTION_COLUMNS
+    ))
+    .bind(since)
+    .fetch_all(&state.read_pool)
+    .await?;
+    for d in decisions {
+        lines.push(format!(
+            "{} {} {} from {} to {}: {}",
+            d.app_name, d.version, d.state, d.from_channel, d.to_channel, d.reason
+        ));
+    }
+    Ok(Section {
+        title: "Rollouts",
+        lines,
+    })
+}
+
+async fn failures(state: &AppState, since: DateTime<Utc>) -> Result<Section, sqlx::Error> {
+    let rows: Vec<(i64, String, String, String, String, String, i64)> = sqlx::query_as(
+        "SELECT r.id, r.app_name, r.version, r.target, r.arch, i.outcome, COUNT(*)
+         FROM install_reports i JOIN releases r ON r.id = i.release_id
+         WHERE i.created_at > ? GROUP BY r.id, i.outcome",
+    )
+    .bind(since)
+    .fetch_all(&state.read_pool)
+    .await?;
+
+    let mut releases: BTreeMap<i64, (String, OutcomeCounts)> = BTreeMap::new();
+    for (id, app, version, target, arch, outcome, count) in rows {
+        let (_, counts) = releases.entry(id).or_insert_with(|| {
+            (
+                format!("{} {} {}/{}", app, version, target, arch),
+                OutcomeCounts::default(),
+            )
+        });
+        match outcome.as_str() {
+            "installed" => counts.installed += count,
+            "failed" => counts.failed += count,
+            "rolled_back" => counts.rolled_back += count,
+            _ => {}
+        }
+    }
+    let mut releases: Vec<(String, OutcomeCounts)> = releases.into_values().collect();
+    releases.sort_by(|a, b| b.1.failure_rate().total_cmp(&a.1.failure_rate()));
+    Ok(Section {
+        title: "Install failures",
+        lines: releases
+            .into_iter()
+            .take(MAX_FAILURE_LINES)
+            .map(|(label, c)| {
+                format!(
+                    "{}: {:.1}% of {} installs ({} failed, {} rolled back)",
+                    label,
+                    c.failure_rate() * 100.0,
+                    c.total(),
+                    c.failed,
+                    c.rolled_back
+                )
+            })
+            .collect(),
+    })
+}
+
+async fn check_link(client: &HttpClient, url: &str) -> Result<(), String> {
+    let request = Request::builder()
+        .method(Method::HEAD)
+        .uri(url)
+        .body(Full::new(Bytes::new()))
+        .map_err(|e| format!("{} is not a valid URL: {}", url, e))?;
+    let response = client.send(request, 0).await?;
+    // Release assets answer with a redirect to their storage.
+    if response.status.is_client_error() || response.status.is_server_error() {
+        return Err(format!("{} answered {}", url, response.status));
+    }
+    Ok(())
+}
+
+/// Checks the download link of the newest upload for each platform and
+/// channel, which is what update checks offer.
+async fn broken_links(state: &AppState) -> Result<Section, sqlx::Error> {
+    let releases = sqlx::query_as::<_, Release>(&format!(
+        "SELECT {} FROM releases WHERE id IN (SELECT MAX(id) FROM releases WHERE yanked = 0 AND archived = 0 GROUP BY app_name, target, arch, channel) ORDER BY app_name, id LIMIT ?",
+        RELEASE_COLUMNS
+    ))
+    .bind(MAX_LINK_CHECKS)
+    .fetch_all(&state.read_pool)
+    .await?;
+    let client = match HttpClient::new(state.config.webhook_timeout) {
+        Ok(client) => Arc::new(client),
+        Err(e) => {
+            return Ok(Section {
+                title: "Broken download links",
+                lines: vec![format!("Links were not checked: {}", e)],
+            });
+        }
+    };
+
+    let mut lines = Vec::new();
+    for batch in releases.chunks(LINK_CHECK_CONCURRENCY) {
+        let mut checks = JoinSet::new();
+        for (i, r) in batch.iter().enumerate() {
+            let client = client.clone();
+            let url = r.url.clone();
+            checks.spawn(async move { (i, check_link(&client, &url).await) });
+        }
+        let mut results = checks.join_all().await;
+        results.sort_by_key(|(i, _)| *i);
+        for (i, result) in results {
+            if let Err(e) = result {
+                let r = &batch[i];
+                lines.push(format!(
+                    "{} {} {}/{} on {}: {}",
+                    r.app_name, r.version, r.target, r.arch, r.channel, e
+                ));
+            }
+        }
+    }
+    Ok(Section {
+        title: "Broken download links",
+        lines,
+    })
+}
+
+async fn usage(state: &AppState) -> Result<Section, sqlx::Error> {
+    let orgs: Vec<(i64, String)> =
+        sqlx::query_as("SELECT id, slug FROM organizations ORDER BY slug")
+            .fetch_all(&state.read_pool)
+            .await?;
+    let mut lines = Vec::new();
+    for (id, slug) in orgs {
+        let u = quotas::org_usage(&state.read_pool, &state.config, id, &slug).await?;
+        lines.push(format!(
+            "{}: {} releases, {} stored, {} uploads today",
+            slug,
+            of_limit(
+                u.releases.to_string(),
+                u.limits.max_releases.map(|m| m.to_string())
+            ),
+            of_limit(
+                megabytes(u.storage_bytes),
+                u.limits.max_storage_bytes.map(megabytes)
+            ),
+            of_limit(
+                u.uploads_last_day.to_string(),
+                u.limits.max_uploads_per_day.map(|m| m.to_string())
+            ),
+        ));
+    }
+    Ok(Section {
+        title: "Storage and quotas",
+        lines,
+    })
+}
+
+impl Digest {
+    /// Gathers the digest of the 24 hours up to now.
+    pub async fn build(state: &AppState) -> Result<Self, sqlx::Error> {
+        let since = Utc::now() - Duration::days(1);
+        Ok(Self {
+            since,
+            sections: vec![
+                published(state, since).await?,
+                rollouts(state, since).await?,
+                failures(state, since).await?,
+                broken_links(state).await?,
+                usage(state).await?,
+            ],
+        })
+    }
+
+    fn title(&self) -> String {
+        format!(
+            "Release digest since {}",
+            self.since.format("%Y-%m-%d %H:%M UTC")
+        )
+    }
+
+    pub fn text(&self) -> String {
+        let mut text = format!("{}\n", self.title());
+        for section in &self.sections {
+            text.push_str(&format!("\n{}\n", section.title));
+            if section.lines.is_empty() {
+                text.push_str("- None\n");
+            }
+            for line in &section.lines {
+                text.push_str(&format!("- {}\n", line));
+            }
+        }
+        text
+    }
+
+    fn slack_message(&self) -> serde_json::Value {
+        let blocks: Vec<serde_json::Value> = self
+            .sections
+            .iter()
+            .map(|section| {
+                let mut text = format!("*{}*", section.title);
+                if section.lines.is_empty() {
+                    text.push_str("\nNone");
+                }
+                for line in &section.lines {
+                    text.push_str(&format!("\n• {}", slack_escape(line)));
+                }
+                json!({ "type": "section", "text": { "type": "mrkdwn", "text": text } })
+            })
+            .collect();
+        json!({ "text": self.title(), "blocks": blocks })
+    }
+
+    fn email(&self, from: &str, to: &str) -> String {
+        let domain = from.rsplit_once('@').map(|(_, d)| d).unwrap_or("localhost");
+        let mut id = [0u8; 16];
+        let _ = getrandom::getrandom(&mut id);
+        let headers = [
+            ("From", from.to_string()),
+            ("To", to.to_string()),
+            ("Subject", smtp::encode_header(&self.title())),
+            ("Date", Utc::now().to_rfc2822()),
+            ("Message-ID", format!("<{}@{}>", hex::encode(id), domain)),
+            ("MIME-Version", "1.0".to_string()),
+            ("Content-Type", "text/plain; charset=utf-8".to_string()),
+            ("Content-Transfer-Encoding", "base64".to_string()),
+        ];
+        let mut message = String::new();
+        for (name, value) in headers {
+            message.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        message.push_str("\r\n");
+        message.push_str(&smtp::encode_body(&self.text()));
+        message.push_str("\r\n");
+        message
+    }
+
+    async fn send(&self, state: &AppState) {
+        let config = &state.config;
+        if let Some(url) = &config.slack_webhook_url {
+            let body = self.slack_message().to_string().into_bytes();
+            let sent = match HttpClient::new(config.webhook_timeout) {
+                Ok(client) => client.post_json(url, &[], body).await,
+                Err(e) => Err(e.to_string()),
+            };
+            match sent {
+                Ok(r) if r.status.is_success() => (),
+                Ok(r) => println!("Slack rejected the digest with {}: {}", r.status, r.body),
+                Err(e) => println!("Failed to post the digest to Slack: {}", e),
+            }
+        }
+        if let Some(smtp_url) = &config.smtp_url
+            && !config.digest_email_to.is_empty()
+        {
+            let mailer = match Mailer::from_url(smtp_url) {
+                Ok(mailer) => mailer,
+                Err(e) => {
+                    println!("Failed to email the digest: {}", e);
+                    return;
+                }
+            };
+            let emails: Vec<Email> = config
+                .digest_email_to
+                .iter()
+                .map(|to| Email {
+                    to: to.clone(),
+                    data: self.email(&config.smtp_from, to),
+                })
+                .collect();
+            for (to, e) in mailer.send_all(&config.smtp_from, &emails).await {
+                println!("Failed to email the digest to {}: {}", to, e);
+            }
+        }
+    }
+}
+
+/// Time until the next `hour:00` UTC.
+fn until_next(hour: u32, now: DateTime<Utc>) -> std::time::Duration {
+    let today = now
+        .date_naive()
+        .and_time(NaiveTime::from_hms_opt(hour, 0, 0).unwrap_or_default())
+        .and_utc();
+    let next = if today > now {
+        today
+    } else {
+        today + Duration::days(1)
+    };
+    (next - now).to_std().unwrap_or_default()
+}
+
+/// Sends today's digest unless another replica already has.
+async fn send_daily(state: &AppState) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    let claimed = sqlx::query(
+        "INSERT INTO digests (day, sent_at) VALUES (?, ?) ON CONFLICT (day) DO NOTHING",
+    )
+    .bind(now.format("%Y-%m-%d").to_string())
+    .bind(now)
+    .execute(&state.pool)
+    .await?
+    .rows_affected()
+        == 1;
+    if !claimed {
+        return Ok(());
+    }
+    let digest = Digest::build(state).await?;
+    digest.send(state).await;
+    println!("Sent the daily digest");
+    Ok(())
+}
+
+/// Starts sending the digest each day at `DIGEST_HOUR_UTC`.
+pub fn spawn(state: AppState) {
+    let Some(hour) = state.config.digest_hour else {
+        return;
+    };
+    let config = &state.config;
+    if config.slack_webhook_url.is_none()
+        && (config.smtp_url.is_none() || config.digest_email_to.is_empty())
+    {
+        println!(
+            "DIGEST_HOUR_UTC is set, but there is neither SLACK_WEBHOOK_URL nor SMTP_URL with DIGEST_EMAIL_TO to send the digest to"
+        );
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(until_next(hour, Utc::now())).await;
+            if let Err(e) = send_daily(&state).await {
+                println!("Daily digest failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Preview the daily digest
+///
+/// The digest of the last 24 hours as it would be sent now, in plain text.
+/// Nothing is sent. Operator token only.
+#[utoipa::path(
+    get,
+    path = "/admin/digest",
+    responses(
+        (status = 200, description = "The digest", body = String, content_type = "text/plain"),
+        (status = 403, description = "Not the operator token", body = ErrorBody)
+    )
+)]
+pub async fn preview_digest(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<impl IntoResponse> {
+    principal.require_operator()?;
+    let digest = Digest::build(&state)
+        .await
+        .map_err(|e| AppError::internal("Failed to build the digest", e))?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        digest.text(),
+    ))
+}
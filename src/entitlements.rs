@@ -0,0 +1,318 @@
+//! Entitlement hooks.
+//!
+//! An app can name an HTTP endpoint (a billing system, say) that decides,
+//! per update check, whether the update we are about to offer is served.
+//! The hook receives the check's context and the offered release, and
+//! answers:
+//!
+//! - `{"decision": "allow"}` to serve it;
+//! - `{"decision": "deny", "message": "..."}` to refuse with a 403 the app
+//!   can show its user;
+//! - `{"decision": "modify", "version": "1.4.2"}` to serve that version of
+//!   the same app, target, arch and channel instead.
+//!
+//! Answers may set `cache_secs` to override the app's cache period. When the
+//! hook can't be reached or answers nonsense, the check fails with a 503,
+//! unless the app is configured to fail open and serve the update anyway.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::{
+    Extension,
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+
+use crate::auth::{self, Principal};
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::routes::validate_http_url;
+use crate::schema::{
+    APP_COLUMNS, App, AppState, EntitlementHookRequest, RELEASE_COLUMNS, Release,
+    UpdateCheckContext,
+};
+use crate::webhooks::{SIGNATURE_HEADER, sign};
+
+/// Longest an answer may be cached for.
+const MAX_CACHE_SECS: i64 = 24 * 60 * 60;
+
+/// Cached answers kept before expired ones are swept.
+const MAX_CACHED_ANSWERS: usize = 10_000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "decision", rename_all = "lowercase")]
+enum Decision {
+    Allow,
+    Deny {
+        #[serde(default)]
+        message: Option<String>,
+    },
+    Modify {
+        version: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct Answer {
+    #[serde(flatten)]
+    decision: Decision,
+    #[serde(default)]
+    cache_secs: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct OfferedRelease<'a> {
+    version: &'a str,
+    channel: &'a str,
+    pub_date: DateTime<Utc>,
+    critical: bool,
+}
+
+#[derive(Serialize)]
+struct HookRequest<'a> {
+    #[serde(flatten)]
+    context: &'a UpdateCheckContext,
+    offered: OfferedRelease<'a>,
+}
+
+#[derive(FromRow)]
+struct HookConfig {
+    entitlement_url: Option<String>,
+    entitlement_secret: Option<String>,
+    entitlement_fail_open: bool,
+    entitlement_cache_secs: i64,
+}
+
+/// In-process cache of hook answers, keyed by everything the hook was told.
+#[derive(Default)]
+pub struct EntitlementCache {
+    answers: Mutex<HashMap<String, (Instant, Decision)>>,
+}
+
+impl EntitlementCache {
+    fn get(&self, key: &str) -> Option<Decision> {
+        let answers = self.answers.lock().unwrap();
+        answers
+            .get(key)
+            .filter(|(expires, _)| *expires > Instant::now())
+            .map(|(_, decision)| decision.clone())
+    }
+
+    fn clear(&self) {
+        self.answers.lock().unwrap().clear();
+    }
+
+    fn put(&self, key: String, ttl: Duration, decision: Decision) {
+        if ttl.is_zero() {
+            return;
+        }
+        let mut answers = self.answers.lock().unwrap();
+        if answers.len() >= MAX_CACHED_ANSWERS {
+            let now = Instant::now();
+            answers.retain(|_, (expires, _)| *expires > now);
+            if answers.len() >= MAX_CACHED_ANSWERS {
+                answers.clear();
+            }
+        }
+        answers.insert(key, (Instant::now() + ttl, decision));
+    }
+}
+
+fn cache_key(context: &UpdateCheckContext, offered: &Release) -> String {
+    format!(
+        "{}:{}:{}:{}:{}:{}:{}",
+        context.app_name,
+        context.target,
+        context.arch,
+        context.channel,
+        context.current_version.as_deref().unwrap_or(""),
+        context.customer_id.as_deref().unwrap_or(""),
+        offered.version
+    )
+}
+
+/// Asks the hook about `offered`. `Err` carries why it couldn't answer.
+async fn ask(
+    state: &AppState,
+    config: &HookConfig,
+    url: &str,
+    context: &UpdateCheckContext,
+    offered: &Release,
+) -> Result<Answer, String> {
+    let body = serde_json::to_vec(&HookRequest {
+        context,
+        offered: OfferedRelease {
+            version: &offered.version,
+            channel: &offered.channel,
+            pub_date: offered.pub_date,
+            critical: offered.critical,
+        },
+    })
+    .map_err(|e| e.to_string())?;
+    let signature = config
+        .entitlement_secret
+        .as_deref()
+        .map(|secret| sign(secret, &body));
+    let headers: Vec<(&str, &str)> = signature
+        .as_deref()
+        .map(|s| (SIGNATURE_HEADER, s))
+        .into_iter()
+        .collect();
+
+    let response = state.http.post_json(url, &headers, body).await?;
+    if !response.status.is_success() {
+        return Err(format!("{} answered {}", url, response.status));
+    }
+    serde_json::from_str(&response.body)
+        .map_err(|e| format!("unexpected answer from {}: {}", url, e))
+}
+
+/// Lets the app's entitlement hook decide what to serve instead of
+/// `offered`: the same release, another one, or nothing.
+pub async fn check_entitlement(
+    state: &AppState,
+    context: &UpdateCheckContext,
+    offered: Release,
+) -> AppResult<Option<Release>> {
+    let config = sqlx::query_as::<_, HookConfig>(
+        "SELECT entitlement_url, entitlement_secret, entitlement_fail_open, entitlement_cache_secs FROM apps WHERE name = ?",
+    )
+    .bind(&context.app_name)
+    .fetch_optional(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load the entitlement hook", e))?;
+    let Some(config) = config else {
+        return Ok(Some(offered));
+    };
+    let Some(url) = config.entitlement_url.clone() else {
+        return Ok(Some(offered));
+    };
+
+    let key = cache_key(context, &offered);
+    let decision = match state.entitlements.get(&key) {
+        Some(decision) => decision,
+        None => match ask(state, &config, &url, context, &offered).await {
+            Ok(answer) => {
+                let secs = answer
+                    .cache_secs
+                    .unwrap_or(config.entitlement_cache_secs)
+                    .clamp(0, MAX_CACHE_SECS);
+                state.entitlements.put(
+                    key,
+                    Duration::from_secs(secs as u64),
+                    answer.decision.clone(),
+                );
+                answer.decision
+            }
+            Err(e) if config.entitlement_fail_open => {
+                println!(
+                    "Entitlement hook for {} failed, serving the update anyway: {}",
+                    context.app_name, e
+                );
+                Decision::Allow
+            }
+            Err(e) => {
+                println!("Entitlement hook for {} failed: {}", context.app_name, e);
+                return Err(AppError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "unavailable",
+                    "Entitlement check unavailable, try again later",
+                ));
+            }
+        },
+    };
+
+    match decision {
+        Decision::Allow => Ok(Some(offered)),
+        Decision::Deny { message } => Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            "entitlement_denied",
+            message.unwrap_or_else(|| "This update is not included in your plan".to_string()),
+        )),
+        Decision::Modify { version } => {
+            let release = sqlx::query_as::<_, Release>(&format!(
+                "SELECT {} FROM releases WHERE app_name = ? AND target = ? AND arch = ? AND channel = ? AND version = ? AND yanked = 0 AND archived = 0",
+                RELEASE_COLUMNS
+            ))
+            .bind(&context.app_name)
+            .bind(&context.target)
+            .bind(&context.arch)
+            .bind(&context.channel)
+            .bind(&version)
+            .fetch_optional(&state.read_pool)
+            .await
+            .map_err(|e| AppError::internal("Failed to load the entitled release", e))?;
+            if release.is_none() {
+                println!(
+                    "Entitlement hook for {} named unknown version {}; offering nothing",
+                    context.app_name, version
+                );
+            }
+            Ok(release)
+        }
+    }
+}
+
+/// Configure an app's entitlement hook
+#[utoipa::path(
+    put,
+    path = "/apps/{name}/entitlement-hook",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    request_body = EntitlementHookRequest,
+    responses(
+        (status = 200, description = "Hook saved", body = App),
+        (status = 400, description = "Invalid URL or cache period", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody)
+    )
+)]
+pub async fn set_entitlement_hook(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<EntitlementHookRequest>,
+) -> AppResult<Json<App>> {
+    let url = request
+        .url
+        .as_deref()
+        .map(str::trim)
+        .filter(|u| !u.is_empty());
+    if let Some(url) = url {
+        validate_http_url(url)?;
+    }
+    let cache_secs = request.cache_secs.unwrap_or(60);
+    if !(0..=MAX_CACHE_SECS).contains(&cache_secs) {
+        return Err(AppError::bad_request(format!(
+            "cache_secs must be between 0 and {}",
+            MAX_CACHE_SECS
+        )));
+    }
+    auth::claim_app(&state.pool, principal, &name).await?;
+
+    let app = sqlx::query_as::<_, App>(&format!(
+        "UPDATE apps SET entitlement_url = ?, entitlement_secret = ?, entitlement_fail_open = ?, entitlement_cache_secs = ? WHERE name = ? RETURNING {}",
+        APP_COLUMNS
+    ))
+    .bind(url)
+    .bind(request.secret.as_deref().filter(|s| !s.is_empty()))
+    .bind(request.fail_open)
+    .bind(cache_secs)
+    .bind(&name)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to save the entitlement hook", e))?;
+    // Answers from the old hook (or under the old cache period) don't apply.
+    state.entitlements.clear();
+
+    match &app.entitlement_url {
+        Some(url) => println!("Update checks for {} now consult {}", app.name, url),
+        None => println!("Removed the entitlement hook of {}", app.name),
+    }
+    Ok(Json(app))
+}
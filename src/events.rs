@@ -0,0 +1,157 @@
+//! Release activity feed.
+//!
+//! Handlers emit an event after every publish, yank and promotion, as do
+//! promotion policies when they promote or hold a release. `GET /events`
+//! streams them to dashboards as server-sent events. When
+//! `REDIS_URL` is configured, events travel through a pub/sub channel so a
+//! client connected to any replica sees activity from all of them.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::redis::RedisClient;
+use crate::schema::Release;
+
+const EVENTS_CHANNEL: &str = "updater:events";
+
+/// Events buffered per subscriber before a slow client starts missing some.
+const SUBSCRIBER_BUFFER: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReleaseEventKind {
+    Published,
+    Yanked,
+    Promoted,
+    /// A promotion policy stopped a release from moving on.
+    Held,
+}
+
+impl ReleaseEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReleaseEventKind::Published => "published",
+            ReleaseEventKind::Yanked => "yanked",
+            ReleaseEventKind::Promoted => "promoted",
+            ReleaseEventKind::Held => "held",
+        }
+    }
+}
+
+impl std::str::FromStr for ReleaseEventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "published" => Ok(ReleaseEventKind::Published),
+            "yanked" => Ok(ReleaseEventKind::Yanked),
+            "promoted" => Ok(ReleaseEventKind::Promoted),
+            "held" => Ok(ReleaseEventKind::Held),
+            other => Err(format!("unknown event '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct ReleaseEvent {
+    pub kind: ReleaseEventKind,
+    /// The release as it is after the change.
+    pub release: Release,
+    /// Channel the release was promoted from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_channel: Option<String>,
+    /// Why a promotion policy promoted or held the release.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+impl ReleaseEvent {
+    pub fn new(kind: ReleaseEventKind, release: Release) -> Self {
+        Self {
+            kind,
+            release,
+            previous_channel: None,
+            reason: None,
+            at: Utc::now(),
+        }
+    }
+}
+
+pub struct EventBus {
+    /// Events from every replica, for streaming to clients.
+    local: broadcast::Sender<ReleaseEvent>,
+    /// Events emitted by this replica only, for side effects that must run
+    /// exactly once per event (webhooks, notifications).
+    own: broadcast::Sender<ReleaseEvent>,
+    redis: Option<RedisClient>,
+}
+
+impl EventBus {
+    pub fn new(redis_url: Option<&str>) -> Self {
+        let redis = redis_url.and_then(|url| RedisClient::new(url).ok());
+        let (local, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        let (own, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        Self { local, own, redis }
+    }
+
+    /// Relays events published by every replica (including this one) to
+    /// local subscribers.
+    pub fn spawn_listener(self: &Arc<Self>) {
+        let Some(redis) = &self.redis else {
+            return;
+        };
+        let mut rx = redis.subscribe(EVENTS_CHANNEL);
+        let bus = self.clone();
+        tokio::spawn(async move {
+            while let Some(payload) = rx.recv().await {
+                match serde_json::from_str::<ReleaseEvent>(&payload) {
+                    Ok(event) => {
+                        let _ = bus.local.send(event);
+                    }
+                    Err(e) => println!("Ignoring malformed release event: {}", e),
+                }
+            }
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ReleaseEvent> {
+        self.local.subscribe()
+    }
+
+    pub fn subscribe_own(&self) -> broadcast::Receiver<ReleaseEvent> {
+        self.own.subscribe()
+    }
+
+    pub async fn emit(&self, event: ReleaseEvent) {
+        println!(
+            "Release event: {} {} {} ({}/{}, {})",
+            event.kind.as_str(),
+            event.release.app_name,
+            event.release.version,
+            event.release.target,
+            event.release.arch,
+            event.release.channel
+        );
+
+        let _ = self.own.send(event.clone());
+
+        // With Redis, local subscribers get the event back through the
+        // subscription; only deliver directly when publishing failed.
+        if let Some(redis) = &self.redis {
+            match serde_json::to_string(&event) {
+                Ok(json) => match redis.publish(EVENTS_CHANNEL, &json).await {
+                    Ok(_) => return,
+                    Err(e) => println!("Redis PUBLISH {} failed: {}", EVENTS_CHANNEL, e),
+                },
+                Err(e) => println!("Failed to serialize release event: {}", e),
+            }
+        }
+
+        // No subscribers is not an error.
+        let _ = self.local.send(event);
+    }
+}
@@ -0,0 +1,147 @@
+//! Atom feed of releases, one entry per version.
+
+use chrono::{DateTime, Utc};
+
+use crate::schema::Release;
+
+/// Versions listed in a feed; older ones drop off the end.
+pub const MAX_ENTRIES: usize = 20;
+
+struct Entry<'a> {
+    version: &'a str,
+    channel: &'a str,
+    updated: DateTime<Utc>,
+    notes: &'a str,
+    downloads: Vec<&'a Release>,
+}
+
+/// Renders an Atom document for `app_name`. `releases` must be newest first;
+/// builds of the same version and channel are merged into a single entry
+/// with one download link per platform.
+pub fn render(
+    app_name: &str,
+    channel: &str,
+    self_url: Option<&str>,
+    releases: &[Release],
+) -> String {
+    let mut entries: Vec<Entry> = Vec::new();
+    for release in releases {
+        if let Some(entry) = entries
+            .iter_mut()
+            .find(|e| e.version == release.version && e.channel == release.channel)
+        {
+            entry.updated = entry.updated.max(release.pub_date);
+            entry.downloads.push(release);
+            continue;
+        }
+        if entries.len() == MAX_ENTRIES {
+            continue;
+        }
+        entries.push(Entry {
+            version: &release.version,
+            channel: &release.channel,
+            updated: release.pub_date,
+            notes: &release.notes,
+            downloads: vec![release],
+        });
+    }
+
+    let updated = entries
+        .iter()
+        .map(|e| e.updated)
+        .max()
+        .unwrap_or(DateTime::UNIX_EPOCH);
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!(
+        "  <id>tag:app-release-manager,2024:{}:{}</id>\n",
+        escape(app_name),
+        escape(channel)
+    ));
+    xml.push_str(&format!(
+        "  <title>{} releases ({})</title>\n",
+        escape(app_name),
+        escape(channel)
+    ));
+    xml.push_str(&format!("  <updated>{}</updated>\n", updated.to_rfc3339()));
+    xml.push_str("  <author><name>App Release Manager</name></author>\n");
+    if let Some(url) = self_url {
+        xml.push_str(&format!(
+            "  <link rel=\"self\" type=\"application/atom+xml\" href=\"{}\"/>\n",
+            escape(url)
+        ));
+    }
+
+    for entry in &entries {
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!(
+            "    <id>tag:app-release-manager,2024:{}:{}:{}</id>\n",
+            escape(app_name),
+            escape(entry.channel),
+            escape(entry.version)
+        ));
+        xml.push_str(&format!(
+            "    <title>{} {}</title>\n",
+            escape(app_name),
+            escape(entry.version)
+        ));
+        xml.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            entry.updated.to_rfc3339()
+        ));
+        xml.push_str(&format!(
+            "    <category term=\"{}\"/>\n",
+            escape(entry.channel)
+        ));
+        if let Some(first) = entry.downloads.first() {
+            xml.push_str(&format!(
+                "    <link rel=\"alternate\" href=\"{}\"/>\n",
+                escape(&first.url)
+            ));
+        }
+        for download in &entry.downloads {
+            xml.push_str(&format!(
+                "    <link rel=\"enclosure\" title=\"{} {}\" href=\"{}\"/>\n",
+                escape(&download.target),
+                escape(&download.arch),
+                escape(&download.url)
+            ));
+        }
+
+        let mut content = String::from(entry.notes);
+        content.push_str("\n\nDownloads:\n");
+        for download in &entry.downloads {
+            content.push_str(&format!(
+                "- {}/{}: {}\n",
+                download.target, download.arch, download.url
+            ));
+        }
+        xml.push_str(&format!(
+            "    <content type=\"text\">{}</content>\n",
+            escape(content.trim_start())
+        ));
+        xml.push_str("  </entry>\n");
+    }
+
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // Control characters other than tab/newline are not allowed in XML 1.0.
+            c if c.is_control() && c != '\n' && c != '\t' && c != '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,190 @@
+//! Seeding a new database from a fixtures file.
+//!
+//! With `SEED_FIXTURES` set, or `--seed <path>` on the command line, a
+//! database without releases is filled from a JSON file of releases, such as
+//! `fixtures/example.json`, so a development setup has something to serve.
+//! An optional `apps` list sets the version scheme of apps whose releases
+//! aren't semver. Without either a new database starts empty, and a database that already
+//! has releases is never touched.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::{Pool, Sqlite};
+
+use crate::auth;
+use crate::schema::{DEFAULT_CHANNEL, VersionScheme, is_valid_channel};
+use crate::versioning;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixtures {
+    #[serde(default)]
+    apps: Vec<FixtureApp>,
+    #[serde(default)]
+    releases: Vec<FixtureRelease>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FixtureApp {
+    name: String,
+    #[serde(default)]
+    version_scheme: VersionScheme,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FixtureRelease {
+    app_name: String,
+    target: String,
+    arch: String,
+    version: String,
+    url: String,
+    #[serde(default)]
+    signature: String,
+    /// Defaults to the time of seeding.
+    pub_date: Option<DateTime<Utc>>,
+    #[serde(default)]
+    notes: String,
+    channel: Option<String>,
+    #[serde(default)]
+    critical: bool,
+}
+
+/// The fixtures file named by `--seed <path>` or `--seed=<path>`, else by
+/// `SEED_FIXTURES`.
+pub fn path_from_args(configured: Option<&str>) -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--seed" {
+            return args.next();
+        }
+        if let Some(path) = arg.strip_prefix("--seed=") {
+            return Some(path.to_string());
+        }
+    }
+    configured.map(String::from)
+}
+
+fn validate(index: usize, release: &FixtureRelease, scheme: VersionScheme) -> Result<(), String> {
+    for (field, value) in [
+        ("app_name", &release.app_name),
+        ("target", &release.target),
+        ("arch", &release.arch),
+        ("url", &release.url),
+    ] {
+        if value.trim().is_empty() {
+            return Err(format!("release {} has no {}", index, field));
+        }
+    }
+    versioning::parse(scheme, &release.version).map_err(|e| {
+        format!(
+            "release {} has version '{}', which is not {}: {}",
+            index,
+            release.version,
+            scheme.as_str(),
+            e
+        )
+    })?;
+    if let Some(channel) = &release.channel
+        && !is_valid_channel(channel)
+    {
+        return Err(format!(
+            "release {} has an invalid channel '{}'",
+            index, channel
+        ));
+    }
+    Ok(())
+}
+
+/// Fills an empty database from the fixtures file at `path`. Every app the
+/// releases name belongs to the default organization.
+pub async fn seed(pool: &Pool<Sqlite>, path: &str) -> Result<(), String> {
+    let count: i64 = sqlx::query_scalar("SELECT count(*) FROM releases")
+        .fetch_one(pool)
+        .await
+        .map_err(|e| format!("Failed to count releases: {}", e))?;
+    if count > 0 {
+        println!(
+            "Database already has releases, not seeding it from {}",
+            path
+        );
+        return Ok(());
+    }
+
+    let raw = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read fixtures {}: {}", path, e))?;
+    let fixtures: Fixtures =
+        serde_json::from_str(&raw).map_err(|e| format!("Invalid fixtures in {}: {}", path, e))?;
+    // Apps the fixtures don't list keep the scheme they already have.
+    let mut schemes: HashMap<&str, VersionScheme> = fixtures
+        .apps
+        .iter()
+        .map(|app| (app.name.as_str(), app.version_scheme))
+        .collect();
+    for (index, release) in fixtures.releases.iter().enumerate() {
+        let scheme = match schemes.get(release.app_name.as_str()) {
+            Some(scheme) => *scheme,
+            None => {
+                let scheme = versioning::app_scheme(pool, &release.app_name)
+                    .await
+                    .map_err(|e| format!("Failed to look up the version scheme: {}", e))?;
+                schemes.insert(&release.app_name, scheme);
+                scheme
+            }
+        };
+        validate(index, release, scheme)
+            .map_err(|e| format!("Invalid fixtures in {}: {}", path, e))?;
+    }
+
+    let seeded: Result<(), sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+        for app in &fixtures.apps {
+            sqlx::query(
+                "INSERT INTO apps (name, org_id, created_at, version_scheme) VALUES (?, ?, ?, ?) ON CONFLICT (name) DO UPDATE SET version_scheme = excluded.version_scheme",
+            )
+            .bind(&app.name)
+            .bind(auth::DEFAULT_ORG_ID)
+            .bind(Utc::now())
+            .bind(app.version_scheme.as_str())
+            .execute(&mut *tx)
+            .await?;
+        }
+        for release in &fixtures.releases {
+            sqlx::query(
+                "INSERT INTO releases (app_name, target, arch, version, url, signature, pub_date, notes, channel, critical) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(&release.app_name)
+            .bind(&release.target)
+            .bind(&release.arch)
+            .bind(&release.version)
+            .bind(&release.url)
+            .bind(&release.signature)
+            .bind(release.pub_date.unwrap_or_else(Utc::now))
+            .bind(&release.notes)
+            .bind(release.channel.as_deref().unwrap_or(DEFAULT_CHANNEL))
+            .bind(release.critical)
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query(
+            "INSERT OR IGNORE INTO apps (name, org_id, created_at) SELECT DISTINCT app_name, ?, ? FROM releases",
+        )
+        .bind(auth::DEFAULT_ORG_ID)
+        .bind(Utc::now())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+    .await;
+    seeded.map_err(|e| format!("Failed to seed from {}: {}", path, e))?;
+
+    println!(
+        "Seeded the database with {} releases from {}",
+        fixtures.releases.len(),
+        path
+    );
+    Ok(())
+}
@@ -0,0 +1,278 @@
+//! Feature flags and remote config.
+//!
+//! The update check is the one request our desktop apps reliably make, so
+//! it also carries their remote config: per-app key/value flags, optionally
+//! limited to a version range or to a percentage of clients. Answers with
+//! an update have them in the body as `flags`; a 204 has no body, so there
+//! they come as JSON in [`FLAGS_HEADER`].
+//!
+//! Percentage rollouts bucket clients by `client_id` (or `device_id`), per
+//! flag, so a client keeps its flags between checks. Clients that send
+//! neither only get flags rolled out to everybody.
+
+use std::collections::BTreeMap;
+
+use axum::{
+    Extension,
+    extract::{Path, Query, State},
+    http::{HeaderName, HeaderValue, StatusCode},
+    response::Json,
+};
+use chrono::Utc;
+use semver::{Version, VersionReq};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+
+use crate::auth::{self, Principal, app_scope};
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::schema::{
+    AppState, FEATURE_FLAG_COLUMNS, FeatureFlag, FeatureFlagFilter, FeatureFlagRequest,
+};
+
+/// Flags of a client, by key.
+pub type Flags = BTreeMap<String, serde_json::Value>;
+
+/// Flags of a client told there is no update, as JSON.
+pub const FLAGS_HEADER: HeaderName = HeaderName::from_static("x-flags");
+
+/// Limits that keep [`FLAGS_HEADER`] well under common header size limits.
+const MAX_FLAGS_PER_APP: i64 = 50;
+const MAX_VALUE_BYTES: usize = 512;
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 64
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// Which of 100 buckets, 0 to 99, `key` falls in for a percentage rollout
+/// identified by `salt`. Rollouts with different salts split clients
+/// independently of each other.
+pub fn bucket(salt: &str, key: &str) -> u8 {
+    let digest = Sha256::digest(format!("{}\0{}", salt, key).as_bytes());
+    (u64::from_be_bytes(digest[..8].try_into().expect("8 bytes")) % 100) as u8
+}
+
+/// Whether `client_id` falls in the first `percent` of 100 buckets for
+/// `key`.
+fn in_rollout(app_name: &str, key: &str, client_id: &str, percent: i64) -> bool {
+    i64::from(bucket(&format!("{}\0{}", app_name, key), client_id)) < percent
+}
+
+/// Flags of `app_name` that apply to a client on `current`.
+pub async fn client_flags(
+    pool: &Pool<Sqlite>,
+    app_name: &str,
+    current: &Version,
+    client_id: Option<&str>,
+) -> Result<Flags, sqlx::Error> {
+    let flags = sqlx::query_as::<_, FeatureFlag>(&format!(
+        "SELECT {} FROM feature_flags WHERE app_name = ? AND rollout_percent > 0",
+        FEATURE_FLAG_COLUMNS
+    ))
+    .bind(app_name)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(flags
+        .into_iter()
+        .filter(|f| {
+            f.version_req
+                .as_deref()
+                .is_none_or(|req| VersionReq::parse(req).is_ok_and(|req| req.matches(current)))
+        })
+        .filter(|f| {
+            f.rollout_percent >= 100
+                || client_id.is_some_and(|id| in_rollout(app_name, &f.key, id, f.rollout_percent))
+        })
+        .map(|f| (f.key, f.value.0))
+        .collect())
+}
+
+/// `flags` as a header value: JSON with anything outside ASCII escaped.
+pub fn header_value(flags: &Flags) -> Option<HeaderValue> {
+    let json = serde_json::to_string(flags).ok()?;
+    let mut ascii = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            ascii.push(c);
+        } else {
+            let mut units = [0u16; 2];
+            for unit in c.encode_utf16(&mut units) {
+                ascii.push_str(&format!("\\u{:04x}", unit));
+            }
+        }
+    }
+    HeaderValue::from_str(&ascii).ok()
+}
+
+/// List feature flags
+#[utoipa::path(
+    get,
+    path = "/flags",
+    params(FeatureFlagFilter),
+    responses(
+        (status = 200, description = "Feature flags", body = Vec<FeatureFlag>)
+    )
+)]
+pub async fn list_flags(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<FeatureFlagFilter>,
+) -> AppResult<Json<Vec<FeatureFlag>>> {
+    let flags = sqlx::query_as::<_, FeatureFlag>(&format!(
+        "SELECT {} FROM feature_flags WHERE (?1 IS NULL OR app_name = ?1) AND {} ORDER BY app_name, key",
+        FEATURE_FLAG_COLUMNS,
+        app_scope(2)
+    ))
+    .bind(&filter.app_name)
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load feature flags", e))?;
+
+    Ok(Json(flags))
+}
+
+/// Set a feature flag
+#[utoipa::path(
+    put,
+    path = "/flags/{app_name}/{key}",
+    params(
+        ("app_name" = String, Path, description = "Application name"),
+        ("key" = String, Path, description = "Flag key: letters, digits, '_', '-' and '.'")
+    ),
+    request_body = FeatureFlagRequest,
+    responses(
+        (status = 200, description = "Flag saved", body = FeatureFlag),
+        (status = 400, description = "Invalid key, value, version requirement or percentage, or too many flags", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody)
+    )
+)]
+pub async fn set_flag(
+    Path((app_name, key)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<FeatureFlagRequest>,
+) -> AppResult<Json<FeatureFlag>> {
+    if !is_valid_key(&key) {
+        return Err(AppError::bad_request(
+            "key must be 1-64 letters, digits, '_', '-' or '.'",
+        ));
+    }
+    if request.value.to_string().len() > MAX_VALUE_BYTES {
+        return Err(AppError::bad_request(format!(
+            "value must be at most {} bytes of JSON",
+            MAX_VALUE_BYTES
+        )));
+    }
+    let version_req = request
+        .version_req
+        .as_deref()
+        .map(str::trim)
+        .filter(|r| !r.is_empty());
+    if let Some(req) = version_req
+        && let Err(e) = VersionReq::parse(req)
+    {
+        return Err(AppError::bad_request(format!(
+            "version_req '{}' is not a semver requirement: {}",
+            req, e
+        )));
+    }
+    let rollout_percent = request.rollout_percent.unwrap_or(100);
+    if !(0..=100).contains(&rollout_percent) {
+        return Err(AppError::bad_request(
+            "rollout_percent must be between 0 and 100",
+        ));
+    }
+    auth::claim_app(&state.pool, principal, &app_name).await?;
+
+    let others: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM feature_flags WHERE app_name = ? AND key != ?")
+            .bind(&app_name)
+            .bind(&key)
+            .fetch_one(&state.pool)
+            .await
+            .map_err(|e| AppError::internal("Failed to save feature flag", e))?;
+    if others >= MAX_FLAGS_PER_APP {
+        return Err(AppError::bad_request(format!(
+            "Apps can have at most {} flags",
+            MAX_FLAGS_PER_APP
+        )));
+    }
+
+    let flag = sqlx::query_as::<_, FeatureFlag>(&format!(
+        "INSERT INTO feature_flags (app_name, key, value, version_req, rollout_percent, updated_at) VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT (app_name, key) DO UPDATE SET value = excluded.value, version_req = excluded.version_req, rollout_percent = excluded.rollout_percent, updated_at = excluded.updated_at RETURNING {}",
+        FEATURE_FLAG_COLUMNS
+    ))
+    .bind(&app_name)
+    .bind(&key)
+    .bind(sqlx::types::Json(&request.value))
+    .bind(version_req)
+    .bind(rollout_percent)
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to save feature flag", e))?;
+
+    println!(
+        "Set flag {} of {} for {}% of clients",
+        flag.key, flag.app_name, flag.rollout_percent
+    );
+    Ok(Json(flag))
+}
+
+/// Delete a feature flag
+#[utoipa::path(
+    delete,
+    path = "/flags/{app_name}/{key}",
+    params(
+        ("app_name" = String, Path, description = "Application name"),
+        ("key" = String, Path, description = "Flag key")
+    ),
+    responses(
+        (status = 204, description = "Flag deleted; clients stop receiving it"),
+        (status = 404, description = "Flag not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_flag(
+    Path((app_name, key)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let deleted = sqlx::query(&format!(
+        "DELETE FROM feature_flags WHERE app_name = ?1 AND key = ?2 AND {}",
+        app_scope(3)
+    ))
+    .bind(&app_name)
+    .bind(&key)
+    .bind(principal.org_id())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to delete feature flag", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("Flag not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_stable_per_salt() {
+        assert_eq!(bucket("classprime\0dark_mode", "client-1"), 77);
+        assert_eq!(bucket("classprime", "client-1"), 65);
+    }
+
+    #[test]
+    fn rollout_percent_bounds() {
+        assert!(!in_rollout("classprime", "dark_mode", "client-1", 0));
+        assert!(!in_rollout("classprime", "dark_mode", "client-1", 77));
+        assert!(in_rollout("classprime", "dark_mode", "client-1", 78));
+        assert!(in_rollout("classprime", "dark_mode", "client-1", 100));
+    }
+}
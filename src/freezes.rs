@@ -0,0 +1,226 @@
+//! Publish freezes.
+//!
+//! Unlike [blackouts](crate::blackouts), which stop clients from being
+//! offered updates, a freeze stops releases from being shipped: while one is
+//! open, uploads and promotions for the frozen apps are refused unless the
+//! operator token passes `override=true`.
+
+use axum::{
+    Extension,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Sqlite};
+
+use crate::auth::{self, Principal, org_scope};
+use crate::blackouts::resolve_window;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::schema::{
+    AppState, FreezeFilter, OrgQuery, OverrideQuery, PUBLISH_FREEZE_COLUMNS, PublishFreeze,
+    PublishFreezeRequest,
+};
+
+/// The open freeze covering `app_name` at `now`, if any.
+pub async fn active_freeze(
+    pool: &Pool<Sqlite>,
+    app_name: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<PublishFreeze>, sqlx::Error> {
+    sqlx::query_as::<_, PublishFreeze>(&format!(
+        "SELECT {} FROM publish_freezes WHERE org_id = (SELECT org_id FROM apps WHERE name = ?1) AND (app_name IS NULL OR app_name = ?1) AND starts_at <= ?2 AND ends_at > ?2 ORDER BY ends_at DESC LIMIT 1",
+        PUBLISH_FREEZE_COLUMNS
+    ))
+    .bind(app_name)
+    .bind(now)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Refuses to publish `app_name` during a freeze, unless the operator
+/// explicitly overrides it.
+pub async fn check_publish(
+    pool: &Pool<Sqlite>,
+    principal: Principal,
+    app_name: &str,
+    query: &OverrideQuery,
+) -> AppResult<()> {
+    let Some(freeze) = active_freeze(pool, app_name, Utc::now())
+        .await
+        .map_err(|e| AppError::internal("Failed to check publish freezes", e))?
+    else {
+        return Ok(());
+    };
+
+    let reason = if freeze.reason.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", freeze.reason)
+    };
+    if !query.override_freeze {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "frozen",
+            format!(
+                "Publishing {} is frozen until {}{}; retry with override=true to publish anyway",
+                app_name, freeze.ends_at, reason
+            ),
+        ));
+    }
+    if principal.require_operator().is_err() {
+        return Err(AppError::forbidden(format!(
+            "Publishing {} is frozen until {}{}; only the operator token may override",
+            app_name, freeze.ends_at, reason
+        )));
+    }
+    println!(
+        "Operator overrode publish freeze {} for {}",
+        freeze.id, app_name
+    );
+    Ok(())
+}
+
+/// List publish freezes
+#[utoipa::path(
+    get,
+    path = "/freezes",
+    params(FreezeFilter),
+    responses(
+        (status = 200, description = "Current and upcoming freezes, soonest first", body = Vec<PublishFreeze>)
+    )
+)]
+pub async fn list_freezes(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<FreezeFilter>,
+) -> AppResult<Json<Vec<PublishFreeze>>> {
+    let freezes = sqlx::query_as::<_, PublishFreeze>(&format!(
+        "SELECT {} FROM publish_freezes WHERE (?1 OR ends_at > ?2) AND {} ORDER BY starts_at, id",
+        PUBLISH_FREEZE_COLUMNS,
+        org_scope(3)
+    ))
+    .bind(filter.include_past)
+    .bind(Utc::now())
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load publish freezes", e))?;
+
+    Ok(Json(freezes))
+}
+
+/// Schedule a publish freeze
+///
+/// A freeze naming an app belongs to the app's organization; one without
+/// covers every app of the caller's organization (or of `org_id`, for the
+/// operator).
+#[utoipa::path(
+    post,
+    path = "/freezes",
+    params(OrgQuery),
+    request_body = PublishFreezeRequest,
+    responses(
+        (status = 201, description = "Freeze scheduled", body = PublishFreeze),
+        (status = 400, description = "Invalid time range or timezone", body = ErrorBody),
+        (status = 403, description = "The app or org_id belongs to another organization", body = ErrorBody),
+        (status = 404, description = "Organization not found", body = ErrorBody)
+    )
+)]
+pub async fn create_freeze(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(org): Query<OrgQuery>,
+    Json(request): Json<PublishFreezeRequest>,
+) -> AppResult<(StatusCode, Json<PublishFreeze>)> {
+    let (starts_at, ends_at, offset) = resolve_window(
+        request.starts_at,
+        request.ends_at,
+        request.timezone.as_deref(),
+    )?;
+    let app_name = request
+        .app_name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty());
+
+    let org_id = match app_name {
+        Some(app_name) => {
+            auth::claim_app(&state.pool, principal, app_name).await?;
+            sqlx::query_scalar::<_, i64>("SELECT org_id FROM apps WHERE name = ?")
+                .bind(app_name)
+                .fetch_one(&state.pool)
+                .await
+                .map_err(|e| AppError::internal("Failed to load app", e))?
+        }
+        None => {
+            let org_id = principal.target_org(org.org_id)?;
+            let exists: Option<i64> =
+                sqlx::query_scalar("SELECT id FROM organizations WHERE id = ?")
+                    .bind(org_id)
+                    .fetch_optional(&state.pool)
+                    .await
+                    .map_err(|e| AppError::internal("Failed to load organization", e))?;
+            exists.ok_or_else(|| AppError::not_found("Organization not found"))?
+        }
+    };
+
+    let freeze = sqlx::query_as::<_, PublishFreeze>(&format!(
+        "INSERT INTO publish_freezes (org_id, app_name, starts_at, ends_at, timezone, reason, created_at) VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING {}",
+        PUBLISH_FREEZE_COLUMNS
+    ))
+    .bind(org_id)
+    .bind(app_name)
+    .bind(starts_at)
+    .bind(ends_at)
+    .bind(offset.to_string())
+    .bind(request.reason.trim())
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to schedule publish freeze", e))?;
+
+    println!(
+        "Scheduled publish freeze {} for {} from {} to {}",
+        freeze.id,
+        freeze
+            .app_name
+            .clone()
+            .unwrap_or_else(|| format!("organization {}", freeze.org_id)),
+        freeze.starts_at,
+        freeze.ends_at
+    );
+    Ok((StatusCode::CREATED, Json(freeze)))
+}
+
+/// Remove a publish freeze
+#[utoipa::path(
+    delete,
+    path = "/freezes/{id}",
+    params(
+        ("id" = i64, Path, description = "Freeze ID")
+    ),
+    responses(
+        (status = 204, description = "Freeze removed"),
+        (status = 404, description = "Freeze not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_freeze(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let deleted = sqlx::query(&format!(
+        "DELETE FROM publish_freezes WHERE id = ?1 AND {}",
+        org_scope(2)
+    ))
+    .bind(id)
+    .bind(principal.org_id())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to remove publish freeze", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("Freeze not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
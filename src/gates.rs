@@ -0,0 +1,865 @@
+//! External check gates.
+//!
+//! An app can require checks, such as a smoke-test pipeline, to pass before
+//! its uploads are published. Uploads of such an app are staged instead:
+//! their artifact is stored, but clients aren't offered it. Each check with
+//! a `trigger_url` gets a POST naming the staged release and a callback URL
+//! under `/check-runs/{token}`, where it reports success or failure. Once
+//! every check succeeded the staged release is published as if it had just
+//! been uploaded; a failure, or a check that doesn't report within its
+//! timeout, fails it.
+//!
+//! Admins can publish a staged release by hand whatever its checks say, or
+//! discard it. Publish freezes apply to uploads, manual publishing and
+//! staged releases alike: one whose checks pass during a freeze stays
+//! staged until the freeze ends, unless the operator publishes it by hand
+//! with `override=true`.
+
+use std::time::Duration;
+
+use axum::{
+    Extension,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::Utc;
+use semver::VersionReq;
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+
+use crate::api_version;
+use crate::artifacts;
+use crate::auth::{self, Principal, app_scope};
+use crate::cache;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::events::{ReleaseEvent, ReleaseEventKind};
+use crate::freezes;
+use crate::notarization;
+use crate::plugins;
+use crate::quotas;
+use crate::routes::{delete_orphaned, validate_http_url};
+use crate::schema::{
+    AppState, CHECK_GATE_COLUMNS, CHECK_RUN_COLUMNS, CheckConclusion, CheckGate, CheckGateFilter,
+    CheckGateRequest, CheckReportRequest, CheckRun, OverrideQuery, RELEASE_COLUMNS, Release,
+    STAGED_RELEASE_COLUMNS, StagedRelease, StagedReleaseFilter,
+};
+
+/// How often checks are looked at for timeouts, and releases held back by
+/// a freeze for whether it ended.
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+const DEFAULT_TIMEOUT_MINS: i64 = 60;
+const MAX_TIMEOUT_MINS: i64 = 7 * 24 * 60;
+
+/// Longest message kept from a check's report.
+const MAX_MESSAGE_LEN: usize = 1000;
+
+/// Columns a staged release is published with, in both tables.
+const PUBLISHED_COLUMNS: &str = "app_name, target, arch, version, url, signature, pub_date, notes, sha256, channel, ring_schedule, critical, notarization_id";
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// Whether uploads of `app_name` have to pass checks first.
+pub async fn has_gates(pool: &Pool<Sqlite>, app_name: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM check_gates WHERE app_name = ?)")
+        .bind(app_name)
+        .fetch_one(pool)
+        .await
+}
+
+async fn load_staged(
+    pool: &Pool<Sqlite>,
+    id: i64,
+    org_id: Option<i64>,
+) -> Result<Option<StagedRelease>, sqlx::Error> {
+    let Some(mut staged) = sqlx::query_as::<_, StagedRelease>(&format!(
+        "SELECT {} FROM staged_releases WHERE id = ?1 AND {}",
+        STAGED_RELEASE_COLUMNS,
+        app_scope(2)
+    ))
+    .bind(id)
+    .bind(org_id)
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    staged.checks = load_checks(pool, id).await?;
+    Ok(Some(staged))
+}
+
+async fn load_checks(pool: &Pool<Sqlite>, staged_id: i64) -> Result<Vec<CheckRun>, sqlx::Error> {
+    sqlx::query_as::<_, CheckRun>(&format!(
+        "SELECT {} FROM check_runs WHERE staged_release_id = ? ORDER BY name",
+        CHECK_RUN_COLUMNS
+    ))
+    .bind(staged_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Stages `release`, an upload of a gated app that has no id yet, and
+/// triggers its checks. Its artifact is retained like a release's.
+/// `builtin` are checks the server runs itself, such as notarization, on
+/// top of the app's. A plugin's `host_requirement` is only recorded once the
+/// release is published.
+pub async fn stage(
+    state: &AppState,
+    release: Release,
+    size: i64,
+    github_asset_id: Option<i64>,
+    builtin: Vec<CheckGate>,
+    host_requirement: Option<&VersionReq>,
+) -> AppResult<StagedRelease> {
+    let mut gates = sqlx::query_as::<_, CheckGate>(&format!(
+        "SELECT {} FROM check_gates WHERE app_name = ? ORDER BY name",
+        CHECK_GATE_COLUMNS
+    ))
+    .bind(&release.app_name)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load check gates", e))?;
+    gates.extend(builtin);
+    let triggers = gates
+        .into_iter()
+        .map(|gate| Ok((gate, auth::random_token()?)))
+        .collect::<AppResult<Vec<_>>>()?;
+
+    let sha256 = release.sha256.clone().unwrap_or_default();
+    let staged: Result<Result<StagedRelease, AppError>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        if let Some(refused) =
+            quotas::record_upload(&mut tx, &state.config, &release.app_name, &sha256, size, true)
+                .await?
+        {
+            return Ok(Err(refused));
+        }
+        artifacts::retain(&mut tx, &release.app_name, &sha256, &release.url, size, github_asset_id).await?;
+        let staged = sqlx::query_as::<_, StagedRelease>(&format!(
+            "INSERT INTO staged_releases ({}, created_at, host_version_req) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING {}",
+            PUBLISHED_COLUMNS, STAGED_RELEASE_COLUMNS
+        ))
+        .bind(&release.app_name).bind(&release.target).bind(&release.arch).bind(&release.version)
+        .bind(&release.url).bind(&release.signature).bind(release.pub_date).bind(&release.notes)
+        .bind(&sha256).bind(&release.channel).bind(&release.ring_schedule).bind(release.critical)
+        .bind(&release.notarization_id).bind(Utc::now())
+        .bind(host_requirement.map(|req| req.to_string()))
+        .fetch_one(&mut *tx)
+        .await?;
+
+        for (gate, token) in &triggers {
+            sqlx::query(
+                "INSERT INTO check_runs (staged_release_id, name, token_hash, deadline) VALUES (?, ?, ?, ?)",
+            )
+            .bind(staged.id)
+            .bind(&gate.name)
+            .bind(auth::hash_token(token))
+            .bind(Utc::now() + chrono::Duration::minutes(gate.timeout_mins))
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(Ok(staged))
+    }
+    .await;
+    let mut staged = staged.map_err(|e| AppError::internal("Failed to stage the release", e))??;
+    staged.checks = load_checks(&state.pool, staged.id)
+        .await
+        .map_err(|e| AppError::internal("Failed to load checks", e))?;
+
+    println!(
+        "Staged {} {} ({}/{}) as {} until {} checks pass",
+        staged.app_name,
+        staged.version,
+        staged.target,
+        staged.arch,
+        staged.id,
+        staged.checks.len()
+    );
+    let payload = staged.clone();
+    for (gate, token) in triggers {
+        let callback_url = callback_url(state, &token);
+        if let Some(check) = staged.checks.iter_mut().find(|c| c.name == gate.name) {
+            check.callback_url = Some(callback_url.clone());
+        }
+        let Some(url) = gate.trigger_url.clone() else {
+            continue;
+        };
+        let state = state.clone();
+        let payload = payload.clone();
+        tokio::spawn(
+            async move { trigger(&state, &gate, &url, &payload, &token, &callback_url).await },
+        );
+    }
+    Ok(staged)
+}
+
+fn callback_url(state: &AppState, token: &str) -> String {
+    format!(
+        "{}{}/check-runs/{}",
+        state.config.public_url,
+        api_version::PREFIX,
+        token
+    )
+}
+
+async fn trigger(
+    state: &AppState,
+    gate: &CheckGate,
+    url: &str,
+    staged: &StagedRelease,
+    token: &str,
+    callback_url: &str,
+) {
+    let body = json!({
+        "check": gate.name,
+        "staged_release": staged,
+        "callback_url": callback_url,
+    });
+    let error = match state
+        .http
+        .post_json(url, &[], body.to_string().into_bytes())
+        .await
+    {
+        Ok(r) if r.status.is_success() => return,
+        Ok(r) => format!("Trigger answered {}", r.status),
+        Err(e) => format!("Trigger failed: {}", e),
+    };
+    println!(
+        "Check {} of staged release {}: {}",
+        gate.name, staged.id, error
+    );
+    // A check that never started can't pass.
+    let failed = async {
+        sqlx::query(
+            "UPDATE check_runs SET status = 'failure', message = ?, completed_at = ? WHERE token_hash = ? AND status = 'pending'",
+        )
+        .bind(&error)
+        .bind(Utc::now())
+        .bind(auth::hash_token(token))
+        .execute(&state.pool)
+        .await?;
+        conclude(state, staged.id).await
+    }
+    .await;
+    if let Err(e) = failed {
+        println!("Failed to record the trigger failure: {}", e);
+    }
+}
+
+/// Records the result of a check the server runs itself, and publishes or
+/// fails the staged release if that was the last one it waited on. Checks
+/// that already have a result, e.g. because they timed out, are left alone.
+pub async fn complete_check(
+    state: &AppState,
+    staged_id: i64,
+    name: &str,
+    conclusion: CheckConclusion,
+    message: &str,
+    details_url: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE check_runs SET status = ?, message = ?, details_url = ?, completed_at = ? WHERE staged_release_id = ? AND name = ? AND status = 'pending'",
+    )
+    .bind(match conclusion {
+        CheckConclusion::Success => "success",
+        CheckConclusion::Failure => "failure",
+    })
+    .bind(message)
+    .bind(details_url)
+    .bind(Utc::now())
+    .bind(staged_id)
+    .bind(name)
+    .execute(&state.pool)
+    .await?;
+    conclude(state, staged_id).await
+}
+
+/// Whether a check of a pending staged release still waits for a result.
+pub async fn is_pending(
+    pool: &Pool<Sqlite>,
+    staged_id: i64,
+    name: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM check_runs c JOIN staged_releases s ON s.id = c.staged_release_id WHERE c.staged_release_id = ? AND c.name = ? AND c.status = 'pending' AND s.status = 'pending')",
+    )
+    .bind(staged_id)
+    .bind(name)
+    .fetch_one(pool)
+    .await
+}
+
+/// What the checks, with these run statuses, decided: failure as soon as
+/// one failed or timed out, success once all succeeded, and nothing yet
+/// while some are pending.
+fn outcome(statuses: &[String]) -> Option<CheckConclusion> {
+    if statuses.iter().any(|s| s == "failure" || s == "timed_out") {
+        Some(CheckConclusion::Failure)
+    } else if statuses.iter().all(|s| s == "success") {
+        Some(CheckConclusion::Success)
+    } else {
+        None
+    }
+}
+
+/// Publishes or fails a pending staged release once its checks allow.
+async fn conclude(state: &AppState, staged_id: i64) -> Result<(), sqlx::Error> {
+    let statuses: Vec<String> =
+        sqlx::query_scalar("SELECT status FROM check_runs WHERE staged_release_id = ?")
+            .bind(staged_id)
+            .fetch_all(&state.pool)
+            .await?;
+    let outcome = outcome(&statuses);
+    if outcome == Some(CheckConclusion::Failure) {
+        let failed = sqlx::query(
+            "UPDATE staged_releases SET status = 'failed', completed_at = ? WHERE id = ? AND status = 'pending'",
+        )
+        .bind(Utc::now())
+        .bind(staged_id)
+        .execute(&state.pool)
+        .await?;
+        if failed.rows_affected() > 0 {
+            println!("Staged release {} failed its checks", staged_id);
+        }
+    } else if outcome == Some(CheckConclusion::Success) {
+        let app_name: Option<String> = sqlx::query_scalar(
+            "SELECT app_name FROM staged_releases WHERE id = ? AND status = 'pending'",
+        )
+        .bind(staged_id)
+        .fetch_optional(&state.pool)
+        .await?;
+        let Some(app_name) = app_name else {
+            return Ok(());
+        };
+        if let Some(freeze) = freezes::active_freeze(&state.pool, &app_name, Utc::now()).await? {
+            println!(
+                "Staged release {} passed its checks; held until the freeze of {} ends at {}",
+                staged_id, app_name, freeze.ends_at
+            );
+            return Ok(());
+        }
+        publish(state, staged_id).await?;
+    }
+    Ok(())
+}
+
+/// Publishes staged releases whose checks passed during a freeze that has
+/// since ended.
+async fn publish_thawed(state: &AppState) -> Result<(), sqlx::Error> {
+    let passed: Vec<(i64, String)> = sqlx::query_as(
+        "SELECT s.id, s.app_name FROM staged_releases s WHERE s.status = 'pending' AND EXISTS (SELECT 1 FROM check_runs c WHERE c.staged_release_id = s.id) AND NOT EXISTS (SELECT 1 FROM check_runs c WHERE c.staged_release_id = s.id AND c.status != 'success') ORDER BY s.id",
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    let now = Utc::now();
+    for (id, app_name) in passed {
+        if freezes::active_freeze(&state.pool, &app_name, now)
+            .await?
+            .is_none()
+        {
+            publish(state, id).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Turns a staged release that isn't published yet into a release.
+async fn publish(state: &AppState, staged_id: i64) -> Result<Option<Release>, sqlx::Error> {
+    let mut tx = state.pool.begin().await?;
+    let claimed = sqlx::query(
+        "UPDATE staged_releases SET status = 'published', completed_at = ? WHERE id = ? AND status != 'published'",
+    )
+    .bind(Utc::now())
+    .bind(staged_id)
+    .execute(&mut *tx)
+    .await?;
+    if claimed.rows_affected() == 0 {
+        return Ok(None);
+    }
+    // The staged release's artifact reference becomes the release's.
+    let release = sqlx::query_as::<_, Release>(&format!(
+        "INSERT INTO releases ({0}) SELECT {0} FROM staged_releases WHERE id = ? RETURNING {1}",
+        PUBLISHED_COLUMNS, RELEASE_COLUMNS
+    ))
+    .bind(staged_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE staged_releases SET release_id = ? WHERE id = ?")
+        .bind(release.id)
+        .bind(staged_id)
+        .execute(&mut *tx)
+        .await?;
+    plugins::publish_host_requirement(&mut tx, staged_id).await?;
+    tx.commit().await?;
+
+    state
+        .cache
+        .invalidate(&cache::latest_key(
+            &release.app_name,
+            &release.target,
+            &release.arch,
+            &release.channel,
+        ))
+        .await;
+    println!(
+        "Published staged release {} as release {}",
+        staged_id, release.id
+    );
+    state
+        .events
+        .emit(ReleaseEvent::new(
+            ReleaseEventKind::Published,
+            release.clone(),
+        ))
+        .await;
+    Ok(Some(release))
+}
+
+/// Fails checks that are past their deadline, and publishes releases a
+/// freeze held back once it ends.
+pub fn spawn(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(TIMEOUT_POLL_INTERVAL).await;
+            if let Err(e) = time_out(&state).await {
+                println!("Failed to time out checks: {}", e);
+            }
+            if let Err(e) = publish_thawed(&state).await {
+                println!("Failed to publish releases held by a freeze: {}", e);
+            }
+        }
+    });
+}
+
+async fn time_out(state: &AppState) -> Result<(), sqlx::Error> {
+    let mut staged_ids: Vec<i64> = sqlx::query_scalar(
+        "UPDATE check_runs SET status = 'timed_out', message = 'No result before the deadline', completed_at = ?1 WHERE status = 'pending' AND deadline <= ?1 RETURNING staged_release_id",
+    )
+    .bind(Utc::now())
+    .fetch_all(&state.pool)
+    .await?;
+    staged_ids.sort_unstable();
+    staged_ids.dedup();
+    for id in staged_ids {
+        conclude(state, id).await?;
+    }
+    Ok(())
+}
+
+/// Report a check's result
+///
+/// Called by the check itself, with the callback URL it was triggered with.
+#[utoipa::path(
+    post,
+    path = "/check-runs/{token}",
+    security(()),
+    params(
+        ("token" = String, Path, description = "Callback token from the trigger")
+    ),
+    request_body = CheckReportRequest,
+    responses(
+        (status = 204, description = "Result recorded"),
+        (status = 404, description = "Unknown token", body = ErrorBody),
+        (status = 409, description = "The check already has a result", body = ErrorBody)
+    )
+)]
+pub async fn report_check(
+    Path(token): Path<String>,
+    State(state): State<AppState>,
+    Json(report): Json<CheckReportRequest>,
+) -> AppResult<StatusCode> {
+    let status = match report.conclusion {
+        CheckConclusion::Success => "success",
+        CheckConclusion::Failure => "failure",
+    };
+    let details_url = report
+        .details_url
+        .as_deref()
+        .map(str::trim)
+        .filter(|u| !u.is_empty());
+    if let Some(url) = details_url {
+        validate_http_url(url)?;
+    }
+    let message: Option<String> = report
+        .message
+        .as_deref()
+        .map(|m| m.trim().chars().take(MAX_MESSAGE_LEN).collect())
+        .filter(|m: &String| !m.is_empty());
+    let token_hash = auth::hash_token(&token);
+
+    let updated: Option<(i64, String)> = sqlx::query_as(
+        "UPDATE check_runs SET status = ?, message = ?, details_url = ?, completed_at = ? WHERE token_hash = ? AND status = 'pending' RETURNING staged_release_id, name",
+    )
+    .bind(status)
+    .bind(&message)
+    .bind(details_url)
+    .bind(Utc::now())
+    .bind(&token_hash)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to record the check result", e))?;
+    let Some((staged_id, name)) = updated else {
+        let existing: Option<String> =
+            sqlx::query_scalar("SELECT status FROM check_runs WHERE token_hash = ?")
+                .bind(&token_hash)
+                .fetch_optional(&state.pool)
+                .await
+                .map_err(|e| AppError::internal("Failed to look up the check", e))?;
+        return Err(match existing {
+            Some(status) => AppError::conflict(format!("The check already concluded: {}", status)),
+            None => AppError::not_found("Unknown check"),
+        });
+    };
+
+    println!("Check {} of staged release {}: {}", name, staged_id, status);
+    conclude(&state, staged_id)
+        .await
+        .map_err(|e| AppError::internal("Failed to conclude the checks", e))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List check gates
+#[utoipa::path(
+    get,
+    path = "/check-gates",
+    params(CheckGateFilter),
+    responses(
+        (status = 200, description = "Checks required before publishing", body = Vec<CheckGate>)
+    )
+)]
+pub async fn list_gates(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<CheckGateFilter>,
+) -> AppResult<Json<Vec<CheckGate>>> {
+    let gates = sqlx::query_as::<_, CheckGate>(&format!(
+        "SELECT {} FROM check_gates WHERE (?1 IS NULL OR app_name = ?1) AND {} ORDER BY app_name, name",
+        CHECK_GATE_COLUMNS,
+        app_scope(2)
+    ))
+    .bind(&filter.app_name)
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load check gates", e))?;
+
+    Ok(Json(gates))
+}
+
+/// Require a check before publishing
+///
+/// Uploads staged before the check was added or changed keep the checks
+/// they were staged with.
+#[utoipa::path(
+    put,
+    path = "/check-gates/{app_name}/{name}",
+    params(
+        ("app_name" = String, Path, description = "Application name"),
+        ("name" = String, Path, description = "Check name: letters, digits, '_', '-' and '.'")
+    ),
+    request_body = CheckGateRequest,
+    responses(
+        (status = 200, description = "Check saved", body = CheckGate),
+        (status = 400, description = "Invalid name, trigger URL or timeout", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody)
+    )
+)]
+pub async fn set_gate(
+    Path((app_name, name)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<CheckGateRequest>,
+) -> AppResult<Json<CheckGate>> {
+    if !is_valid_name(&name) {
+        return Err(AppError::bad_request(
+            "name must be 1-64 letters, digits, '_', '-' or '.'",
+        ));
+    }
+    if name == notarization::CHECK_NAME {
+        return Err(AppError::bad_request(format!(
+            "'{}' is the server's own check of macOS uploads",
+            name
+        )));
+    }
+    let trigger_url = request
+        .trigger_url
+        .as_deref()
+        .map(str::trim)
+        .filter(|u| !u.is_empty());
+    if let Some(url) = trigger_url {
+        validate_http_url(url)?;
+    }
+    let timeout_mins = request.timeout_mins.unwrap_or(DEFAULT_TIMEOUT_MINS);
+    if !(1..=MAX_TIMEOUT_MINS).contains(&timeout_mins) {
+        return Err(AppError::bad_request(format!(
+            "timeout_mins must be between 1 and {}",
+            MAX_TIMEOUT_MINS
+        )));
+    }
+    auth::claim_app(&state.pool, principal, &app_name).await?;
+
+    let gate = sqlx::query_as::<_, CheckGate>(&format!(
+        "INSERT INTO check_gates (app_name, name, trigger_url, timeout_mins, created_at) VALUES (?, ?, ?, ?, ?) ON CONFLICT (app_name, name) DO UPDATE SET trigger_url = excluded.trigger_url, timeout_mins = excluded.timeout_mins RETURNING {}",
+        CHECK_GATE_COLUMNS
+    ))
+    .bind(&app_name)
+    .bind(&name)
+    .bind(trigger_url)
+    .bind(timeout_mins)
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to save check gate", e))?;
+
+    println!(
+        "Uploads of {} now wait on check {}",
+        gate.app_name, gate.name
+    );
+    Ok(Json(gate))
+}
+
+/// Stop requiring a check
+#[utoipa::path(
+    delete,
+    path = "/check-gates/{app_name}/{name}",
+    params(
+        ("app_name" = String, Path, description = "Application name"),
+        ("name" = String, Path, description = "Check name")
+    ),
+    responses(
+        (status = 204, description = "Check removed; releases staged already still wait on it"),
+        (status = 404, description = "Check not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_gate(
+    Path((app_name, name)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let deleted = sqlx::query(&format!(
+        "DELETE FROM check_gates WHERE app_name = ?1 AND name = ?2 AND {}",
+        app_scope(3)
+    ))
+    .bind(&app_name)
+    .bind(&name)
+    .bind(principal.org_id())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to delete check gate", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("Check not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List staged releases
+#[utoipa::path(
+    get,
+    path = "/staged-releases",
+    params(StagedReleaseFilter),
+    responses(
+        (status = 200, description = "Staged releases with their checks, newest first", body = Vec<StagedRelease>)
+    )
+)]
+pub async fn list_staged(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<StagedReleaseFilter>,
+) -> AppResult<Json<Vec<StagedRelease>>> {
+    let mut staged = sqlx::query_as::<_, StagedRelease>(&format!(
+        "SELECT {} FROM staged_releases WHERE (?1 IS NULL OR app_name = ?1) AND (?2 IS NULL OR status = ?2) AND {} ORDER BY id DESC",
+        STAGED_RELEASE_COLUMNS,
+        app_scope(3)
+    ))
+    .bind(&filter.app_name)
+    .bind(&filter.status)
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load staged releases", e))?;
+    for s in &mut staged {
+        s.checks = load_checks(&state.read_pool, s.id)
+            .await
+            .map_err(|e| AppError::internal("Failed to load checks", e))?;
+    }
+
+    Ok(Json(staged))
+}
+
+/// Get a staged release
+#[utoipa::path(
+    get,
+    path = "/staged-releases/{id}",
+    params(
+        ("id" = i64, Path, description = "Staged release ID")
+    ),
+    responses(
+        (status = 200, description = "Staged release with its checks", body = StagedRelease),
+        (status = 404, description = "Staged release not found", body = ErrorBody)
+    )
+)]
+pub async fn get_staged(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<StagedRelease>> {
+    let staged = load_staged(&state.read_pool, id, principal.org_id())
+        .await
+        .map_err(|e| AppError::internal("Failed to load the staged release", e))?
+        .ok_or_else(|| AppError::not_found("Staged release not found"))?;
+    Ok(Json(staged))
+}
+
+/// Publish a staged release now
+///
+/// Overrides its checks, whether they are still running or failed.
+#[utoipa::path(
+    post,
+    path = "/staged-releases/{id}/publish",
+    params(
+        ("id" = i64, Path, description = "Staged release ID"),
+        OverrideQuery
+    ),
+    responses(
+        (status = 200, description = "Published", body = Release),
+        (status = 404, description = "Staged release not found", body = ErrorBody),
+        (status = 409, description = "Already published, or publishing is frozen", body = ErrorBody)
+    )
+)]
+pub async fn publish_staged(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(freeze): Query<OverrideQuery>,
+) -> AppResult<Json<Release>> {
+    let staged = load_staged(&state.pool, id, principal.org_id())
+        .await
+        .map_err(|e| AppError::internal("Failed to load the staged release", e))?
+        .ok_or_else(|| AppError::not_found("Staged release not found"))?;
+    freezes::check_publish(&state.pool, principal, &staged.app_name, &freeze).await?;
+
+    let pending = staged
+        .checks
+        .iter()
+        .filter(|c| c.status != "success")
+        .map(|c| c.name.as_str())
+        .collect::<Vec<_>>();
+    let release = publish(&state, id)
+        .await
+        .map_err(|e| AppError::internal("Failed to publish the staged release", e))?
+        .ok_or_else(|| AppError::conflict("The staged release is already published"))?;
+    if !pending.is_empty() {
+        println!(
+            "Published staged release {} overriding checks {}",
+            id,
+            pending.join(", ")
+        );
+    }
+    Ok(Json(release))
+}
+
+/// Discard a staged release
+#[utoipa::path(
+    delete,
+    path = "/staged-releases/{id}",
+    params(
+        ("id" = i64, Path, description = "Staged release ID")
+    ),
+    responses(
+        (status = 204, description = "Discarded, together with its artifact unless a release uses it"),
+        (status = 404, description = "Staged release not found", body = ErrorBody),
+        (status = 409, description = "Already published; delete the release instead", body = ErrorBody)
+    )
+)]
+pub async fn discard_staged(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let discarded: Result<Option<Option<Option<crate::schema::Artifact>>>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        let Some(status) = sqlx::query_scalar::<_, String>(&format!(
+            "SELECT status FROM staged_releases WHERE id = ?1 AND {}",
+            app_scope(2)
+        ))
+        .bind(id)
+        .bind(principal.org_id())
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        if status == "published" {
+            return Ok(Some(None));
+        }
+        let (app_name, sha256): (String, String) =
+            sqlx::query_as("DELETE FROM staged_releases WHERE id = ? RETURNING app_name, sha256")
+                .bind(id)
+                .fetch_one(&mut *tx)
+                .await?;
+        sqlx::query("DELETE FROM check_runs WHERE staged_release_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        let orphaned = artifacts::release(&mut tx, &app_name, &sha256).await?;
+        tx.commit().await?;
+        Ok(Some(Some(orphaned)))
+    }
+    .await;
+
+    let orphaned = discarded
+        .map_err(|e| AppError::internal("Failed to discard the staged release", e))?
+        .ok_or_else(|| AppError::not_found("Staged release not found"))?
+        .ok_or_else(|| {
+            AppError::conflict(
+                "The staged release is already published; delete the release instead",
+            )
+        })?;
+    delete_orphaned(&state, orphaned).await;
+
+    println!("Discarded staged release {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statuses(raw: &[&str]) -> Vec<String> {
+        raw.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn checks_conclude_once_all_succeed_or_one_fails() {
+        assert_eq!(
+            outcome(&statuses(&["success", "success"])),
+            Some(CheckConclusion::Success)
+        );
+        assert_eq!(outcome(&statuses(&["success", "pending"])), None);
+        assert_eq!(
+            outcome(&statuses(&["pending", "failure"])),
+            Some(CheckConclusion::Failure)
+        );
+        assert_eq!(
+            outcome(&statuses(&["success", "timed_out"])),
+            Some(CheckConclusion::Failure)
+        );
+    }
+
+    #[test]
+    fn gate_names() {
+        assert!(is_valid_name("smoke-tests"));
+        assert!(is_valid_name("e2e.windows_x64"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("smoke tests"));
+        assert!(!is_valid_name(&"a".repeat(65)));
+    }
+}
@@ -0,0 +1,312 @@
+//! GitHub Releases storage: every artifact is an asset on a `{app}-v{version}` release.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::http::{self, header};
+use http_body_util::BodyExt;
+use octocrab::models::repos::{Asset, Release};
+use octocrab::{FromResponse, Octocrab};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+
+use crate::config::Config;
+
+/// Bounds the lookup cache for deployments with very many versions.
+const MAX_CACHED_RELEASES: usize = 1_000;
+
+pub enum PublishError {
+    /// The release already has an asset with this file name.
+    Conflict,
+    Github(String),
+}
+
+/// A release as far as uploads need to know it.
+#[derive(Debug, Clone)]
+struct CachedRelease {
+    /// Without the `{?name,label}` template.
+    upload_url: String,
+    /// Asset IDs and file names.
+    assets: Vec<(u64, String)>,
+}
+
+impl From<&Release> for CachedRelease {
+    fn from(release: &Release) -> Self {
+        Self {
+            upload_url: release.upload_url.replace("{?name,label}", ""),
+            assets: release
+                .assets
+                .iter()
+                .map(|a| (*a.id, a.name.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Short-lived, in-process memory of which release tags exist and which
+/// assets they have, so a burst of uploads of one version for every
+/// platform looks the release up once instead of on every upload. Our own
+/// uploads and deletions keep it current; changes made elsewhere show once
+/// an entry expires (`GITHUB_LOOKUP_CACHE_TTL_SECS`, 0 disables it).
+pub struct ReleaseLookups {
+    ttl: Duration,
+    releases: Mutex<HashMap<String, (Instant, CachedRelease)>>,
+}
+
+impl ReleaseLookups {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            releases: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, tag: &str) -> Option<CachedRelease> {
+        let releases = self.releases.lock().unwrap();
+        releases
+            .get(tag)
+            .filter(|(expires, _)| *expires > Instant::now())
+            .map(|(_, release)| release.clone())
+    }
+
+    fn put(&self, tag: &str, release: CachedRelease) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut releases = self.releases.lock().unwrap();
+        if releases.len() >= MAX_CACHED_RELEASES {
+            let now = Instant::now();
+            releases.retain(|_, (expires, _)| *expires > now);
+            if releases.len() >= MAX_CACHED_RELEASES {
+                releases.clear();
+            }
+        }
+        releases.insert(tag.to_string(), (Instant::now() + self.ttl, release));
+    }
+
+    fn forget(&self, tag: &str) {
+        self.releases.lock().unwrap().remove(tag);
+    }
+
+    fn add_asset(&self, tag: &str, asset: &Asset) {
+        if let Some((_, release)) = self.releases.lock().unwrap().get_mut(tag) {
+            release.assets.push((*asset.id, asset.name.clone()));
+        }
+    }
+
+    fn remove_asset(&self, asset_id: u64) {
+        for (_, release) in self.releases.lock().unwrap().values_mut() {
+            release.assets.retain(|(id, _)| *id != asset_id);
+        }
+    }
+}
+
+pub struct GitHub {
+    octo: Octocrab,
+    owner: String,
+    repo: String,
+}
+
+impl GitHub {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        println!("Initializing GitHub client...");
+        let token = config
+            .github_token
+            .clone()
+            .ok_or_else(|| "GITHUB_TOKEN must be set".to_string())?;
+        let build_error = |e: octocrab::Error| format!("Failed to build GitHub client: {}", e);
+        let mut builder = Octocrab::builder().personal_token(token);
+        if let Some(url) = &config.github_api_url {
+            builder = builder.base_uri(url.as_str()).map_err(build_error)?;
+        }
+        if let Some(url) = &config.github_upload_url {
+            builder = builder.upload_uri(url.as_str()).map_err(build_error)?;
+        }
+        let octo = builder.build().map_err(build_error)?;
+        Ok(Self {
+            octo,
+            owner: config.github_owner.clone(),
+            repo: config.github_repo.clone(),
+        })
+    }
+
+    /// Uploads `data` as `file_name` on the `tag` release, creating the
+    /// release (with `notes` as its body) if it doesn't exist yet.
+    pub async fn publish_asset(
+        &self,
+        lookups: &ReleaseLookups,
+        tag: &str,
+        notes: &str,
+        file_name: &str,
+        data: Vec<u8>,
+    ) -> Result<Asset, PublishError> {
+        let releases = self.octo.repos(&self.owner, &self.repo);
+
+        println!("Checking if release tag {} exists...", tag);
+        let cached = lookups.get(tag);
+        if cached.is_some() {
+            println!("Tag {} exists (cached).", tag);
+        }
+        let release = match cached {
+            Some(r) => r,
+            None => match releases.releases().get_by_tag(tag).await {
+                Ok(r) => {
+                    println!("Tag {} exists.", tag);
+                    let r = CachedRelease::from(&r);
+                    lookups.put(tag, r.clone());
+                    r
+                }
+                Err(_) => self.create_release(lookups, tag, notes).await?,
+            },
+        };
+        // Check if asset exists
+        if release.assets.iter().any(|(_, name)| name == file_name) {
+            println!(
+                "Conflict: Asset {} already exists in release {}",
+                file_name, tag
+            );
+            return Err(PublishError::Conflict);
+        }
+        println!("Release {} ready for upload.", tag);
+
+        println!("Uploading asset to GitHub release...");
+        match self.upload(&release, file_name, data).await {
+            Ok(a) => {
+                println!(
+                    "Asset uploaded successfully: url={}",
+                    a.browser_download_url
+                );
+                lookups.add_asset(tag, &a);
+                Ok(a)
+            }
+            Err(e) => {
+                println!("Failed to upload asset: {:?}", e);
+                // The cached release may be what's out of date.
+                lookups.forget(tag);
+                Err(PublishError::Github(format!("GH Upload Fail: {}", e)))
+            }
+        }
+    }
+
+    /// Posts the asset to the release's upload URL. Octocrab's
+    /// `upload_asset` looks the release up again first just to learn that
+    /// URL, which would cost an API call per upload.
+    async fn upload(
+        &self,
+        release: &CachedRelease,
+        file_name: &str,
+        data: Vec<u8>,
+    ) -> Result<Asset, String> {
+        let url = format!(
+            "{}?name={}",
+            release.upload_url,
+            utf8_percent_encode(file_name, NON_ALPHANUMERIC)
+        );
+        let request = http::Request::post(url)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_LENGTH, data.len())
+            .body(data)
+            .map_err(|e| e.to_string())?;
+        let response = self
+            .octo
+            .execute(request)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        let response = octocrab::map_github_error(response)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+        Asset::from_response(response)
+            .await
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    async fn create_release(
+        &self,
+        lookups: &ReleaseLookups,
+        tag: &str,
+        notes: &str,
+    ) -> Result<CachedRelease, PublishError> {
+        println!("Release not found, creating new release for tag {}...", tag);
+        match self
+            .octo
+            .repos(&self.owner, &self.repo)
+            .releases()
+            .create(tag)
+            .name(tag)
+            .body(notes)
+            .send()
+            .await
+        {
+            Ok(r) => {
+                println!("GitHub release created successfully: id={}", r.id);
+                let r = CachedRelease::from(&r);
+                lookups.put(tag, r.clone());
+                Ok(r)
+            }
+            Err(e) => {
+                println!("Failed to create GitHub release: {:?}", e);
+                Err(PublishError::Github(format!("GH Release Fail: {:?}", e)))
+            }
+        }
+    }
+
+    pub async fn delete_asset(
+        &self,
+        lookups: &ReleaseLookups,
+        asset_id: u64,
+    ) -> Result<(), String> {
+        self.octo
+            .repos(&self.owner, &self.repo)
+            .release_assets()
+            .delete(asset_id)
+            .await
+            .map_err(|e| format!("GH Delete Fail: {:?}", e))?;
+        lookups.remove_asset(asset_id);
+        Ok(())
+    }
+
+    /// Fetches the bytes of an asset.
+    pub async fn download_asset(&self, asset_id: u64) -> Result<Vec<u8>, String> {
+        let fail = |e: octocrab::Error| format!("GH Download Fail: {:?}", e);
+        let request = http::Request::get(format!(
+            "/repos/{}/{}/releases/assets/{}",
+            self.owner, self.repo, asset_id
+        ))
+        .header(header::ACCEPT, "application/octet-stream");
+        let request = self
+            .octo
+            .build_request(request, None::<&()>)
+            .map_err(fail)?;
+        let response = self.octo.execute(request).await.map_err(fail)?;
+        let response = self
+            .octo
+            .follow_location_to_data(response)
+            .await
+            .map_err(fail)?;
+        let response = octocrab::map_github_error(response).await.map_err(fail)?;
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(fail)?
+            .to_bytes();
+        Ok(body.to_vec())
+    }
+
+    /// Checks that the token can see the repository and publish to it.
+    pub async fn check_access(&self) -> Result<String, String> {
+        let repo = self
+            .octo
+            .repos(&self.owner, &self.repo)
+            .get()
+            .await
+            .map_err(|e| format!("Cannot read {}/{}: {}", self.owner, self.repo, e))?;
+        if repo.permissions.is_some_and(|p| !p.push) {
+            return Err(format!(
+                "The token cannot publish releases to {}/{}",
+                self.owner, self.repo
+            ));
+        }
+        Ok(format!("{}/{} is reachable", self.owner, self.repo))
+    }
+}
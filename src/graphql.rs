@@ -0,0 +1,1207 @@
+//! Read-only GraphQL endpoint for admin tooling.
+//!
+//! Supports the subset of GraphQL the dashboard needs: query operations
+//! (anonymous or named), variables with defaults, aliases, arguments, nested
+//! selection sets and `__typename`. Fragments, directives, mutations and
+//! introspection are rejected with a GraphQL error; the schema is published
+//! as SDL at `/graphql/schema` instead.
+//!
+//! Results are limited to the caller's organization (see [`crate::auth`]):
+//! its apps, their releases and the assets it stores. Listings
+//! return at most `MAX_LIMIT` items, and documents may nest selections,
+//! lists and objects `MAX_DEPTH` deep.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use axum::{
+    Extension,
+    extract::{Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Json},
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::{Map, Value as JsonValue};
+
+use crate::artifacts;
+use crate::auth::{Principal, app_scope, org_scope};
+use crate::routes::find_latest_release;
+use crate::schema::{
+    ARTIFACT_COLUMNS, AppState, Artifact, DEFAULT_CHANNEL, RELEASE_COLUMNS, Release,
+};
+
+/// Most items a listing returns, whatever its `limit` asks for.
+const MAX_LIMIT: i64 = 500;
+
+/// Deepest nesting of selection sets, lists, objects and list types a
+/// document may use; parsing recurses, so this bounds the stack it takes.
+const MAX_DEPTH: usize = 32;
+
+pub const SCHEMA: &str = r#"type Query {
+  releases(app_name: String, target: String, arch: String, channel: String, yanked: Boolean, published_after: String, published_before: String, limit: Int = 100, offset: Int = 0): [Release!]!
+  release(id: Int!): Release
+  assets(limit: Int = 100, offset: Int = 0): [Asset!]!
+  asset(sha256: String!): Asset
+  apps: [App!]!
+  app(name: String!): App
+  stats: Stats!
+}
+
+type Release {
+  id: Int!
+  app_name: String!
+  target: String!
+  arch: String!
+  version: String!
+  url: String!
+  signature: String!
+  pub_date: String!
+  notes: String!
+  sha256: String
+  channel: String!
+  yanked: Boolean!
+  archived: Boolean!
+  asset: Asset
+  app: App!
+}
+
+type Asset {
+  sha256: String!
+  url: String!
+  size: Int!
+  github_asset_id: Int
+  ref_count: Int!
+  releases(limit: Int = 100): [Release!]!
+}
+
+type App {
+  name: String!
+  release_count: Int!
+  channels: [String!]!
+  releases(target: String, arch: String, channel: String, yanked: Boolean, published_after: String, published_before: String, limit: Int = 100, offset: Int = 0): [Release!]!
+  latest(target: String!, arch: String!, channel: String = "stable"): Release
+}
+
+type Stats {
+  release_count: Int!
+  app_count: Int!
+  asset_count: Int!
+  stored_bytes: Int!
+  apps: [AppStats!]!
+}
+
+type AppStats {
+  app_name: String!
+  release_count: Int!
+  last_published: String
+}
+"#;
+
+#[derive(utoipa::OpenApi)]
+#[openapi(
+    paths(graphql_post, graphql_get, graphql_schema),
+    components(schemas(GraphQLRequest))
+)]
+pub struct GraphqlDoc;
+
+#[derive(Debug, Deserialize, utoipa::ToSchema, utoipa::IntoParams)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphQLRequest {
+    /// GraphQL query document
+    #[schema(
+        example = "{ releases(app_name: \"classprime\", limit: 5) { version pub_date asset { size } } }"
+    )]
+    pub query: String,
+    /// Variables referenced by the query (a JSON object; a JSON string for GET)
+    #[schema(value_type = Option<Object>)]
+    #[param(value_type = Option<String>)]
+    pub variables: Option<JsonValue>,
+    /// Operation to run when the document contains several
+    pub operation_name: Option<String>,
+}
+
+/// Run a GraphQL query
+#[utoipa::path(
+    post,
+    path = "/graphql",
+    request_body = GraphQLRequest,
+    responses(
+        (status = 200, description = "GraphQL response with `data` and/or `errors`", body = Object),
+        (status = 400, description = "Query could not be parsed or validated", body = Object)
+    )
+)]
+pub async fn graphql_post(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<GraphQLRequest>,
+) -> impl IntoResponse {
+    execute_request(&state, principal, request).await
+}
+
+/// Run a GraphQL query passed in the query string
+#[utoipa::path(
+    get,
+    path = "/graphql",
+    params(GraphQLRequest),
+    responses(
+        (status = 200, description = "GraphQL response with `data` and/or `errors`", body = Object),
+        (status = 400, description = "Query could not be parsed or validated", body = Object)
+    )
+)]
+pub async fn graphql_get(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(mut request): Query<GraphQLRequest>,
+) -> impl IntoResponse {
+    // Over GET, variables arrive as a JSON-encoded string.
+    if let Some(JsonValue::String(raw)) = &request.variables {
+        match serde_json::from_str(raw) {
+            Ok(vars) => request.variables = Some(vars),
+            Err(e) => {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid variables: {}", e),
+                );
+            }
+        }
+    }
+    execute_request(&state, principal, request).await
+}
+
+/// GraphQL schema (SDL)
+#[utoipa::path(
+    get,
+    path = "/graphql/schema",
+    responses(
+        (status = 200, description = "Schema in GraphQL SDL", body = String, content_type = "text/plain")
+    )
+)]
+pub async fn graphql_schema() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        SCHEMA,
+    )
+}
+
+async fn execute_request(
+    state: &AppState,
+    principal: Principal,
+    request: GraphQLRequest,
+) -> axum::response::Response {
+    println!(
+        "Received GraphQL query: {}",
+        request.query.replace('\n', " ")
+    );
+
+    let operation = match parse(&request.query)
+        .and_then(|ops| select_operation(ops, request.operation_name.as_deref()))
+    {
+        Ok(op) => op,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    let variables = match bind_variables(&operation, request.variables) {
+        Ok(v) => v,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    let ctx = Context {
+        state,
+        org_id: principal.org_id(),
+        variables,
+    };
+    match resolve_selection(&ctx, &Node::Query, &operation.selection).await {
+        Ok(data) => (StatusCode::OK, Json(serde_json::json!({ "data": data }))).into_response(),
+        Err(e) => {
+            println!("GraphQL execution error: {}", e);
+            error_response(StatusCode::OK, e)
+        }
+    }
+}
+
+fn error_response(status: StatusCode, message: String) -> axum::response::Response {
+    (
+        status,
+        Json(serde_json::json!({ "data": null, "errors": [{ "message": message }] })),
+    )
+        .into_response()
+}
+
+// ---------------------------------------------------------------------------
+// Parsing
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punct(char),
+    Spread,
+    Name(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Enum(String),
+    List(Vec<Value>),
+    Object(Vec<(String, Value)>),
+    Variable(String),
+}
+
+#[derive(Debug)]
+struct Field {
+    alias: Option<String>,
+    name: String,
+    args: Vec<(String, Value)>,
+    selection: Vec<Field>,
+}
+
+impl Field {
+    fn response_key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug)]
+struct Operation {
+    name: Option<String>,
+    variables: Vec<(String, Option<Value>)>,
+    selection: Vec<Field>,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            // Commas are insignificant in GraphQL.
+            c if c.is_whitespace() || c == ',' || c == '\u{feff}' => i += 1,
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '{' | '}' | '(' | ')' | '[' | ']' | ':' | '$' | '!' | '=' | '@' | '|' | '&' => {
+                tokens.push(Token::Punct(c));
+                i += 1;
+            }
+            '.' => {
+                if chars.get(i + 1) == Some(&'.') && chars.get(i + 2) == Some(&'.') {
+                    tokens.push(Token::Spread);
+                    i += 3;
+                } else {
+                    return Err(format!("Unexpected character '.' at {}", i));
+                }
+            }
+            '"' => {
+                if chars[i..].starts_with(&['"', '"', '"']) {
+                    let start = i + 3;
+                    let end = (start..chars.len())
+                        .find(|&j| chars[j..].starts_with(&['"', '"', '"']))
+                        .ok_or("Unterminated block string")?;
+                    tokens.push(Token::Str(
+                        chars[start..end]
+                            .iter()
+                            .collect::<String>()
+                            .trim()
+                            .to_string(),
+                    ));
+                    i = end + 3;
+                    continue;
+                }
+                i += 1;
+                let mut value = String::new();
+                loop {
+                    let Some(&c) = chars.get(i) else {
+                        return Err("Unterminated string".to_string());
+                    };
+                    i += 1;
+                    match c {
+                        '"' => break,
+                        '\\' => {
+                            let escaped = chars.get(i).copied().ok_or("Unterminated string")?;
+                            i += 1;
+                            value.push(match escaped {
+                                'n' => '\n',
+                                't' => '\t',
+                                'r' => '\r',
+                                'b' => '\u{8}',
+                                'f' => '\u{c}',
+                                'u' => {
+                                    let hex: String = chars
+                                        .get(i..i + 4)
+                                        .ok_or("Bad unicode escape")?
+                                        .iter()
+                                        .collect();
+                                    i += 4;
+                                    u32::from_str_radix(&hex, 16)
+                                        .ok()
+                                        .and_then(char::from_u32)
+                                        .ok_or("Bad unicode escape")?
+                                }
+                                other => other,
+                            });
+                        }
+                        c => value.push(c),
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let start = i;
+                i += 1;
+                let mut is_float = false;
+                while let Some(&c) = chars.get(i) {
+                    if c.is_ascii_digit() {
+                        i += 1;
+                    } else if matches!(c, '.' | 'e' | 'E' | '+')
+                        || (c == '-' && matches!(chars[i - 1], 'e' | 'E'))
+                    {
+                        is_float = true;
+                        i += 1;
+                    } else {
+                        break;
+                    }
+                }
+                let text: String = chars[start..i].iter().collect();
+                tokens.push(if is_float {
+                    Token::Float(
+                        text.parse()
+                            .map_err(|_| format!("Invalid number '{}'", text))?,
+                    )
+                } else {
+                    Token::Int(
+                        text.parse()
+                            .map_err(|_| format!("Invalid number '{}'", text))?,
+                    )
+                });
+            }
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let start = i;
+                while chars
+                    .get(i)
+                    .is_some_and(|c| *c == '_' || c.is_ascii_alphanumeric())
+                {
+                    i += 1;
+                }
+                tokens.push(Token::Name(chars[start..i].iter().collect()));
+            }
+            other => return Err(format!("Unexpected character '{}'", other)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    /// Runs `parse` one level deeper, refusing documents nested beyond
+    /// `MAX_DEPTH`.
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(format!("Document is nested more than {} deep", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("Expected '{}', found {}", c, self.describe()))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Name(n)) => Ok(n),
+            _ => {
+                self.pos -= 1;
+                Err(format!("Expected a name, found {}", self.describe()))
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.peek() {
+            None => "end of document".to_string(),
+            Some(Token::Punct(c)) => format!("'{}'", c),
+            Some(Token::Spread) => "'...'".to_string(),
+            Some(Token::Name(n)) => format!("'{}'", n),
+            Some(Token::Int(n)) => n.to_string(),
+            Some(Token::Float(n)) => n.to_string(),
+            Some(Token::Str(s)) => format!("\"{}\"", s),
+        }
+    }
+
+    fn document(&mut self) -> Result<Vec<Operation>, String> {
+        let mut operations = Vec::new();
+        while self.peek().is_some() {
+            operations.push(self.operation()?);
+        }
+        if operations.is_empty() {
+            return Err("Document contains no operations".to_string());
+        }
+        Ok(operations)
+    }
+
+    fn operation(&mut self) -> Result<Operation, String> {
+        if self.peek() == Some(&Token::Punct('{')) {
+            return Ok(Operation {
+                name: None,
+                variables: Vec::new(),
+                selection: self.selection_set()?,
+            });
+        }
+
+        match self.name()?.as_str() {
+            "query" => {}
+            "mutation" | "subscription" => {
+                return Err("Only query operations are supported".to_string());
+            }
+            "fragment" => return Err("Fragments are not supported".to_string()),
+            other => return Err(format!("Unexpected '{}'", other)),
+        }
+
+        let name = match self.peek() {
+            Some(Token::Name(_)) => Some(self.name()?),
+            _ => None,
+        };
+
+        let mut variables = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                self.expect('$')?;
+                let var = self.name()?;
+                self.expect(':')?;
+                self.type_ref()?;
+                let default = if self.eat('=') {
+                    Some(self.value()?)
+                } else {
+                    None
+                };
+                variables.push((var, default));
+            }
+        }
+        self.reject_directives()?;
+
+        Ok(Operation {
+            name,
+            variables,
+            selection: self.selection_set()?,
+        })
+    }
+
+    /// Variable types are accepted but not checked; resolvers validate their arguments.
+    fn type_ref(&mut self) -> Result<(), String> {
+        if self.eat('[') {
+            self.nested(Self::type_ref)?;
+            self.expect(']')?;
+        } else {
+            self.name()?;
+        }
+        self.eat('!');
+        Ok(())
+    }
+
+    fn reject_directives(&self) -> Result<(), String> {
+        if self.peek() == Some(&Token::Punct('@')) {
+            return Err("Directives are not supported".to_string());
+        }
+        Ok(())
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Field>, String> {
+        self.nested(Self::selection_set_items)
+    }
+
+    fn selection_set_items(&mut self) -> Result<Vec<Field>, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        while !self.eat('}') {
+            if self.peek() == Some(&Token::Spread) {
+                return Err("Fragments are not supported".to_string());
+            }
+            fields.push(self.field()?);
+        }
+        if fields.is_empty() {
+            return Err("Selection set must not be empty".to_string());
+        }
+        Ok(fields)
+    }
+
+    fn field(&mut self) -> Result<Field, String> {
+        let first = self.name()?;
+        let (alias, name) = if self.eat(':') {
+            (Some(first), self.name()?)
+        } else {
+            (None, first)
+        };
+
+        let mut args = Vec::new();
+        if self.eat('(') {
+            while !self.eat(')') {
+                let arg = self.name()?;
+                self.expect(':')?;
+                args.push((arg, self.value()?));
+            }
+        }
+        self.reject_directives()?;
+
+        let selection = if self.peek() == Some(&Token::Punct('{')) {
+            self.selection_set()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Field {
+            alias,
+            name,
+            args,
+            selection,
+        })
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.next() {
+            Some(Token::Punct('$')) => Ok(Value::Variable(self.name()?)),
+            Some(Token::Int(n)) => Ok(Value::Int(n)),
+            Some(Token::Float(n)) => Ok(Value::Float(n)),
+            Some(Token::Str(s)) => Ok(Value::String(s)),
+            Some(Token::Name(n)) => Ok(match n.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                _ => Value::Enum(n),
+            }),
+            Some(Token::Punct('[')) => {
+                let mut items = Vec::new();
+                while !self.eat(']') {
+                    items.push(self.nested(Self::value)?);
+                }
+                Ok(Value::List(items))
+            }
+            Some(Token::Punct('{')) => {
+                let mut fields = Vec::new();
+                while !self.eat('}') {
+                    let key = self.name()?;
+                    self.expect(':')?;
+                    fields.push((key, self.nested(Self::value)?));
+                }
+                Ok(Value::Object(fields))
+            }
+            _ => {
+                self.pos -= 1;
+                Err(format!("Expected a value, found {}", self.describe()))
+            }
+        }
+    }
+}
+
+fn parse(source: &str) -> Result<Vec<Operation>, String> {
+    Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+        depth: 0,
+    }
+    .document()
+}
+
+fn select_operation(operations: Vec<Operation>, name: Option<&str>) -> Result<Operation, String> {
+    match name {
+        Some(name) => operations
+            .into_iter()
+            .find(|op| op.name.as_deref() == Some(name))
+            .ok_or_else(|| format!("Unknown operation '{}'", name)),
+        None if operations.len() == 1 => Ok(operations.into_iter().next().unwrap()),
+        None => {
+            Err("operationName is required when the document has several operations".to_string())
+        }
+    }
+}
+
+fn bind_variables(
+    operation: &Operation,
+    provided: Option<JsonValue>,
+) -> Result<Map<String, JsonValue>, String> {
+    let mut provided = match provided {
+        None | Some(JsonValue::Null) => Map::new(),
+        Some(JsonValue::Object(map)) => map,
+        Some(_) => return Err("variables must be a JSON object".to_string()),
+    };
+
+    let mut bound = Map::new();
+    for (name, default) in &operation.variables {
+        let value = match (provided.remove(name), default) {
+            (Some(v), _) => v,
+            (None, Some(default)) => to_json(default, &Map::new())?,
+            (None, None) => JsonValue::Null,
+        };
+        bound.insert(name.clone(), value);
+    }
+    Ok(bound)
+}
+
+fn to_json(value: &Value, variables: &Map<String, JsonValue>) -> Result<JsonValue, String> {
+    Ok(match value {
+        Value::Null => JsonValue::Null,
+        Value::Bool(b) => JsonValue::Bool(*b),
+        Value::Int(n) => JsonValue::from(*n),
+        Value::Float(n) => JsonValue::from(*n),
+        Value::String(s) | Value::Enum(s) => JsonValue::String(s.clone()),
+        Value::List(items) => JsonValue::Array(
+            items
+                .iter()
+                .map(|v| to_json(v, variables))
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::Object(fields) => JsonValue::Object(
+            fields
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), to_json(v, variables)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+        Value::Variable(name) => variables
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("Variable '${}' is not defined", name))?,
+    })
+}
+
+// ---------------------------------------------------------------------------
+// Execution
+
+struct Context<'a> {
+    state: &'a AppState,
+    /// Organization results are limited to; `None` for the operator.
+    org_id: Option<i64>,
+    variables: Map<String, JsonValue>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct AppStats {
+    app_name: String,
+    release_count: i64,
+    last_published: Option<DateTime<Utc>>,
+}
+
+enum Node {
+    Query,
+    Release(Box<Release>),
+    Asset(Artifact),
+    App(String),
+    Stats,
+    AppStats(AppStats),
+}
+
+impl Node {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Node::Query => "Query",
+            Node::Release(_) => "Release",
+            Node::Asset(_) => "Asset",
+            Node::App(_) => "App",
+            Node::Stats => "Stats",
+            Node::AppStats(_) => "AppStats",
+        }
+    }
+}
+
+enum Resolved {
+    Value(JsonValue),
+    Node(Option<Node>),
+    Nodes(Vec<Node>),
+}
+
+struct Args(Map<String, JsonValue>);
+
+impl Args {
+    fn string(&self, name: &str) -> Result<Option<String>, String> {
+        match self.0.get(name) {
+            None | Some(JsonValue::Null) => Ok(None),
+            Some(JsonValue::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(format!("Argument '{}' must be a String", name)),
+        }
+    }
+
+    fn required_string(&self, name: &str) -> Result<String, String> {
+        self.string(name)?
+            .ok_or_else(|| format!("Argument '{}' is required", name))
+    }
+
+    fn int(&self, name: &str, default: i64) -> Result<i64, String> {
+        match self.0.get(name) {
+            None | Some(JsonValue::Null) => Ok(default),
+            Some(v) => v
+                .as_i64()
+                .ok_or_else(|| format!("Argument '{}' must be an Int", name)),
+        }
+    }
+
+    /// The `limit` of a listing, capped at `MAX_LIMIT`.
+    fn limit(&self) -> Result<i64, String> {
+        Ok(self.int("limit", 100)?.clamp(0, MAX_LIMIT))
+    }
+
+    fn bool(&self, name: &str) -> Result<Option<bool>, String> {
+        match self.0.get(name) {
+            None | Some(JsonValue::Null) => Ok(None),
+            Some(JsonValue::Bool(b)) => Ok(Some(*b)),
+            Some(_) => Err(format!("Argument '{}' must be a Boolean", name)),
+        }
+    }
+
+    fn timestamp(&self, name: &str) -> Result<Option<DateTime<Utc>>, String> {
+        self.string(name)?
+            .map(|s| {
+                DateTime::parse_from_rfc3339(&s)
+                    .map(|dt| dt.with_timezone(&Utc))
+                    .map_err(|e| format!("Argument '{}' must be an RFC3339 timestamp: {}", name, e))
+            })
+            .transpose()
+    }
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+fn resolve_selection<'a>(
+    ctx: &'a Context<'a>,
+    node: &'a Node,
+    fields: &'a [Field],
+) -> BoxFuture<'a, Result<Map<String, JsonValue>, String>> {
+    Box::pin(async move {
+        let mut out = Map::new();
+        for field in fields {
+            let value = match resolve_field(ctx, node, field).await? {
+                Resolved::Value(v) => {
+                    if !field.selection.is_empty() {
+                        return Err(format!(
+                            "Field '{}' is a scalar and cannot have a selection set",
+                            field.name
+                        ));
+                    }
+                    v
+                }
+                Resolved::Node(None) => JsonValue::Null,
+                Resolved::Node(Some(child)) => {
+                    JsonValue::Object(complete(ctx, &child, field).await?)
+                }
+                Resolved::Nodes(children) => {
+                    let mut items = Vec::with_capacity(children.len());
+                    for child in &children {
+                        items.push(JsonValue::Object(complete(ctx, child, field).await?));
+                    }
+                    JsonValue::Array(items)
+                }
+            };
+            out.insert(field.response_key().to_string(), value);
+        }
+        Ok(out)
+    })
+}
+
+async fn complete<'a>(
+    ctx: &'a Context<'a>,
+    node: &'a Node,
+    field: &'a Field,
+) -> Result<Map<String, JsonValue>, String> {
+    if field.selection.is_empty() {
+        return Err(format!(
+            "Field '{}' of type {} must have a selection set",
+            field.name,
+            node.type_name()
+        ));
+    }
+    resolve_selection(ctx, node, &field.selection).await
+}
+
+/// Scalar fields of a serializable row, looked up by name.
+fn scalar_of<T: serde::Serialize>(row: &T, name: &str) -> Option<Resolved> {
+    match serde_json::to_value(row) {
+        Ok(JsonValue::Object(mut map)) => map.remove(name).map(Resolved::Value),
+        _ => None,
+    }
+}
+
+async fn resolve_field(ctx: &Context<'_>, node: &Node, field: &Field) -> Result<Resolved, String> {
+    if field.name == "__typename" {
+        return Ok(Resolved::Value(JsonValue::from(node.type_name())));
+    }
+
+    let args = Args(
+        field
+            .args
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), to_json(v, &ctx.variables)?)))
+            .collect::<Result<_, String>>()?,
+    );
+    let pool = &ctx.state.read_pool;
+    let org_id = ctx.org_id;
+    let db = |e: sqlx::Error| format!("Database error: {}", e);
+
+    let resolved = match (node, field.name.as_str()) {
+        (Node::Query, "releases") => Some(Resolved::Nodes(
+            query_releases(ctx, ReleaseQuery::from_args(&args, args.string("app_name")?)?)
+            .await
+            .map_err(db)?
+            .into_iter()
+            .map(|r| Node::Release(Box::new(r)))
+            .collect(),
+        )),
+        (Node::Query, "release") => {
+            let id = args.int("id", -1)?;
+            let release = sqlx::query_as::<_, Release>(&format!(
+                "SELECT {} FROM releases WHERE id = ?1 AND {}",
+                RELEASE_COLUMNS,
+                app_scope(2)
+            ))
+            .bind(id)
+            .bind(org_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(db)?;
+            Some(Resolved::Node(release.map(|r| Node::Release(Box::new(r)))))
+        }
+        (Node::Query, "assets") => Some(Resolved::Nodes(
+            sqlx::query_as::<_, Artifact>(&format!(
+                "SELECT {} FROM artifacts WHERE {} ORDER BY sha256, org_id LIMIT ?1 OFFSET ?2",
+                ARTIFACT_COLUMNS,
+                org_scope(3)
+            ))
+            .bind(args.limit()?)
+            .bind(args.int("offset", 0)?)
+            .bind(org_id)
+            .fetch_all(pool)
+            .await
+            .map_err(db)?
+            .into_iter()
+            .map(Node::Asset)
+            .collect(),
+        )),
+        (Node::Query, "asset") => Some(Resolved::Node(
+            find_asset(ctx, &args.required_string("sha256")?)
+                .await
+                .map_err(db)?
+                .map(Node::Asset),
+        )),
+        (Node::Query, "apps") => Some(Resolved::Nodes(
+            sqlx::query_scalar::<_, String>(&format!(
+                "SELECT DISTINCT app_name FROM releases WHERE {} ORDER BY app_name",
+                app_scope(1)
+            ))
+            .bind(org_id)
+            .fetch_all(pool)
+            .await
+            .map_err(db)?
+            .into_iter()
+            .map(Node::App)
+            .collect(),
+        )),
+        (Node::Query, "app") => {
+            let name = args.required_string("name")?;
+            let exists: bool = sqlx::query_scalar(&format!(
+                "SELECT EXISTS(SELECT 1 FROM releases WHERE app_name = ?1 AND {})",
+                app_scope(2)
+            ))
+            .bind(&name)
+            .bind(org_id)
+            .fetch_one(pool)
+            .await
+            .map_err(db)?;
+            Some(Resolved::Node(exists.then_some(Node::App(name))))
+        }
+        (Node::Query, "stats") => Some(Resolved::Node(Some(Node::Stats))),
+
+        (Node::Release(r), "asset") => Some(Resolved::Node(match &r.sha256 {
+            Some(sha256) => artifacts::find(pool, &r.app_name, sha256)
+                .await
+                .map_err(db)?
+                .map(Node::Asset),
+            None => None,
+        })),
+        (Node::Release(r), "app") => Some(Resolved::Node(Some(Node::App(r.app_name.clone())))),
+        (Node::Release(r), name) => scalar_of(r, name),
+
+        (Node::Asset(a), "releases") => Some(Resolved::Nodes(
+            sqlx::query_as::<_, Release>(&format!(
+                "SELECT {} FROM releases WHERE sha256 = ?1 AND {} ORDER BY pub_date DESC LIMIT ?3",
+                RELEASE_COLUMNS,
+                app_scope(2)
+            ))
+            .bind(&a.sha256)
+            .bind(a.org_id)
+            .bind(args.limit()?)
+            .fetch_all(pool)
+            .await
+            .map_err(db)?
+            .into_iter()
+            .map(|r| Node::Release(Box::new(r)))
+            .collect(),
+        )),
+        (Node::Asset(a), name) => scalar_of(a, name),
+
+        (Node::App(app), "name") => Some(Resolved::Value(JsonValue::from(app.as_str()))),
+        (Node::App(app), "release_count") => {
+            let count: i64 = sqlx::query_scalar(&format!(
+                "SELECT count(*) FROM releases WHERE app_name = ?1 AND {}",
+                app_scope(2)
+            ))
+            .bind(app)
+            .bind(org_id)
+            .fetch_one(pool)
+            .await
+            .map_err(db)?;
+            Some(Resolved::Value(JsonValue::from(count)))
+        }
+        (Node::App(app), "releases") => Some(Resolved::Nodes(
+            query_releases(ctx, ReleaseQuery::from_args(&args, Some(app.clone()))?)
+            .await
+            .map_err(db)?
+            .into_iter()
+            .map(|r| Node::Release(Box::new(r)))
+            .collect(),
+        )),
+        (Node::App(app), "channels") => Some(Resolved::Value(JsonValue::from(
+            sqlx::query_scalar::<_, String>(&format!(
+                "SELECT DISTINCT channel FROM releases WHERE app_name = ?1 AND {} ORDER BY channel",
+                app_scope(2)
+            ))
+            .bind(app)
+            .bind(org_id)
+            .fetch_all(pool)
+            .await
+            .map_err(db)?,
+        ))),
+        (Node::App(app), "latest") => {
+            let target = args.required_string("target")?;
+            let arch = args.required_string("arch")?;
+            let channel = args.string("channel")?;
+            let channel = channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
+            let latest = find_latest_release(ctx.state, app, &target, &arch, channel)
+                .await
+                .map_err(|e| format!("Database error: {}", e))?;
+            Some(Resolved::Node(latest.map(|r| Node::Release(Box::new(r)))))
+        }
+
+        (Node::Stats, "apps") => Some(Resolved::Nodes(
+            sqlx::query_as::<_, AppStats>(&format!(
+                "SELECT app_name, count(*) AS release_count, max(pub_date) AS last_published FROM releases WHERE {} GROUP BY app_name ORDER BY app_name",
+                app_scope(1)
+            ))
+            .bind(org_id)
+            .fetch_all(pool)
+            .await
+            .map_err(db)?
+            .into_iter()
+            .map(Node::AppStats)
+            .collect(),
+        )),
+        (Node::Stats, name) => {
+            let sql = match name {
+                "release_count" => format!("SELECT count(*) FROM releases WHERE {}", app_scope(1)),
+                "app_count" => format!(
+                    "SELECT count(DISTINCT app_name) FROM releases WHERE {}",
+                    app_scope(1)
+                ),
+                "asset_count" => format!("SELECT count(*) FROM artifacts WHERE {}", org_scope(1)),
+                "stored_bytes" => format!(
+                    "SELECT coalesce(sum(size), 0) FROM artifacts WHERE {}",
+                    org_scope(1)
+                ),
+                _ => String::new(),
+            };
+            if sql.is_empty() {
+                None
+            } else {
+                let value: i64 = sqlx::query_scalar(&sql)
+                    .bind(org_id)
+                    .fetch_one(pool)
+                    .await
+                    .map_err(db)?;
+                Some(Resolved::Value(JsonValue::from(value)))
+            }
+        }
+
+        (Node::AppStats(s), "app_name") => Some(Resolved::Value(JsonValue::from(s.app_name.as_str()))),
+        (Node::AppStats(s), "release_count") => Some(Resolved::Value(JsonValue::from(s.release_count))),
+        (Node::AppStats(s), "last_published") => Some(Resolved::Value(
+            serde_json::to_value(s.last_published).unwrap_or(JsonValue::Null),
+        )),
+
+        _ => None,
+    };
+
+    resolved.ok_or_else(|| {
+        format!(
+            "Cannot query field '{}' on type '{}'",
+            field.name,
+            node.type_name()
+        )
+    })
+}
+
+/// Filters shared by `Query.releases` and `App.releases`.
+struct ReleaseQuery {
+    app_name: Option<String>,
+    target: Option<String>,
+    arch: Option<String>,
+    channel: Option<String>,
+    yanked: Option<bool>,
+    published_after: Option<DateTime<Utc>>,
+    published_before: Option<DateTime<Utc>>,
+    limit: i64,
+    offset: i64,
+}
+
+impl ReleaseQuery {
+    fn from_args(args: &Args, app_name: Option<String>) -> Result<Self, String> {
+        Ok(Self {
+            app_name,
+            target: args.string("target")?,
+            arch: args.string("arch")?,
+            channel: args.string("channel")?,
+            yanked: args.bool("yanked")?,
+            published_after: args.timestamp("published_after")?,
+            published_before: args.timestamp("published_before")?,
+            limit: args.limit()?,
+            offset: args.int("offset", 0)?,
+        })
+    }
+}
+
+async fn query_releases(ctx: &Context<'_>, q: ReleaseQuery) -> Result<Vec<Release>, sqlx::Error> {
+    sqlx::query_as::<_, Release>(&format!(
+        "SELECT {} FROM releases
+         WHERE (?1 IS NULL OR app_name = ?1) AND (?2 IS NULL OR target = ?2) AND (?3 IS NULL OR arch = ?3)
+           AND (?4 IS NULL OR channel = ?4) AND (?5 IS NULL OR yanked = ?5)
+           AND (?6 IS NULL OR pub_date > ?6) AND (?7 IS NULL OR pub_date < ?7) AND {}
+         ORDER BY pub_date DESC LIMIT ?8 OFFSET ?9",
+        RELEASE_COLUMNS,
+        app_scope(10)
+    ))
+    .bind(q.app_name)
+    .bind(q.target)
+    .bind(q.arch)
+    .bind(q.channel)
+    .bind(q.yanked)
+    .bind(q.published_after)
+    .bind(q.published_before)
+    .bind(q.limit)
+    .bind(q.offset)
+    .bind(ctx.org_id)
+    .fetch_all(&ctx.state.read_pool)
+    .await
+}
+
+/// The caller's artifact with this SHA-256; the operator gets the first
+/// organization's.
+async fn find_asset(ctx: &Context<'_>, sha256: &str) -> Result<Option<Artifact>, sqlx::Error> {
+    sqlx::query_as::<_, Artifact>(&format!(
+        "SELECT {} FROM artifacts WHERE sha256 = ?1 AND {} ORDER BY org_id LIMIT 1",
+        ARTIFACT_COLUMNS,
+        org_scope(2)
+    ))
+    .bind(sha256)
+    .bind(ctx.org_id)
+    .fetch_optional(&ctx.state.read_pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested_fields(depth: usize) -> String {
+        format!("{}a{}", "{a".repeat(depth), "}".repeat(depth))
+    }
+
+    #[test]
+    fn parses_queries_with_variables_and_aliases() {
+        let operations =
+            parse("query Recent($n: Int = 5) { latest: releases(limit: $n) { version } }").unwrap();
+        let operation = select_operation(operations, Some("Recent")).unwrap();
+        assert_eq!(operation.variables.len(), 1);
+        let field = &operation.selection[0];
+        assert_eq!(field.response_key(), "latest");
+        assert_eq!(field.name, "releases");
+        assert_eq!(field.selection[0].name, "version");
+    }
+
+    #[test]
+    fn rejects_deeply_nested_documents() {
+        assert!(parse(&nested_fields(8)).is_ok());
+        for document in [
+            nested_fields(MAX_DEPTH + 1),
+            nested_fields(100_000),
+            format!(
+                "{{ a(x: {}1{}) }}",
+                "[".repeat(100_000),
+                "]".repeat(100_000)
+            ),
+            format!(
+                "{{ a(x: {}1{}) }}",
+                "{b: ".repeat(100_000),
+                "}".repeat(100_000)
+            ),
+        ] {
+            let error = parse(&document).unwrap_err();
+            assert!(error.contains("nested more than 32 deep"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_operations() {
+        assert!(parse("mutation { yank(id: 1) }").is_err());
+        assert!(parse("fragment F on Release { version }").is_err());
+        assert!(parse("{ releases { ...F } }").is_err());
+    }
+
+    #[test]
+    fn listings_are_capped() {
+        let args = |limit: JsonValue| Args(Map::from_iter([("limit".to_string(), limit)]));
+        assert_eq!(Args(Map::new()).limit().unwrap(), 100);
+        assert_eq!(args(JsonValue::from(20)).limit().unwrap(), 20);
+        assert_eq!(args(JsonValue::from(1_000_000)).limit().unwrap(), MAX_LIMIT);
+        assert_eq!(args(JsonValue::from(-5)).limit().unwrap(), 0);
+        assert!(args(JsonValue::from("many")).limit().is_err());
+    }
+}
@@ -0,0 +1,465 @@
+//! gRPC transport.
+//!
+//! Serves `UpdaterService` of `proto/updater/v1/updater.proto` on
+//! `GRPC_PORT`, for backend services that speak gRPC. Every RPC runs the
+//! code of its REST equivalent, so licenses, rollouts, freezes and quotas
+//! decide alike on both transports: update checks share
+//! [`routes::answer_update_check`], and publishing goes through the
+//! `/upload` handler itself. Metadata is read as request headers, so
+//! `x-license-key` and `authorization` work as they do over HTTP.
+//!
+//! Failures map the REST status onto the closest gRPC code and carry the
+//! REST error code in the `x-error-code` metadata entry.
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{
+    Router,
+    body::Body,
+    extract::DefaultBodyLimit,
+    http::{self, StatusCode, header},
+    routing::post,
+};
+use chrono::{DateTime, Utc};
+use prost_types::{ListValue, Struct, Timestamp, value::Kind};
+use tonic::{Code, Request, Response, Status, Streaming, metadata::MetadataValue};
+use tower::ServiceExt;
+
+use crate::auth;
+use crate::error::{AppError, ErrorBody};
+use crate::licenses;
+use crate::rings::RingSchedule;
+use crate::routes;
+use crate::rules;
+use crate::schema::{
+    AppState, CheckRun, DEFAULT_CHANNEL, Release, ReleaseFilter, StagedRelease, UpdateCheckQuery,
+    UpdateResponse,
+};
+
+// Generated by `build.rs`.
+#[allow(clippy::large_enum_variant)]
+pub mod pb {
+    tonic::include_proto!("updater.v1");
+}
+
+use pb::publish_release_request::Payload;
+use pb::publish_release_response::Result as Published;
+use pb::updater_service_server::{UpdaterService, UpdaterServiceServer};
+
+/// The service, ready to be added to a [`tonic::transport::Server`].
+pub fn server(state: AppState) -> UpdaterServiceServer<Service> {
+    let upload = Router::new()
+        .route("/upload", post(routes::upload_release))
+        .layer(DefaultBodyLimit::disable())
+        .with_state(state.clone());
+    UpdaterServiceServer::new(Service { state, upload })
+}
+
+pub struct Service {
+    state: AppState,
+    /// `/upload` alone, so publishing parses the same multipart body.
+    upload: Router,
+}
+
+#[tonic::async_trait]
+impl UpdaterService for Service {
+    async fn check_update(
+        &self,
+        request: Request<pb::CheckUpdateRequest>,
+    ) -> Result<Response<pb::CheckUpdateResponse>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let check = request.into_inner();
+        let mut components: Vec<String> = check
+            .components
+            .iter()
+            .map(|(name, version)| format!("{}={}", name, version))
+            .collect();
+        components.sort();
+        let query = UpdateCheckQuery {
+            channel: check.channel,
+            customer_id: check.customer_id,
+            license_key: check.license_key,
+            device_id: check.device_id,
+            client_id: check.client_id,
+            components: (!components.is_empty()).then(|| components.join(",")),
+            os_version: check.os_version,
+            country: check.country,
+        };
+        let raw_query: BTreeMap<String, String> = check
+            .params
+            .into_iter()
+            .map(|(name, value)| (format!("{}{}", rules::PARAM_PREFIX, name), value))
+            .collect();
+        let answer = routes::answer_update_check(
+            &self.state,
+            (
+                &check.app_name,
+                &check.target,
+                &check.arch,
+                &check.current_version,
+            ),
+            &query,
+            &raw_query,
+            &headers,
+        )
+        .await
+        .map_err(status)?;
+        Ok(Response::new(pb::CheckUpdateResponse {
+            update: answer.update.map(update_info),
+        }))
+    }
+
+    async fn get_latest(
+        &self,
+        request: Request<pb::GetLatestRequest>,
+    ) -> Result<Response<pb::Release>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let latest = request.into_inner();
+        let channel = latest.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
+        let platform = (
+            latest.app_name.as_str(),
+            latest.target.as_str(),
+            latest.arch.as_str(),
+            channel,
+        );
+        let key = licenses::request_key(&headers, latest.license_key.as_deref());
+        licenses::check_download(&self.state, key, platform)
+            .await
+            .map_err(status)?;
+        let release = routes::find_latest_release(
+            &self.state,
+            &latest.app_name,
+            &latest.target,
+            &latest.arch,
+            channel,
+        )
+        .await
+        .map_err(|e| {
+            status(AppError::internal(
+                "Failed to look up the latest release",
+                e,
+            ))
+        })?
+        .ok_or_else(|| {
+            status(AppError::not_found(format!(
+                "No release of {} for {} {} on {}",
+                latest.app_name, latest.target, latest.arch, channel
+            )))
+        })?;
+        Ok(Response::new(release_message(release)))
+    }
+
+    async fn list_releases(
+        &self,
+        request: Request<pb::ListReleasesRequest>,
+    ) -> Result<Response<pb::ListReleasesResponse>, Status> {
+        let principal = auth::authenticate(&self.state, request.metadata().as_ref())
+            .await
+            .map_err(status)?;
+        let list = request.into_inner();
+        let filter = ReleaseFilter {
+            published_after: list.published_after.as_ref().map(time).transpose()?,
+            published_before: list.published_before.as_ref().map(time).transpose()?,
+        };
+        let releases = routes::visible_releases(&self.state, principal, &filter)
+            .await
+            .map_err(|e| status(AppError::internal("Failed to load releases", e)))?;
+        Ok(Response::new(pb::ListReleasesResponse {
+            releases: releases.into_iter().map(release_message).collect(),
+        }))
+    }
+
+    async fn publish_release(
+        &self,
+        request: Request<Streaming<pb::PublishReleaseRequest>>,
+    ) -> Result<Response<pb::PublishReleaseResponse>, Status> {
+        // Before the artifact is read, as the admin API refuses requests
+        // without a valid token before their bodies are.
+        let principal = auth::authenticate(&self.state, request.metadata().as_ref())
+            .await
+            .map_err(status)?;
+        let mut stream = request.into_inner();
+        let Some(Payload::Metadata(metadata)) = stream.message().await?.and_then(|m| m.payload)
+        else {
+            return Err(Status::invalid_argument(
+                "The first message must carry the release metadata",
+            ));
+        };
+        let mut file = Vec::new();
+        while let Some(message) = stream.message().await? {
+            match message.payload {
+                Some(Payload::Chunk(chunk)) => file.extend_from_slice(&chunk),
+                _ => {
+                    return Err(Status::invalid_argument(
+                        "Only the first message may carry metadata",
+                    ));
+                }
+            }
+        }
+
+        let mut fields = vec![
+            ("app_name", metadata.app_name),
+            ("version", metadata.version),
+            ("target", metadata.target),
+            ("arch", metadata.arch),
+            ("signature", metadata.signature),
+            ("notes", metadata.notes),
+            ("channel", metadata.channel.unwrap_or_default()),
+            ("critical", metadata.critical.to_string()),
+            ("variant", metadata.variant.unwrap_or_default()),
+            ("host_version", metadata.host_version.unwrap_or_default()),
+        ];
+        if let Some(pub_date) = &metadata.pub_date {
+            fields.push(("pub_date", time(pub_date)?.to_rfc3339()));
+        }
+        if !metadata.rings.is_empty() {
+            let schedule = metadata
+                .rings
+                .iter()
+                .map(|(ring, at)| Ok((ring.clone(), time(at)?)))
+                .collect::<Result<RingSchedule, Status>>()?;
+            let rings = serde_json::to_string(&schedule)
+                .map_err(|e| status(AppError::internal("Failed to encode the rings", e)))?;
+            fields.push(("rings", rings));
+        }
+        if let Some(split) = metadata.split_percent {
+            fields.push(("split_percent", split.to_string()));
+        }
+
+        let mut boundary = [0u8; 16];
+        let _ = getrandom::getrandom(&mut boundary);
+        let boundary = hex::encode(boundary);
+        let body = multipart_body(&boundary, &fields, &metadata.file_name, file);
+        let uri = if metadata.r#override {
+            "/upload?override=true"
+        } else {
+            "/upload"
+        };
+        let mut upload = http::Request::post(uri)
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(Body::from(body))
+            .map_err(|e| status(AppError::internal("Failed to build the upload", e)))?;
+        upload.extensions_mut().insert(principal);
+        let response = self
+            .upload
+            .clone()
+            .oneshot(upload)
+            .await
+            .unwrap_or_else(|never| match never {});
+
+        let code = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .map_err(|e| status(AppError::internal("Failed to read the upload answer", e)))?;
+        let published = match code {
+            StatusCode::CREATED => serde_json::from_slice(&body).map(Published::Url),
+            StatusCode::ACCEPTED => serde_json::from_slice(&body)
+                .map(|staged| Published::Staged(staged_message(staged))),
+            _ => {
+                let error: ErrorBody = serde_json::from_slice(&body).unwrap_or(ErrorBody {
+                    code: "internal".to_string(),
+                    message: String::from_utf8_lossy(&body).into_owned(),
+                    request_id: None,
+                });
+                return Err(from_parts(code, &error.code, &error.message));
+            }
+        }
+        .map_err(|e| status(AppError::internal("Failed to parse the upload answer", e)))?;
+        Ok(Response::new(pb::PublishReleaseResponse {
+            result: Some(published),
+        }))
+    }
+}
+
+/// `fields` and the artifact as a `multipart/form-data` body, as `/upload`
+/// takes it.
+fn multipart_body(
+    boundary: &str,
+    fields: &[(&str, String)],
+    file_name: &str,
+    file: Vec<u8>,
+) -> Vec<u8> {
+    let mut body = Vec::with_capacity(file.len() + 1024);
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            )
+            .as_bytes(),
+        );
+    }
+    let file_name = file_name.replace(['"', '\r', '\n'], "_");
+    body.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            boundary, file_name
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(&file);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+fn status(error: AppError) -> Status {
+    from_parts(error.status(), error.code(), error.message())
+}
+
+fn from_parts(status: StatusCode, code: &str, message: &str) -> Status {
+    let grpc_code = match status {
+        StatusCode::BAD_REQUEST => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::PAYMENT_REQUIRED => Code::FailedPrecondition,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::CONFLICT if code == "frozen" => Code::FailedPrecondition,
+        StatusCode::CONFLICT => Code::AlreadyExists,
+        StatusCode::PAYLOAD_TOO_LARGE | StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        _ => Code::Internal,
+    };
+    let mut status = Status::new(grpc_code, message);
+    if let Ok(value) = MetadataValue::try_from(code) {
+        status.metadata_mut().insert("x-error-code", value);
+    }
+    status
+}
+
+fn timestamp(time: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.timestamp_subsec_nanos() as i32,
+    }
+}
+
+fn time(timestamp: &Timestamp) -> Result<DateTime<Utc>, Status> {
+    u32::try_from(timestamp.nanos)
+        .ok()
+        .and_then(|nanos| DateTime::from_timestamp(timestamp.seconds, nanos))
+        .ok_or_else(|| Status::invalid_argument("Timestamp out of range"))
+}
+
+fn schedule(schedule: Option<RingSchedule>) -> HashMap<String, Timestamp> {
+    schedule
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(ring, at)| (ring, timestamp(at)))
+        .collect()
+}
+
+fn value(json: serde_json::Value) -> prost_types::Value {
+    let kind = match json {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(b) => Kind::BoolValue(b),
+        serde_json::Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or_default()),
+        serde_json::Value::String(s) => Kind::StringValue(s),
+        serde_json::Value::Array(items) => Kind::ListValue(ListValue {
+            values: items.into_iter().map(value).collect(),
+        }),
+        serde_json::Value::Object(fields) => Kind::StructValue(Struct {
+            fields: fields.into_iter().map(|(k, v)| (k, value(v))).collect(),
+        }),
+    };
+    prost_types::Value { kind: Some(kind) }
+}
+
+fn release_message(release: Release) -> pb::Release {
+    pb::Release {
+        id: release.id,
+        app_name: release.app_name,
+        target: release.target,
+        arch: release.arch,
+        version: release.version,
+        url: release.url,
+        signature: release.signature,
+        pub_date: Some(timestamp(release.pub_date)),
+        notes: release.notes,
+        sha256: release.sha256,
+        channel: release.channel,
+        yanked: release.yanked,
+        ring_schedule: schedule(release.ring_schedule.map(|s| s.0)),
+        critical: release.critical,
+        notarization_id: release.notarization_id,
+        archived: release.archived,
+    }
+}
+
+fn update_info(update: UpdateResponse) -> pb::UpdateInfo {
+    pb::UpdateInfo {
+        version: update.version,
+        url: update.url,
+        signature: update.signature,
+        pub_date: Some(timestamp(update.pub_date)),
+        notes: update.notes,
+        messages: update
+            .messages
+            .into_iter()
+            .map(|m| pb::CampaignMessage {
+                id: m.id,
+                title: m.title,
+                body: m.body,
+                link_url: m.link_url,
+            })
+            .collect(),
+        flags: update
+            .flags
+            .into_iter()
+            .map(|(key, v)| (key, value(v)))
+            .collect(),
+        variant: update.variant,
+        components: update
+            .components
+            .into_iter()
+            .map(|c| pb::ComponentUpdate {
+                name: c.name,
+                version: c.version,
+                url: c.url,
+                signature: c.signature,
+                sha256: c.sha256,
+                size: c.size,
+            })
+            .collect(),
+    }
+}
+
+fn check_run_message(check: CheckRun) -> pb::CheckRun {
+    pb::CheckRun {
+        id: check.id,
+        staged_release_id: check.staged_release_id,
+        name: check.name,
+        status: check.status,
+        message: check.message,
+        details_url: check.details_url,
+        deadline: Some(timestamp(check.deadline)),
+        completed_at: check.completed_at.map(timestamp),
+        callback_url: check.callback_url,
+    }
+}
+
+fn staged_message(staged: StagedRelease) -> pb::StagedRelease {
+    pb::StagedRelease {
+        id: staged.id,
+        app_name: staged.app_name,
+        target: staged.target,
+        arch: staged.arch,
+        version: staged.version,
+        url: staged.url,
+        signature: staged.signature,
+        pub_date: Some(timestamp(staged.pub_date)),
+        notes: staged.notes,
+        sha256: staged.sha256,
+        channel: staged.channel,
+        ring_schedule: schedule(staged.ring_schedule.map(|s| s.0)),
+        critical: staged.critical,
+        status: staged.status,
+        release_id: staged.release_id,
+        created_at: Some(timestamp(staged.created_at)),
+        completed_at: staged.completed_at.map(timestamp),
+        notarization_id: staged.notarization_id,
+        checks: staged.checks.into_iter().map(check_run_message).collect(),
+    }
+}
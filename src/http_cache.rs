@@ -0,0 +1,167 @@
+//! HTTP caching helpers: ETags for the update-check endpoints and
+//! per-route `Cache-Control` headers.
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Response, header};
+use sha2::{Digest, Sha256};
+use tower_http::set_header::SetResponseHeaderLayer;
+
+use crate::flags::Flags;
+use crate::response_fields::ResponseFields;
+use crate::schema::{CampaignMessage, ComponentUpdate, Release, WebBundleUpdateResponse};
+
+/// Size in bytes of the artifact a download redirect points at.
+pub const ASSET_SIZE_HEADER: HeaderName = HeaderName::from_static("x-asset-size");
+
+/// Strong ETag for the latest release of an app/target/arch. Derived from
+/// every field a client sees, so any change to the release changes the tag.
+pub fn release_etag(release: Option<&Release>) -> String {
+    let mut hasher = Sha256::new();
+    match release {
+        Some(r) => {
+            for field in [
+                r.id.to_string().as_str(),
+                &r.version,
+                &r.url,
+                &r.signature,
+                &r.pub_date.to_rfc3339(),
+                &r.notes,
+            ] {
+                hasher.update(field.as_bytes());
+                hasher.update([0]);
+            }
+        }
+        None => hasher.update(b"none"),
+    }
+    format!("\"{}\"", hex::encode(&hasher.finalize()[..16]))
+}
+
+/// ETag of an update check answer: the offered release, any campaign
+/// messages and the client's flags. Without messages or flags it is the
+/// release's own tag.
+pub fn update_check_etag(
+    release: Option<&Release>,
+    components: &[ComponentUpdate],
+    messages: &[CampaignMessage],
+    flags: &Flags,
+) -> String {
+    let etag = release_etag(release);
+    if components.is_empty() && messages.is_empty() && flags.is_empty() {
+        return etag;
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(etag.as_bytes());
+    for component in components {
+        hasher.update([2]);
+        hasher.update(component.name.as_bytes());
+        hasher.update([0]);
+        hasher.update(component.sha256.as_bytes());
+    }
+    for message in messages {
+        for field in [
+            message.id.to_string().as_str(),
+            &message.title,
+            &message.body,
+            message.link_url.as_deref().unwrap_or(""),
+        ] {
+            hasher.update([0]);
+            hasher.update(field.as_bytes());
+        }
+    }
+    for (key, value) in flags {
+        hasher.update([1]);
+        hasher.update(key.as_bytes());
+        hasher.update([0]);
+        hasher.update(value.to_string().as_bytes());
+    }
+    format!("\"{}\"", hex::encode(&hasher.finalize()[..16]))
+}
+
+/// ETag of a web bundle check answer: the offered bundle, or whether the
+/// client is told to go back to the shipped assets.
+pub fn web_bundle_etag(offer: Option<&WebBundleUpdateResponse>, reset: bool) -> String {
+    let mut hasher = Sha256::new();
+    match offer {
+        Some(b) => {
+            for field in [
+                b.version.as_str(),
+                &b.url,
+                &b.signature,
+                &b.sha256,
+                &b.notes,
+                if b.rollback { "rollback" } else { "update" },
+            ] {
+                hasher.update(field.as_bytes());
+                hasher.update([0]);
+            }
+        }
+        None if reset => hasher.update(b"reset"),
+        None => hasher.update(b"none"),
+    }
+    format!("\"{}\"", hex::encode(&hasher.finalize()[..16]))
+}
+
+/// Adds `name` to the headers a response varies on, keeping any already
+/// listed, so shared caches key the answer on that request header too.
+pub fn vary<B>(mut response: Response<B>, name: HeaderName) -> Response<B> {
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_name(name));
+    response
+}
+
+/// `etag` of an answer whose fields are renamed by `fields`, so renaming
+/// them changes the tag of every answer.
+pub fn with_response_fields(etag: String, fields: Option<&ResponseFields>) -> String {
+    let Some(fields) = fields else {
+        return etag;
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(etag.as_bytes());
+    for (field, name) in fields {
+        hasher.update([3]);
+        hasher.update(field.as_bytes());
+        hasher.update([0]);
+        hasher.update(name.as_bytes());
+    }
+    format!("\"{}\"", hex::encode(&hasher.finalize()[..16]))
+}
+
+/// Returns true when the request's `If-None-Match` already covers `etag`.
+pub fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+fn header_value(value: &str) -> HeaderValue {
+    HeaderValue::from_str(value).unwrap_or_else(|_| {
+        println!("Invalid Cache-Control value '{}', using no-store", value);
+        HeaderValue::from_static("no-store")
+    })
+}
+
+/// Sets `Cache-Control` on successful (2xx/3xx) responses only, so a CDN in
+/// front of the service never pins an error. Handlers can still override it.
+pub fn public_cache_control<B>(
+    value: &str,
+) -> SetResponseHeaderLayer<impl Fn(&Response<B>) -> Option<HeaderValue> + Clone + use<B>> {
+    let value = header_value(value);
+    SetResponseHeaderLayer::if_not_present(header::CACHE_CONTROL, move |res: &Response<B>| {
+        let status = res.status();
+        (status.is_success() || status.is_redirection()).then(|| value.clone())
+    })
+}
+
+/// Sets `Cache-Control` on every response, errors included.
+pub fn private_cache_control<B>(
+    value: &str,
+) -> SetResponseHeaderLayer<impl Fn(&Response<B>) -> Option<HeaderValue> + Clone + use<B>> {
+    let value = header_value(value);
+    SetResponseHeaderLayer::if_not_present(header::CACHE_CONTROL, move |_: &Response<B>| {
+        Some(value.clone())
+    })
+}
@@ -0,0 +1,144 @@
+//! Minimal outbound HTTP client for webhook and notification deliveries,
+//! signing services and the [`crate::client`] SDK.
+
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::http::{HeaderValue, Method, Request, StatusCode, header};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::{Client, connect::HttpConnector};
+use hyper_util::rt::TokioExecutor;
+
+/// Response bodies are only kept for logs, so anything past this is dropped.
+const MAX_RESPONSE_BYTES: usize = 16 * 1024;
+
+#[derive(Clone)]
+pub struct HttpClient {
+    inner: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    timeout: Duration,
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl HttpClient {
+    pub fn new(timeout: Duration) -> std::io::Result<Self> {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()?
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Self {
+            inner: Client::builder(TokioExecutor::new()).build(connector),
+            timeout,
+        })
+    }
+
+    /// POSTs `body` as JSON. Any response, including 4xx/5xx, is `Ok`; only
+    /// connection failures and timeouts are errors.
+    pub async fn post_json(
+        &self,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<HttpResponse, String> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(header::CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| format!("Invalid request to {}: {}", url, e))?;
+        self.send(request, MAX_RESPONSE_BYTES).await
+    }
+
+    /// POSTs a binary `body` and returns the binary response, which is an
+    /// error past `max_body` bytes. Error statuses are `Ok`.
+    pub async fn post_bytes(
+        &self,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+        max_body: usize,
+    ) -> Result<(StatusCode, Bytes), String> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(header::CONTENT_TYPE, "application/octet-stream");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| format!("Invalid request to {}: {}", url, e))?;
+        let (status, bytes, cut_off) = self.send_raw(request, max_body).await?;
+        if cut_off {
+            return Err(format!("Response from {} is over {} bytes", url, max_body));
+        }
+        Ok((status, bytes))
+    }
+
+    /// Sends `request` and reads up to `max_body` bytes of the response.
+    /// Like [`HttpClient::post_json`], error statuses are `Ok`.
+    pub async fn send(
+        &self,
+        request: Request<Full<Bytes>>,
+        max_body: usize,
+    ) -> Result<HttpResponse, String> {
+        let (status, bytes, _) = self.send_raw(request, max_body).await?;
+        let body = String::from_utf8_lossy(&bytes).into_owned();
+        Ok(HttpResponse { status, body })
+    }
+
+    /// Reads at most `max_body` bytes of the response, so a huge or endless
+    /// one can't exhaust memory. The rest is dropped, and the flag says so.
+    async fn send_raw(
+        &self,
+        mut request: Request<Full<Bytes>>,
+        max_body: usize,
+    ) -> Result<(StatusCode, Bytes, bool), String> {
+        let url = request.uri().to_string();
+        request
+            .headers_mut()
+            .entry(header::USER_AGENT)
+            .or_insert(HeaderValue::from_static("App-Release-Manager"));
+
+        tokio::time::timeout(self.timeout, async {
+            let response = self
+                .inner
+                .request(request)
+                .await
+                .map_err(|e| format!("Request to {} failed: {}", url, e))?;
+            let status = response.status();
+            let mut body = Limited::new(response.into_body(), max_body);
+            let mut bytes = Vec::new();
+            let mut cut_off = false;
+            while let Some(frame) = body.frame().await {
+                match frame {
+                    Ok(frame) => {
+                        if let Ok(data) = frame.into_data() {
+                            bytes.extend_from_slice(&data);
+                        }
+                    }
+                    Err(e) if e.is::<LengthLimitError>() => {
+                        cut_off = true;
+                        break;
+                    }
+                    Err(e) => {
+                        return Err(format!("Failed to read response from {}: {}", url, e));
+                    }
+                }
+            }
+            Ok((status, Bytes::from(bytes), cut_off))
+        })
+        .await
+        .unwrap_or_else(|_| Err(format!("Request to {} timed out", url)))
+    }
+}
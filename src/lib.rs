@@ -0,0 +1,64 @@
+//! Release server for Edustart desktop apps.
+//!
+//! The `updater` binary runs the server; this library holds everything it is
+//! built from. Tooling that only talks to a running server needs [`client`]
+//! and the request/response types in [`schema`].
+
+pub mod api_version;
+pub mod archive;
+pub mod artifacts;
+pub mod attachments;
+pub mod auth;
+pub mod authenticode;
+pub mod blackouts;
+pub mod bundles;
+pub mod cache;
+pub mod campaigns;
+pub mod client;
+pub mod components;
+pub mod config;
+pub mod db;
+pub mod deltas;
+pub mod devices;
+pub mod digest;
+pub mod entitlements;
+pub mod error;
+pub mod events;
+pub mod feed;
+pub mod fixtures;
+pub mod flags;
+pub mod freezes;
+pub mod gates;
+pub mod github;
+pub mod graphql;
+pub mod grpc;
+pub mod http_cache;
+pub mod http_client;
+pub mod licenses;
+pub mod load_shedding;
+pub mod lookup;
+pub mod mdm;
+pub mod normalize;
+pub mod notarization;
+pub mod notify;
+pub mod openapi;
+pub mod orgs;
+pub mod platforms;
+pub mod plugins;
+pub mod promotions;
+pub mod quotas;
+pub mod rate_limit;
+pub mod redis;
+pub mod reports;
+pub mod response_fields;
+pub mod rings;
+pub mod routes;
+pub mod rules;
+pub mod s3;
+pub mod schema;
+pub mod selfcheck;
+pub mod smtp;
+pub mod variants;
+pub mod versioning;
+pub mod web_bundles;
+pub mod webhooks;
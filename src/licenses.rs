@@ -0,0 +1,440 @@
+//! License-key gating of updates.
+//!
+//! An app can require update checks to carry a license key, sent as
+//! `X-License-Key` (or `license_key` in the query). The same goes for the
+//! other client routes that hand out its artifact URLs: `/latest`, the
+//! download redirects, MDM exports, delta manifests, bundle resolution,
+//! plugin update checks (against the plugin's own policy) and web bundle
+//! checks, all of which go through [`check_license`] or [`check_download`]
+//! and vary on the key header. Its Atom feed isn't served at all, as feed
+//! readers can't send a key. Keys are checked against
+//! the `licenses` table, or against the app's validation endpoint when it
+//! has one. Clients can tell the outcomes apart and tell the user: a missing
+//! or unknown key is a 403 (`license_required`, `license_invalid`), an
+//! expired one a 402 (`license_expired`).
+
+use axum::{
+    Extension,
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderName, StatusCode},
+    response::Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{self, Principal, app_scope};
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::routes::validate_http_url;
+use crate::schema::{
+    APP_COLUMNS, App, AppState, IssuedLicense, LICENSE_COLUMNS, License, LicenseFilter,
+    LicensePolicyRequest, LicenseRequest, LicenseUpdateRequest, UpdateCheckContext,
+};
+
+pub const LICENSE_KEY_HEADER: HeaderName = HeaderName::from_static("x-license-key");
+
+/// Characters of a key shown in listings.
+const KEY_HINT_LEN: usize = 4;
+
+/// Shortest key accepted for registration, so keys can't be guessed.
+const MIN_KEY_LEN: usize = 8;
+
+/// Body POSTed to an app's validation endpoint.
+#[derive(Serialize)]
+struct ValidationRequest<'a> {
+    license_key: &'a str,
+    #[serde(flatten)]
+    context: &'a UpdateCheckContext,
+}
+
+/// What a validation endpoint answers.
+#[derive(Deserialize)]
+struct ValidationResponse {
+    valid: bool,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+fn invalid_license() -> AppError {
+    AppError::new(
+        StatusCode::FORBIDDEN,
+        "license_invalid",
+        "This license key is not valid",
+    )
+}
+
+/// Validates `key` against `url`. Returns whether it is valid and when it
+/// expires.
+async fn validate_remote(
+    state: &AppState,
+    url: &str,
+    key: &str,
+    context: &UpdateCheckContext,
+) -> AppResult<(bool, Option<DateTime<Utc>>)> {
+    let unavailable = |e: String| {
+        println!("License check for {} failed: {}", context.app_name, e);
+        AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "unavailable",
+            "License check unavailable, try again later",
+        )
+    };
+    let body = serde_json::to_vec(&ValidationRequest {
+        license_key: key,
+        context,
+    })
+    .map_err(|e| unavailable(e.to_string()))?;
+    let response = state
+        .http
+        .post_json(url, &[], body)
+        .await
+        .map_err(unavailable)?;
+    if !response.status.is_success() {
+        return Err(unavailable(format!("{} answered {}", url, response.status)));
+    }
+    let answer: ValidationResponse = serde_json::from_str(&response.body)
+        .map_err(|e| unavailable(format!("unexpected answer from {}: {}", url, e)))?;
+    Ok((answer.valid, answer.expires_at))
+}
+
+/// The key a client sent: the `X-License-Key` header, else `query_key`.
+pub fn request_key<'a>(headers: &'a HeaderMap, query_key: Option<&'a str>) -> Option<&'a str> {
+    headers
+        .get(&LICENSE_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .or(query_key)
+}
+
+/// Whether the app only serves its releases to licensed clients.
+pub async fn requires_license(state: &AppState, app_name: &str) -> AppResult<bool> {
+    let required: Option<bool> =
+        sqlx::query_scalar("SELECT require_license FROM apps WHERE name = ?")
+            .bind(app_name)
+            .fetch_optional(&state.read_pool)
+            .await
+            .map_err(|e| AppError::internal("Failed to load the license policy", e))?;
+    Ok(required.unwrap_or(false))
+}
+
+/// Refuses a download of the app's latest `target`/`arch` build unless the
+/// client is licensed, as an update check would be.
+pub async fn check_download(
+    state: &AppState,
+    key: Option<&str>,
+    (app_name, target, arch, channel): (&str, &str, &str, &str),
+) -> AppResult<()> {
+    let context = UpdateCheckContext {
+        app_name: app_name.to_string(),
+        target: target.to_string(),
+        arch: arch.to_string(),
+        current_version: None,
+        channel: channel.to_string(),
+        customer_id: None,
+    };
+    check_license(state, &context, key).await
+}
+
+/// Refuses the update check unless the app doesn't require a license or
+/// `key` is a valid, unexpired one.
+pub async fn check_license(
+    state: &AppState,
+    context: &UpdateCheckContext,
+    key: Option<&str>,
+) -> AppResult<()> {
+    let policy: Option<(bool, Option<String>)> =
+        sqlx::query_as("SELECT require_license, license_url FROM apps WHERE name = ?")
+            .bind(&context.app_name)
+            .fetch_optional(&state.read_pool)
+            .await
+            .map_err(|e| AppError::internal("Failed to load the license policy", e))?;
+    let Some((true, license_url)) = policy else {
+        return Ok(());
+    };
+
+    let Some(key) = key.map(str::trim).filter(|k| !k.is_empty()) else {
+        return Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            "license_required",
+            format!("Updates for {} require a license key", context.app_name),
+        ));
+    };
+
+    let (valid, expires_at) = match license_url {
+        Some(url) => validate_remote(state, &url, key, context).await?,
+        None => {
+            let row: Option<Option<DateTime<Utc>>> = sqlx::query_scalar(
+                "SELECT expires_at FROM licenses WHERE app_name = ? AND key_hash = ?",
+            )
+            .bind(&context.app_name)
+            .bind(auth::hash_token(key))
+            .fetch_optional(&state.read_pool)
+            .await
+            .map_err(|e| AppError::internal("Failed to check the license", e))?;
+            (row.is_some(), row.flatten())
+        }
+    };
+
+    verdict(valid, expires_at, Utc::now())
+}
+
+/// What a client whose key is `valid` and expires at `expires_at` gets at
+/// `now`. Expiry is reported first, so a lapsed key says how to fix it.
+fn verdict(valid: bool, expires_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> AppResult<()> {
+    if let Some(expires_at) = expires_at.filter(|at| *at <= now) {
+        return Err(AppError::new(
+            StatusCode::PAYMENT_REQUIRED,
+            "license_expired",
+            format!(
+                "This license expired on {}; renew it to keep receiving updates",
+                expires_at.format("%Y-%m-%d")
+            ),
+        ));
+    }
+    if !valid {
+        return Err(invalid_license());
+    }
+    Ok(())
+}
+
+/// Set an app's license policy
+#[utoipa::path(
+    put,
+    path = "/apps/{name}/license-policy",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    request_body = LicensePolicyRequest,
+    responses(
+        (status = 200, description = "Policy saved", body = App),
+        (status = 400, description = "Invalid validation URL", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody)
+    )
+)]
+pub async fn set_license_policy(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<LicensePolicyRequest>,
+) -> AppResult<Json<App>> {
+    let license_url = request
+        .license_url
+        .as_deref()
+        .map(str::trim)
+        .filter(|u| !u.is_empty());
+    if let Some(url) = license_url {
+        validate_http_url(url)?;
+    }
+    auth::claim_app(&state.pool, principal, &name).await?;
+
+    let app = sqlx::query_as::<_, App>(&format!(
+        "UPDATE apps SET require_license = ?, license_url = ? WHERE name = ? RETURNING {}",
+        APP_COLUMNS
+    ))
+    .bind(request.require_license)
+    .bind(license_url)
+    .bind(&name)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to save the license policy", e))?;
+
+    println!(
+        "{} {} a license key for updates",
+        app.name,
+        if app.require_license {
+            "now requires"
+        } else {
+            "no longer requires"
+        }
+    );
+    Ok(Json(app))
+}
+
+/// List licenses
+#[utoipa::path(
+    get,
+    path = "/licenses",
+    params(LicenseFilter),
+    responses(
+        (status = 200, description = "Licenses, without their keys", body = Vec<License>)
+    )
+)]
+pub async fn list_licenses(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<LicenseFilter>,
+) -> AppResult<Json<Vec<License>>> {
+    let licenses = sqlx::query_as::<_, License>(&format!(
+        "SELECT {} FROM licenses WHERE (?1 IS NULL OR app_name = ?1) AND (?2 IS NULL OR customer_id = ?2) AND {} ORDER BY app_name, id",
+        LICENSE_COLUMNS,
+        app_scope(3)
+    ))
+    .bind(&filter.app_name)
+    .bind(&filter.customer_id)
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load licenses", e))?;
+
+    Ok(Json(licenses))
+}
+
+/// Register a license key
+#[utoipa::path(
+    post,
+    path = "/licenses",
+    request_body = LicenseRequest,
+    responses(
+        (status = 201, description = "License registered; the key is only returned here", body = IssuedLicense),
+        (status = 400, description = "Missing app or key too short", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody),
+        (status = 409, description = "Key already registered for this app", body = ErrorBody)
+    )
+)]
+pub async fn create_license(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<LicenseRequest>,
+) -> AppResult<(StatusCode, Json<IssuedLicense>)> {
+    let app_name = request.app_name.trim();
+    if app_name.is_empty() {
+        return Err(AppError::bad_request("app_name is required"));
+    }
+    let key = match request.key.as_deref().map(str::trim) {
+        Some(key) if key.chars().count() < MIN_KEY_LEN => {
+            return Err(AppError::bad_request(format!(
+                "key must be at least {} characters",
+                MIN_KEY_LEN
+            )));
+        }
+        Some(key) => key.to_string(),
+        None => auth::random_token()?,
+    };
+    auth::claim_app(&state.pool, principal, app_name).await?;
+
+    let chars: Vec<char> = key.chars().collect();
+    let hint: String = chars[chars.len() - KEY_HINT_LEN..].iter().collect();
+    let license = sqlx::query_as::<_, License>(&format!(
+        "INSERT INTO licenses (app_name, key_hash, key_hint, customer_id, expires_at, created_at) VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT (app_name, key_hash) DO NOTHING RETURNING {}",
+        LICENSE_COLUMNS
+    ))
+    .bind(app_name)
+    .bind(auth::hash_token(&key))
+    .bind(hint)
+    .bind(&request.customer_id)
+    .bind(request.expires_at)
+    .bind(Utc::now())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to register license", e))?
+    .ok_or_else(|| AppError::conflict("This key is already registered for the app"))?;
+
+    println!("Registered license {} for {}", license.id, license.app_name);
+    Ok((StatusCode::CREATED, Json(IssuedLicense { license, key })))
+}
+
+/// Renew or change a license's expiry
+#[utoipa::path(
+    put,
+    path = "/licenses/{id}",
+    params(
+        ("id" = i64, Path, description = "License ID")
+    ),
+    request_body = LicenseUpdateRequest,
+    responses(
+        (status = 200, description = "License updated", body = License),
+        (status = 404, description = "License not found", body = ErrorBody)
+    )
+)]
+pub async fn update_license(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<LicenseUpdateRequest>,
+) -> AppResult<Json<License>> {
+    let license = sqlx::query_as::<_, License>(&format!(
+        "UPDATE licenses SET expires_at = ?1 WHERE id = ?2 AND {} RETURNING {}",
+        app_scope(3),
+        LICENSE_COLUMNS
+    ))
+    .bind(request.expires_at)
+    .bind(id)
+    .bind(principal.org_id())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to update license", e))?
+    .ok_or_else(|| AppError::not_found("License not found"))?;
+
+    Ok(Json(license))
+}
+
+/// Revoke a license
+#[utoipa::path(
+    delete,
+    path = "/licenses/{id}",
+    params(
+        ("id" = i64, Path, description = "License ID")
+    ),
+    responses(
+        (status = 204, description = "License revoked; its key stops working"),
+        (status = 404, description = "License not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_license(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let deleted = sqlx::query(&format!(
+        "DELETE FROM licenses WHERE id = ?1 AND {}",
+        app_scope(2)
+    ))
+    .bind(id)
+    .bind(principal.org_id())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to revoke license", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("License not found"));
+    }
+    println!("Revoked license {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn now() -> DateTime<Utc> {
+        "2026-03-01T12:00:00Z".parse().unwrap()
+    }
+
+    #[test]
+    fn unexpired_keys_pass() {
+        assert!(verdict(true, None, now()).is_ok());
+        assert!(verdict(true, Some(now() + Duration::seconds(1)), now()).is_ok());
+    }
+
+    #[test]
+    fn expired_keys_are_payment_required() {
+        for expires_at in [now(), now() - Duration::days(30)] {
+            let e = verdict(true, Some(expires_at), now()).unwrap_err();
+            assert_eq!(e.status(), StatusCode::PAYMENT_REQUIRED);
+            assert_eq!(e.code(), "license_expired");
+        }
+        let e = verdict(false, Some(now() - Duration::days(1)), now()).unwrap_err();
+        assert_eq!(e.code(), "license_expired");
+        assert_eq!(
+            e.message(),
+            "This license expired on 2026-02-28; renew it to keep receiving updates"
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_forbidden() {
+        let e = verdict(false, None, now()).unwrap_err();
+        assert_eq!(e.status(), StatusCode::FORBIDDEN);
+        assert_eq!(e.code(), "license_invalid");
+        let e = verdict(false, Some(now() + Duration::days(1)), now()).unwrap_err();
+        assert_eq!(e.code(), "license_invalid");
+    }
+}
@@ -0,0 +1,194 @@
+//! Load shedding for client traffic.
+//!
+//! Update checks, `/latest` and the other routes installed apps call are
+//! admitted `SHED_MAX_IN_FLIGHT` at a time. Beyond that up to
+//! `SHED_MAX_QUEUE` requests wait, each for at most `SHED_QUEUE_TIMEOUT_MS`;
+//! the rest are answered right away with 503 and `Retry-After`, instead of
+//! piling onto the database pool until every request times out. Admin and
+//! upload routes don't pass through here, so operators can still publish
+//! and yank while clients are turned away. `GET /admin/load` reports how
+//! much is being shed.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use axum::{
+    Extension,
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use tokio::sync::Semaphore;
+
+use crate::auth::Principal;
+use crate::config::Config;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::schema::{AppState, LoadStats};
+
+/// Seconds the last-minute figures cover.
+const WINDOW_SECS: u64 = 60;
+
+pub struct LoadShedder {
+    /// `None` when shedding is off; requests are still counted.
+    permits: Option<Semaphore>,
+    max_in_flight: usize,
+    max_queue: usize,
+    queue_timeout: Duration,
+    retry_after_secs: u64,
+    queued: AtomicUsize,
+    served: AtomicU64,
+    shed: AtomicU64,
+    started: Instant,
+    /// Served and shed requests per second of the last minute.
+    recent: Mutex<VecDeque<(u64, u64, u64)>>,
+}
+
+/// A place in the queue, given up however the wait ends.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl LoadShedder {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            permits: (config.shed_max_in_flight > 0)
+                .then(|| Semaphore::new(config.shed_max_in_flight)),
+            max_in_flight: config.shed_max_in_flight,
+            max_queue: config.shed_max_queue,
+            queue_timeout: config.shed_queue_timeout,
+            retry_after_secs: config.shed_retry_after_secs,
+            queued: AtomicUsize::new(0),
+            served: AtomicU64::new(0),
+            shed: AtomicU64::new(0),
+            started: Instant::now(),
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn record(&self, shed: bool) {
+        let counter = if shed { &self.shed } else { &self.served };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        // Read before taking the lock, so another request may already have
+        // recorded a later second.
+        let second = self.started.elapsed().as_secs();
+        let mut recent = self.recent.lock().unwrap();
+        while recent
+            .front()
+            .is_some_and(|(s, _, _)| second.saturating_sub(*s) >= WINDOW_SECS)
+        {
+            recent.pop_front();
+        }
+        if recent.back().is_none_or(|(s, _, _)| *s != second) {
+            recent.push_back((second, 0, 0));
+        }
+        if let Some((_, served, shed_count)) = recent.back_mut() {
+            if shed {
+                *shed_count += 1;
+            } else {
+                *served += 1;
+            }
+        }
+    }
+
+    fn overloaded(&self) -> Response {
+        self.record(true);
+        let mut response = AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "overloaded",
+            "The server is busy; retry later",
+        )
+        .into_response();
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(self.retry_after_secs),
+        );
+        response
+    }
+
+    pub fn stats(&self) -> LoadStats {
+        let in_flight = self
+            .permits
+            .as_ref()
+            .map_or(0, |p| self.max_in_flight - p.available_permits());
+        let second = self.started.elapsed().as_secs();
+        let (served_last_minute, shed_last_minute) = self
+            .recent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(s, _, _)| second.saturating_sub(*s) < WINDOW_SECS)
+            .fold((0, 0), |(served, shed), (_, sv, sh)| {
+                (served + sv, shed + sh)
+            });
+        let total = served_last_minute + shed_last_minute;
+        LoadStats {
+            in_flight,
+            queued: self.queued.load(Ordering::Relaxed),
+            max_in_flight: self.max_in_flight,
+            max_queue: self.max_queue,
+            served_total: self.served.load(Ordering::Relaxed),
+            shed_total: self.shed.load(Ordering::Relaxed),
+            served_last_minute,
+            shed_last_minute,
+            shed_rate_last_minute: match total {
+                0 => 0.0,
+                total => shed_last_minute as f64 / total as f64,
+            },
+        }
+    }
+}
+
+/// Middleware for the routes clients call.
+pub async fn shed(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let load = &state.load;
+    let Some(permits) = &load.permits else {
+        load.record(false);
+        return next.run(request).await;
+    };
+    let _permit = match permits.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            if load.queued.fetch_add(1, Ordering::Relaxed) >= load.max_queue {
+                load.queued.fetch_sub(1, Ordering::Relaxed);
+                return load.overloaded();
+            }
+            let waiting = Waiting(&load.queued);
+            let acquired = tokio::time::timeout(load.queue_timeout, permits.acquire()).await;
+            drop(waiting);
+            match acquired {
+                Ok(Ok(permit)) => permit,
+                _ => return load.overloaded(),
+            }
+        }
+    };
+    load.record(false);
+    next.run(request).await
+}
+
+/// Get client load
+///
+/// Client requests in flight and queued, and how many were shed with 503.
+/// Operator token only.
+#[utoipa::path(
+    get,
+    path = "/admin/load",
+    responses(
+        (status = 200, description = "Current load", body = LoadStats),
+        (status = 403, description = "Not the operator token", body = ErrorBody)
+    )
+)]
+pub async fn get_load(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<LoadStats>> {
+    principal.require_operator()?;
+    Ok(Json(state.load.stats()))
+}
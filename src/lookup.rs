@@ -0,0 +1,97 @@
+//! Reverse lookup of artifacts.
+//!
+//! Given a file's SHA-256 or signature, finds every release, variant,
+//! component, delta, staged release and bundle published with it, so
+//! support and security can tell exactly which build a user has.
+
+use axum::{
+    Extension,
+    extract::{Query, State},
+    response::Json,
+};
+
+use crate::auth::{Principal, app_scope};
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::schema::{AppState, ArtifactMatch, LookupQuery};
+
+/// Everything that stores an artifact, with the columns of [`ArtifactMatch`].
+/// `?1` is the SHA-256 and `?2` the signature; either may be NULL.
+const MATCHES: &str = "
+    SELECT 'release' AS kind, app_name, version, target, arch, channel, id AS release_id,
+        NULL AS detail, yanked AS withdrawn
+    FROM releases WHERE sha256 = ?1 OR signature = ?2
+    UNION ALL
+    SELECT 'variant', r.app_name, r.version, r.target, r.arch, r.channel, r.id, NULL, r.yanked
+    FROM release_variants v JOIN releases r ON r.id = v.release_id
+    WHERE v.sha256 = ?1 OR v.signature = ?2
+    UNION ALL
+    SELECT 'component', r.app_name, r.version, r.target, r.arch, r.channel, r.id,
+        c.name || ' ' || c.version, r.yanked
+    FROM release_components c JOIN releases r ON r.id = c.release_id
+    WHERE c.sha256 = ?1 OR c.signature = ?2
+    UNION ALL
+    SELECT 'delta', r.app_name, r.version, r.target, r.arch, r.channel, r.id,
+        'from ' || d.from_version, r.yanked
+    FROM release_deltas d JOIN releases r ON r.id = d.release_id
+    WHERE d.sha256 = ?1 OR d.signature = ?2
+    UNION ALL
+    SELECT 'staged', app_name, version, target, arch, channel, NULL, status, status = 'failed'
+    FROM staged_releases
+    WHERE status != 'published' AND (sha256 = ?1 OR signature = ?2)
+    UNION ALL
+    SELECT 'bundle', app_name, version, NULLIF(target, ''), NULLIF(arch, ''), NULL, NULL,
+        name, 0
+    FROM bundles WHERE sha256 = ?1
+    UNION ALL
+    SELECT 'web_bundle', app_name, version, NULL, NULL, channel, NULL, NULL, rolled_back
+    FROM web_bundles WHERE sha256 = ?1 OR signature = ?2";
+
+/// Identify a file by its hash or signature
+///
+/// Lists what was published with the file whose SHA-256 or signature is
+/// given: its app, version and platform, newest release first.
+#[utoipa::path(
+    get,
+    path = "/lookup",
+    params(LookupQuery),
+    responses(
+        (status = 200, description = "Everything published with the file; empty if nothing was", body = Vec<ArtifactMatch>),
+        (status = 400, description = "Neither or both of sha256 and signature given, or an invalid hash", body = ErrorBody)
+    )
+)]
+pub async fn lookup_artifact(
+    Query(query): Query<LookupQuery>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<ArtifactMatch>>> {
+    let sha256 = query.sha256.map(|s| s.trim().to_ascii_lowercase());
+    let signature = query.signature.map(|s| s.trim().to_string());
+    match (&sha256, &signature) {
+        (Some(sha256), None) => {
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(AppError::bad_request(
+                    "sha256 must be 64 hexadecimal characters",
+                ));
+            }
+        }
+        (None, Some(signature)) if !signature.is_empty() => (),
+        _ => {
+            return Err(AppError::bad_request(
+                "Give exactly one of sha256 and signature",
+            ));
+        }
+    }
+
+    let matches = sqlx::query_as::<_, ArtifactMatch>(&format!(
+        "SELECT * FROM ({}) WHERE {} ORDER BY release_id IS NULL, release_id DESC, kind",
+        MATCHES,
+        app_scope(3)
+    ))
+    .bind(sha256)
+    .bind(signature)
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to look up artifact", e))?;
+    Ok(Json(matches))
+}
@@ -1,107 +1,133 @@
 use axum::{
     Router,
     extract::DefaultBodyLimit,
-    routing::{get, post},
+    routing::{delete, get, post, put},
 };
-use sqlx::{Pool, Row, Sqlite, sqlite::SqlitePoolOptions};
+use sqlx::{Pool, Sqlite};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tower_http::cors::CorsLayer;
 
-use crate::schema::AppState;
-mod routes;
-mod schema;
+use updater::cache::ReleaseCache;
+use updater::config::Config;
+use updater::entitlements::EntitlementCache;
+use updater::events::EventBus;
+use updater::github::ReleaseLookups;
+use updater::http_client::HttpClient;
+use updater::notify::Notifier;
+use updater::schema::AppState;
+use updater::webhooks::Webhooks;
+use updater::{
+    api_version, archive, attachments, auth, authenticode, blackouts, bundles, campaigns,
+    components, db, deltas, devices, digest, entitlements, error, fixtures, flags, freezes, gates,
+    graphql, grpc, http_cache, licenses, load_shedding, lookup, normalize, notarization, openapi,
+    orgs, plugins, promotions, quotas, rate_limit, reports, response_fields, rings, routes, rules,
+    selfcheck, variants, versioning, web_bundles,
+};
 
-async fn ensure_db() -> Result<Pool<Sqlite>, sqlx::Error> {
-    let database_url =
-        std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:updater.db".to_string());
+async fn ensure_db(config: &Config) -> Result<Pool<Sqlite>, Box<dyn std::error::Error>> {
+    let pool = db::connect_primary(config).await?;
+    db::migrate(&pool).await?;
 
-    // Create the database file if it doesn't exist
-    if !std::path::Path::new("updater.db").exists() {
-        std::fs::File::create("updater.db")?;
+    if let Some(path) = fixtures::path_from_args(config.seed_fixtures.as_deref()) {
+        fixtures::seed(&pool, &path).await?;
     }
-
-    let pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
-        .await?;
-
-    // Run migrations (create table if not exists)
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS releases (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            app_name TEXT NOT NULL,
-            target TEXT NOT NULL,
-            arch TEXT NOT NULL,
-            version TEXT NOT NULL,
-            url TEXT NOT NULL,
-            signature TEXT NOT NULL,
-            pub_date TEXT NOT NULL,
-            notes TEXT NOT NULL
-        )
-        "#,
-    )
-    .execute(&pool)
-    .await?;
-
-    // Seed some data for testing if empty
-    let count: i64 = sqlx::query("SELECT count(*) FROM releases")
-        .fetch_one(&pool)
-        .await?
-        .get(0);
-
-    if count == 0 {
-        println!("Seeding database with dummy data");
-        sqlx::query(
-            r#"
-            INSERT INTO releases (app_name, target, arch, version, url, signature, pub_date, notes)
-            VALUES
-            ('classprime', 'darwin', 'aarch64', '1.0.1', 'https://github.com/user/repo/releases/download/v1.0.1/app-aarch64.app.tar.gz', 'sig123', '2024-01-01T12:00:00Z', 'Initial release'),
-            ('classprime', 'darwin', 'x86_64', '1.0.1', 'https://github.com/user/repo/releases/download/v1.0.1/app-x64.app.tar.gz', 'sig123', '2024-01-01T12:00:00Z', 'Initial release'),
-            ('classfi', 'windows', 'x86_64', '1.0.1', 'https://github.com/user/repo/releases/download/v1.0.1/app-setup.exe', 'sig123', '2024-01-01T12:00:00Z', 'Initial release')
-            "#,
-        )
-        .execute(&pool)
-        .await?;
-    }
-    return Ok(pool);
+    Ok(pool)
 }
 
-use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
-#[derive(OpenApi)]
-#[openapi(
-    paths(
-        routes::check_update,
-        routes::upload_release,
-        routes::get_latest_version,
-        routes::download_latest_release,
-        routes::get_releases,
-        routes::root
-    ),
-    components(
-        schemas(schema::Release, schema::UpdateResponse, schema::UploadReleaseForm, schema::SupportedApp, schema::SupportedTarget)
-    ),
-    tags(
-        (name = "updater", description = "Updater API")
-    )
-)]
-struct ApiDoc;
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let pool = ensure_db().await?;
-    let state = AppState { pool };
+    let config = Arc::new(Config::from_env());
+    if config.admin_token.is_none() {
+        println!("ADMIN_TOKEN is not set; only organization tokens can use the admin API");
+    }
+    let pool = ensure_db(&config).await?;
+    let read_pool = db::connect_read(&config).await?;
 
-    let app = Router::new()
-        .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi()))
-        .route("/", get(routes::root))
-        .route("/releases", get(routes::get_releases))
+    let cache = Arc::new(ReleaseCache::new(
+        config.latest_cache_ttl,
+        config.redis_url.as_deref(),
+    ));
+    cache.spawn_invalidation_listener();
+
+    let events = Arc::new(EventBus::new(config.redis_url.as_deref()));
+    events.spawn_listener();
+
+    let webhooks = Arc::new(Webhooks::new(pool.clone(), &config)?);
+    webhooks.spawn(&events);
+
+    if let Some(notifier) = Notifier::from_config(pool.clone(), &config)? {
+        Arc::new(notifier).spawn(&events);
+    }
+
+    let state = AppState {
+        pool,
+        read_pool,
+        config: config.clone(),
+        cache,
+        events,
+        http: HttpClient::new(config.check_hook_timeout)?,
+        entitlements: Arc::new(EntitlementCache::default()),
+        github_lookups: Arc::new(ReleaseLookups::new(config.github_lookup_cache_ttl)),
+        signer: authenticode::Signer::from_config(&config)?.map(Arc::new),
+        notary: notarization::Notary::from_config(&config)?.map(Arc::new),
+        archive: archive::Archive::from_config(&config)?.map(Arc::new),
+        load: Arc::new(load_shedding::LoadShedder::new(&config)),
+        rate_limiter: Arc::new(rate_limit::RateLimiter::new(&config)),
+    };
+    promotions::spawn(state.clone());
+    gates::spawn(state.clone());
+    archive::spawn(state.clone());
+    selfcheck::spawn(state.clone());
+    digest::spawn(state.clone());
+
+    if let Some(port) = config.grpc_port {
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        let service = grpc::server(state.clone());
+        println!("gRPC listening on {}", addr);
+        tokio::spawn(async move {
+            if let Err(e) = tonic::transport::Server::builder()
+                .add_service(service)
+                .serve(addr)
+                .await
+            {
+                println!("gRPC server stopped: {}", e);
+            }
+        });
+    }
+
+    let update_routes = Router::new()
         .route(
             "/{app_name}/{target}/{arch}/{current_version}",
             get(routes::check_update),
         )
+        .route(
+            "/{app_name}/{target}/{arch}/{current_version}/deltas",
+            get(deltas::get_delta_manifest),
+        )
+        .route(
+            "/plugins/{host_app}/{plugin}/{target}/{arch}/{current_version}",
+            get(plugins::check_plugin_update),
+        )
+        .route(
+            "/web/{app_name}/{app_version}",
+            get(web_bundles::check_web_bundle),
+        )
+        .layer(http_cache::public_cache_control(
+            &config.cache_control_update_check,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            load_shedding::shed,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit,
+        ));
+
+    let latest_routes = Router::new()
         .route(
             "/latest/{app_name}/{target}/{arch}",
             get(routes::get_latest_version),
@@ -110,8 +136,295 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             "/download/latest/{app_name}/{target}/{arch}",
             get(routes::download_latest_release),
         )
+        .route("/download/{app_name}", get(routes::download_app))
+        .route("/metadata/{app_name}", get(routes::get_app_metadata))
+        .route(
+            "/messages/{app_name}/{target}/{current_version}",
+            get(routes::get_campaign_messages),
+        )
+        .route(
+            "/mdm/{format}/{app_name}/{arch}",
+            get(routes::export_mdm_descriptor),
+        )
+        .route("/feed/{feed_name}", get(routes::release_feed))
+        .route(
+            "/attachments/{release_id}/{name}",
+            get(attachments::get_attachment),
+        )
+        .route(
+            "/bundles/{app_name}/{target}/{arch}/{app_version}",
+            get(bundles::resolve_bundles),
+        )
+        .layer(http_cache::public_cache_control(
+            &config.cache_control_latest,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            load_shedding::shed,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit,
+        ));
+
+    let admin_routes = Router::new()
+        .route("/releases", get(routes::get_releases))
+        .route("/releases/{id}", delete(routes::delete_release))
+        .route("/lookup", get(lookup::lookup_artifact))
+        .route("/releases/{id}/yank", post(routes::yank_release))
+        .route("/releases/{id}/promote", post(routes::promote_release))
+        .route("/releases/{id}/rings", put(rings::set_release_rings))
+        .route("/releases/{id}/archive", get(archive::download_archived))
+        .route(
+            "/releases/{id}/components",
+            get(components::list_components),
+        )
+        .route(
+            "/releases/{id}/components/{name}",
+            post(components::upload_component).delete(components::delete_component),
+        )
+        .route(
+            "/releases/{id}/attachments",
+            get(attachments::list_attachments),
+        )
+        .route(
+            "/releases/{id}/attachments/{name}",
+            post(attachments::upload_attachment).delete(attachments::delete_attachment),
+        )
+        .route(
+            "/releases/{id}/deltas",
+            get(deltas::list_deltas).post(deltas::upload_delta),
+        )
+        .route(
+            "/releases/{id}/deltas/{from_version}",
+            delete(deltas::delete_delta),
+        )
+        .route(
+            "/releases/{id}/rules",
+            get(rules::get_release_rules).put(rules::set_release_rules),
+        )
+        .route(
+            "/releases/{id}/bundles",
+            get(bundles::get_release_bundles).put(bundles::set_release_bundles),
+        )
+        .route(
+            "/releases/{id}/variant",
+            get(variants::get_variant)
+                .put(variants::set_variant_split)
+                .delete(variants::delete_variant),
+        )
+        .route("/releases/{id}/metrics", get(variants::release_metrics))
+        .route(
+            "/releases/{id}/critical",
+            put(blackouts::set_release_critical),
+        )
+        .route("/events", get(routes::stream_events))
+        .route(
+            "/webhooks",
+            get(routes::list_webhooks).post(routes::create_webhook),
+        )
+        .route(
+            "/webhooks/{id}",
+            get(routes::get_webhook)
+                .put(routes::update_webhook)
+                .delete(routes::delete_webhook),
+        )
+        .route(
+            "/webhooks/{id}/deliveries",
+            get(routes::list_webhook_deliveries),
+        )
+        .route(
+            "/subscriptions",
+            get(routes::list_subscriptions).post(routes::create_subscription),
+        )
+        .route("/subscriptions/{id}", delete(routes::delete_subscription))
         .route("/upload", post(routes::upload_release))
+        .route(
+            "/graphql",
+            get(graphql::graphql_get).post(graphql::graphql_post),
+        )
+        .route("/graphql/schema", get(graphql::graphql_schema))
+        .route("/orgs", get(orgs::list_orgs).post(orgs::create_org))
+        .route("/orgs/{id}", get(orgs::get_org))
+        .route("/orgs/{id}/update-policy", put(orgs::set_update_policy))
+        .route(
+            "/orgs/{id}/quota",
+            put(quotas::set_org_quota).delete(quotas::delete_org_quota),
+        )
+        .route("/orgs/{id}/usage", get(quotas::get_org_usage))
+        .route(
+            "/orgs/{id}/tokens",
+            get(orgs::list_tokens).post(orgs::create_token),
+        )
+        .route("/orgs/{id}/tokens/{token_id}", delete(orgs::delete_token))
+        .route("/apps", get(orgs::list_apps))
+        .route("/apps/{name}/metadata", put(orgs::set_app_metadata))
+        .route(
+            "/apps/{name}/quota",
+            put(quotas::set_app_quota).delete(quotas::delete_app_quota),
+        )
+        .route("/apps/{name}/usage", get(quotas::get_app_usage))
+        .route(
+            "/apps/{name}/archive-policy",
+            put(archive::set_archive_policy),
+        )
+        .route("/admin/selfcheck", get(selfcheck::get_selfcheck))
+        .route("/admin/digest", get(digest::preview_digest))
+        .route("/admin/load", get(load_shedding::get_load))
+        .route(
+            "/apps/{name}/license-policy",
+            put(licenses::set_license_policy),
+        )
+        .route(
+            "/apps/{name}/version-scheme",
+            put(versioning::set_version_scheme),
+        )
+        .route(
+            "/apps/{name}/response-fields",
+            put(response_fields::set_response_fields),
+        )
+        .route(
+            "/apps/{name}/promotion-policy",
+            get(promotions::get_promotion_policy)
+                .put(promotions::set_promotion_policy)
+                .delete(promotions::delete_promotion_policy),
+        )
+        .route("/promotions", get(promotions::list_promotions))
+        .route("/apps/{name}/plugins", get(plugins::list_plugins))
+        .route("/apps/{name}/bundles", get(bundles::list_bundles))
+        .route(
+            "/apps/{name}/web-bundles",
+            get(web_bundles::list_web_bundles).post(web_bundles::upload_web_bundle),
+        )
+        .route("/web-bundles/{id}", delete(web_bundles::delete_web_bundle))
+        .route(
+            "/web-bundles/{id}/rollback",
+            post(web_bundles::roll_back_web_bundle).delete(web_bundles::restore_web_bundle),
+        )
+        .route("/normalize", post(normalize::normalize_artifact))
+        .route("/authenticode", post(authenticode::sign_artifact))
+        .route(
+            "/apps/{name}/bundles/{bundle}",
+            post(bundles::upload_bundle),
+        )
+        .route(
+            "/apps/{name}/bundles/{bundle}/{version}",
+            delete(bundles::delete_bundle),
+        )
+        .route(
+            "/apps/{name}/plugins/{plugin}",
+            put(plugins::register_plugin).delete(plugins::unregister_plugin),
+        )
+        .route("/check-gates", get(gates::list_gates))
+        .route(
+            "/check-gates/{app_name}/{name}",
+            put(gates::set_gate).delete(gates::delete_gate),
+        )
+        .route("/staged-releases", get(gates::list_staged))
+        .route(
+            "/staged-releases/{id}",
+            get(gates::get_staged).delete(gates::discard_staged),
+        )
+        .route("/staged-releases/{id}/publish", post(gates::publish_staged))
+        .route(
+            "/apps/{name}/entitlement-hook",
+            put(entitlements::set_entitlement_hook),
+        )
+        .route(
+            "/licenses",
+            get(licenses::list_licenses).post(licenses::create_license),
+        )
+        .route(
+            "/licenses/{id}",
+            put(licenses::update_license).delete(licenses::delete_license),
+        )
+        .route("/customers", get(rings::list_customers))
+        .route(
+            "/customers/{customer_id}/update-policy",
+            put(orgs::set_customer_update_policy).delete(orgs::delete_customer_update_policy),
+        )
+        .route(
+            "/blackouts",
+            get(blackouts::list_blackouts).post(blackouts::create_blackout),
+        )
+        .route("/blackouts/{id}", delete(blackouts::delete_blackout))
+        .route(
+            "/freezes",
+            get(freezes::list_freezes).post(freezes::create_freeze),
+        )
+        .route("/freezes/{id}", delete(freezes::delete_freeze))
+        .route(
+            "/campaigns",
+            get(campaigns::list_campaigns).post(campaigns::create_campaign),
+        )
+        .route(
+            "/campaigns/{id}",
+            put(campaigns::update_campaign).delete(campaigns::delete_campaign),
+        )
+        .route("/flags", get(flags::list_flags))
+        .route(
+            "/flags/{app_name}/{key}",
+            put(flags::set_flag).delete(flags::delete_flag),
+        )
+        .route("/devices", get(devices::list_devices))
+        .route(
+            "/devices/{app_name}/{device_id}",
+            get(devices::get_device)
+                .put(devices::register_device)
+                .delete(devices::delete_device),
+        )
+        .route(
+            "/device-targets",
+            get(devices::list_device_targets).post(devices::create_device_target),
+        )
+        .route(
+            "/device-targets/{id}",
+            delete(devices::delete_device_target),
+        )
+        .route(
+            "/customers/{customer_id}",
+            put(rings::assign_customer).delete(rings::unassign_customer),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_token,
+        ))
+        .layer(http_cache::private_cache_control(
+            &config.cache_control_admin,
+        ));
+
+    let api = Router::new()
+        .route(
+            "/reports",
+            post(reports::submit_report).layer(DefaultBodyLimit::max(16 * 1024)),
+        )
+        .route(
+            "/check-runs/{token}",
+            post(gates::report_check).layer(DefaultBodyLimit::max(16 * 1024)),
+        )
+        .route(
+            "/unsubscribe/{token}",
+            get(routes::unsubscribe_page).post(routes::unsubscribe),
+        )
+        .merge(update_routes)
+        .merge(latest_routes)
+        .merge(admin_routes);
+
+    let app = Router::new()
+        .merge(SwaggerUi::new("/docs").url(openapi::DOCUMENT_PATH, openapi::document()))
+        .route(
+            "/swagger-ui",
+            get(|| async { axum::response::Redirect::permanent("/docs/") }),
+        )
+        .route("/", get(routes::root))
+        .nest(
+            api_version::PREFIX,
+            api.clone()
+                .layer(axum::middleware::from_fn(api_version::versioned)),
+        )
+        .merge(api.layer(axum::middleware::from_fn(api_version::deprecated_alias)))
         .layer(DefaultBodyLimit::disable())
+        .layer(axum::middleware::from_fn(error::request_id))
         .layer(CorsLayer::permissive())
         .with_state(state);
 
@@ -119,7 +432,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("listening on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
@@ -0,0 +1,150 @@
+//! Deployment descriptors for MDM systems.
+//!
+//! District IT imports our releases into their MDM instead of filling in the
+//! app forms by hand. Windows releases are described as an Intune Win32 app
+//! (the `win32LobApp` body of Microsoft Graph), macOS releases as a Jamf Pro
+//! package record. Both still need the installer itself uploaded next to
+//! them; its download link is part of the descriptor.
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::schema::Release;
+
+/// MDM systems we can describe releases for, by the name used in URLs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MdmFormat {
+    Intune,
+    Jamf,
+}
+
+impl MdmFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "intune" => Some(MdmFormat::Intune),
+            "jamf" => Some(MdmFormat::Jamf),
+            _ => None,
+        }
+    }
+
+    /// Target whose releases the format describes.
+    pub fn target(self) -> &'static str {
+        match self {
+            MdmFormat::Intune => "windows",
+            MdmFormat::Jamf => "darwin",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MdmFormat::Intune => "intune",
+            MdmFormat::Jamf => "jamf",
+        }
+    }
+}
+
+/// Last path segment of the release's download URL.
+fn file_name(release: &Release) -> &str {
+    let path = release.url.split(['?', '#']).next().unwrap_or_default();
+    path.rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .unwrap_or(&release.app_name)
+}
+
+/// Intune's name for an architecture; `None` lets it install anywhere.
+fn intune_architecture(arch: &str) -> Option<&'static str> {
+    match arch {
+        "x86_64" | "x64" | "amd64" => Some("x64"),
+        "aarch64" | "arm64" => Some("arm64"),
+        "i686" | "x86" => Some("x86"),
+        _ => None,
+    }
+}
+
+/// Describes a Windows release as an Intune Win32 app. Installers are run
+/// silently as system: MSIs through `msiexec`, anything else as an NSIS
+/// setup with `/S`. The app counts as installed once its executable under
+/// Program Files reaches the release's version.
+pub fn intune(release: &Release, publisher: &str) -> serde_json::Value {
+    let file = file_name(release);
+    let app = &release.app_name;
+    let (install, uninstall) = if file.to_ascii_lowercase().ends_with(".msi") {
+        (
+            format!("msiexec /i \"{}\" /qn /norestart", file),
+            format!("msiexec /x \"{}\" /qn /norestart", file),
+        )
+    } else {
+        (
+            format!("\"{}\" /S", file),
+            format!("\"%ProgramFiles%\\{}\\uninstall.exe\" /S", app),
+        )
+    };
+    let architectures = intune_architecture(&release.arch).unwrap_or("x86,x64,arm64");
+
+    json!({
+        "@odata.type": "#microsoft.graph.win32LobApp",
+        "displayName": app,
+        "description": format!("{} {}", app, release.version),
+        "publisher": publisher,
+        "developer": publisher,
+        "displayVersion": release.version,
+        "notes": release.notes,
+        "informationUrl": release.url,
+        "fileName": file,
+        "setupFilePath": file,
+        "installCommandLine": install,
+        "uninstallCommandLine": uninstall,
+        "applicableArchitectures": architectures,
+        "minimumSupportedWindowsRelease": "1607",
+        "installExperience": {
+            "@odata.type": "#microsoft.graph.win32LobAppInstallExperience",
+            "runAsAccount": "system",
+            "deviceRestartBehavior": "suppress",
+            "maxRunTimeInMinutes": 60
+        },
+        "returnCodes": [
+            {"@odata.type": "#microsoft.graph.win32LobAppReturnCode", "returnCode": 0, "type": "success"},
+            {"@odata.type": "#microsoft.graph.win32LobAppReturnCode", "returnCode": 1707, "type": "success"},
+            {"@odata.type": "#microsoft.graph.win32LobAppReturnCode", "returnCode": 3010, "type": "softReboot"},
+            {"@odata.type": "#microsoft.graph.win32LobAppReturnCode", "returnCode": 1641, "type": "hardReboot"},
+            {"@odata.type": "#microsoft.graph.win32LobAppReturnCode", "returnCode": 1618, "type": "retry"}
+        ],
+        "rules": [
+            {
+                "@odata.type": "#microsoft.graph.win32LobAppFileSystemRule",
+                "ruleType": "detection",
+                "path": format!("%ProgramFiles%\\{}", app),
+                "fileOrFolderName": format!("{}.exe", app),
+                "check32BitOn64System": false,
+                "operationType": "version",
+                "operator": "greaterThanOrEqual",
+                "comparisonValue": release.version
+            }
+        ]
+    })
+}
+
+/// Describes a macOS release as a Jamf Pro package record, ready to POST to
+/// `/api/v1/packages`. The download link goes in the package's info, as
+/// Jamf has no field for it.
+pub fn jamf(release: &Release) -> serde_json::Value {
+    json!({
+        "packageName": format!("{} {}", release.app_name, release.version),
+        "fileName": file_name(release),
+        "categoryId": "-1",
+        "info": format!("Download: {}", release.url),
+        "notes": release.notes,
+        "priority": 10,
+        "osRequirements": "",
+        "fillUserTemplate": false,
+        "rebootRequired": false,
+        "osInstall": false,
+        "suppressUpdates": false,
+        "suppressFromDock": false,
+        "suppressEula": false,
+        "suppressRegistration": false,
+        "sha256": release.sha256
+    })
+}
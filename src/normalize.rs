@@ -0,0 +1,427 @@
+//! Artifact normalization.
+//!
+//! With `NORMALIZE_ARTIFACTS` set, uploads are repackaged before they are
+//! stored, so the same build produces the same bytes whichever CI runner
+//! made it, and CDNs and deduplication see identical artifacts:
+//!
+//! - `.tar.gz` archives are rewritten with sorted entries, zeroed owners and
+//!   timestamps, plain `0644`/`0755` permissions, no extended attributes
+//!   (pax `xattr` records and macOS `._` AppleDouble files) and fixed gzip
+//!   settings. Other formats keep their bytes.
+//! - Files are named `{app}_{version}_{target}_{arch}.{ext}`.
+//!
+//! Normalizing changes the bytes an updater signature covers, so CI signs
+//! the normalized archive: `POST /normalize` returns it, and normalizing it
+//! again on upload leaves it unchanged.
+
+use std::io::{Read, Write};
+
+use axum::{
+    extract::Multipart,
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+
+use crate::artifacts;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::schema::NormalizeForm;
+
+const BLOCK: usize = 512;
+
+/// Largest unpacked archive that is repackaged.
+const MAX_UNPACKED_BYTES: u64 = 4 << 30;
+
+/// Multi-part extensions kept whole in canonical names; anything else keeps
+/// its last extension.
+const COMPOUND_EXTENSIONS: &[&str] = &[
+    ".app.tar.gz",
+    ".AppImage.tar.gz",
+    ".nsis.zip",
+    ".msi.zip",
+    ".tar.gz",
+    ".tar.xz",
+    ".tar.zst",
+];
+
+/// Pax records kept when rewriting; the rest are timestamps, owners and
+/// extended attributes.
+const KEPT_PAX_KEYS: &[&str] = &["path", "linkpath", "size"];
+
+fn extension(file_name: &str) -> &str {
+    let lower = file_name.to_ascii_lowercase();
+    if let Some(ext) = COMPOUND_EXTENSIONS
+        .iter()
+        .find(|ext| lower.ends_with(&ext.to_ascii_lowercase()))
+    {
+        return &file_name[file_name.len() - ext.len()..];
+    }
+    match file_name.rfind('.') {
+        Some(i) if i > 0 => &file_name[i..],
+        _ => "",
+    }
+}
+
+pub fn canonical_file_name(
+    app_name: &str,
+    version: &str,
+    target: &str,
+    arch: &str,
+    file_name: &str,
+) -> String {
+    format!(
+        "{}_{}_{}_{}{}",
+        app_name,
+        version,
+        target,
+        arch,
+        extension(file_name)
+    )
+}
+
+fn is_tar_gz(file_name: &str) -> bool {
+    let lower = file_name.to_ascii_lowercase();
+    lower.ends_with(".tar.gz") || lower.ends_with(".tgz")
+}
+
+/// Repackages `data` if its format is one that can be normalized.
+pub fn normalize(file_name: &str, data: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !is_tar_gz(file_name) {
+        return Ok(data);
+    }
+    let tar = rewrite_tar(&gunzip(&data)?)?;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder
+        .write_all(&tar)
+        .and_then(|_| encoder.finish())
+        .map_err(|e| format!("failed to compress: {}", e))
+}
+
+/// A file, directory or link unpacked from a `.tar.gz`.
+pub struct TarEntry {
+    pub path: String,
+    /// `b'0'` for files, `b'5'` for directories, `b'2'` for symlinks and
+    /// `b'1'` for hard links.
+    pub typeflag: u8,
+    pub mode: u32,
+    /// Target of a link.
+    pub link_name: String,
+    pub data: Vec<u8>,
+}
+
+/// Reads the entries of a `.tar.gz`, leaving out what normalization
+/// strips.
+pub fn unpack_tar_gz(data: &[u8]) -> Result<Vec<TarEntry>, String> {
+    read_entries(&gunzip(data)?)?
+        .into_iter()
+        .map(|entry| {
+            let link_name = match entry.pax.iter().find(|(k, _)| k == "linkpath") {
+                Some((_, l)) => l.clone(),
+                None => match entry.gnu_long.iter().find(|(t, _)| *t == b'K') {
+                    Some((_, l)) => field(l, 0, l.len()).to_vec(),
+                    None => field(&entry.header, 157, 100).to_vec(),
+                },
+            };
+            Ok(TarEntry {
+                path: String::from_utf8_lossy(&entry.path).into_owned(),
+                typeflag: match entry.header[156] {
+                    0 | b'7' => b'0',
+                    t => t,
+                },
+                mode: parse_number(&entry.header, 100, 8)? as u32,
+                link_name: String::from_utf8_lossy(&link_name).into_owned(),
+                data: entry.data,
+            })
+        })
+        .collect()
+}
+
+fn gunzip(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut tar = Vec::new();
+    GzDecoder::new(data)
+        .take(MAX_UNPACKED_BYTES + 1)
+        .read_to_end(&mut tar)
+        .map_err(|e| format!("not a gzip archive: {}", e))?;
+    if tar.len() as u64 > MAX_UNPACKED_BYTES {
+        return Err(format!("unpacks to more than {} bytes", MAX_UNPACKED_BYTES));
+    }
+    Ok(tar)
+}
+
+struct Entry {
+    path: Vec<u8>,
+    /// GNU long name and long link headers, with their data.
+    gnu_long: Vec<(u8, Vec<u8>)>,
+    pax: Vec<(String, Vec<u8>)>,
+    header: [u8; BLOCK],
+    data: Vec<u8>,
+}
+
+fn field(header: &[u8], start: usize, len: usize) -> &[u8] {
+    let f = &header[start..start + len];
+    let end = f.iter().position(|&b| b == 0).unwrap_or(len);
+    &f[..end]
+}
+
+fn parse_number(header: &[u8], start: usize, len: usize) -> Result<u64, String> {
+    let f = &header[start..start + len];
+    // GNU base-256, for sizes that don't fit in octal.
+    if f[0] & 0x80 != 0 {
+        return Ok(f[1..]
+            .iter()
+            .fold(u64::from(f[0] & 0x7f), |n, &b| (n << 8) | u64::from(b)));
+    }
+    let text = std::str::from_utf8(field(header, start, len))
+        .map_err(|_| "malformed tar header".to_string())?
+        .trim();
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| "malformed tar header".to_string())
+}
+
+fn parse_pax(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut records = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or("malformed pax header")?;
+        let len: usize = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|l| l.parse().ok())
+            .filter(|&l| l > space + 1 && l <= rest.len())
+            .ok_or("malformed pax header")?;
+        let record = &rest[space + 1..len - 1];
+        let eq = record
+            .iter()
+            .position(|&b| b == b'=')
+            .ok_or("malformed pax header")?;
+        let key = String::from_utf8_lossy(&record[..eq]).into_owned();
+        records.push((key, record[eq + 1..].to_vec()));
+        rest = &rest[len..];
+    }
+    Ok(records)
+}
+
+fn pax_data(records: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (key, value) in records {
+        let body = key.len() + value.len() + 3;
+        // The length prefix counts its own digits.
+        let mut len = body + 1;
+        while len != body + len.to_string().len() {
+            len = body + len.to_string().len();
+        }
+        data.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+        data.extend_from_slice(value);
+        data.push(b'\n');
+    }
+    data
+}
+
+fn padded_len(size: usize) -> usize {
+    size.div_ceil(BLOCK) * BLOCK
+}
+
+fn read_entries(tar: &[u8]) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    let mut pax = Vec::new();
+    let mut gnu_long = Vec::new();
+    let mut offset = 0;
+    while offset + BLOCK <= tar.len() {
+        let header: [u8; BLOCK] = tar[offset..offset + BLOCK].try_into().expect("one block");
+        offset += BLOCK;
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let typeflag = header[156];
+        let mut size = parse_number(&header, 124, 12)? as usize;
+        if let Some((_, s)) = pax.iter().find(|(k, _): &&(String, Vec<u8>)| k == "size") {
+            size = String::from_utf8_lossy(s)
+                .parse()
+                .map_err(|_| "malformed pax size".to_string())?;
+        }
+        let end = offset
+            .checked_add(size)
+            .filter(|&e| e <= tar.len())
+            .ok_or("truncated tar archive")?;
+        let data = tar[offset..end].to_vec();
+        offset += padded_len(size);
+
+        match typeflag {
+            b'g' => {}
+            b'x' => pax = parse_pax(&data)?,
+            b'L' | b'K' => gnu_long.push((typeflag, data)),
+            _ => {
+                let long_name = gnu_long
+                    .iter()
+                    .find(|(t, _)| *t == b'L')
+                    .map(|(_, d)| field(d, 0, d.len()).to_vec());
+                let path = match pax.iter().find(|(k, _)| k == "path") {
+                    Some((_, p)) => p.clone(),
+                    None => long_name.unwrap_or_else(|| {
+                        let (prefix, name) = (field(&header, 345, 155), field(&header, 0, 100));
+                        if prefix.is_empty() {
+                            name.to_vec()
+                        } else {
+                            [prefix, b"/", name].concat()
+                        }
+                    }),
+                };
+                let base = path.rsplit(|&b| b == b'/').find(|s| !s.is_empty());
+                if !base.is_some_and(|b| b.starts_with(b"._")) {
+                    entries.push(Entry {
+                        path,
+                        gnu_long: std::mem::take(&mut gnu_long),
+                        pax: std::mem::take(&mut pax)
+                            .into_iter()
+                            .filter(|(k, _)| KEPT_PAX_KEYS.contains(&k.as_str()))
+                            .collect(),
+                        header,
+                        data,
+                    });
+                }
+                pax.clear();
+                gnu_long.clear();
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn rewrite_tar(tar: &[u8]) -> Result<Vec<u8>, String> {
+    let mut entries = read_entries(tar)?;
+    // Hard links refer to earlier entries, so they go last.
+    entries.sort_by(|a, b| (a.header[156] == b'1', &a.path).cmp(&(b.header[156] == b'1', &b.path)));
+
+    let mut out = Vec::with_capacity(tar.len());
+    for mut entry in entries {
+        if !entry.pax.is_empty() {
+            let data = pax_data(&entry.pax);
+            let mut header = [0u8; BLOCK];
+            header[..14].copy_from_slice(b"././@PaxHeader");
+            header[156] = b'x';
+            header[257..263].copy_from_slice(b"ustar\0");
+            header[263..265].copy_from_slice(b"00");
+            write_octal(&mut header, 124, 12, data.len() as u64);
+            push_entry(&mut out, &mut header, 0o644, &data);
+        }
+        for (typeflag, data) in &entry.gnu_long {
+            let mut header = [0u8; BLOCK];
+            header[..13].copy_from_slice(b"././@LongLink");
+            header[156] = *typeflag;
+            header[257..265].copy_from_slice(b"ustar  \0");
+            write_octal(&mut header, 124, 12, data.len() as u64);
+            push_entry(&mut out, &mut header, 0o644, data);
+        }
+        let mode = match entry.header[156] {
+            b'5' => 0o755,
+            b'2' => 0o777,
+            _ if parse_number(&entry.header, 100, 8)? & 0o111 != 0 => 0o755,
+            _ => 0o644,
+        };
+        push_entry(&mut out, &mut entry.header, mode, &entry.data);
+    }
+    out.resize(out.len() + 2 * BLOCK, 0);
+    Ok(out)
+}
+
+fn write_octal(header: &mut [u8], start: usize, len: usize, value: u64) {
+    let text = format!("{:0width$o}\0", value, width = len - 1);
+    header[start..start + len].copy_from_slice(text.as_bytes());
+}
+
+fn push_entry(out: &mut Vec<u8>, header: &mut [u8; BLOCK], mode: u64, data: &[u8]) {
+    write_octal(header, 100, 8, mode);
+    write_octal(header, 108, 8, 0);
+    write_octal(header, 116, 8, 0);
+    write_octal(header, 136, 12, 0);
+    header[265..329].fill(0);
+    header[148..156].fill(b' ');
+    let sum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    out.extend_from_slice(header);
+    out.extend_from_slice(data);
+    out.resize(out.len() + padded_len(data.len()) - data.len(), 0);
+}
+
+/// Normalize an artifact
+///
+/// Returns the file as an upload would store it with `NORMALIZE_ARTIFACTS`
+/// set, for CI to sign before uploading.
+#[utoipa::path(
+    post,
+    path = "/normalize",
+    request_body(content = NormalizeForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "The normalized file, named in Content-Disposition", content_type = "application/octet-stream"),
+        (status = 400, description = "Missing field, or an archive that can't be read", body = ErrorBody)
+    )
+)]
+pub async fn normalize_artifact(mut multipart: Multipart) -> AppResult<Response> {
+    let (mut app_name, mut version, mut target, mut arch) =
+        (String::new(), String::new(), String::new(), String::new());
+    let mut file_name = String::new();
+    let mut file_data = Vec::new();
+    while let Some(res) = multipart.next_field().await.transpose() {
+        let field =
+            res.map_err(|e| AppError::bad_request(format!("Malformed multipart body: {}", e)))?;
+        match field.name().unwrap_or_default() {
+            "app_name" => app_name = field.text().await.unwrap_or_default(),
+            "version" => version = field.text().await.unwrap_or_default(),
+            "target" => target = field.text().await.unwrap_or_default(),
+            "arch" => arch = field.text().await.unwrap_or_default(),
+            "file" => {
+                file_name = field.file_name().unwrap_or("installer").to_string();
+                file_data = field
+                    .bytes()
+                    .await
+                    .map_err(|e| AppError::bad_request(format!("Failed to read file: {}", e)))?
+                    .to_vec();
+            }
+            _ => (),
+        }
+    }
+    if file_data.is_empty() {
+        return Err(AppError::bad_request("No file uploaded or file is empty"));
+    }
+    for (name, value) in [
+        ("app_name", &app_name),
+        ("version", &version),
+        ("target", &target),
+        ("arch", &arch),
+    ] {
+        if value.trim().is_empty() {
+            return Err(AppError::bad_request(format!("{} is required", name)));
+        }
+    }
+
+    let data = normalize(&file_name, file_data)
+        .map_err(|e| AppError::bad_request(format!("Cannot normalize {}: {}", file_name, e)))?;
+    let name = canonical_file_name(
+        app_name.trim(),
+        version.trim(),
+        target.trim(),
+        arch.trim(),
+        &file_name,
+    );
+    let disposition = HeaderValue::try_from(format!("attachment; filename=\"{}\"", name))
+        .map_err(|_| AppError::bad_request("Names must be plain text"))?;
+    let sha256 = HeaderValue::try_from(artifacts::sha256_hex(&data)).expect("hex is valid");
+    Ok((
+        StatusCode::OK,
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::ETAG, sha256),
+        ],
+        data,
+    )
+        .into_response())
+}
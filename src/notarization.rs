@@ -0,0 +1,619 @@
+//! Apple notarization of macOS artifacts.
+//!
+//! With an App Store Connect API key configured (`NOTARY_KEY_ID`,
+//! `NOTARY_ISSUER_ID` and `NOTARY_PRIVATE_KEY_PATH`), darwin uploads are
+//! staged like uploads of gated apps, with a `notarization` check the server
+//! runs itself: the artifact is submitted to Apple's notary service and
+//! polled every `NOTARY_POLL_INTERVAL_SECS`. Once Apple accepts it, and the
+//! app's own checks passed, the staged release is published with the
+//! submission ID as its `notarization_id`. A rejection fails it, with Apple's
+//! log as the check's details, as does no answer within
+//! `NOTARY_TIMEOUT_MINS` or a restart while the submission is in progress.
+//!
+//! Apple takes `.zip`, `.dmg` and `.pkg` files; `.app.tar.gz` updater
+//! archives are submitted as a zip of the same files. With
+//! `NOTARY_STAPLE_COMMAND` set, accepted `.dmg` and `.pkg` files get the
+//! ticket stapled and the stapled file is published instead. Stapling
+//! changes the file, so no updater signature would cover it: such uploads
+//! are refused with a signature. Archives can't be stapled; Gatekeeper
+//! looks their ticket up online.
+//!
+//! Variant uploads aren't staged, so a variant B that would need notarizing
+//! is refused.
+
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::http::{Method, Request, header};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use chrono::Utc;
+use http_body_util::Full;
+use ring::rand::SystemRandom;
+use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair};
+use serde_json::{Value, json};
+
+use crate::artifacts;
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::gates;
+use crate::http_client::HttpClient;
+use crate::normalize;
+use crate::routes::{delete_orphaned, release_tag, store_artifact};
+use crate::s3;
+use crate::schema::{AppState, Artifact, CheckConclusion, CheckGate, StagedRelease};
+
+/// Name of the check notarization adds to staged releases.
+pub const CHECK_NAME: &str = "notarization";
+
+/// Submissions can be large, so requests get longer than other calls.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+
+const MAX_API_RESPONSE_BYTES: usize = 64 * 1024;
+
+/// Region of the bucket Apple hands out for uploads.
+const S3_REGION: &str = "us-west-2";
+
+pub struct Notary {
+    key_id: String,
+    issuer_id: String,
+    key: EcdsaKeyPair,
+    api_url: String,
+    upload_url: String,
+    poll_interval: Duration,
+    timeout_mins: i64,
+    staple_command: Option<Vec<String>>,
+    client: HttpClient,
+}
+
+/// What's sent to Apple for one upload.
+pub struct Submission {
+    file_name: String,
+    data: Vec<u8>,
+    /// Whether `data` is the upload itself and gets stapled.
+    staple: bool,
+}
+
+struct UploadCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: String,
+    bucket: String,
+    object: String,
+}
+
+/// Why notarization failed, for the check's result.
+struct Failure {
+    message: String,
+    log_url: Option<String>,
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Self {
+            message,
+            log_url: None,
+        }
+    }
+}
+
+impl Notary {
+    pub fn from_config(config: &Config) -> std::io::Result<Option<Self>> {
+        let (Some(key_id), Some(issuer_id), Some(path)) = (
+            &config.notary_key_id,
+            &config.notary_issuer_id,
+            &config.notary_private_key_path,
+        ) else {
+            return Ok(None);
+        };
+        let pem = std::fs::read_to_string(path)?;
+        let der = STANDARD
+            .decode(
+                pem.lines()
+                    .filter(|l| !l.starts_with("-----"))
+                    .collect::<String>(),
+            )
+            .map_err(|e| std::io::Error::other(format!("{} is not a PEM file: {}", path, e)))?;
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &der, &SystemRandom::new())
+                .map_err(|e| {
+                    std::io::Error::other(format!(
+                        "{} is not an App Store Connect API key: {}",
+                        path, e
+                    ))
+                })?;
+        Ok(Some(Self {
+            key_id: key_id.clone(),
+            issuer_id: issuer_id.clone(),
+            key,
+            api_url: config.notary_api_url.trim_end_matches('/').to_string(),
+            upload_url: config.notary_upload_url.trim_end_matches('/').to_string(),
+            poll_interval: config.notary_poll_interval,
+            timeout_mins: config.notary_timeout_mins,
+            staple_command: config
+                .notary_staple_command
+                .as_ref()
+                .map(|c| c.split_whitespace().map(String::from).collect::<Vec<_>>())
+                .filter(|args| !args.is_empty()),
+            client: HttpClient::new(REQUEST_TIMEOUT)?,
+        }))
+    }
+
+    /// The check staged releases get while notarization runs.
+    pub fn check(&self, app_name: &str) -> CheckGate {
+        CheckGate {
+            app_name: app_name.to_string(),
+            name: CHECK_NAME.to_string(),
+            trigger_url: None,
+            timeout_mins: self.timeout_mins,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// Short-lived App Store Connect API token.
+    fn token(&self) -> Result<String, String> {
+        let now = Utc::now().timestamp();
+        let header = json!({"alg": "ES256", "kid": self.key_id, "typ": "JWT"});
+        let claims = json!({
+            "iss": self.issuer_id,
+            "iat": now,
+            "exp": now + 15 * 60,
+            "aud": "appstoreconnect-v1",
+        });
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = self
+            .key
+            .sign(&SystemRandom::new(), signing_input.as_bytes())
+            .map_err(|_| "failed to sign the API token".to_string())?;
+        Ok(format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.as_ref())
+        ))
+    }
+
+    async fn api(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value, String> {
+        let url = format!("{}{}", self.api_url, path);
+        let mut request = Request::builder()
+            .method(method)
+            .uri(&url)
+            .header(header::AUTHORIZATION, format!("Bearer {}", self.token()?));
+        let body = match body {
+            Some(body) => {
+                request = request.header(header::CONTENT_TYPE, "application/json");
+                body.to_string().into_bytes()
+            }
+            None => Vec::new(),
+        };
+        let request = request
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| format!("Invalid request to {}: {}", url, e))?;
+        let response = self.client.send(request, MAX_API_RESPONSE_BYTES).await?;
+        if !response.status.is_success() {
+            return Err(format!(
+                "{} answered {}: {}",
+                url,
+                response.status,
+                response.body.chars().take(300).collect::<String>()
+            ));
+        }
+        serde_json::from_str(&response.body)
+            .map_err(|e| format!("Unreadable answer from {}: {}", url, e))
+    }
+
+    /// PUTs the submission to the bucket Apple named, signed with the
+    /// temporary AWS credentials it handed out.
+    async fn upload(&self, credentials: UploadCredentials, data: Vec<u8>) -> Result<(), String> {
+        let bucket = s3::Bucket {
+            url: self.upload_url.replace("{bucket}", &credentials.bucket),
+            region: S3_REGION.to_string(),
+            credentials: s3::Credentials {
+                access_key_id: credentials.access_key_id,
+                secret_access_key: credentials.secret_access_key,
+                session_token: Some(credentials.session_token),
+            },
+        };
+        bucket
+            .put(&self.client, &credentials.object, &[], data)
+            .await
+            .map(|_| ())
+    }
+
+    async fn staple(
+        &self,
+        args: &[String],
+        file_name: &str,
+        data: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let dir = std::env::temp_dir().join(format!(
+            "updater-staple-{}",
+            crate::auth::random_token().map_err(|e| e.to_string())?
+        ));
+        tokio::fs::create_dir(&dir)
+            .await
+            .map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+        let path = dir.join(file_name.rsplit('/').next().unwrap_or("artifact"));
+
+        let stapled = async {
+            tokio::fs::write(&path, data)
+                .await
+                .map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+            let args: Vec<String> = args
+                .iter()
+                .map(|a| a.replace("{path}", &path.to_string_lossy()))
+                .collect();
+            let run = tokio::process::Command::new(&args[0])
+                .args(&args[1..])
+                .kill_on_drop(true)
+                .output();
+            let finished = tokio::time::timeout(REQUEST_TIMEOUT, run)
+                .await
+                .map_err(|_| format!("{} timed out", args[0]))?
+                .map_err(|e| format!("failed to run {}: {}", args[0], e))?;
+            if !finished.status.success() {
+                return Err(format!(
+                    "stapling failed: {} exited with {}",
+                    args[0], finished.status
+                ));
+            }
+            tokio::fs::read(&path)
+                .await
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))
+        }
+        .await;
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        stapled
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Repacks a `.tar.gz` as an uncompressed zip, keeping permissions and
+/// symlinks, which a bundle's code signature depends on.
+fn zip_from_tar_gz(data: &[u8]) -> Result<Vec<u8>, String> {
+    // 1980-01-01, the earliest date a zip can hold.
+    const DOS_DATE: u16 = 0x21;
+    let too_large = || "too large to submit as a zip".to_string();
+    let entries = normalize::unpack_tar_gz(data)?;
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    let mut count: u16 = 0;
+    for entry in &entries {
+        let (mode, contents): (u32, &[u8]) = match entry.typeflag {
+            b'0' => (0o100000 | (entry.mode & 0o777), &entry.data),
+            b'5' => (0o040755, &[]),
+            b'2' => (0o120777, entry.link_name.as_bytes()),
+            b'1' => {
+                let target = entries
+                    .iter()
+                    .find(|e| e.typeflag == b'0' && e.path == entry.link_name)
+                    .ok_or_else(|| format!("{} links to a missing file", entry.path))?;
+                (0o100000 | (target.mode & 0o777), &target.data)
+            }
+            // Devices and fifos have no place in an app bundle.
+            _ => continue,
+        };
+        let mut name = entry.path.trim_start_matches("./").to_string();
+        if name.is_empty() {
+            continue;
+        }
+        if entry.typeflag == b'5' && !name.ends_with('/') {
+            name.push('/');
+        }
+        let mut crc = flate2::Crc::new();
+        crc.update(contents);
+        let size = u32::try_from(contents.len()).map_err(|_| too_large())?;
+        let offset = u32::try_from(out.len()).map_err(|_| too_large())?;
+        let name_len = u16::try_from(name.len()).map_err(|_| format!("{} is too long", name))?;
+        count = count.checked_add(1).ok_or_else(too_large)?;
+
+        push_u32(&mut out, 0x04034b50);
+        push_u16(&mut out, 20);
+        push_u16(&mut out, 0x0800);
+        push_u16(&mut out, 0);
+        push_u16(&mut out, 0);
+        push_u16(&mut out, DOS_DATE);
+        push_u32(&mut out, crc.sum());
+        push_u32(&mut out, size);
+        push_u32(&mut out, size);
+        push_u16(&mut out, name_len);
+        push_u16(&mut out, 0);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(contents);
+
+        push_u32(&mut central, 0x02014b50);
+        // Made on Unix, so the external attributes hold the mode.
+        push_u16(&mut central, (3 << 8) | 20);
+        push_u16(&mut central, 20);
+        push_u16(&mut central, 0x0800);
+        push_u16(&mut central, 0);
+        push_u16(&mut central, 0);
+        push_u16(&mut central, DOS_DATE);
+        push_u32(&mut central, crc.sum());
+        push_u32(&mut central, size);
+        push_u32(&mut central, size);
+        push_u16(&mut central, name_len);
+        push_u16(&mut central, 0);
+        push_u16(&mut central, 0);
+        push_u16(&mut central, 0);
+        push_u16(&mut central, 0);
+        push_u32(
+            &mut central,
+            (mode << 16) | if entry.typeflag == b'5' { 0x10 } else { 0 },
+        );
+        push_u32(&mut central, offset);
+        central.extend_from_slice(name.as_bytes());
+    }
+    let central_offset = u32::try_from(out.len()).map_err(|_| too_large())?;
+    let central_size = u32::try_from(central.len()).map_err(|_| too_large())?;
+    out.extend_from_slice(&central);
+    push_u32(&mut out, 0x06054b50);
+    push_u16(&mut out, 0);
+    push_u16(&mut out, 0);
+    push_u16(&mut out, count);
+    push_u16(&mut out, count);
+    push_u32(&mut out, central_size);
+    push_u32(&mut out, central_offset);
+    push_u16(&mut out, 0);
+    Ok(out)
+}
+
+/// What to submit for a darwin upload, if notarization is configured.
+/// Formats Apple doesn't take are refused before anything is stored, as are
+/// updater signatures of files that will be stapled.
+pub fn prepare(
+    state: &AppState,
+    target: &str,
+    file_name: &str,
+    data: &[u8],
+    signature: &str,
+) -> AppResult<Option<Submission>> {
+    let Some(notary) = &state.notary else {
+        return Ok(None);
+    };
+    if target != "darwin" {
+        return Ok(None);
+    }
+    let lower = file_name.to_ascii_lowercase();
+    let submission = if let Some(stem) = lower
+        .strip_suffix(".tar.gz")
+        .or_else(|| lower.strip_suffix(".tgz"))
+    {
+        Submission {
+            file_name: format!("{}.zip", &file_name[..stem.len()]),
+            data: zip_from_tar_gz(data).map_err(|e| {
+                AppError::bad_request(format!("Cannot notarize {}: {}", file_name, e))
+            })?,
+            staple: false,
+        }
+    } else if lower.ends_with(".zip") {
+        Submission {
+            file_name: file_name.to_string(),
+            data: data.to_vec(),
+            staple: false,
+        }
+    } else if lower.ends_with(".dmg") || lower.ends_with(".pkg") {
+        if notary.staple_command.is_some() && !signature.trim().is_empty() {
+            return Err(AppError::bad_request(format!(
+                "{} gets stapled once notarized, which breaks its updater signature; upload it unsigned, or updates as .app.tar.gz",
+                file_name
+            )));
+        }
+        Submission {
+            file_name: file_name.to_string(),
+            data: data.to_vec(),
+            staple: notary.staple_command.is_some(),
+        }
+    } else {
+        return Err(AppError::bad_request(format!(
+            "{} can't be notarized; macOS artifacts must be .app.tar.gz, .zip, .dmg or .pkg",
+            file_name
+        )));
+    };
+    Ok(Some(submission))
+}
+
+/// Notarizes a staged release in the background and records the result as
+/// its notarization check.
+pub fn submit(state: &AppState, staged: StagedRelease, submission: Submission) {
+    let Some(notary) = state.notary.clone() else {
+        return;
+    };
+    let state = state.clone();
+    tokio::spawn(async move {
+        let (conclusion, message, log_url) =
+            match notarize(&state, &notary, &staged, submission).await {
+                Ok(None) => return,
+                Ok(Some(id)) => (
+                    CheckConclusion::Success,
+                    format!("Accepted by Apple as submission {}", id),
+                    None,
+                ),
+                Err(failure) => {
+                    println!(
+                        "Notarization of staged release {} failed: {}",
+                        staged.id, failure.message
+                    );
+                    (CheckConclusion::Failure, failure.message, failure.log_url)
+                }
+            };
+        if let Err(e) = gates::complete_check(
+            &state,
+            staged.id,
+            CHECK_NAME,
+            conclusion,
+            &message,
+            log_url.as_deref(),
+        )
+        .await
+        {
+            println!(
+                "Failed to record notarization of staged release {}: {}",
+                staged.id, e
+            );
+        }
+    });
+}
+
+/// Returns the accepted submission's ID, or `None` if the staged release
+/// stopped waiting for it.
+async fn notarize(
+    state: &AppState,
+    notary: &Notary,
+    staged: &StagedRelease,
+    submission: Submission,
+) -> Result<Option<String>, Failure> {
+    let created = notary
+        .api(
+            Method::POST,
+            "/submissions",
+            Some(json!({
+                "submissionName": submission.file_name,
+                "sha256": artifacts::sha256_hex(&submission.data),
+            })),
+        )
+        .await?;
+    let id = created["data"]["id"]
+        .as_str()
+        .ok_or_else(|| "Apple's answer has no submission ID".to_string())?
+        .to_string();
+    let attribute = |name: &str| {
+        created["data"]["attributes"][name]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| format!("Apple's answer has no {}", name))
+    };
+    let credentials = UploadCredentials {
+        access_key_id: attribute("awsAccessKeyId")?,
+        secret_access_key: attribute("awsSecretAccessKey")?,
+        session_token: attribute("awsSessionToken")?,
+        bucket: attribute("bucket")?,
+        object: attribute("object")?,
+    };
+    let (upload, original) = if submission.staple {
+        (submission.data.clone(), Some(submission.data))
+    } else {
+        (submission.data, None)
+    };
+    notary.upload(credentials, upload).await?;
+    println!(
+        "Submitted staged release {} for notarization as {}",
+        staged.id, id
+    );
+
+    loop {
+        tokio::time::sleep(notary.poll_interval).await;
+        match gates::is_pending(&state.pool, staged.id, CHECK_NAME).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(None),
+            Err(e) => {
+                println!("Failed to look up staged release {}: {}", staged.id, e);
+                continue;
+            }
+        }
+        let status = match notary
+            .api(Method::GET, &format!("/submissions/{}", id), None)
+            .await
+        {
+            Ok(answer) => answer["data"]["attributes"]["status"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            Err(e) => {
+                println!("Failed to poll notarization {}: {}", id, e);
+                continue;
+            }
+        };
+        match status.as_str() {
+            "Accepted" => break,
+            "In Progress" | "" => continue,
+            other => {
+                let log_url = notary
+                    .api(Method::GET, &format!("/submissions/{}/logs", id), None)
+                    .await
+                    .ok()
+                    .and_then(|answer| {
+                        answer["data"]["attributes"]["developerLogUrl"]
+                            .as_str()
+                            .map(String::from)
+                    });
+                return Err(Failure {
+                    message: format!("Apple answered {} for submission {}", other, id),
+                    log_url,
+                });
+            }
+        }
+    }
+
+    if let (Some(args), Some(original)) = (&notary.staple_command, original) {
+        let stapled = notary
+            .staple(args, &submission.file_name, &original)
+            .await?;
+        replace_artifact(state, staged, &submission.file_name, stapled).await?;
+    }
+    sqlx::query("UPDATE staged_releases SET notarization_id = ? WHERE id = ?")
+        .bind(&id)
+        .bind(staged.id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| format!("Failed to record submission {}: {}", id, e))?;
+    Ok(Some(id))
+}
+
+/// Swaps a staged release's artifact for its stapled copy.
+async fn replace_artifact(
+    state: &AppState,
+    staged: &StagedRelease,
+    file_name: &str,
+    data: Vec<u8>,
+) -> Result<(), String> {
+    let sha256 = artifacts::sha256_hex(&data);
+    // Stored under `{app}-stapled-v{version}`, as the unstapled file
+    // already has its name in the version's release.
+    let (sha256, size, url, github_asset_id) = store_artifact(
+        state,
+        &staged.app_name,
+        sha256,
+        &release_tag(&format!("{}-stapled", staged.app_name), &staged.version),
+        "",
+        file_name,
+        data,
+    )
+    .await
+    .map_err(|e| format!("Failed to store the stapled file: {}", e))?;
+    let replaced: Result<Option<Artifact>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        artifacts::retain(
+            &mut tx,
+            &staged.app_name,
+            &sha256,
+            &url,
+            size,
+            github_asset_id,
+        )
+        .await?;
+        sqlx::query("UPDATE staged_releases SET url = ?, sha256 = ? WHERE id = ?")
+            .bind(&url)
+            .bind(&sha256)
+            .bind(staged.id)
+            .execute(&mut *tx)
+            .await?;
+        let orphaned = artifacts::release(&mut tx, &staged.app_name, &staged.sha256).await?;
+        tx.commit().await?;
+        Ok(orphaned)
+    }
+    .await;
+    let orphaned = replaced.map_err(|e| format!("Failed to save the stapled file: {}", e))?;
+    delete_orphaned(state, orphaned).await;
+    Ok(())
+}
@@ -0,0 +1,428 @@
+//! Chat and email notifications for release activity.
+//!
+//! When `SLACK_WEBHOOK_URL` or `DISCORD_WEBHOOK_URL` is set, every release
+//! published or yanked on this replica is announced there, as are releases
+//! promotion policies promote or hold (manual promotions are not). With
+//! `SMTP_URL` set, email subscribers of the app and channel also get a message for
+//! every version published on or promoted into it, manually or not. Each
+//! platform is uploaded and promoted separately, so publish and manual
+//! promotion events for the same app, version and channel are collected for
+//! `NOTIFY_DEBOUNCE_SECS` and announced together with one download link per
+//! platform.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use serde_json::json;
+use sqlx::{Pool, Sqlite};
+use tokio::sync::broadcast;
+
+use crate::api_version;
+use crate::config::Config;
+use crate::events::{EventBus, ReleaseEvent, ReleaseEventKind};
+use crate::http_client::HttpClient;
+use crate::schema::Release;
+use crate::smtp::{self, Email, Mailer};
+
+/// Notes longer than this are cut in announcements.
+const NOTES_EXCERPT_CHARS: usize = 300;
+
+const ATTEMPTS: u32 = 3;
+
+/// One announcement: a version and every platform build it covers.
+struct Announcement {
+    kind: ReleaseEventKind,
+    releases: Vec<Release>,
+    /// Why a promotion policy acted; shown instead of the notes.
+    reason: Option<String>,
+}
+
+/// What collected builds are announced as: kind, app, version and channel.
+type PendingKey = (ReleaseEventKind, String, String, String);
+
+struct EmailSettings {
+    mailer: Mailer,
+    from: String,
+    public_url: String,
+}
+
+pub struct Notifier {
+    pool: Pool<Sqlite>,
+    client: HttpClient,
+    slack_url: Option<String>,
+    discord_url: Option<String>,
+    email: Option<EmailSettings>,
+    debounce: Duration,
+    pending: Mutex<HashMap<PendingKey, Vec<Release>>>,
+}
+
+impl Notifier {
+    /// Returns `None` when neither chat webhooks nor SMTP are configured.
+    pub fn from_config(pool: Pool<Sqlite>, config: &Config) -> std::io::Result<Option<Self>> {
+        let email = match &config.smtp_url {
+            Some(url) => Some(EmailSettings {
+                mailer: Mailer::from_url(url)?,
+                from: config.smtp_from.clone(),
+                public_url: config.public_url.clone(),
+            }),
+            None => None,
+        };
+        if config.slack_webhook_url.is_none()
+            && config.discord_webhook_url.is_none()
+            && email.is_none()
+        {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            pool,
+            client: HttpClient::new(config.webhook_timeout)?,
+            slack_url: config.slack_webhook_url.clone(),
+            discord_url: config.discord_webhook_url.clone(),
+            email,
+            debounce: config.notify_debounce,
+            pending: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    pub fn spawn(self: &Arc<Self>, events: &EventBus) {
+        let mut rx = events.subscribe_own();
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => notifier.handle(event),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        println!("Chat notifier fell behind, {} events skipped", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+
+    fn handle(self: &Arc<Self>, event: ReleaseEvent) {
+        match event.kind {
+            ReleaseEventKind::Published => self.collect(event.kind, event.release),
+            ReleaseEventKind::Promoted if event.reason.is_none() => {
+                self.collect(event.kind, event.release)
+            }
+            ReleaseEventKind::Promoted | ReleaseEventKind::Yanked | ReleaseEventKind::Held => {
+                self.announce_now(event)
+            }
+        }
+    }
+
+    /// Waits for the other platforms of the release before announcing it.
+    fn collect(self: &Arc<Self>, kind: ReleaseEventKind, release: Release) {
+        let key = (
+            kind,
+            release.app_name.clone(),
+            release.version.clone(),
+            release.channel.clone(),
+        );
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(builds) = pending.get_mut(&key) {
+            builds.push(release);
+            return;
+        }
+        pending.insert(key.clone(), vec![release]);
+
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(notifier.debounce).await;
+            let releases = notifier.pending.lock().unwrap().remove(&key);
+            if let Some(releases) = releases {
+                notifier
+                    .send(Announcement {
+                        kind,
+                        releases,
+                        reason: None,
+                    })
+                    .await;
+            }
+        });
+    }
+
+    fn announce_now(self: &Arc<Self>, event: ReleaseEvent) {
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            notifier
+                .send(Announcement {
+                    kind: event.kind,
+                    releases: vec![event.release],
+                    reason: event.reason,
+                })
+                .await;
+        });
+    }
+
+    async fn send(&self, announcement: Announcement) {
+        let manual_promotion =
+            announcement.kind == ReleaseEventKind::Promoted && announcement.reason.is_none();
+        if !manual_promotion {
+            if let Some(url) = &self.slack_url {
+                self.post("Slack", url, slack_message(&announcement)).await;
+            }
+            if let Some(url) = &self.discord_url {
+                self.post("Discord", url, discord_message(&announcement))
+                    .await;
+            }
+        }
+        if let Some(email) = &self.email
+            && matches!(
+                announcement.kind,
+                ReleaseEventKind::Published | ReleaseEventKind::Promoted
+            )
+        {
+            self.email_subscribers(email, &announcement).await;
+        }
+    }
+
+    async fn email_subscribers(&self, settings: &EmailSettings, announcement: &Announcement) {
+        let first = &announcement.releases[0];
+        let subscribers: Vec<(String, String)> = match sqlx::query_as(
+            "SELECT email, token FROM email_subscriptions WHERE app_name = ? AND channel = ?",
+        )
+        .bind(&first.app_name)
+        .bind(&first.channel)
+        .fetch_all(&self.pool)
+        .await
+        {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Failed to load email subscribers: {}", e);
+                return;
+            }
+        };
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let emails: Vec<Email> = subscribers
+            .iter()
+            .map(|(to, token)| Email {
+                to: to.clone(),
+                data: release_email(settings, announcement, to, token),
+            })
+            .collect();
+        let failures = settings.mailer.send_all(&settings.from, &emails).await;
+        for (to, e) in &failures {
+            println!("Failed to email {}: {}", to, e);
+        }
+        println!(
+            "Emailed {} of {} subscribers about {} {}",
+            emails.len() - failures.len(),
+            emails.len(),
+            first.app_name,
+            first.version
+        );
+    }
+
+    async fn post(&self, service: &str, url: &str, body: serde_json::Value) {
+        let body = body.to_string().into_bytes();
+        for attempt in 1..=ATTEMPTS {
+            let error = match self.client.post_json(url, &[], body.clone()).await {
+                Ok(r) if r.status.is_success() => return,
+                Ok(r) => format!("{} responded with {}: {}", service, r.status, r.body),
+                Err(e) => e,
+            };
+            println!(
+                "{} notification attempt {} failed: {}",
+                service, attempt, error
+            );
+            if attempt < ATTEMPTS {
+                tokio::time::sleep(Duration::from_secs(2u64.pow(attempt))).await;
+            }
+        }
+    }
+}
+
+fn excerpt(notes: &str) -> String {
+    let notes = notes.trim();
+    match notes.char_indices().nth(NOTES_EXCERPT_CHARS) {
+        Some((end, _)) => format!("{}…", notes[..end].trim_end()),
+        None => notes.to_string(),
+    }
+}
+
+/// The policy's reason when it acted, otherwise the release notes.
+fn details(announcement: &Announcement) -> String {
+    match &announcement.reason {
+        Some(reason) => reason.clone(),
+        None => excerpt(&announcement.releases[0].notes),
+    }
+}
+
+fn headline(announcement: &Announcement) -> (String, &Release) {
+    let first = &announcement.releases[0];
+    let verb = match announcement.kind {
+        ReleaseEventKind::Published => "published on",
+        ReleaseEventKind::Yanked => "yanked on",
+        ReleaseEventKind::Promoted => "promoted to",
+        ReleaseEventKind::Held => "held on",
+    };
+    (
+        format!(
+            "{} {} {} {}",
+            first.app_name, first.version, verb, first.channel
+        ),
+        first,
+    )
+}
+
+/// Slack treats `&`, `<` and `>` as control characters in mrkdwn.
+pub fn slack_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn slack_message(announcement: &Announcement) -> serde_json::Value {
+    let (title, _) = headline(announcement);
+    let mut text = format!("*{}*", slack_escape(&title));
+    let notes = details(announcement);
+    if !notes.is_empty() {
+        for line in notes.lines() {
+            text.push_str(&format!("\n> {}", slack_escape(line)));
+        }
+    }
+    for r in &announcement.releases {
+        match announcement.kind {
+            ReleaseEventKind::Published | ReleaseEventKind::Promoted => {
+                text.push_str(&format!("\n• <{}|{}/{}>", r.url, r.target, r.arch))
+            }
+            ReleaseEventKind::Yanked => {
+                text.push_str(&format!("\n• {}/{} is no longer offered", r.target, r.arch))
+            }
+            ReleaseEventKind::Held => text.push_str(&format!(
+                "\n• {}/{} stays on {} until promoted by hand",
+                r.target, r.arch, r.channel
+            )),
+        }
+    }
+
+    json!({
+        "text": title,
+        "blocks": [
+            { "type": "section", "text": { "type": "mrkdwn", "text": text } }
+        ]
+    })
+}
+
+fn discord_message(announcement: &Announcement) -> serde_json::Value {
+    let (title, first) = headline(announcement);
+    let (color, platforms_label, platforms) = match announcement.kind {
+        ReleaseEventKind::Yanked | ReleaseEventKind::Held => (
+            if announcement.kind == ReleaseEventKind::Held {
+                0xe8_a3_17
+            } else {
+                0xd9_2d_20
+            },
+            "Platforms",
+            announcement
+                .releases
+                .iter()
+                .map(|r| format!("{}/{}", r.target, r.arch))
+                .collect::<Vec<_>>(),
+        ),
+        _ => (
+            0x2e_b6_7d,
+            "Downloads",
+            announcement
+                .releases
+                .iter()
+                .map(|r| format!("[{}/{}]({})", r.target, r.arch, r.url))
+                .collect::<Vec<_>>(),
+        ),
+    };
+
+    let mut embed = json!({
+        "title": title,
+        "color": color,
+        "fields": [
+            { "name": "Channel", "value": first.channel, "inline": true },
+            { "name": "Version", "value": first.version, "inline": true },
+            { "name": platforms_label, "value": platforms.join("\n") }
+        ]
+    });
+    // Discord rejects empty descriptions.
+    let notes = details(announcement);
+    if !notes.is_empty() {
+        embed["description"] = json!(notes);
+    }
+
+    json!({ "embeds": [embed] })
+}
+
+fn release_email(
+    settings: &EmailSettings,
+    announcement: &Announcement,
+    to: &str,
+    token: &str,
+) -> String {
+    let first = &announcement.releases[0];
+    let unsubscribe_url = format!(
+        "{}{}/unsubscribe/{}",
+        settings.public_url,
+        api_version::PREFIX,
+        token
+    );
+
+    let mut body = format!(
+        "{} {} is now available on the {} channel.\n",
+        first.app_name, first.version, first.channel
+    );
+    let notes = first.notes.trim();
+    if !notes.is_empty() {
+        body.push_str(&format!("\n{}\n", notes));
+    }
+    body.push_str("\nDownloads:\n");
+    for r in &announcement.releases {
+        body.push_str(&format!("- {}/{}: {}\n", r.target, r.arch, r.url));
+    }
+    body.push_str(&format!(
+        "\n--\nYou are receiving this because {} is subscribed to {} releases ({}).\nUnsubscribe: {}\n",
+        to, first.app_name, first.channel, unsubscribe_url
+    ));
+
+    let domain = settings
+        .from
+        .rsplit_once('@')
+        .map(|(_, d)| d)
+        .unwrap_or("localhost");
+    let mut id = [0u8; 16];
+    let _ = getrandom::getrandom(&mut id);
+
+    let headers = [
+        ("From", settings.from.clone()),
+        ("To", to.to_string()),
+        (
+            "Subject",
+            smtp::encode_header(&format!(
+                "{} {} is available",
+                first.app_name, first.version
+            )),
+        ),
+        ("Date", Utc::now().to_rfc2822()),
+        ("Message-ID", format!("<{}@{}>", hex::encode(id), domain)),
+        ("MIME-Version", "1.0".to_string()),
+        ("Content-Type", "text/plain; charset=utf-8".to_string()),
+        ("Content-Transfer-Encoding", "base64".to_string()),
+        ("List-Unsubscribe", format!("<{}>", unsubscribe_url)),
+        (
+            "List-Unsubscribe-Post",
+            "List-Unsubscribe=One-Click".to_string(),
+        ),
+    ];
+
+    let mut message = String::new();
+    for (name, value) in headers {
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str("\r\n");
+    message.push_str(&smtp::encode_body(&body));
+    message.push_str("\r\n");
+    message
+}
@@ -0,0 +1,190 @@
+//! OpenAPI document and Swagger UI.
+//!
+//! The document is served at [`DOCUMENT_PATH`] and browsable at `/docs`.
+//! Every handler mounted in `main.rs` must be listed in [`ApiDoc`] (or, for
+//! GraphQL, [`crate::graphql::GraphqlDoc`]) along with the schemas its
+//! requests and responses use.
+
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use crate::{
+    api_version, archive, attachments, authenticode, blackouts, bundles, campaigns, components,
+    deltas, devices, digest, entitlements, error, events, flags, freezes, gates, graphql, licenses,
+    load_shedding, lookup, mdm, normalize, orgs, plugins, promotions, quotas, reports,
+    response_fields, rings, routes, rules, schema, selfcheck, variants, versioning, web_bundles,
+};
+
+pub const DOCUMENT_PATH: &str = "/api-docs/openapi.json";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "App Release Manager",
+        description = "Desktop app updates and release management. Every endpoint is served under `/v1`; the unprefixed paths are deprecated aliases."
+    ),
+    paths(
+        routes::check_update,
+        deltas::get_delta_manifest,
+        plugins::check_plugin_update,
+        routes::upload_release,
+        routes::get_latest_version,
+        routes::download_latest_release,
+        routes::download_app,
+        routes::get_app_metadata,
+        routes::get_campaign_messages,
+        routes::export_mdm_descriptor,
+        routes::release_feed,
+        routes::get_releases,
+        lookup::lookup_artifact,
+        routes::delete_release,
+        routes::yank_release,
+        routes::promote_release,
+        variants::get_variant,
+        variants::set_variant_split,
+        variants::delete_variant,
+        variants::release_metrics,
+        reports::submit_report,
+        promotions::get_promotion_policy,
+        promotions::set_promotion_policy,
+        promotions::delete_promotion_policy,
+        promotions::list_promotions,
+        quotas::get_org_usage,
+        quotas::set_org_quota,
+        quotas::delete_org_quota,
+        quotas::get_app_usage,
+        quotas::set_app_quota,
+        quotas::delete_app_quota,
+        selfcheck::get_selfcheck,
+        digest::preview_digest,
+        load_shedding::get_load,
+        archive::set_archive_policy,
+        archive::download_archived,
+        gates::list_gates,
+        gates::set_gate,
+        gates::delete_gate,
+        gates::list_staged,
+        gates::get_staged,
+        gates::publish_staged,
+        gates::discard_staged,
+        gates::report_check,
+        plugins::list_plugins,
+        plugins::register_plugin,
+        plugins::unregister_plugin,
+        components::upload_component,
+        components::list_components,
+        components::delete_component,
+        bundles::upload_bundle,
+        bundles::list_bundles,
+        bundles::delete_bundle,
+        bundles::get_release_bundles,
+        bundles::set_release_bundles,
+        deltas::upload_delta,
+        deltas::list_deltas,
+        deltas::delete_delta,
+        attachments::upload_attachment,
+        attachments::list_attachments,
+        attachments::delete_attachment,
+        attachments::get_attachment,
+        rules::get_release_rules,
+        rules::set_release_rules,
+        bundles::resolve_bundles,
+        web_bundles::upload_web_bundle,
+        web_bundles::check_web_bundle,
+        web_bundles::list_web_bundles,
+        web_bundles::roll_back_web_bundle,
+        web_bundles::restore_web_bundle,
+        web_bundles::delete_web_bundle,
+        normalize::normalize_artifact,
+        authenticode::sign_artifact,
+        routes::stream_events,
+        routes::create_webhook,
+        routes::list_webhooks,
+        routes::get_webhook,
+        routes::update_webhook,
+        routes::delete_webhook,
+        routes::list_webhook_deliveries,
+        routes::create_subscription,
+        routes::list_subscriptions,
+        routes::delete_subscription,
+        routes::unsubscribe_page,
+        routes::unsubscribe,
+        routes::root,
+        orgs::list_orgs,
+        orgs::create_org,
+        orgs::get_org,
+        orgs::set_update_policy,
+        orgs::set_customer_update_policy,
+        orgs::delete_customer_update_policy,
+        orgs::list_tokens,
+        orgs::create_token,
+        orgs::delete_token,
+        orgs::list_apps,
+        orgs::set_app_metadata,
+        licenses::set_license_policy,
+        versioning::set_version_scheme,
+        response_fields::set_response_fields,
+        entitlements::set_entitlement_hook,
+        licenses::list_licenses,
+        licenses::create_license,
+        licenses::update_license,
+        licenses::delete_license,
+        rings::set_release_rings,
+        rings::list_customers,
+        rings::assign_customer,
+        rings::unassign_customer,
+        blackouts::list_blackouts,
+        blackouts::create_blackout,
+        blackouts::delete_blackout,
+        blackouts::set_release_critical,
+        campaigns::list_campaigns,
+        campaigns::create_campaign,
+        campaigns::update_campaign,
+        campaigns::delete_campaign,
+        flags::list_flags,
+        flags::set_flag,
+        flags::delete_flag,
+        devices::list_devices,
+        devices::get_device,
+        devices::register_device,
+        devices::delete_device,
+        devices::list_device_targets,
+        devices::create_device_target,
+        devices::delete_device_target,
+        freezes::list_freezes,
+        freezes::create_freeze,
+        freezes::delete_freeze
+    ),
+    components(
+        schemas(schema::Release, schema::Artifact, schema::ArtifactMatch, schema::UpdateResponse, schema::UploadReleaseForm, schema::SupportedApp, schema::SupportedTarget, mdm::MdmFormat, schema::PromoteRequest, events::ReleaseEvent, events::ReleaseEventKind, schema::Webhook, schema::WebhookRequest, schema::WebhookDelivery, schema::Subscription, schema::SubscriptionRequest, schema::Organization, schema::OrganizationRequest, schema::App, schema::AppMetadataFields, schema::AppMetadata, schema::AppMetadataRequest, schema::ApiToken, schema::ApiTokenRequest, schema::IssuedApiToken, schema::RingScheduleRequest, schema::CustomerRing, schema::CustomerRingRequest, schema::CustomerUpdatePolicy, schema::Blackout, schema::BlackoutRequest, schema::CriticalRequest, schema::PublishFreeze, schema::PublishFreezeRequest, schema::UpdatePolicyRequest, schema::LicensePolicyRequest, schema::VersionScheme, schema::VersionSchemeRequest, schema::ResponseFieldsRequest, schema::ArchivePolicyRequest, schema::License, schema::LicenseRequest, schema::LicenseUpdateRequest, schema::IssuedLicense, schema::EntitlementHookRequest, schema::Campaign, schema::CampaignRequest, schema::CampaignMessage, schema::FeatureFlag, schema::FeatureFlagRequest, schema::ReleaseVariant, schema::VariantSplitRequest, schema::InstallOutcome, schema::InstallReportRequest, schema::VariantMetrics, schema::PromotionPolicy, schema::PromotionPolicyRequest, schema::PromotionState, schema::ReleasePromotion, schema::QuotaLimits, schema::QuotaUsage, schema::CheckStatus, schema::SelfCheck, schema::SelfCheckReport, schema::LoadStats, schema::CheckGate, schema::CheckGateRequest, schema::StagedRelease, schema::CheckRun, schema::CheckConclusion, schema::CheckReportRequest, schema::PluginUpdateResponse, schema::Bundle, schema::BundleUploadForm, schema::RuleAction, schema::RuleConditions, schema::TargetingRule, schema::TargetingRulesRequest, schema::BundleRequirementsRequest, schema::BundleRequirement, schema::ResolvedBundle, schema::ReleaseComponent, schema::ComponentUploadForm, schema::ComponentUpdate, schema::ReleaseAttachment, schema::AttachmentUploadForm, schema::ReleaseDelta, schema::DeltaUploadForm, schema::DeltaStep, schema::FullDownload, schema::DeltaManifest, schema::WebBundle, schema::WebBundleUploadForm, schema::WebBundleUpdateResponse, schema::NormalizeForm, schema::AuthenticodeForm, schema::Device, schema::DeviceRequest, schema::DeviceTarget, schema::DeviceTargetRequest, error::ErrorBody)
+    ),
+    modifiers(&BearerAuth),
+    security(("bearer" = [])),
+    tags(
+        (name = "updater", description = "Updater API")
+    )
+)]
+pub struct ApiDoc;
+
+/// Admin endpoints take an organization or operator token; the public
+/// update endpoints opt out with an empty `security`.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_default()
+            .add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+    }
+}
+
+/// The full document, with GraphQL merged in and paths under `/v1`.
+pub fn document() -> utoipa::openapi::OpenApi {
+    let mut doc = ApiDoc::openapi();
+    doc.merge(graphql::GraphqlDoc::openapi());
+    api_version::prefix_paths(doc)
+}
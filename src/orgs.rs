@@ -0,0 +1,557 @@
+//! Organizations, their API tokens and their apps, including the apps'
+//! public metadata.
+//!
+//! Only the operator token creates organizations. An organization's own
+//! tokens can list and manage that organization's tokens, so partners
+//! rotate credentials without involving the operator.
+//!
+//! Organizations also set their update policy: how many days non-critical
+//! releases are held back from their clients, the way district IT would
+//! defer updates through an MDM. A customer (the `customer_id` update checks
+//! send, e.g. one district) can be given a deferral of its own, which
+//! replaces the organization's for that customer's clients.
+
+use axum::{
+    Extension,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Pool, Sqlite};
+
+use crate::auth::{self, Principal};
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::rings;
+use crate::routes::validate_http_url;
+use crate::schema::{
+    API_TOKEN_COLUMNS, APP_COLUMNS, ApiToken, ApiTokenRequest, App, AppMetadataRequest, AppState,
+    CUSTOMER_UPDATE_POLICY_COLUMNS, CustomerUpdatePolicy, IssuedApiToken, ORGANIZATION_COLUMNS,
+    OrgQuery, Organization, OrganizationRequest, Release, UpdatePolicyRequest, is_valid_channel,
+};
+
+/// Longest deferral an organization can configure.
+const MAX_DEFER_DAYS: i64 = 365;
+
+/// Days non-critical updates of `app_name` are deferred by for
+/// `customer_id`: the customer's own policy in the organization owning the
+/// app, else the organization's.
+pub async fn deferral_days(
+    pool: &Pool<Sqlite>,
+    app_name: &str,
+    customer_id: Option<&str>,
+) -> Result<i64, sqlx::Error> {
+    let days: Option<i64> = sqlx::query_scalar(
+        "SELECT COALESCE((SELECT p.defer_days FROM customer_update_policies p WHERE p.org_id = a.org_id AND p.customer_id = ?2), o.defer_days)
+         FROM organizations o JOIN apps a ON a.org_id = o.id WHERE a.name = ?1",
+    )
+    .bind(app_name)
+    .bind(customer_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(days.unwrap_or(0))
+}
+
+fn validate_defer_days(days: i64) -> AppResult<()> {
+    if !(0..=MAX_DEFER_DAYS).contains(&days) {
+        return Err(AppError::bad_request(format!(
+            "defer_days must be between 0 and {}",
+            MAX_DEFER_DAYS
+        )));
+    }
+    Ok(())
+}
+
+/// Whether a deferral of `days` still holds `release` back at `now`.
+/// Critical releases are never deferred.
+pub fn is_deferred(release: &Release, days: i64, now: DateTime<Utc>) -> bool {
+    !release.critical && days > 0 && release.pub_date + Duration::days(days) > now
+}
+
+/// Loads an organization the caller may see. Others' organizations look
+/// missing rather than forbidden, so their ids don't leak.
+async fn visible_org(
+    pool: &Pool<Sqlite>,
+    principal: Principal,
+    id: i64,
+) -> AppResult<Organization> {
+    sqlx::query_as::<_, Organization>(&format!(
+        "SELECT {} FROM organizations WHERE id = ?",
+        ORGANIZATION_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load organization", e))?
+    .filter(|org| principal.can_see(org.id))
+    .ok_or_else(|| AppError::not_found("Organization not found"))
+}
+
+/// List organizations
+#[utoipa::path(
+    get,
+    path = "/orgs",
+    responses(
+        (status = 200, description = "Every organization for the operator; the caller's own otherwise", body = Vec<Organization>)
+    )
+)]
+pub async fn list_orgs(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<Organization>>> {
+    let orgs = sqlx::query_as::<_, Organization>(&format!(
+        "SELECT {} FROM organizations WHERE (?1 IS NULL OR id = ?1) ORDER BY id",
+        ORGANIZATION_COLUMNS
+    ))
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load organizations", e))?;
+
+    Ok(Json(orgs))
+}
+
+/// Create an organization
+#[utoipa::path(
+    post,
+    path = "/orgs",
+    request_body = OrganizationRequest,
+    responses(
+        (status = 201, description = "Organization created", body = Organization),
+        (status = 400, description = "Invalid slug or name", body = ErrorBody),
+        (status = 403, description = "Not the operator token", body = ErrorBody),
+        (status = 409, description = "Slug already taken", body = ErrorBody)
+    )
+)]
+pub async fn create_org(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<OrganizationRequest>,
+) -> AppResult<(StatusCode, Json<Organization>)> {
+    principal.require_operator()?;
+    // Same shape as channel names: short lowercase slugs.
+    if !is_valid_channel(&request.slug) {
+        return Err(AppError::bad_request(
+            "slug must be a lowercase slug (letters, digits, '-')",
+        ));
+    }
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err(AppError::bad_request("name is required"));
+    }
+
+    let org = sqlx::query_as::<_, Organization>(&format!(
+        "INSERT INTO organizations (slug, name, created_at) VALUES (?, ?, ?) ON CONFLICT (slug) DO NOTHING RETURNING {}",
+        ORGANIZATION_COLUMNS
+    ))
+    .bind(&request.slug)
+    .bind(name)
+    .bind(Utc::now())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to create organization", e))?
+    .ok_or_else(|| AppError::conflict(format!("Organization '{}' already exists", request.slug)))?;
+
+    println!("Created organization {} ({})", org.id, org.slug);
+    Ok((StatusCode::CREATED, Json(org)))
+}
+
+/// Get an organization
+#[utoipa::path(
+    get,
+    path = "/orgs/{id}",
+    params(
+        ("id" = i64, Path, description = "Organization ID")
+    ),
+    responses(
+        (status = 200, description = "Organization", body = Organization),
+        (status = 404, description = "Organization not found", body = ErrorBody)
+    )
+)]
+pub async fn get_org(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Organization>> {
+    Ok(Json(visible_org(&state.read_pool, principal, id).await?))
+}
+
+/// Set an organization's update policy
+#[utoipa::path(
+    put,
+    path = "/orgs/{id}/update-policy",
+    params(
+        ("id" = i64, Path, description = "Organization ID")
+    ),
+    request_body = UpdatePolicyRequest,
+    responses(
+        (status = 200, description = "Policy saved", body = Organization),
+        (status = 400, description = "defer_days out of range", body = ErrorBody),
+        (status = 404, description = "Organization not found", body = ErrorBody)
+    )
+)]
+pub async fn set_update_policy(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<UpdatePolicyRequest>,
+) -> AppResult<Json<Organization>> {
+    let org = visible_org(&state.read_pool, principal, id).await?;
+    validate_defer_days(request.defer_days)?;
+
+    let org = sqlx::query_as::<_, Organization>(&format!(
+        "UPDATE organizations SET defer_days = ? WHERE id = ? RETURNING {}",
+        ORGANIZATION_COLUMNS
+    ))
+    .bind(request.defer_days)
+    .bind(org.id)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to update organization", e))?;
+
+    println!(
+        "Organization {} now defers non-critical updates by {} days",
+        org.slug, org.defer_days
+    );
+    Ok(Json(org))
+}
+
+/// Set a customer's update policy
+///
+/// The customer's clients are deferred by these days instead of the
+/// organization's.
+#[utoipa::path(
+    put,
+    path = "/customers/{customer_id}/update-policy",
+    params(
+        ("customer_id" = String, Path, description = "Customer identifier the app sends as `customer_id`"),
+        OrgQuery
+    ),
+    request_body = UpdatePolicyRequest,
+    responses(
+        (status = 200, description = "Policy saved", body = CustomerUpdatePolicy),
+        (status = 400, description = "defer_days out of range, or an invalid customer ID", body = ErrorBody),
+        (status = 403, description = "org_id names another organization", body = ErrorBody),
+        (status = 404, description = "Organization not found", body = ErrorBody)
+    )
+)]
+pub async fn set_customer_update_policy(
+    Path(customer_id): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(org): Query<OrgQuery>,
+    Json(request): Json<UpdatePolicyRequest>,
+) -> AppResult<Json<CustomerUpdatePolicy>> {
+    rings::validate_customer_id(&customer_id)?;
+    validate_defer_days(request.defer_days)?;
+    let org = visible_org(
+        &state.read_pool,
+        principal,
+        principal.target_org(org.org_id)?,
+    )
+    .await?;
+
+    let policy = sqlx::query_as::<_, CustomerUpdatePolicy>(&format!(
+        "INSERT INTO customer_update_policies ({0}) VALUES (?, ?, ?, ?)
+         ON CONFLICT (org_id, customer_id) DO UPDATE SET defer_days = excluded.defer_days, updated_at = excluded.updated_at
+         RETURNING {0}",
+        CUSTOMER_UPDATE_POLICY_COLUMNS
+    ))
+    .bind(org.id)
+    .bind(&customer_id)
+    .bind(request.defer_days)
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to save the update policy", e))?;
+
+    println!(
+        "Customer {} of organization {} now defers non-critical updates by {} days",
+        policy.customer_id, org.slug, policy.defer_days
+    );
+    Ok(Json(policy))
+}
+
+/// Remove a customer's update policy
+#[utoipa::path(
+    delete,
+    path = "/customers/{customer_id}/update-policy",
+    params(
+        ("customer_id" = String, Path, description = "Customer identifier"),
+        OrgQuery
+    ),
+    responses(
+        (status = 204, description = "Policy removed; the organization's deferral applies again"),
+        (status = 403, description = "org_id names another organization", body = ErrorBody),
+        (status = 404, description = "The customer has no policy of its own", body = ErrorBody)
+    )
+)]
+pub async fn delete_customer_update_policy(
+    Path(customer_id): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(org): Query<OrgQuery>,
+) -> AppResult<StatusCode> {
+    let org_id = principal.target_org(org.org_id)?;
+    let deleted =
+        sqlx::query("DELETE FROM customer_update_policies WHERE org_id = ? AND customer_id = ?")
+            .bind(org_id)
+            .bind(&customer_id)
+            .execute(&state.pool)
+            .await
+            .map_err(|e| AppError::internal("Failed to remove the update policy", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("The customer has no update policy"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List an organization's API tokens
+#[utoipa::path(
+    get,
+    path = "/orgs/{id}/tokens",
+    params(
+        ("id" = i64, Path, description = "Organization ID")
+    ),
+    responses(
+        (status = 200, description = "Tokens, without their secrets", body = Vec<ApiToken>),
+        (status = 404, description = "Organization not found", body = ErrorBody)
+    )
+)]
+pub async fn list_tokens(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<ApiToken>>> {
+    let org = visible_org(&state.read_pool, principal, id).await?;
+    let tokens = sqlx::query_as::<_, ApiToken>(&format!(
+        "SELECT {} FROM api_tokens WHERE org_id = ? ORDER BY id",
+        API_TOKEN_COLUMNS
+    ))
+    .bind(org.id)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load tokens", e))?;
+
+    Ok(Json(tokens))
+}
+
+/// Issue an API token for an organization
+#[utoipa::path(
+    post,
+    path = "/orgs/{id}/tokens",
+    params(
+        ("id" = i64, Path, description = "Organization ID")
+    ),
+    request_body = ApiTokenRequest,
+    responses(
+        (status = 201, description = "Token issued; the secret is only returned here", body = IssuedApiToken),
+        (status = 400, description = "Missing name", body = ErrorBody),
+        (status = 404, description = "Organization not found", body = ErrorBody)
+    )
+)]
+pub async fn create_token(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<ApiTokenRequest>,
+) -> AppResult<(StatusCode, Json<IssuedApiToken>)> {
+    let org = visible_org(&state.read_pool, principal, id).await?;
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err(AppError::bad_request("name is required"));
+    }
+
+    let secret = auth::new_api_token()?;
+    let token = sqlx::query_as::<_, ApiToken>(&format!(
+        "INSERT INTO api_tokens (org_id, name, token_hash, created_at) VALUES (?, ?, ?, ?) RETURNING {}",
+        API_TOKEN_COLUMNS
+    ))
+    .bind(org.id)
+    .bind(name)
+    .bind(auth::hash_token(&secret))
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to issue token", e))?;
+
+    println!(
+        "Issued token {} '{}' for organization {}",
+        token.id, token.name, org.slug
+    );
+    Ok((StatusCode::CREATED, Json(IssuedApiToken { token, secret })))
+}
+
+/// Revoke an API token
+#[utoipa::path(
+    delete,
+    path = "/orgs/{id}/tokens/{token_id}",
+    params(
+        ("id" = i64, Path, description = "Organization ID"),
+        ("token_id" = i64, Path, description = "Token ID")
+    ),
+    responses(
+        (status = 204, description = "Token revoked"),
+        (status = 404, description = "Organization or token not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_token(
+    Path((id, token_id)): Path<(i64, i64)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let org = visible_org(&state.read_pool, principal, id).await?;
+    let deleted = sqlx::query("DELETE FROM api_tokens WHERE id = ? AND org_id = ?")
+        .bind(token_id)
+        .bind(org.id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to revoke token", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("Token not found"));
+    }
+    println!("Revoked token {} of organization {}", token_id, org.slug);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List apps
+#[utoipa::path(
+    get,
+    path = "/apps",
+    responses(
+        (status = 200, description = "Apps owned by the caller's organization (every app for the operator)", body = Vec<App>)
+    )
+)]
+pub async fn list_apps(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<App>>> {
+    let apps = sqlx::query_as::<_, App>(&format!(
+        "SELECT {} FROM apps WHERE {} ORDER BY name",
+        APP_COLUMNS,
+        auth::org_scope(1)
+    ))
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load apps", e))?;
+
+    Ok(Json(apps))
+}
+
+/// Longest display name and description accepted, so the download page and
+/// update dialogs can lay them out.
+const MAX_DISPLAY_NAME_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 2000;
+
+fn optional_text(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty())
+}
+
+fn optional_url<'a>(field: &str, value: &'a Option<String>) -> AppResult<Option<&'a str>> {
+    let url = optional_text(value);
+    if let Some(url) = url
+        && validate_http_url(url).is_err()
+    {
+        return Err(AppError::bad_request(format!(
+            "{} must be an absolute http(s) URL",
+            field
+        )));
+    }
+    Ok(url)
+}
+
+/// Set an app's metadata
+#[utoipa::path(
+    put,
+    path = "/apps/{name}/metadata",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    request_body = AppMetadataRequest,
+    responses(
+        (status = 200, description = "Metadata saved", body = App),
+        (status = 400, description = "Invalid URL or text too long", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody)
+    )
+)]
+pub async fn set_app_metadata(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<AppMetadataRequest>,
+) -> AppResult<Json<App>> {
+    let display_name = optional_text(&request.display_name);
+    if display_name.is_some_and(|n| n.chars().count() > MAX_DISPLAY_NAME_LEN) {
+        return Err(AppError::bad_request(format!(
+            "display_name must be at most {} characters",
+            MAX_DISPLAY_NAME_LEN
+        )));
+    }
+    let description = optional_text(&request.description);
+    if description.is_some_and(|d| d.chars().count() > MAX_DESCRIPTION_LEN) {
+        return Err(AppError::bad_request(format!(
+            "description must be at most {} characters",
+            MAX_DESCRIPTION_LEN
+        )));
+    }
+    let icon_url = optional_url("icon_url", &request.icon_url)?;
+    let homepage_url = optional_url("homepage_url", &request.homepage_url)?;
+    let support_url = optional_url("support_url", &request.support_url)?;
+    auth::claim_app(&state.pool, principal, &name).await?;
+
+    let app = sqlx::query_as::<_, App>(&format!(
+        "UPDATE apps SET display_name = ?, icon_url = ?, homepage_url = ?, support_url = ?, description = ? WHERE name = ? RETURNING {}",
+        APP_COLUMNS
+    ))
+    .bind(display_name)
+    .bind(icon_url)
+    .bind(homepage_url)
+    .bind(support_url)
+    .bind(description)
+    .bind(&name)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to save app metadata", e))?;
+
+    println!("Updated metadata of {}", app.name);
+    Ok(Json(app))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[tokio::test]
+    async fn other_organizations_look_missing() {
+        let pool = db::memory_pool().await;
+        sqlx::query(
+            "INSERT INTO organizations (id, slug, name, created_at) VALUES (2, 'acme', 'Acme', ?)",
+        )
+        .bind(Utc::now())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(
+            visible_org(&pool, Principal::Org(2), 2).await.unwrap().slug,
+            "acme"
+        );
+        assert_eq!(
+            visible_org(&pool, Principal::Operator, 2).await.unwrap().id,
+            2
+        );
+        assert_eq!(
+            visible_org(&pool, Principal::Operator, 1).await.unwrap().id,
+            1
+        );
+
+        let hidden = visible_org(&pool, Principal::Org(2), 1).await.unwrap_err();
+        assert_eq!(hidden.status(), StatusCode::NOT_FOUND);
+        let missing = visible_org(&pool, Principal::Operator, 3)
+            .await
+            .unwrap_err();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    }
+}
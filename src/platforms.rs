@@ -0,0 +1,161 @@
+//! Platform inference from the User-Agent.
+//!
+//! A browser following an app's single "Download" link can't say which
+//! target and arch it needs. `/download/{app_name}`, and `auto` in place of
+//! the target or arch of `/latest` and `/download/latest`, read them from
+//! the User-Agent instead and pick the best match among the builds the app
+//! has on the channel. Browsers on macOS always claim an Intel CPU, so Macs
+//! get a universal build when there is one, else one Rosetta can run.
+
+use axum::http::{HeaderMap, header};
+use sqlx::{Pool, Sqlite};
+
+/// Path segment asking for the target or arch to be inferred.
+pub const AUTO: &str = "auto";
+
+/// What the User-Agent gives away; either part may be unknown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Platform {
+    pub target: Option<&'static str>,
+    pub arch: Option<&'static str>,
+}
+
+/// Reads the platform from a browser or HTTP client User-Agent.
+pub fn from_user_agent(user_agent: &str) -> Platform {
+    let ua = user_agent.to_ascii_lowercase();
+    let has = |needle: &str| ua.contains(needle);
+    // Phones, tablets and Chromebooks first, as they mention the desktop
+    // platforms too. They are told apart so they don't get a desktop build.
+    for (needle, target) in [
+        ("iphone", "ios"),
+        ("ipad", "ios"),
+        ("android", "android"),
+        ("cros", "chromeos"),
+    ] {
+        if has(needle) {
+            return Platform {
+                target: Some(target),
+                arch: None,
+            };
+        }
+    }
+    if has("windows") {
+        let arch = if has("arm64") || has("aarch64") {
+            Some("aarch64")
+        } else if has("win64") || has("x64") || has("wow64") || has("x86_64") {
+            Some("x86_64")
+        } else {
+            None
+        };
+        return Platform {
+            target: Some("windows"),
+            arch,
+        };
+    }
+    if has("macintosh") || has("mac os x") || has("darwin") {
+        // "Intel Mac OS X" is all a browser ever says, whatever the CPU.
+        return Platform {
+            target: Some("darwin"),
+            arch: None,
+        };
+    }
+    if has("linux") {
+        let arch = if has("aarch64") || has("arm64") || has("armv8") {
+            Some("aarch64")
+        } else if has("x86_64") || has("amd64") {
+            Some("x86_64")
+        } else {
+            None
+        };
+        return Platform {
+            target: Some("linux"),
+            arch,
+        };
+    }
+    Platform::default()
+}
+
+/// The platform of the request's User-Agent.
+pub fn from_headers(headers: &HeaderMap) -> Platform {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(from_user_agent)
+        .unwrap_or_default()
+}
+
+/// How well a build for `arch` suits `platform`, lower is better; `None`
+/// when it won't run there.
+fn arch_rank(platform: Platform, arch: &str) -> Option<usize> {
+    match platform.arch {
+        Some(wanted) if arch == wanted => Some(0),
+        _ if arch == "universal" => Some(1),
+        // Windows on ARM and Apple Silicon emulate x86_64.
+        Some("aarch64") if arch == "x86_64" => Some(2),
+        Some(_) => None,
+        None if arch == "x86_64" => Some(2),
+        None => Some(3),
+    }
+}
+
+/// Picks the build for `platform` among the `(target, arch)` pairs an app
+/// has. Without a known target, an app that ships for a single target is
+/// still matched.
+pub fn best_match(platform: Platform, available: &[(String, String)]) -> Option<(String, String)> {
+    let target = match platform.target {
+        Some(target) => target.to_string(),
+        None => {
+            let (first, _) = available.first()?;
+            if available.iter().any(|(t, _)| t != first) {
+                return None;
+            }
+            first.clone()
+        }
+    };
+    available
+        .iter()
+        .filter(|(t, _)| *t == target)
+        .filter_map(|(t, a)| Some((arch_rank(platform, a)?, a, t)))
+        .min()
+        .map(|(_, a, t)| (t.clone(), a.clone()))
+}
+
+/// Resolves `auto` segments of a request for the latest release to the
+/// best-matching build, reading the platform from the User-Agent. Explicit
+/// segments are kept; `None` means no build of the app suits the client.
+pub async fn resolve(
+    pool: &Pool<Sqlite>,
+    headers: &HeaderMap,
+    app_name: &str,
+    (target, arch): (&str, &str),
+    channel: &str,
+) -> Result<Option<(String, String)>, sqlx::Error> {
+    let mut platform = from_headers(headers);
+    if target != AUTO {
+        if platform.target != Some(target) {
+            platform.arch = None;
+        }
+        platform.target = None;
+    }
+    if arch != AUTO {
+        platform.arch = None;
+    }
+    let available: Vec<(String, String)> = sqlx::query_as(
+        "SELECT DISTINCT target, arch FROM releases WHERE app_name = ? AND channel = ? AND yanked = 0 AND archived = 0 ORDER BY target, arch",
+    )
+    .bind(app_name)
+    .bind(channel)
+    .fetch_all(pool)
+    .await?;
+    let available: Vec<(String, String)> = available
+        .into_iter()
+        .filter(|(t, a)| (target == AUTO || t == target) && (arch == AUTO || a == arch))
+        .collect();
+    Ok(best_match(platform, &available))
+}
+
+/// Whether the request leaves its target or arch to be inferred, so the
+/// answer depends on the User-Agent.
+pub fn is_inferred(target: &str, arch: &str) -> bool {
+    target == AUTO || arch == AUTO
+}
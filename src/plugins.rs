@@ -0,0 +1,497 @@
+//! Plugins.
+//!
+//! A plugin is an app of its own, with its own releases and channels, that
+//! is registered under the host app loading it. Plugin uploads may carry a
+//! `host_version` requirement, such as `>=2.0.0, <3.0.0`, which holds for
+//! that plugin version on every platform. Plugin update checks name the
+//! host version they run in and are offered the newest release whose
+//! requirement it meets. Among those, the plugin's own rollout policy
+//! decides as it would for an app: yanks, rings and deferral, targeting
+//! rules, blackout windows, licenses and entitlements.
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{
+    Extension,
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderName, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::Utc;
+use semver::VersionReq;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+
+use crate::auth::{self, Principal, org_scope};
+use crate::blackouts;
+use crate::entitlements;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::http_cache;
+use crate::licenses;
+use crate::routes::offered_release;
+use crate::rules::{self, RuleContext};
+use crate::schema::{
+    APP_COLUMNS, App, AppState, DEFAULT_CHANNEL, PluginUpdateCheckQuery, PluginUpdateResponse,
+    RELEASE_COLUMNS, Release, UpdateCheckContext, UpdateCheckQuery,
+};
+use crate::versioning::{self, AppVersion};
+
+/// Parses the `host_version` field of an upload of `app_name`, which must
+/// be a registered plugin when it is given.
+pub async fn parse_host_requirement(
+    pool: &Pool<Sqlite>,
+    app_name: &str,
+    raw: &str,
+) -> AppResult<Option<VersionReq>> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Ok(None);
+    }
+    let req = VersionReq::parse(raw).map_err(|e| {
+        AppError::bad_request(format!(
+            "host_version '{}' is not a semver requirement: {}",
+            raw, e
+        ))
+    })?;
+    let host: Option<String> = sqlx::query_scalar("SELECT host_app FROM apps WHERE name = ?")
+        .bind(app_name)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up the plugin", e))?
+        .flatten();
+    if host.is_none() {
+        return Err(AppError::bad_request(format!(
+            "host_version only applies to plugins; register {} under its host app first",
+            app_name
+        )));
+    }
+    Ok(Some(req))
+}
+
+/// Records which host versions `version` of a plugin runs in, replacing
+/// what an earlier upload of that version said. Written with the release,
+/// so a failed or still staged upload doesn't change what the published
+/// releases of that version are offered with.
+pub async fn set_host_requirement(
+    conn: &mut SqliteConnection,
+    app_name: &str,
+    version: &str,
+    req: &VersionReq,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO plugin_host_requirements (app_name, version, host_version_req) VALUES (?, ?, ?) ON CONFLICT (app_name, version) DO UPDATE SET host_version_req = excluded.host_version_req",
+    )
+    .bind(app_name)
+    .bind(version)
+    .bind(req.to_string())
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// [`set_host_requirement`] for a staged upload being published, with the
+/// requirement it was uploaded with.
+pub async fn publish_host_requirement(
+    conn: &mut SqliteConnection,
+    staged_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO plugin_host_requirements (app_name, version, host_version_req) SELECT app_name, version, host_version_req FROM staged_releases WHERE id = ? AND host_version_req IS NOT NULL ON CONFLICT (app_name, version) DO UPDATE SET host_version_req = excluded.host_version_req",
+    )
+    .bind(staged_id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn host_requirements(
+    pool: &Pool<Sqlite>,
+    app_name: &str,
+) -> Result<HashMap<String, String>, sqlx::Error> {
+    let rows: Vec<(String, String)> = sqlx::query_as(
+        "SELECT version, host_version_req FROM plugin_host_requirements WHERE app_name = ?",
+    )
+    .bind(app_name)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().collect())
+}
+
+/// Check for plugin updates
+///
+/// Offers the newest release of the plugin that runs in `host_version`,
+/// narrowed down like an update check of the plugin would be.
+#[utoipa::path(
+    get,
+    path = "/plugins/{host_app}/{plugin}/{target}/{arch}/{current_version}",
+    security(()),
+    params(
+        ("host_app" = String, Path, description = "App the plugin is registered under"),
+        ("plugin" = String, Path, description = "Plugin name"),
+        ("target" = String, Path, description = "Target OS"),
+        ("arch" = String, Path, description = "Architecture (e.g., aarch64, x86_64)"),
+        ("current_version" = String, Path, description = "Installed version of the plugin"),
+        PluginUpdateCheckQuery,
+        UpdateCheckQuery
+    ),
+    responses(
+        (status = 200, description = "Update available", body = PluginUpdateResponse),
+        (status = 204, description = "No compatible update available, or a blackout window is holding it back"),
+        (status = 400, description = "Invalid plugin or host version", body = ErrorBody),
+        (status = 402, description = "The license key has expired (`license_expired`)", body = ErrorBody),
+        (status = 403, description = "The plugin requires a license key and none or an invalid one was sent (`license_required`, `license_invalid`), or its entitlement hook refused the update (`entitlement_denied`)", body = ErrorBody),
+        (status = 404, description = "No such plugin under this host app", body = ErrorBody),
+        (status = 503, description = "The license or entitlement could not be checked", body = ErrorBody)
+    )
+)]
+pub async fn check_plugin_update(
+    Path((host_app, plugin, target, arch, current_version)): Path<(
+        String,
+        String,
+        String,
+        String,
+        String,
+    )>,
+    Query(query): Query<PluginUpdateCheckQuery>,
+    Query(check): Query<UpdateCheckQuery>,
+    Query(raw_query): Query<BTreeMap<String, String>>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let channel = check.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
+    let scheme = versioning::scheme_for(&state, &plugin).await?;
+    let current = versioning::parse_field(scheme, "current_version", &current_version)?;
+    // Requirements are semver ranges, matched as campaigns and flags match
+    // them against other schemes.
+    let host_scheme = versioning::scheme_for(&state, &host_app).await?;
+    let host_version =
+        versioning::parse_field(host_scheme, "host_version", query.host_version.trim())?
+            .to_semver();
+
+    let registered: Option<String> = sqlx::query_scalar("SELECT host_app FROM apps WHERE name = ?")
+        .bind(&plugin)
+        .fetch_optional(&state.read_pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up the plugin", e))?
+        .flatten();
+    if registered.as_deref() != Some(host_app.as_str()) {
+        return Err(AppError::not_found(format!(
+            "{} is not a plugin of {}",
+            plugin, host_app
+        )));
+    }
+
+    let releases = sqlx::query_as::<_, Release>(&format!(
+        "SELECT {} FROM releases WHERE app_name = ? AND target = ? AND arch = ? AND channel = ? AND yanked = 0 AND archived = 0",
+        RELEASE_COLUMNS
+    ))
+    .bind(&plugin)
+    .bind(&target)
+    .bind(&arch)
+    .bind(channel)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to look up plugin releases", e))?;
+    let mut requirements = host_requirements(&state.read_pool, &plugin)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up host requirements", e))?;
+    // Requirements were validated on upload.
+    let compatible = |release: &Release| {
+        requirements
+            .get(&release.version)
+            .and_then(|r| VersionReq::parse(r).ok())
+            .is_none_or(|r| r.matches(&host_version))
+    };
+    let is_newer =
+        |release: &Release| versioning::parse(scheme, &release.version).is_ok_and(|v| v > current);
+
+    let mut newest_incompatible: Option<(AppVersion, String)> = None;
+    let mut best: Option<(AppVersion, Release)> = None;
+    for release in releases {
+        let Ok(version) = versioning::parse(scheme, &release.version) else {
+            continue;
+        };
+        if version <= current {
+            continue;
+        }
+        if !compatible(&release) {
+            if newest_incompatible
+                .as_ref()
+                .is_none_or(|(v, _)| version > *v)
+            {
+                newest_incompatible = Some((version, release.version.clone()));
+            }
+            continue;
+        }
+        if best.as_ref().is_none_or(|(v, _)| version > *v) {
+            best = Some((version, release));
+        }
+    }
+    if let Some((v, raw)) = &newest_incompatible
+        && best.as_ref().is_none_or(|(b, _)| b < v)
+    {
+        println!(
+            "Plugin {} {} needs host {} {}, not {}",
+            plugin,
+            raw,
+            host_app,
+            requirements
+                .get(raw)
+                .map(String::as_str)
+                .unwrap_or_default(),
+            query.host_version
+        );
+    }
+
+    // The same rollout policy as an update check of the plugin: rings and
+    // deferral, targeting rules, blackouts, then licenses and entitlements.
+    let latest = offered_release(
+        &state,
+        best.map(|(_, release)| release),
+        (&plugin, &target, &arch, channel),
+        check.customer_id.as_deref(),
+        (scheme, &current),
+        &compatible,
+    )
+    .await?;
+    let context = RuleContext::new(
+        &state,
+        &headers,
+        (&plugin, channel),
+        current.to_semver(),
+        &check,
+        &raw_query,
+    );
+    let (latest, by_country) = rules::apply(&state, latest, &context).await?;
+    let latest = latest.filter(|r| compatible(r) && is_newer(r));
+    let latest = match latest {
+        Some(release) if !release.critical => {
+            match blackouts::active_blackout(&state.read_pool, &plugin, Utc::now())
+                .await
+                .map_err(|e| AppError::internal("Failed to check blackout windows", e))?
+            {
+                Some(reason) => {
+                    println!(
+                        "Holding back {} {} during blackout: {}",
+                        plugin, release.version, reason
+                    );
+                    None
+                }
+                None => Some(release),
+            }
+        }
+        latest => latest,
+    };
+    let latest = match latest {
+        Some(release) => {
+            let context = UpdateCheckContext {
+                app_name: plugin.clone(),
+                target: target.clone(),
+                arch: arch.clone(),
+                current_version: Some(current_version.clone()),
+                channel: channel.to_string(),
+                customer_id: check.customer_id.clone(),
+            };
+            let key = licenses::request_key(&headers, check.license_key.as_deref());
+            licenses::check_license(&state, &context, key).await?;
+            entitlements::check_entitlement(&state, &context, release).await?
+        }
+        None => None,
+    };
+
+    let country_header = state
+        .config
+        .country_header
+        .as_deref()
+        .filter(|_| by_country)
+        .and_then(|name| HeaderName::try_from(name).ok());
+    let vary = |response: Response| {
+        let response = http_cache::vary(response, licenses::LICENSE_KEY_HEADER);
+        match &country_header {
+            Some(name) => http_cache::vary(response, name.clone()),
+            None => response,
+        }
+    };
+    let Some(release) = latest.filter(|r| is_newer(r)) else {
+        return Ok(vary(StatusCode::NO_CONTENT.into_response()));
+    };
+    println!(
+        "Plugin update available: {} {} -> {} (host {} {})",
+        plugin, current_version, release.version, host_app, query.host_version
+    );
+    let host_version_req = requirements.remove(&release.version);
+    Ok(vary(
+        Json(PluginUpdateResponse {
+            version: release.version,
+            url: release.url,
+            signature: release.signature,
+            pub_date: release.pub_date,
+            notes: release.notes,
+            host_version_req,
+        })
+        .into_response(),
+    ))
+}
+
+async fn visible_app(state: &AppState, principal: Principal, name: &str) -> AppResult<App> {
+    sqlx::query_as::<_, App>(&format!(
+        "SELECT {} FROM apps WHERE name = ?1 AND {}",
+        APP_COLUMNS,
+        org_scope(2)
+    ))
+    .bind(name)
+    .bind(principal.org_id())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load app", e))?
+    .ok_or_else(|| AppError::not_found(format!("App '{}' not found", name)))
+}
+
+/// List an app's plugins
+#[utoipa::path(
+    get,
+    path = "/apps/{name}/plugins",
+    params(
+        ("name" = String, Path, description = "Host application name")
+    ),
+    responses(
+        (status = 200, description = "Plugins registered under the app", body = Vec<App>),
+        (status = 404, description = "App not found", body = ErrorBody)
+    )
+)]
+pub async fn list_plugins(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<App>>> {
+    let host = visible_app(&state, principal, &name).await?;
+    let plugins = sqlx::query_as::<_, App>(&format!(
+        "SELECT {} FROM apps WHERE host_app = ? ORDER BY name",
+        APP_COLUMNS
+    ))
+    .bind(&host.name)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load plugins", e))?;
+
+    Ok(Json(plugins))
+}
+
+/// Register a plugin under an app
+///
+/// Either app is registered to the caller's organization if it doesn't
+/// exist yet. Its releases are uploaded like any app's.
+#[utoipa::path(
+    put,
+    path = "/apps/{name}/plugins/{plugin}",
+    params(
+        ("name" = String, Path, description = "Host application name"),
+        ("plugin" = String, Path, description = "Plugin name")
+    ),
+    responses(
+        (status = 200, description = "Plugin registered", body = App),
+        (status = 400, description = "The plugin is the host itself, has plugins of its own, or belongs to another organization than the host", body = ErrorBody),
+        (status = 403, description = "An app belongs to another organization", body = ErrorBody),
+        (status = 409, description = "Already a plugin of another app", body = ErrorBody)
+    )
+)]
+pub async fn register_plugin(
+    Path((name, plugin)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<App>> {
+    if plugin == name {
+        return Err(AppError::bad_request("An app can't be its own plugin"));
+    }
+    auth::claim_app(&state.pool, principal, &name).await?;
+    let host = visible_app(&state, principal, &name).await?;
+    if host.host_app.is_some() {
+        return Err(AppError::bad_request(format!(
+            "{} is a plugin itself; plugins can't have plugins",
+            host.name
+        )));
+    }
+    auth::claim_app(&state.pool, principal, &plugin).await?;
+    let existing = visible_app(&state, principal, &plugin).await?;
+    if existing.org_id != host.org_id {
+        return Err(AppError::bad_request(format!(
+            "{} and {} belong to different organizations",
+            plugin, host.name
+        )));
+    }
+    match existing.host_app.as_deref() {
+        Some(h) if h == host.name => return Ok(Json(existing)),
+        Some(h) => {
+            return Err(AppError::conflict(format!(
+                "{} is already a plugin of {}; unregister it first",
+                plugin, h
+            )));
+        }
+        None => {}
+    }
+    let has_plugins: bool =
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM apps WHERE host_app = ?)")
+            .bind(&plugin)
+            .fetch_one(&state.pool)
+            .await
+            .map_err(|e| AppError::internal("Failed to look up plugins", e))?;
+    if has_plugins {
+        return Err(AppError::bad_request(format!(
+            "{} has plugins of its own; plugins can't have plugins",
+            plugin
+        )));
+    }
+
+    let app = sqlx::query_as::<_, App>(&format!(
+        "UPDATE apps SET host_app = ? WHERE name = ? RETURNING {}",
+        APP_COLUMNS
+    ))
+    .bind(&host.name)
+    .bind(&plugin)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to register plugin", e))?;
+
+    println!("Registered {} as a plugin of {}", app.name, host.name);
+    Ok(Json(app))
+}
+
+/// Unregister a plugin
+///
+/// The plugin stays an app with its releases, but plugin update checks
+/// no longer find it.
+#[utoipa::path(
+    delete,
+    path = "/apps/{name}/plugins/{plugin}",
+    params(
+        ("name" = String, Path, description = "Host application name"),
+        ("plugin" = String, Path, description = "Plugin name")
+    ),
+    responses(
+        (status = 204, description = "Plugin unregistered"),
+        (status = 404, description = "No such plugin under this app", body = ErrorBody)
+    )
+)]
+pub async fn unregister_plugin(
+    Path((name, plugin)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let updated = sqlx::query(&format!(
+        "UPDATE apps SET host_app = NULL WHERE name = ?1 AND host_app = ?2 AND {}",
+        org_scope(3)
+    ))
+    .bind(&plugin)
+    .bind(&name)
+    .bind(principal.org_id())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to unregister plugin", e))?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::not_found(format!(
+            "{} is not a plugin of {}",
+            plugin, name
+        )));
+    }
+    println!("Unregistered plugin {} of {}", plugin, name);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,413 @@
+//! Automatic canary promotion.
+//!
+//! An app's promotion policy moves its releases from one channel to the
+//! next, `canary` to `stable` by default, once they have been live for
+//! `min_days` with install failures and rollbacks, as sent to `/reports`,
+//! within its thresholds. Every replica evaluates the policies each
+//! `PROMOTION_INTERVAL_SECS`; the database update decides which one acts.
+//!
+//! A release that breaches a threshold is held: it stays on its channel
+//! until someone promotes or yanks it by hand. Promotions and holds are
+//! recorded in `release_promotions` and emitted as `promoted` and `held`
+//! release events, so webhooks, chat and the event stream hear of both.
+//! Nothing is promoted during a publish freeze.
+
+use axum::{
+    Extension,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{Duration, Utc};
+
+use crate::auth::{self, Principal, app_scope};
+use crate::cache;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::events::{ReleaseEvent, ReleaseEventKind};
+use crate::freezes;
+use crate::reports::{self, OutcomeCounts};
+use crate::schema::{
+    AppState, PROMOTION_POLICY_COLUMNS, PromotionFilter, PromotionPolicy, PromotionPolicyRequest,
+    PromotionState, RELEASE_COLUMNS, RELEASE_PROMOTION_COLUMNS, Release, ReleasePromotion,
+    is_valid_channel,
+};
+
+const DEFAULT_FROM_CHANNEL: &str = "canary";
+const DEFAULT_TO_CHANNEL: &str = "stable";
+
+/// What a policy makes of a release today.
+enum Verdict {
+    /// Not live long enough, or too few reports, to tell yet.
+    Wait,
+    Promote(String),
+    Hold(String),
+}
+
+fn percent(rate: f64) -> String {
+    format!("{:.1}%", rate * 100.0)
+}
+
+fn judge(policy: &PromotionPolicy, release: &Release, counts: OutcomeCounts) -> Verdict {
+    // Rollbacks are counted, not averaged, so they can hold a release
+    // before there are enough reports to judge its failure rate.
+    if counts.rolled_back > policy.max_rollbacks {
+        return Verdict::Hold(format!(
+            "{} rollbacks, more than the {} allowed",
+            counts.rolled_back, policy.max_rollbacks
+        ));
+    }
+    if counts.total() < policy.min_reports {
+        return Verdict::Wait;
+    }
+    let failure_rate = counts.failure_rate();
+    if failure_rate > policy.max_failure_rate {
+        return Verdict::Hold(format!(
+            "failure rate {} over {} ({} of {} installs)",
+            percent(failure_rate),
+            percent(policy.max_failure_rate),
+            counts.failed + counts.rolled_back,
+            counts.total()
+        ));
+    }
+    let live = Utc::now() - release.pub_date;
+    if live < Duration::days(policy.min_days) {
+        return Verdict::Wait;
+    }
+    Verdict::Promote(format!(
+        "live {} days on {}, failure rate {} ({} installs), {} rollbacks",
+        live.num_days(),
+        policy.from_channel,
+        percent(failure_rate),
+        counts.total(),
+        counts.rolled_back
+    ))
+}
+
+/// Starts evaluating promotion policies in the background.
+pub fn spawn(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(state.config.promotion_interval).await;
+            if let Err(e) = run(&state).await {
+                println!("Promotion pass failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Evaluates every enabled policy once.
+pub async fn run(state: &AppState) -> Result<(), sqlx::Error> {
+    let policies = sqlx::query_as::<_, PromotionPolicy>(&format!(
+        "SELECT {} FROM promotion_policies WHERE enabled = 1",
+        PROMOTION_POLICY_COLUMNS
+    ))
+    .fetch_all(&state.pool)
+    .await?;
+
+    let now = Utc::now();
+    for policy in policies {
+        if freezes::active_freeze(&state.pool, &policy.app_name, now)
+            .await?
+            .is_some()
+        {
+            continue;
+        }
+        let candidates = sqlx::query_as::<_, Release>(&format!(
+            "SELECT {} FROM releases WHERE app_name = ? AND channel = ? AND yanked = 0 AND archived = 0 AND id NOT IN (SELECT release_id FROM release_promotions) ORDER BY id",
+            RELEASE_COLUMNS
+        ))
+        .bind(&policy.app_name)
+        .bind(&policy.from_channel)
+        .fetch_all(&state.pool)
+        .await?;
+
+        for release in candidates {
+            let counts = reports::outcome_counts(&state.pool, release.id)
+                .await?
+                .into_values()
+                .fold(OutcomeCounts::default(), |sum, c| OutcomeCounts {
+                    installed: sum.installed + c.installed,
+                    failed: sum.failed + c.failed,
+                    rolled_back: sum.rolled_back + c.rolled_back,
+                });
+            match judge(&policy, &release, counts) {
+                Verdict::Wait => {}
+                Verdict::Promote(reason) => promote(state, &policy, release, reason).await?,
+                Verdict::Hold(reason) => hold(state, &policy, release, reason).await?,
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn record(
+    conn: &mut sqlx::SqliteConnection,
+    policy: &PromotionPolicy,
+    release: &Release,
+    decision: PromotionState,
+    reason: &str,
+) -> Result<bool, sqlx::Error> {
+    let recorded = sqlx::query(
+        "INSERT INTO release_promotions (release_id, app_name, version, state, from_channel, to_channel, reason, decided_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (release_id) DO NOTHING",
+    )
+    .bind(release.id)
+    .bind(&release.app_name)
+    .bind(&release.version)
+    .bind(decision.as_str())
+    .bind(&policy.from_channel)
+    .bind(&policy.to_channel)
+    .bind(reason)
+    .bind(Utc::now())
+    .execute(conn)
+    .await?;
+    Ok(recorded.rows_affected() > 0)
+}
+
+async fn promote(
+    state: &AppState,
+    policy: &PromotionPolicy,
+    release: Release,
+    reason: String,
+) -> Result<(), sqlx::Error> {
+    let mut tx = state.pool.begin().await?;
+    // Another replica, or an admin, may have moved the release meanwhile.
+    let Some(promoted) = sqlx::query_as::<_, Release>(&format!(
+        "UPDATE releases SET channel = ? WHERE id = ? AND channel = ? AND yanked = 0 AND archived = 0 RETURNING {}",
+        RELEASE_COLUMNS
+    ))
+    .bind(&policy.to_channel)
+    .bind(release.id)
+    .bind(&policy.from_channel)
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(());
+    };
+    if !record(
+        &mut tx,
+        policy,
+        &promoted,
+        PromotionState::Promoted,
+        &reason,
+    )
+    .await?
+    {
+        return Ok(());
+    }
+    tx.commit().await?;
+
+    for channel in [&policy.from_channel, &policy.to_channel] {
+        state
+            .cache
+            .invalidate(&cache::latest_key(
+                &promoted.app_name,
+                &promoted.target,
+                &promoted.arch,
+                channel,
+            ))
+            .await;
+    }
+    println!(
+        "Promoted release {} ({} {}) to {}: {}",
+        promoted.id, promoted.app_name, promoted.version, promoted.channel, reason
+    );
+    let mut event = ReleaseEvent::new(ReleaseEventKind::Promoted, promoted);
+    event.previous_channel = Some(policy.from_channel.clone());
+    event.reason = Some(reason);
+    state.events.emit(event).await;
+    Ok(())
+}
+
+async fn hold(
+    state: &AppState,
+    policy: &PromotionPolicy,
+    release: Release,
+    reason: String,
+) -> Result<(), sqlx::Error> {
+    let mut conn = state.pool.acquire().await?;
+    let recorded = record(&mut conn, policy, &release, PromotionState::Held, &reason).await?;
+    drop(conn);
+    if !recorded {
+        return Ok(());
+    }
+    println!(
+        "Held release {} ({} {}) on {}: {}",
+        release.id, release.app_name, release.version, release.channel, reason
+    );
+    let mut event = ReleaseEvent::new(ReleaseEventKind::Held, release);
+    event.reason = Some(reason);
+    state.events.emit(event).await;
+    Ok(())
+}
+
+/// Get an app's promotion policy
+#[utoipa::path(
+    get,
+    path = "/apps/{name}/promotion-policy",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    responses(
+        (status = 200, description = "Promotion policy", body = PromotionPolicy),
+        (status = 404, description = "The app has no promotion policy", body = ErrorBody)
+    )
+)]
+pub async fn get_promotion_policy(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<PromotionPolicy>> {
+    let policy = sqlx::query_as::<_, PromotionPolicy>(&format!(
+        "SELECT {} FROM promotion_policies WHERE app_name = ?1 AND {}",
+        PROMOTION_POLICY_COLUMNS,
+        app_scope(2)
+    ))
+    .bind(&name)
+    .bind(principal.org_id())
+    .fetch_optional(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load the promotion policy", e))?
+    .ok_or_else(|| AppError::not_found("No promotion policy for this app"))?;
+
+    Ok(Json(policy))
+}
+
+/// Set an app's promotion policy
+///
+/// Releases already promoted or held by the previous policy keep that
+/// decision.
+#[utoipa::path(
+    put,
+    path = "/apps/{name}/promotion-policy",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    request_body = PromotionPolicyRequest,
+    responses(
+        (status = 200, description = "Policy saved", body = PromotionPolicy),
+        (status = 400, description = "Invalid channel or threshold", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody)
+    )
+)]
+pub async fn set_promotion_policy(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<PromotionPolicyRequest>,
+) -> AppResult<Json<PromotionPolicy>> {
+    let from_channel = request
+        .from_channel
+        .as_deref()
+        .unwrap_or(DEFAULT_FROM_CHANNEL);
+    let to_channel = request.to_channel.as_deref().unwrap_or(DEFAULT_TO_CHANNEL);
+    if !is_valid_channel(from_channel) || !is_valid_channel(to_channel) {
+        return Err(AppError::bad_request(
+            "from_channel and to_channel must be lowercase slugs (letters, digits, '-')",
+        ));
+    }
+    if from_channel == to_channel {
+        return Err(AppError::bad_request(
+            "from_channel and to_channel must differ",
+        ));
+    }
+    let min_reports = request.min_reports.unwrap_or(0);
+    if request.min_days < 0 || min_reports < 0 || request.max_rollbacks < 0 {
+        return Err(AppError::bad_request(
+            "min_days, min_reports and max_rollbacks cannot be negative",
+        ));
+    }
+    if !(0.0..=1.0).contains(&request.max_failure_rate) {
+        return Err(AppError::bad_request(
+            "max_failure_rate must be between 0 and 1",
+        ));
+    }
+    auth::claim_app(&state.pool, principal, &name).await?;
+
+    let policy = sqlx::query_as::<_, PromotionPolicy>(&format!(
+        "INSERT INTO promotion_policies (app_name, from_channel, to_channel, min_days, min_reports, max_failure_rate, max_rollbacks, enabled, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (app_name) DO UPDATE SET from_channel = excluded.from_channel, to_channel = excluded.to_channel, min_days = excluded.min_days, min_reports = excluded.min_reports, max_failure_rate = excluded.max_failure_rate, max_rollbacks = excluded.max_rollbacks, enabled = excluded.enabled, updated_at = excluded.updated_at RETURNING {}",
+        PROMOTION_POLICY_COLUMNS
+    ))
+    .bind(&name)
+    .bind(from_channel)
+    .bind(to_channel)
+    .bind(request.min_days)
+    .bind(min_reports)
+    .bind(request.max_failure_rate)
+    .bind(request.max_rollbacks)
+    .bind(request.enabled)
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to save the promotion policy", e))?;
+
+    println!(
+        "{} releases of {} move from {} to {} after {} days",
+        if policy.enabled { "Now" } else { "Paused:" },
+        policy.app_name,
+        policy.from_channel,
+        policy.to_channel,
+        policy.min_days
+    );
+    Ok(Json(policy))
+}
+
+/// Remove an app's promotion policy
+#[utoipa::path(
+    delete,
+    path = "/apps/{name}/promotion-policy",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    responses(
+        (status = 204, description = "Policy removed; releases are only promoted by hand"),
+        (status = 404, description = "The app has no promotion policy", body = ErrorBody)
+    )
+)]
+pub async fn delete_promotion_policy(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let deleted = sqlx::query(&format!(
+        "DELETE FROM promotion_policies WHERE app_name = ?1 AND {}",
+        app_scope(2)
+    ))
+    .bind(&name)
+    .bind(principal.org_id())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to delete the promotion policy", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("No promotion policy for this app"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List promotion decisions
+#[utoipa::path(
+    get,
+    path = "/promotions",
+    params(PromotionFilter),
+    responses(
+        (status = 200, description = "Releases promoted or held by promotion policies, newest first", body = Vec<ReleasePromotion>)
+    )
+)]
+pub async fn list_promotions(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<PromotionFilter>,
+) -> AppResult<Json<Vec<ReleasePromotion>>> {
+    let promotions = sqlx::query_as::<_, ReleasePromotion>(&format!(
+        "SELECT {} FROM release_promotions WHERE (?1 IS NULL OR app_name = ?1) AND (?2 IS NULL OR state = ?2) AND {} ORDER BY decided_at DESC, release_id DESC",
+        RELEASE_PROMOTION_COLUMNS,
+        app_scope(3)
+    ))
+    .bind(&filter.app_name)
+    .bind(filter.state.map(PromotionState::as_str))
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load promotions", e))?;
+
+    Ok(Json(promotions))
+}
@@ -0,0 +1,630 @@
+//! Upload quotas.
+//!
+//! Apps and organizations can be limited in the releases they keep, the
+//! artifact bytes those releases use and the uploads they make in 24
+//! hours. The operator sets organization quotas; organizations without one
+//! get the `QUOTA_MAX_*` defaults. Organizations may limit their own apps
+//! further. An upload must fit both its app's and its organization's quota:
+//! too many releases or uploads is answered with 429, too many bytes with
+//! 413.
+//!
+//! Storage counts each artifact once, however many releases share it, so an
+//! upload of bytes the app or organization already stores is free.
+//!
+//! Uploads are checked before they're stored, and again in the transaction
+//! that saves them, which also counts them. The write pool has a single
+//! connection, so two uploads can't both take the last of a quota.
+
+use axum::{
+    Extension,
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{Duration, Utc};
+use sqlx::{Pool, Sqlite, SqliteConnection};
+
+use crate::auth::{self, Principal};
+use crate::config::Config;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::schema::{AppState, QUOTA_LIMIT_COLUMNS, QuotaLimits, QuotaUsage};
+
+/// Apps of the quota's subject, with the app name bound at `?1` or the
+/// organization at `?2`.
+const SUBJECT_APPS: &str = "SELECT name FROM apps WHERE name = ?1 OR org_id = ?2";
+
+/// An app or an organization.
+struct Subject {
+    app_name: Option<String>,
+    org_id: Option<i64>,
+    /// How errors name it.
+    label: String,
+}
+
+impl Subject {
+    fn app(name: &str) -> Self {
+        Self {
+            app_name: Some(name.to_string()),
+            org_id: None,
+            label: name.to_string(),
+        }
+    }
+
+    fn org(id: i64, slug: &str) -> Self {
+        Self {
+            app_name: None,
+            org_id: Some(id),
+            label: format!("Organization {}", slug),
+        }
+    }
+}
+
+fn default_org_limits(config: &Config) -> QuotaLimits {
+    QuotaLimits {
+        max_releases: config.quota_max_releases,
+        max_storage_bytes: config.quota_max_storage_bytes,
+        max_uploads_per_day: config.quota_max_uploads_per_day,
+    }
+}
+
+async fn limits(
+    conn: &mut SqliteConnection,
+    config: &Config,
+    subject: &Subject,
+) -> Result<QuotaLimits, sqlx::Error> {
+    match (&subject.app_name, subject.org_id) {
+        (Some(app_name), _) => Ok(sqlx::query_as::<_, QuotaLimits>(&format!(
+            "SELECT {} FROM app_quotas WHERE app_name = ?",
+            QUOTA_LIMIT_COLUMNS
+        ))
+        .bind(app_name)
+        .fetch_optional(conn)
+        .await?
+        .unwrap_or_default()),
+        (None, org_id) => Ok(sqlx::query_as::<_, QuotaLimits>(&format!(
+            "SELECT {} FROM org_quotas WHERE org_id = ?",
+            QUOTA_LIMIT_COLUMNS
+        ))
+        .bind(org_id)
+        .fetch_optional(conn)
+        .await?
+        .unwrap_or_else(|| default_org_limits(config))),
+    }
+}
+
+/// Releases, stored bytes and uploads in the last 24 hours of `subject`, and
+/// whether it already stores the artifact `sha256`.
+async fn usage(
+    conn: &mut SqliteConnection,
+    subject: &Subject,
+    sha256: Option<&str>,
+) -> Result<(i64, i64, i64, bool), sqlx::Error> {
+    let stored = format!(
+        "SELECT sha256 FROM releases WHERE app_name IN ({0}) UNION SELECT v.sha256 FROM release_variants v JOIN releases r ON r.id = v.release_id WHERE r.app_name IN ({0}) UNION SELECT sha256 FROM staged_releases WHERE app_name IN ({0}) UNION SELECT sha256 FROM bundles WHERE app_name IN ({0}) UNION SELECT sha256 FROM web_bundles WHERE app_name IN ({0}) UNION SELECT c.sha256 FROM release_components c JOIN releases r ON r.id = c.release_id WHERE r.app_name IN ({0}) UNION SELECT d.sha256 FROM release_deltas d JOIN releases r ON r.id = d.release_id WHERE r.app_name IN ({0}) UNION SELECT a.sha256 FROM release_attachments a JOIN releases r ON r.id = a.release_id WHERE r.app_name IN ({0})",
+        SUBJECT_APPS
+    );
+    sqlx::query_as(&format!(
+        "SELECT (SELECT COUNT(*) FROM releases WHERE app_name IN ({0})), (SELECT COALESCE(SUM(size), 0) FROM artifacts WHERE sha256 IN ({1}) AND org_id IN (SELECT org_id FROM apps WHERE name IN ({0}))), (SELECT COUNT(*) FROM uploads WHERE app_name IN ({0}) AND created_at > ?3), COALESCE(?4 IN ({1}), 0)",
+        SUBJECT_APPS, stored
+    ))
+    .bind(&subject.app_name)
+    .bind(subject.org_id)
+    .bind(Utc::now() - Duration::days(1))
+    .bind(sha256)
+    .fetch_one(conn)
+    .await
+}
+
+/// Why the upload doesn't fit the quota of `subject`, if it doesn't.
+async fn exceeded(
+    conn: &mut SqliteConnection,
+    config: &Config,
+    subject: &Subject,
+    sha256: &str,
+    size: i64,
+    adds_release: bool,
+) -> Result<Option<AppError>, sqlx::Error> {
+    let limits = limits(&mut *conn, config, subject).await?;
+    if limits.max_releases.is_none()
+        && limits.max_storage_bytes.is_none()
+        && limits.max_uploads_per_day.is_none()
+    {
+        return Ok(None);
+    }
+    let (releases, storage_bytes, uploads, already_stored) =
+        usage(conn, subject, Some(sha256)).await?;
+
+    if let Some(max) = limits.max_uploads_per_day
+        && uploads >= max
+    {
+        return Ok(Some(AppError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "quota_exceeded",
+            format!(
+                "{} has made {} uploads in the last 24 hours, the most its quota allows",
+                subject.label, uploads
+            ),
+        )));
+    }
+    if let Some(max) = limits.max_releases
+        && adds_release
+        && releases >= max
+    {
+        return Ok(Some(AppError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "quota_exceeded",
+            format!(
+                "{} keeps {} releases, the most its quota allows; delete old releases to upload more",
+                subject.label, releases
+            ),
+        )));
+    }
+    if let Some(max) = limits.max_storage_bytes
+        && !already_stored
+        && storage_bytes + size > max
+    {
+        return Ok(Some(AppError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "quota_exceeded",
+            format!(
+                "{} stores {} of its {} bytes; this {}-byte upload doesn't fit",
+                subject.label, storage_bytes, max, size
+            ),
+        )));
+    }
+    Ok(None)
+}
+
+/// Why the upload doesn't fit the quotas of `app_name` or of its
+/// organization, if it doesn't.
+async fn refusal(
+    conn: &mut SqliteConnection,
+    config: &Config,
+    app_name: &str,
+    sha256: &str,
+    size: i64,
+    adds_release: bool,
+) -> Result<Option<AppError>, sqlx::Error> {
+    let app = Subject::app(app_name);
+    if let Some(refused) = exceeded(&mut *conn, config, &app, sha256, size, adds_release).await? {
+        return Ok(Some(refused));
+    }
+
+    let org: Option<(i64, String)> = sqlx::query_as(
+        "SELECT o.id, o.slug FROM organizations o JOIN apps a ON a.org_id = o.id WHERE a.name = ?",
+    )
+    .bind(app_name)
+    .fetch_optional(&mut *conn)
+    .await?;
+    match org {
+        Some((org_id, slug)) => {
+            let org = Subject::org(org_id, &slug);
+            exceeded(conn, config, &org, sha256, size, adds_release).await
+        }
+        None => Ok(None),
+    }
+}
+
+/// Refuses an upload that doesn't fit the quotas of `app_name` or of its
+/// organization, before it's stored. Variant uploads add bytes but no
+/// release.
+pub async fn check_upload(
+    state: &AppState,
+    app_name: &str,
+    sha256: &str,
+    size: i64,
+    adds_release: bool,
+) -> AppResult<()> {
+    let checked = async {
+        let mut conn = state.pool.acquire().await?;
+        refusal(
+            &mut conn,
+            &state.config,
+            app_name,
+            sha256,
+            size,
+            adds_release,
+        )
+        .await
+    }
+    .await;
+    match checked.map_err(|e| AppError::internal("Failed to check quotas", e))? {
+        Some(refused) => Err(refused),
+        None => Ok(()),
+    }
+}
+
+/// Checks the upload again and counts it against the daily limits, in the
+/// transaction saving it and before its rows are written. Returns the
+/// refusal instead when other uploads used up the quota meanwhile; the
+/// caller then rolls back.
+pub async fn record_upload(
+    conn: &mut SqliteConnection,
+    config: &Config,
+    app_name: &str,
+    sha256: &str,
+    size: i64,
+    adds_release: bool,
+) -> Result<Option<AppError>, sqlx::Error> {
+    if let Some(refused) = refusal(&mut *conn, config, app_name, sha256, size, adds_release).await?
+    {
+        return Ok(Some(refused));
+    }
+    let now = Utc::now();
+    sqlx::query("INSERT INTO uploads (app_name, size, created_at) VALUES (?, ?, ?)")
+        .bind(app_name)
+        .bind(size)
+        .bind(now)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM uploads WHERE created_at <= ?")
+        .bind(now - Duration::days(1))
+        .execute(conn)
+        .await?;
+    Ok(None)
+}
+
+fn validate(limits: &QuotaLimits) -> AppResult<()> {
+    let values = [
+        limits.max_releases,
+        limits.max_storage_bytes,
+        limits.max_uploads_per_day,
+    ];
+    if values.into_iter().flatten().any(|v| v < 0) {
+        return Err(AppError::bad_request("Quota limits cannot be negative"));
+    }
+    Ok(())
+}
+
+async fn quota_usage(
+    pool: &Pool<Sqlite>,
+    config: &Config,
+    subject: &Subject,
+) -> Result<QuotaUsage, sqlx::Error> {
+    let mut conn = pool.acquire().await?;
+    let limits = limits(&mut conn, config, subject).await?;
+    let (releases, storage_bytes, uploads_last_day, _) = usage(&mut conn, subject, None).await?;
+    Ok(QuotaUsage {
+        releases,
+        storage_bytes,
+        uploads_last_day,
+        limits,
+    })
+}
+
+/// Usage and limits of the organization `id`.
+pub async fn org_usage(
+    pool: &Pool<Sqlite>,
+    config: &Config,
+    id: i64,
+    slug: &str,
+) -> Result<QuotaUsage, sqlx::Error> {
+    quota_usage(pool, config, &Subject::org(id, slug)).await
+}
+
+async fn report(state: &AppState, subject: Subject) -> AppResult<Json<QuotaUsage>> {
+    let usage = quota_usage(&state.read_pool, &state.config, &subject)
+        .await
+        .map_err(|e| AppError::internal("Failed to load usage", e))?;
+    Ok(Json(usage))
+}
+
+async fn visible_org(state: &AppState, principal: Principal, id: i64) -> AppResult<Subject> {
+    let slug: String = sqlx::query_scalar("SELECT slug FROM organizations WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&state.read_pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to load organization", e))?
+        .filter(|_| principal.can_see(id))
+        .ok_or_else(|| AppError::not_found("Organization not found"))?;
+    Ok(Subject::org(id, &slug))
+}
+
+async fn visible_app(state: &AppState, principal: Principal, name: &str) -> AppResult<Subject> {
+    sqlx::query_scalar::<_, i64>("SELECT org_id FROM apps WHERE name = ?")
+        .bind(name)
+        .fetch_optional(&state.read_pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to load app", e))?
+        .filter(|org_id| principal.can_see(*org_id))
+        .ok_or_else(|| AppError::not_found("App not found"))?;
+    Ok(Subject::app(name))
+}
+
+/// Get an organization's quota usage
+#[utoipa::path(
+    get,
+    path = "/orgs/{id}/usage",
+    params(
+        ("id" = i64, Path, description = "Organization ID")
+    ),
+    responses(
+        (status = 200, description = "Usage of every app of the organization, and the organization's limits", body = QuotaUsage),
+        (status = 404, description = "Organization not found", body = ErrorBody)
+    )
+)]
+pub async fn get_org_usage(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<QuotaUsage>> {
+    let subject = visible_org(&state, principal, id).await?;
+    report(&state, subject).await
+}
+
+/// Set an organization's quota
+///
+/// Replaces the deployment's default quota for this organization.
+#[utoipa::path(
+    put,
+    path = "/orgs/{id}/quota",
+    params(
+        ("id" = i64, Path, description = "Organization ID")
+    ),
+    request_body = QuotaLimits,
+    responses(
+        (status = 200, description = "Quota saved", body = QuotaLimits),
+        (status = 400, description = "Negative limit", body = ErrorBody),
+        (status = 403, description = "Not the operator token", body = ErrorBody),
+        (status = 404, description = "Organization not found", body = ErrorBody)
+    )
+)]
+pub async fn set_org_quota(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<QuotaLimits>,
+) -> AppResult<Json<QuotaLimits>> {
+    principal.require_operator()?;
+    validate(&request)?;
+    let subject = visible_org(&state, principal, id).await?;
+
+    let quota = sqlx::query_as::<_, QuotaLimits>(&format!(
+        "INSERT INTO org_quotas (org_id, max_releases, max_storage_bytes, max_uploads_per_day, updated_at) VALUES (?, ?, ?, ?, ?) ON CONFLICT (org_id) DO UPDATE SET max_releases = excluded.max_releases, max_storage_bytes = excluded.max_storage_bytes, max_uploads_per_day = excluded.max_uploads_per_day, updated_at = excluded.updated_at RETURNING {}",
+        QUOTA_LIMIT_COLUMNS
+    ))
+    .bind(id)
+    .bind(request.max_releases)
+    .bind(request.max_storage_bytes)
+    .bind(request.max_uploads_per_day)
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to save the quota", e))?;
+
+    println!("{} has a quota of its own now", subject.label);
+    Ok(Json(quota))
+}
+
+/// Remove an organization's quota
+#[utoipa::path(
+    delete,
+    path = "/orgs/{id}/quota",
+    params(
+        ("id" = i64, Path, description = "Organization ID")
+    ),
+    responses(
+        (status = 204, description = "Quota removed; the deployment's default applies again"),
+        (status = 403, description = "Not the operator token", body = ErrorBody),
+        (status = 404, description = "Organization not found or without a quota", body = ErrorBody)
+    )
+)]
+pub async fn delete_org_quota(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    principal.require_operator()?;
+    let deleted = sqlx::query("DELETE FROM org_quotas WHERE org_id = ?")
+        .bind(id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to delete the quota", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("Organization has no quota of its own"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Get an app's quota usage
+#[utoipa::path(
+    get,
+    path = "/apps/{name}/usage",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    responses(
+        (status = 200, description = "Usage of the app and its own limits; its organization's apply too", body = QuotaUsage),
+        (status = 404, description = "App not found", body = ErrorBody)
+    )
+)]
+pub async fn get_app_usage(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<QuotaUsage>> {
+    let subject = visible_app(&state, principal, &name).await?;
+    report(&state, subject).await
+}
+
+/// Set an app's quota
+#[utoipa::path(
+    put,
+    path = "/apps/{name}/quota",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    request_body = QuotaLimits,
+    responses(
+        (status = 200, description = "Quota saved", body = QuotaLimits),
+        (status = 400, description = "Negative limit", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody)
+    )
+)]
+pub async fn set_app_quota(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<QuotaLimits>,
+) -> AppResult<Json<QuotaLimits>> {
+    validate(&request)?;
+    auth::claim_app(&state.pool, principal, &name).await?;
+
+    let quota = sqlx::query_as::<_, QuotaLimits>(&format!(
+        "INSERT INTO app_quotas (app_name, max_releases, max_storage_bytes, max_uploads_per_day, updated_at) VALUES (?, ?, ?, ?, ?) ON CONFLICT (app_name) DO UPDATE SET max_releases = excluded.max_releases, max_storage_bytes = excluded.max_storage_bytes, max_uploads_per_day = excluded.max_uploads_per_day, updated_at = excluded.updated_at RETURNING {}",
+        QUOTA_LIMIT_COLUMNS
+    ))
+    .bind(&name)
+    .bind(request.max_releases)
+    .bind(request.max_storage_bytes)
+    .bind(request.max_uploads_per_day)
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to save the quota", e))?;
+
+    println!("Set the quota of {}", name);
+    Ok(Json(quota))
+}
+
+/// Remove an app's quota
+#[utoipa::path(
+    delete,
+    path = "/apps/{name}/quota",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    responses(
+        (status = 204, description = "Quota removed; only its organization's applies"),
+        (status = 404, description = "App not found or without a quota", body = ErrorBody)
+    )
+)]
+pub async fn delete_app_quota(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    visible_app(&state, principal, &name).await?;
+    let deleted = sqlx::query("DELETE FROM app_quotas WHERE app_name = ?")
+        .bind(&name)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to delete the quota", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("App has no quota"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn config(quota: QuotaLimits) -> Config {
+        let mut config = Config::from_env();
+        config.quota_max_releases = quota.max_releases;
+        config.quota_max_storage_bytes = quota.max_storage_bytes;
+        config.quota_max_uploads_per_day = quota.max_uploads_per_day;
+        config
+    }
+
+    async fn pool() -> Pool<Sqlite> {
+        let pool = db::memory_pool().await;
+        auth::claim_app(&pool, Principal::Operator, "classprime")
+            .await
+            .unwrap();
+        pool
+    }
+
+    async fn add_release(pool: &Pool<Sqlite>, version: &str, sha256: &str) {
+        sqlx::query(
+            "INSERT INTO releases (app_name, target, arch, version, url, signature, pub_date, notes, sha256) VALUES ('classprime', 'darwin', 'aarch64', ?, 'https://example.com/a', 'sig', ?, '', ?)",
+        )
+        .bind(version)
+        .bind(Utc::now())
+        .bind(sha256)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    /// The status of the refusal, if the upload is refused.
+    async fn refused(
+        pool: &Pool<Sqlite>,
+        config: &Config,
+        sha256: &str,
+        size: i64,
+        adds_release: bool,
+    ) -> Option<StatusCode> {
+        let mut conn = pool.acquire().await.unwrap();
+        record_upload(&mut conn, config, "classprime", sha256, size, adds_release)
+            .await
+            .unwrap()
+            .map(|e| {
+                assert_eq!(e.code(), "quota_exceeded");
+                e.status()
+            })
+    }
+
+    #[tokio::test]
+    async fn uploads_are_limited_per_24_hours() {
+        let pool = pool().await;
+        let config = config(QuotaLimits {
+            max_uploads_per_day: Some(2),
+            ..Default::default()
+        });
+        sqlx::query("INSERT INTO uploads (app_name, size, created_at) VALUES ('classprime', 1, ?)")
+            .bind(Utc::now() - Duration::hours(25))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(refused(&pool, &config, "a", 1, true).await, None);
+        assert_eq!(refused(&pool, &config, "b", 1, true).await, None);
+        assert_eq!(
+            refused(&pool, &config, "c", 1, true).await,
+            Some(StatusCode::TOO_MANY_REQUESTS)
+        );
+    }
+
+    #[tokio::test]
+    async fn release_limit_only_limits_uploads_adding_releases() {
+        let pool = pool().await;
+        let config = config(QuotaLimits::default());
+        sqlx::query("INSERT INTO app_quotas (app_name, max_releases, updated_at) VALUES ('classprime', 1, ?)")
+            .bind(Utc::now())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(refused(&pool, &config, "a", 1, true).await, None);
+        add_release(&pool, "1.0.0", "a").await;
+        assert_eq!(
+            refused(&pool, &config, "b", 1, true).await,
+            Some(StatusCode::TOO_MANY_REQUESTS)
+        );
+        assert_eq!(refused(&pool, &config, "b", 1, false).await, None);
+    }
+
+    #[tokio::test]
+    async fn storage_limit_counts_stored_bytes_once() {
+        let pool = pool().await;
+        let config = config(QuotaLimits {
+            max_storage_bytes: Some(100),
+            ..Default::default()
+        });
+        sqlx::query("INSERT INTO artifacts (org_id, sha256, url, size, ref_count) VALUES (1, 'a', 'https://example.com/a', 60, 1)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        add_release(&pool, "1.0.0", "a").await;
+
+        assert_eq!(refused(&pool, &config, "b", 40, true).await, None);
+        assert_eq!(
+            refused(&pool, &config, "b", 41, true).await,
+            Some(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        assert_eq!(refused(&pool, &config, "a", 500, true).await, None);
+    }
+}
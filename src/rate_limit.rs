@@ -0,0 +1,146 @@
+//! Per-client rate limiting of update checks and `/latest`.
+//!
+//! With `RATE_LIMIT_PER_MINUTE` set, a client gets that many requests to
+//! the routes installed apps call per minute; more are answered with 429
+//! and `Retry-After` until the minute is over. Clients are told apart by
+//! the header the edge proxy names in `RATE_LIMIT_CLIENT_HEADER` (e.g.
+//! `X-Forwarded-For`), else by the peer address.
+//!
+//! Counters live in Redis when `REDIS_URL` is set, so every replica counts
+//! against the same limit; each minute has its own key, counted with INCR
+//! and expired once the minute is over. Without Redis, or while it is
+//! unreachable, each replica counts on its own.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::config::Config;
+use crate::error::AppError;
+use crate::redis::RedisClient;
+use crate::schema::AppState;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+pub struct RateLimiter {
+    /// `None` when rate limiting is off.
+    limit: Option<u64>,
+    client_header: Option<String>,
+    redis: Option<RedisClient>,
+    /// Client to its window and count, when not counted in Redis.
+    local: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        let limit = config.rate_limit_per_minute.filter(|limit| *limit > 0);
+        let redis = match (limit, config.redis_url.as_deref()) {
+            (Some(_), Some(url)) => match RedisClient::new(url) {
+                Ok(client) => {
+                    println!("Using Redis for rate-limit counters");
+                    Some(client)
+                }
+                Err(e) => {
+                    println!("Invalid REDIS_URL, counting rate limits locally: {}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        Self {
+            limit,
+            client_header: config.rate_limit_client_header.clone(),
+            redis,
+            local: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Who is asking: the first address in the proxy's header, else the peer.
+    fn client(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<String> {
+        let forwarded = self
+            .client_header
+            .as_deref()
+            .and_then(|name| headers.get(name))
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .map(str::trim)
+            .filter(|v| !v.is_empty());
+        match forwarded {
+            Some(client) => Some(client.to_string()),
+            None => peer.map(|addr| addr.ip().to_string()),
+        }
+    }
+
+    /// Counts a request of `client` in `window`, returning its count so far.
+    async fn count(&self, client: &str, window: u64) -> u64 {
+        if let Some(redis) = &self.redis {
+            let key = format!("updater:ratelimit:{}:{}", client, window);
+            match redis.incr(&key).await {
+                Ok(count) => {
+                    // Twice the window, so a clock running behind on another
+                    // replica doesn't find the key already gone.
+                    if count == 1
+                        && let Err(e) = redis.expire(&key, WINDOW * 2).await
+                    {
+                        println!("Redis EXPIRE {} failed: {}", key, e);
+                    }
+                    return count.max(0) as u64;
+                }
+                Err(e) => println!("Redis INCR {} failed, counting locally: {}", key, e),
+            }
+        }
+
+        let mut local = self.local.lock().unwrap();
+        local.retain(|_, (w, _)| *w == window);
+        let (_, count) = local.entry(client.to_string()).or_insert((window, 0));
+        *count += 1;
+        *count
+    }
+}
+
+/// Middleware for the routes clients call.
+pub async fn limit(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let limiter = &state.rate_limiter;
+    let Some(limit) = limiter.limit else {
+        return next.run(request).await;
+    };
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0);
+    let Some(client) = limiter.client(request.headers(), peer) else {
+        return next.run(request).await;
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let window = now / WINDOW.as_secs();
+    if limiter.count(&client, window).await <= limit {
+        return next.run(request).await;
+    }
+
+    let retry_after = (window + 1) * WINDOW.as_secs() - now;
+    let mut response = AppError::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        "rate_limited",
+        format!(
+            "More than {} requests a minute; retry in {} seconds",
+            limit, retry_after
+        ),
+    )
+    .into_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    response
+}
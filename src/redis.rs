@@ -0,0 +1,242 @@
+//! Minimal Redis (RESP2) client covering the handful of commands the cache,
+//! the event bus and the rate limiter need.
+
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, mpsc};
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+#[derive(Debug, Clone)]
+struct Endpoint {
+    addr: String,
+    password: Option<String>,
+    db: u32,
+}
+
+impl Endpoint {
+    fn parse(redis_url: &str) -> io::Result<Self> {
+        let url = url::Url::parse(redis_url).map_err(|e| invalid(e.to_string()))?;
+        if url.scheme() != "redis" {
+            return Err(invalid(format!("unsupported scheme '{}'", url.scheme())));
+        }
+        let host = url.host_str().unwrap_or("127.0.0.1");
+        let port = url.port().unwrap_or(6379);
+        let db = url
+            .path()
+            .trim_start_matches('/')
+            .parse()
+            .unwrap_or_default();
+        Ok(Self {
+            addr: format!("{}:{}", host, port),
+            password: url.password().map(str::to_string),
+            db,
+        })
+    }
+
+    async fn connect(&self) -> io::Result<BufStream<TcpStream>> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        let mut conn = BufStream::new(stream);
+        if let Some(password) = &self.password {
+            send(&mut conn, &["AUTH", password]).await?;
+        }
+        if self.db != 0 {
+            send(&mut conn, &["SELECT", &self.db.to_string()]).await?;
+        }
+        Ok(conn)
+    }
+}
+
+pub struct RedisClient {
+    endpoint: Endpoint,
+    conn: Mutex<Option<BufStream<TcpStream>>>,
+}
+
+impl RedisClient {
+    pub fn new(redis_url: &str) -> io::Result<Self> {
+        Ok(Self {
+            endpoint: Endpoint::parse(redis_url)?,
+            conn: Mutex::new(None),
+        })
+    }
+
+    /// Runs a single command, reconnecting lazily. A failed command drops the
+    /// connection so the next call starts from a clean stream.
+    pub async fn command(&self, args: &[&str]) -> io::Result<Reply> {
+        let mut guard = self.conn.lock().await;
+        let result = tokio::time::timeout(COMMAND_TIMEOUT, async {
+            if guard.is_none() {
+                *guard = Some(self.endpoint.connect().await?);
+            }
+            send(guard.as_mut().unwrap(), args).await
+        })
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "redis timeout")));
+
+        if result.is_err() {
+            *guard = None;
+        }
+        result
+    }
+
+    pub async fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.command(&["GET", key]).await? {
+            Reply::Bulk(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn set_ex(&self, key: &str, value: &str, ttl: Duration) -> io::Result<()> {
+        let secs = ttl.as_secs().max(1).to_string();
+        match self.command(&["SET", key, value, "EX", &secs]).await? {
+            Reply::Status(status) if status == "OK" => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Increments the counter at `key`, creating it at 1.
+    pub async fn incr(&self, key: &str) -> io::Result<i64> {
+        match self.command(&["INCR", key]).await? {
+            Reply::Integer(count) => Ok(count),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn expire(&self, key: &str, ttl: Duration) -> io::Result<()> {
+        let secs = ttl.as_secs().max(1).to_string();
+        match self.command(&["EXPIRE", key, &secs]).await? {
+            Reply::Integer(_) => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn del(&self, key: &str) -> io::Result<()> {
+        self.command(&["DEL", key]).await?;
+        Ok(())
+    }
+
+    /// Returns the number of subscribers that received the message.
+    pub async fn publish(&self, channel: &str, message: &str) -> io::Result<i64> {
+        match self.command(&["PUBLISH", channel, message]).await? {
+            Reply::Integer(receivers) => Ok(receivers),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Subscribes to `channel` on a dedicated connection and forwards every
+    /// message payload. The connection is re-established after failures.
+    pub fn subscribe(&self, channel: &str) -> mpsc::UnboundedReceiver<String> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let endpoint = self.endpoint.clone();
+        let channel = channel.to_string();
+
+        tokio::spawn(async move {
+            loop {
+                if tx.is_closed() {
+                    return;
+                }
+                if let Err(e) = pump_subscription(&endpoint, &channel, &tx).await {
+                    println!("Redis subscription to {} lost: {}", channel, e);
+                }
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+        });
+
+        rx
+    }
+}
+
+async fn pump_subscription(
+    endpoint: &Endpoint,
+    channel: &str,
+    tx: &mpsc::UnboundedSender<String>,
+) -> io::Result<()> {
+    let mut conn = endpoint.connect().await?;
+    send(&mut conn, &["SUBSCRIBE", channel]).await?;
+    println!("Subscribed to Redis channel {}", channel);
+
+    loop {
+        if let Reply::Array(parts) = read_reply(&mut conn).await?
+            && let [Reply::Bulk(Some(kind)), _, Reply::Bulk(Some(payload))] = parts.as_slice()
+            && kind == b"message"
+            && tx
+                .send(String::from_utf8_lossy(payload).into_owned())
+                .is_err()
+        {
+            return Ok(());
+        }
+    }
+}
+
+async fn send(conn: &mut BufStream<TcpStream>, args: &[&str]) -> io::Result<Reply> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+    conn.write_all(&buf).await?;
+    conn.flush().await?;
+    read_reply(conn).await
+}
+
+async fn read_reply(conn: &mut BufStream<TcpStream>) -> io::Result<Reply> {
+    let mut line = String::new();
+    if conn.read_line(&mut line).await? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "redis closed connection",
+        ));
+    }
+    let line = line.trim_end_matches("\r\n");
+    let (kind, rest) = line.split_at(1.min(line.len()));
+
+    match kind {
+        "+" => Ok(Reply::Status(rest.to_string())),
+        "-" => Err(io::Error::other(format!("redis error: {}", rest))),
+        ":" => Ok(Reply::Integer(parse_len(rest)?)),
+        "$" => {
+            let len = parse_len(rest)?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut data = vec![0u8; len as usize + 2];
+            conn.read_exact(&mut data).await?;
+            data.truncate(len as usize);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        "*" => {
+            let len = parse_len(rest)?;
+            let mut items = Vec::with_capacity(len.max(0) as usize);
+            for _ in 0..len.max(0) {
+                items.push(Box::pin(read_reply(conn)).await?);
+            }
+            Ok(Reply::Array(items))
+        }
+        _ => Err(invalid(format!("unexpected redis reply: {}", line))),
+    }
+}
+
+fn parse_len(s: &str) -> io::Result<i64> {
+    s.parse()
+        .map_err(|_| invalid(format!("bad length '{}'", s)))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn unexpected(reply: Reply) -> io::Error {
+    invalid(format!("unexpected reply {:?}", reply))
+}
@@ -0,0 +1,149 @@
+//! Install reports.
+//!
+//! After trying an update, clients report whether it installed, failed or
+//! was rolled back. Reports feed the per-variant metrics of A/B tested
+//! releases. They are unauthenticated like update checks, so a client only
+//! counts once per release and outcome when it sends a `client_id`.
+
+use std::collections::BTreeMap;
+
+use axum::{extract::State, http::StatusCode, response::Json};
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::schema::{AppState, DEFAULT_CHANNEL, InstallOutcome, InstallReportRequest};
+use crate::variants::{VARIANT_A, VARIANT_B};
+
+/// Longest error message kept from a report.
+const MAX_MESSAGE_LEN: usize = 500;
+
+/// Reported installs of one variant.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutcomeCounts {
+    pub installed: i64,
+    pub failed: i64,
+    pub rolled_back: i64,
+}
+
+impl OutcomeCounts {
+    pub fn total(&self) -> i64 {
+        self.installed + self.failed + self.rolled_back
+    }
+
+    /// Failed and rolled-back installs over all reported ones.
+    pub fn failure_rate(&self) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => (self.failed + self.rolled_back) as f64 / total as f64,
+        }
+    }
+}
+
+/// Outcome counts of a release's install reports, by variant.
+pub async fn outcome_counts(
+    pool: &Pool<Sqlite>,
+    release_id: i64,
+) -> Result<BTreeMap<String, OutcomeCounts>, sqlx::Error> {
+    let rows: Vec<(String, String, i64)> = sqlx::query_as(
+        "SELECT variant, outcome, COUNT(*) FROM install_reports WHERE release_id = ? GROUP BY variant, outcome",
+    )
+    .bind(release_id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut counts: BTreeMap<String, OutcomeCounts> = BTreeMap::new();
+    for (variant, outcome, count) in rows {
+        let entry = counts.entry(variant).or_default();
+        match outcome.as_str() {
+            "installed" => entry.installed += count,
+            "failed" => entry.failed += count,
+            "rolled_back" => entry.rolled_back += count,
+            _ => {}
+        }
+    }
+    Ok(counts)
+}
+
+/// Report how an update went
+#[utoipa::path(
+    post,
+    path = "/reports",
+    security(()),
+    request_body = InstallReportRequest,
+    responses(
+        (status = 204, description = "Report recorded"),
+        (status = 400, description = "Invalid variant", body = ErrorBody),
+        (status = 404, description = "No such release", body = ErrorBody)
+    )
+)]
+pub async fn submit_report(
+    State(state): State<AppState>,
+    Json(report): Json<InstallReportRequest>,
+) -> AppResult<StatusCode> {
+    let variant = match report.variant.as_deref().map(str::trim) {
+        None | Some("") => VARIANT_A,
+        Some(v) if v.eq_ignore_ascii_case(VARIANT_A) => VARIANT_A,
+        Some(v) if v.eq_ignore_ascii_case(VARIANT_B) => VARIANT_B,
+        Some(_) => return Err(AppError::bad_request("variant must be a or b")),
+    };
+    let channel = report
+        .channel
+        .as_deref()
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .unwrap_or(DEFAULT_CHANNEL);
+
+    // Yanked releases still take reports: failures are often why they were
+    // yanked.
+    let release_id: i64 = sqlx::query_scalar(
+        "SELECT id FROM releases WHERE app_name = ? AND target = ? AND arch = ? AND version = ? AND channel = ? ORDER BY id DESC LIMIT 1",
+    )
+    .bind(&report.app_name)
+    .bind(&report.target)
+    .bind(&report.arch)
+    .bind(&report.version)
+    .bind(channel)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to look up the release", e))?
+    .ok_or_else(|| AppError::not_found("Release not found"))?;
+
+    let client_id = report
+        .client_id
+        .as_deref()
+        .map(str::trim)
+        .filter(|c| !c.is_empty());
+    let message: Option<String> = report
+        .message
+        .as_deref()
+        .map(|m| m.trim().chars().take(MAX_MESSAGE_LEN).collect())
+        .filter(|m: &String| !m.is_empty());
+
+    sqlx::query(
+        "INSERT INTO install_reports (release_id, variant, outcome, client_id, message, created_at) VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT (release_id, client_id, outcome) DO NOTHING",
+    )
+    .bind(release_id)
+    .bind(variant)
+    .bind(report.outcome.as_str())
+    .bind(client_id)
+    .bind(&message)
+    .bind(Utc::now())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to record the report", e))?;
+
+    if report.outcome != InstallOutcome::Installed {
+        println!(
+            "Install of {} {} ({}/{}, variant {}) {}: {}",
+            report.app_name,
+            report.version,
+            report.target,
+            report.arch,
+            variant,
+            report.outcome.as_str(),
+            message.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
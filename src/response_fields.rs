@@ -0,0 +1,159 @@
+//! Per-app field names for update answers.
+//!
+//! Some older clients expect the fields of an update check or `/latest`
+//! answer under other names, such as `download_url` for `url` or
+//! `changelog` for `notes`. An app can map any top-level field to the name
+//! its clients read; the answer is otherwise unchanged, so such apps are
+//! served without a fork of the server.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use axum::{
+    Extension,
+    extract::{Path, State},
+    response::Json,
+};
+use serde_json::{Map, Value};
+use sqlx::types::Json as SqlJson;
+
+use crate::auth::{self, Principal};
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::schema::{APP_COLUMNS, App, AppState, ResponseFieldsRequest, UpdateResponse};
+
+/// Field of [`UpdateResponse`] to the name it goes by.
+pub type ResponseFields = BTreeMap<String, String>;
+
+/// Top-level fields of [`UpdateResponse`].
+const FIELDS: [&str; 9] = [
+    "version",
+    "url",
+    "signature",
+    "pub_date",
+    "notes",
+    "messages",
+    "flags",
+    "variant",
+    "components",
+];
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+fn validate(fields: &ResponseFields) -> AppResult<()> {
+    for (field, name) in fields {
+        if !FIELDS.contains(&field.as_str()) {
+            return Err(AppError::bad_request(format!(
+                "Unknown field '{}'; update answers have {}",
+                field,
+                FIELDS.join(", ")
+            )));
+        }
+        if !is_valid_name(name) {
+            return Err(AppError::bad_request(format!(
+                "'{}' can't be a field name: use 1-64 letters, digits, '_', '-' or '.'",
+                name
+            )));
+        }
+    }
+    let mut names = BTreeSet::new();
+    for field in FIELDS {
+        let name = fields.get(field).map(String::as_str).unwrap_or(field);
+        if !names.insert(name) {
+            return Err(AppError::bad_request(format!(
+                "Two fields would both be named '{}'",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The field names `app_name` renames, if any.
+pub async fn load(state: &AppState, app_name: &str) -> AppResult<Option<ResponseFields>> {
+    let fields: Option<Option<SqlJson<ResponseFields>>> =
+        sqlx::query_scalar("SELECT response_fields FROM apps WHERE name = ?")
+            .bind(app_name)
+            .fetch_optional(&state.read_pool)
+            .await
+            .map_err(|e| AppError::internal("Failed to look up the response fields", e))?;
+    Ok(fields.flatten().map(|f| f.0).filter(|f| !f.is_empty()))
+}
+
+/// `response` with `fields` renamed, as the app's clients expect it.
+pub fn render(response: UpdateResponse, fields: Option<&ResponseFields>) -> AppResult<Json<Value>> {
+    let value = serde_json::to_value(&response)
+        .map_err(|e| AppError::internal("Failed to serialize the update", e))?;
+    let Some(fields) = fields else {
+        return Ok(Json(value));
+    };
+    let Value::Object(object) = value else {
+        return Ok(Json(value));
+    };
+    let renamed: Map<String, Value> = object
+        .into_iter()
+        .map(|(field, value)| match fields.get(&field) {
+            Some(name) => (name.clone(), value),
+            None => (field, value),
+        })
+        .collect();
+    Ok(Json(Value::Object(renamed)))
+}
+
+/// Set an app's response field names
+///
+/// Renames fields of the app's update check and `/latest` answers. Fields
+/// left out keep their names.
+#[utoipa::path(
+    put,
+    path = "/apps/{name}/response-fields",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    request_body = ResponseFieldsRequest,
+    responses(
+        (status = 200, description = "Field names saved", body = App),
+        (status = 400, description = "Unknown field, invalid name, or two fields with the same name", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody)
+    )
+)]
+pub async fn set_response_fields(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<ResponseFieldsRequest>,
+) -> AppResult<Json<App>> {
+    let fields = request.fields.filter(|f| !f.is_empty());
+    if let Some(fields) = &fields {
+        validate(fields)?;
+    }
+    auth::claim_app(&state.pool, principal, &name).await?;
+
+    let app = sqlx::query_as::<_, App>(&format!(
+        "UPDATE apps SET response_fields = ? WHERE name = ? RETURNING {}",
+        APP_COLUMNS
+    ))
+    .bind(fields.map(SqlJson))
+    .bind(&name)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to save the response fields", e))?;
+
+    match &app.response_fields {
+        Some(fields) => println!(
+            "{} answers now rename {}",
+            app.name,
+            fields
+                .iter()
+                .map(|(field, name)| format!("{} to {}", field, name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        None => println!("{} answers use the usual field names again", app.name),
+    }
+    Ok(Json(app))
+}
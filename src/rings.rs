@@ -0,0 +1,271 @@
+//! Deployment rings.
+//!
+//! Customers (schools, districts) are assigned to a ring per organization.
+//! A release can carry a ring schedule: the time each ring may start
+//! installing it. Update checks that pass `customer_id` are only offered
+//! releases their ring has reached; customers without an assignment, and
+//! clients that don't identify themselves, wait for `broad`.
+//!
+//! Releases without a schedule go to every ring at once, as before.
+
+use std::collections::BTreeMap;
+
+use axum::{
+    Extension,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Sqlite};
+
+use crate::auth::{Principal, app_scope, org_scope};
+use crate::cache;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::schema::{
+    AppState, CustomerFilter, CustomerRing, CustomerRingRequest, OrgQuery, RELEASE_COLUMNS,
+    Release, RingScheduleRequest,
+};
+
+/// Rings in rollout order.
+pub const RINGS: [&str; 3] = ["canary", "early", "broad"];
+
+/// Ring of customers nobody assigned, and of anonymous update checks.
+pub const DEFAULT_RING: &str = "broad";
+
+/// When each ring may install a release. Rings left out open together with
+/// the closest earlier ring that is listed, or right away if none is.
+pub type RingSchedule = BTreeMap<String, DateTime<Utc>>;
+
+pub fn is_valid_ring(ring: &str) -> bool {
+    RINGS.contains(&ring)
+}
+
+pub fn validate_schedule(schedule: &RingSchedule) -> AppResult<()> {
+    match schedule.keys().find(|ring| !is_valid_ring(ring)) {
+        Some(ring) => Err(unknown_ring(ring)),
+        None => Ok(()),
+    }
+}
+
+pub fn unknown_ring(ring: &str) -> AppError {
+    AppError::bad_request(format!(
+        "Unknown ring '{}'; rings are {}",
+        ring,
+        RINGS.join(", ")
+    ))
+}
+
+/// When `ring` may start installing `release`; `None` if it already could
+/// from the moment it was uploaded.
+pub fn available_at(release: &Release, ring: &str) -> Option<DateTime<Utc>> {
+    let schedule = release.ring_schedule.as_ref()?;
+    let position = RINGS.iter().position(|r| *r == ring)?;
+    RINGS[..=position]
+        .iter()
+        .rev()
+        .find_map(|r| schedule.get(*r).copied())
+}
+
+pub fn is_available(release: &Release, ring: &str, now: DateTime<Utc>) -> bool {
+    available_at(release, ring).is_none_or(|at| at <= now)
+}
+
+/// Ring `customer_id` is assigned to by the organization owning `app_name`.
+pub async fn customer_ring(
+    pool: &Pool<Sqlite>,
+    app_name: &str,
+    customer_id: Option<&str>,
+) -> Result<String, sqlx::Error> {
+    let Some(customer_id) = customer_id else {
+        return Ok(DEFAULT_RING.to_string());
+    };
+    let ring: Option<String> = sqlx::query_scalar(
+        "SELECT ring FROM customer_rings WHERE customer_id = ? AND org_id = (SELECT org_id FROM apps WHERE name = ?)",
+    )
+    .bind(customer_id)
+    .bind(app_name)
+    .fetch_optional(pool)
+    .await?;
+    Ok(ring.unwrap_or_else(|| DEFAULT_RING.to_string()))
+}
+
+/// Set a release's ring schedule
+#[utoipa::path(
+    put,
+    path = "/releases/{id}/rings",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    request_body = RingScheduleRequest,
+    responses(
+        (status = 200, description = "Schedule saved", body = Release),
+        (status = 400, description = "Unknown ring", body = ErrorBody),
+        (status = 404, description = "Release not found", body = ErrorBody)
+    )
+)]
+pub async fn set_release_rings(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<RingScheduleRequest>,
+) -> AppResult<Json<Release>> {
+    let schedule = request.rings.filter(|s| !s.is_empty());
+    if let Some(schedule) = &schedule {
+        validate_schedule(schedule)?;
+    }
+
+    let release = sqlx::query_as::<_, Release>(&format!(
+        "UPDATE releases SET ring_schedule = ?1 WHERE id = ?2 AND {} RETURNING {}",
+        app_scope(3),
+        RELEASE_COLUMNS
+    ))
+    .bind(schedule.map(sqlx::types::Json))
+    .bind(id)
+    .bind(principal.org_id())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to update the ring schedule", e))?
+    .ok_or_else(|| AppError::not_found("Release not found"))?;
+
+    state
+        .cache
+        .invalidate(&cache::latest_key(
+            &release.app_name,
+            &release.target,
+            &release.arch,
+            &release.channel,
+        ))
+        .await;
+
+    println!("Updated ring schedule of release {}", release.id);
+    Ok(Json(release))
+}
+
+pub fn validate_customer_id(customer_id: &str) -> AppResult<()> {
+    if customer_id.is_empty()
+        || customer_id.len() > 128
+        || customer_id.chars().any(|c| c.is_control())
+    {
+        return Err(AppError::bad_request(
+            "customer_id must be 1-128 printable characters",
+        ));
+    }
+    Ok(())
+}
+
+/// List customer ring assignments
+#[utoipa::path(
+    get,
+    path = "/customers",
+    params(CustomerFilter),
+    responses(
+        (status = 200, description = "Customer ring assignments", body = Vec<CustomerRing>)
+    )
+)]
+pub async fn list_customers(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<CustomerFilter>,
+) -> AppResult<Json<Vec<CustomerRing>>> {
+    let customers = sqlx::query_as::<_, CustomerRing>(&format!(
+        "SELECT org_id, customer_id, ring, updated_at FROM customer_rings WHERE (?1 IS NULL OR ring = ?1) AND {} ORDER BY org_id, customer_id",
+        org_scope(2)
+    ))
+    .bind(&filter.ring)
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load customers", e))?;
+
+    Ok(Json(customers))
+}
+
+/// Assign a customer to a ring
+#[utoipa::path(
+    put,
+    path = "/customers/{customer_id}",
+    params(
+        ("customer_id" = String, Path, description = "Customer identifier the app sends as `customer_id`"),
+        OrgQuery
+    ),
+    request_body = CustomerRingRequest,
+    responses(
+        (status = 200, description = "Assignment saved", body = CustomerRing),
+        (status = 400, description = "Unknown ring or invalid customer ID", body = ErrorBody),
+        (status = 403, description = "org_id names another organization", body = ErrorBody),
+        (status = 404, description = "Organization not found", body = ErrorBody)
+    )
+)]
+pub async fn assign_customer(
+    Path(customer_id): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(org): Query<OrgQuery>,
+    Json(request): Json<CustomerRingRequest>,
+) -> AppResult<Json<CustomerRing>> {
+    validate_customer_id(&customer_id)?;
+    if !is_valid_ring(&request.ring) {
+        return Err(unknown_ring(&request.ring));
+    }
+    let org_id = principal.target_org(org.org_id)?;
+    let org_exists: Option<i64> = sqlx::query_scalar("SELECT id FROM organizations WHERE id = ?")
+        .bind(org_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to load organization", e))?;
+    if org_exists.is_none() {
+        return Err(AppError::not_found("Organization not found"));
+    }
+
+    let customer = sqlx::query_as::<_, CustomerRing>(
+        "INSERT INTO customer_rings (org_id, customer_id, ring, updated_at) VALUES (?, ?, ?, ?)
+         ON CONFLICT (org_id, customer_id) DO UPDATE SET ring = excluded.ring, updated_at = excluded.updated_at
+         RETURNING org_id, customer_id, ring, updated_at",
+    )
+    .bind(org_id)
+    .bind(&customer_id)
+    .bind(&request.ring)
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to assign customer", e))?;
+
+    println!(
+        "Assigned customer {} of organization {} to ring {}",
+        customer.customer_id, customer.org_id, customer.ring
+    );
+    Ok(Json(customer))
+}
+
+/// Remove a customer's ring assignment
+#[utoipa::path(
+    delete,
+    path = "/customers/{customer_id}",
+    params(
+        ("customer_id" = String, Path, description = "Customer identifier"),
+        OrgQuery
+    ),
+    responses(
+        (status = 204, description = "Assignment removed; the customer is back on `broad`"),
+        (status = 404, description = "Customer not assigned", body = ErrorBody)
+    )
+)]
+pub async fn unassign_customer(
+    Path(customer_id): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(org): Query<OrgQuery>,
+) -> AppResult<StatusCode> {
+    let org_id = principal.target_org(org.org_id)?;
+    let deleted = sqlx::query("DELETE FROM customer_rings WHERE org_id = ? AND customer_id = ?")
+        .bind(org_id)
+        .bind(&customer_id)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to remove customer", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("Customer not assigned"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
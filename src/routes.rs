@@ -1,119 +1,516 @@
+use crate::artifacts;
+use crate::attachments;
+use crate::auth::{self, Principal, app_scope, org_scope};
+use crate::authenticode;
+use crate::blackouts;
+use crate::cache;
+use crate::campaigns;
+use crate::components;
+use crate::deltas;
+use crate::devices;
+use crate::entitlements;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::events::{ReleaseEvent, ReleaseEventKind};
+use crate::feed;
+use crate::flags::{self, Flags};
+use crate::freezes;
+use crate::gates;
+use crate::github::{GitHub, PublishError};
+use crate::http_cache;
+use crate::licenses;
+use crate::mdm::{self, MdmFormat};
+use crate::normalize;
+use crate::notarization;
+use crate::orgs;
+use crate::platforms;
+use crate::plugins;
+use crate::quotas;
+use crate::response_fields;
+use crate::rings::{self, RingSchedule};
+use crate::rules::{self, RuleContext};
 use crate::schema::{
-    AppState, Release, SupportedApp, SupportedTarget, UpdateResponse, UploadReleaseForm,
+    APP_METADATA_COLUMNS, AppMetadata, AppState, Artifact, CampaignMessage, ChannelQuery,
+    DEFAULT_CHANNEL, DeliveryFilter, EventFilter, LatestQuery, OverrideQuery, PromoteRequest,
+    RELEASE_COLUMNS, Release, ReleaseFilter, SUBSCRIPTION_COLUMNS, StagedRelease, Subscription,
+    SubscriptionFilter, SubscriptionRequest, SupportedApp, SupportedTarget, UpdateCheckContext,
+    UpdateCheckQuery, UpdateResponse, UploadReleaseForm, VersionScheme, Webhook, WebhookDelivery,
+    WebhookRequest, is_valid_channel,
 };
+use crate::variants;
+use crate::versioning::{self, AppVersion};
+use crate::webhooks::{self, DELIVERY_COLUMNS, WEBHOOK_COLUMNS, WebhookRow};
 use axum::extract::Multipart;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use tokio_stream::{Stream, StreamExt, wrappers::BroadcastStream};
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::{IntoResponse, Json},
+    Extension,
+    extract::{OriginalUri, Path, Query, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Json, Response},
 };
-use semver::Version;
+use chrono::{DateTime, Utc};
+
+/// Looks up the highest-versioned release on a channel for an
+/// app/target/arch, going through the release cache first. Yanked releases
+/// are skipped.
+pub async fn find_latest_release(
+    state: &AppState,
+    app_name: &str,
+    target: &str,
+    arch: &str,
+    channel: &str,
+) -> Result<Option<Release>, String> {
+    let key = cache::latest_key(app_name, target, arch, channel);
+    state
+        .cache
+        .get_or_load(&key, |primary| async move {
+            let pool = if primary {
+                &state.pool
+            } else {
+                &state.read_pool
+            };
+            // Fetch all releases for this app/target/arch
+            // We fetch all because SQLite doesn't do semver comparison easily.
+            let scheme = versioning::app_scheme(pool, app_name).await?;
+            let releases = sqlx::query_as::<_, Release>(&format!(
+                "SELECT {} FROM releases WHERE app_name = ? AND target = ? AND arch = ? AND channel = ? AND yanked = 0 AND archived = 0",
+                RELEASE_COLUMNS
+            ))
+            .bind(app_name)
+            .bind(target)
+            .bind(arch)
+            .bind(channel)
+            .fetch_all(pool)
+            .await?;
+
+            Ok::<_, sqlx::Error>(highest_version(scheme, releases))
+        })
+        .await
+}
+
+fn highest_version(
+    scheme: VersionScheme,
+    releases: impl IntoIterator<Item = Release>,
+) -> Option<Release> {
+    releases
+        .into_iter()
+        .filter_map(|r| {
+            let v = versioning::parse(scheme, &r.version).ok()?;
+            Some((v, r))
+        })
+        .max_by(|(v1, _), (v2, _)| v1.cmp(v2))
+        .map(|(_, r)| r)
+}
+
+/// Narrows the latest release down to one this client may install now: its
+/// customer's ring must have been reached, and non-critical releases wait
+/// out the customer's deferral period, or that of the organization owning
+/// the app. When the
+/// latest doesn't qualify, the newest release that does and is `compatible`
+/// is looked up (uncached, as this only happens while a release is rolling
+/// out).
+pub async fn offered_release(
+    state: &AppState,
+    latest: Option<Release>,
+    (app_name, target, arch, channel): (&str, &str, &str, &str),
+    customer_id: Option<&str>,
+    (scheme, current): (VersionScheme, &AppVersion),
+    compatible: &(dyn Fn(&Release) -> bool + Sync),
+) -> AppResult<Option<Release>> {
+    let Some(latest) = latest else {
+        return Ok(None);
+    };
+    // Nothing is offered to an up-to-date client either way.
+    if versioning::parse(scheme, &latest.version).is_ok_and(|v| v <= *current) {
+        return Ok(Some(latest));
+    }
+
+    let defer_days = orgs::deferral_days(&state.read_pool, app_name, customer_id)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up the update policy", e))?;
+    if latest.ring_schedule.is_none() && defer_days == 0 {
+        return Ok(Some(latest));
+    }
+    let ring = rings::customer_ring(&state.read_pool, app_name, customer_id)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up the customer's ring", e))?;
+
+    let now = Utc::now();
+    let eligible =
+        |r: &Release| rings::is_available(r, &ring, now) && !orgs::is_deferred(r, defer_days, now);
+    if eligible(&latest) {
+        return Ok(Some(latest));
+    }
+
+    let releases = sqlx::query_as::<_, Release>(&format!(
+        "SELECT {} FROM releases WHERE app_name = ? AND target = ? AND arch = ? AND channel = ? AND yanked = 0 AND archived = 0",
+        RELEASE_COLUMNS
+    ))
+    .bind(app_name)
+    .bind(target)
+    .bind(arch)
+    .bind(channel)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to look up the latest release", e))?;
+    Ok(highest_version(
+        scheme,
+        releases
+            .into_iter()
+            .filter(|r| compatible(r) && eligible(r)),
+    ))
+}
 
 /// Check for updates
+///
+/// `HEAD` answers with the same status and headers and no body.
 #[utoipa::path(
-    get,
+    method(get, head),
     path = "/{app_name}/{target}/{arch}/{current_version}",
+    security(()),
     params(
         ("app_name" = SupportedApp, Path, description = "Application name"),
         ("target" = SupportedTarget, Path, description = "Target OS"),
         ("arch" = String, Path, description = "Architecture (e.g., aarch64, x86_64)"),
-        ("current_version" = String, Path, description = "Current version of the application")
+        ("current_version" = String, Path, description = "Current version of the application"),
+        UpdateCheckQuery
     ),
     responses(
-        (status = 200, description = "Update available", body = UpdateResponse),
-        (status = 204, description = "No update available"),
-        (status = 400, description = "Bad request (invalid version format)")
+        (status = 200, description = "Update available", body = UpdateResponse, headers(
+            ("Vary" = String, description = "`X-License-Key`, and the `COUNTRY_HEADER` when the release's rules look at the country")
+        )),
+        (status = 204, description = "No update available, or a blackout window is holding it back"),
+        (status = 304, description = "Latest release unchanged since the ETag in If-None-Match"),
+        (status = 400, description = "Bad request (invalid version format)", body = ErrorBody),
+        (status = 402, description = "The license key has expired (`license_expired`)", body = ErrorBody),
+        (status = 403, description = "The app requires a license key and none or an invalid one was sent (`license_required`, `license_invalid`), or its entitlement hook refused the update (`entitlement_denied`)", body = ErrorBody),
+        (status = 503, description = "The license or entitlement could not be checked", body = ErrorBody)
     )
 )]
 pub async fn check_update(
     Path((app_name, target, arch, current_version)): Path<(String, String, String, String)>,
+    Query(query): Query<UpdateCheckQuery>,
+    Query(raw_query): Query<BTreeMap<String, String>>,
     State(state): State<AppState>,
-) -> impl IntoResponse {
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let answer = answer_update_check(
+        &state,
+        (&app_name, &target, &arch, &current_version),
+        &query,
+        &raw_query,
+        &headers,
+    )
+    .await?;
+    let fields = response_fields::load(&state, &app_name).await?;
+    let etag = http_cache::with_response_fields(answer.etag, fields.as_ref());
+    // A licensed answer must not be replayed by a shared cache to a client
+    // without the key, nor one country's answer to another country.
+    let country_header = state
+        .config
+        .country_header
+        .as_deref()
+        .filter(|_| answer.by_country)
+        .and_then(|name| HeaderName::try_from(name).ok());
+    let vary = |response: Response| {
+        let response = http_cache::vary(response, licenses::LICENSE_KEY_HEADER);
+        match &country_header {
+            Some(name) => http_cache::vary(response, name.clone()),
+            None => response,
+        }
+    };
+    if http_cache::not_modified(&headers, &etag) {
+        return Ok(vary(
+            (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response(),
+        ));
+    }
+
+    if let Some(response) = answer.update {
+        let body = response_fields::render(response, fields.as_ref())?;
+        return Ok(vary(
+            (StatusCode::OK, [(header::ETAG, etag)], body).into_response(),
+        ));
+    }
+
+    // No update available. A 204 has no body, so flags go in a header and
+    // clients only learn how many messages wait for them.
+    let mut response = (StatusCode::NO_CONTENT, [(header::ETAG, etag)]).into_response();
+    if !answer.messages.is_empty() {
+        response.headers_mut().insert(
+            campaigns::MESSAGES_HEADER,
+            HeaderValue::from(answer.messages.len()),
+        );
+    }
+    if !answer.flags.is_empty()
+        && let Some(value) = flags::header_value(&answer.flags)
+    {
+        response.headers_mut().insert(flags::FLAGS_HEADER, value);
+    }
+    Ok(vary(response))
+}
+
+/// What an update check offers a client, whichever transport asked.
+pub struct UpdateAnswer {
+    /// The newer release offered, with its messages, flags and components.
+    pub update: Option<UpdateResponse>,
+    /// Messages and flags of a client told there is no update.
+    pub messages: Vec<CampaignMessage>,
+    pub flags: Flags,
+    /// Tag of the answer, before [`http_cache::with_response_fields`].
+    pub etag: String,
+    /// The release's rules looked at the client's country.
+    pub by_country: bool,
+}
+
+/// Runs an update check: device targeting or rings, deferral and rules,
+/// then blackouts, licenses, entitlements, variants and components.
+pub async fn answer_update_check(
+    state: &AppState,
+    (app_name, target, arch, current_version): (&str, &str, &str, &str),
+    query: &UpdateCheckQuery,
+    raw_query: &BTreeMap<String, String>,
+    headers: &HeaderMap,
+) -> AppResult<UpdateAnswer> {
+    let channel = query.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
     println!(
-        "Received update check: app_name={}, target={}, arch={}, version={}",
-        app_name, target, arch, current_version
+        "Received update check: app_name={}, target={}, arch={}, version={}, channel={}, customer_id={}",
+        app_name,
+        target,
+        arch,
+        current_version,
+        channel,
+        query.customer_id.as_deref().unwrap_or("-")
     );
 
-    let current_ver = match Version::parse(&current_version) {
-        Ok(v) => v,
-        Err(e) => {
-            println!(
-                "Failed to parse current version '{}': {}",
-                current_version, e
+    let scheme = versioning::scheme_for(state, app_name).await?;
+    let current_ver = versioning::parse_field(scheme, "current_version", current_version)?;
+    let is_newer = |release: &Release| {
+        versioning::parse(scheme, &release.version).is_ok_and(|v| v > current_ver)
+    };
+    let manifest = query
+        .components
+        .as_deref()
+        .map(components::parse_manifest)
+        .transpose()?;
+
+    let pinned = match query.device_id.as_deref() {
+        Some(device_id) => {
+            devices::check_in(state, app_name, device_id, (target, arch), current_version).await?
+        }
+        None => None,
+    };
+    let (latest, by_country) = match pinned {
+        Some(version) => (
+            devices::targeted_release(
+                &state.read_pool,
+                (app_name, target, arch, channel),
+                &version,
+            )
+            .await
+            .map_err(|e| AppError::internal("Failed to look up the targeted release", e))?,
+            false,
+        ),
+        None => {
+            let latest = find_latest_release(state, app_name, target, arch, channel)
+                .await
+                .map_err(|e| AppError::internal("Failed to look up the latest release", e))?;
+            let latest = offered_release(
+                state,
+                latest,
+                (app_name, target, arch, channel),
+                query.customer_id.as_deref(),
+                (scheme, &current_ver),
+                &|_| true,
+            )
+            .await?;
+            let context = RuleContext::new(
+                state,
+                headers,
+                (app_name, channel),
+                current_ver.to_semver(),
+                query,
+                raw_query,
             );
-            return (StatusCode::BAD_REQUEST, Json(None));
+            rules::apply(state, latest, &context).await?
         }
     };
-
-    // Fetch all releases for this app/target/arch
-    // We fetch all because SQLite doesn't do semver comparison easily.
-    let releases = sqlx::query_as::<_, Release>(
-        "SELECT id, app_name, target, arch, version, url, signature, pub_date, notes FROM releases WHERE app_name = ? AND target = ? AND arch = ?"
+    // During a blackout window nothing but critical updates is offered. The
+    // ETag follows what is offered, so clients notice when the window ends.
+    let latest = match latest {
+        Some(release) if !release.critical && is_newer(&release) => {
+            match blackouts::active_blackout(&state.read_pool, app_name, Utc::now())
+                .await
+                .map_err(|e| AppError::internal("Failed to check blackout windows", e))?
+            {
+                Some(reason) => {
+                    println!(
+                        "Holding back {} {} during blackout: {}",
+                        app_name, release.version, reason
+                    );
+                    None
+                }
+                None => Some(release),
+            }
+        }
+        latest => latest,
+    };
+    // Licenses and entitlements are only checked when there is something to
+    // hand out.
+    let latest = match latest {
+        Some(release) if is_newer(&release) => {
+            let context = UpdateCheckContext {
+                app_name: app_name.to_string(),
+                target: target.to_string(),
+                arch: arch.to_string(),
+                current_version: Some(current_version.to_string()),
+                channel: channel.to_string(),
+                customer_id: query.customer_id.clone(),
+            };
+            let key = licenses::request_key(headers, query.license_key.as_deref());
+            licenses::check_license(state, &context, key).await?;
+            entitlements::check_entitlement(state, &context, release).await?
+        }
+        latest => latest,
+    };
+    let client_id = query.client_id.as_deref().or(query.device_id.as_deref());
+    let (latest, variant) = match latest {
+        Some(release) if is_newer(&release) => {
+            let (release, variant) = variants::for_client(&state.read_pool, release, client_id)
+                .await
+                .map_err(|e| AppError::internal("Failed to look up release variants", e))?;
+            (Some(release), variant)
+        }
+        latest => (latest, None),
+    };
+    let changed = match &latest {
+        Some(release) if is_newer(release) => {
+            components::changed_components(&state.read_pool, release.id, manifest.as_ref())
+                .await
+                .map_err(|e| AppError::internal("Failed to look up release components", e))?
+        }
+        _ => Vec::new(),
+    };
+    let current_semver = current_ver.to_semver();
+    let messages = campaigns::matching_messages(
+        &state.read_pool,
+        app_name,
+        target,
+        &current_semver,
+        Utc::now(),
     )
-    .bind(&app_name)
-    .bind(&target)
-    .bind(&arch)
-    .fetch_all(&state.pool)
     .await
-    .unwrap_or_else(|_| vec![]);
-
-    // Find the latest version > current_version
-    let latest_update = releases
-        .into_iter()
-        .filter_map(|r| {
-            let v = Version::parse(&r.version).ok()?;
-            if v > current_ver {
-                Some((v, r)) // Only consider newer versions
-            } else {
-                None
-            }
-        })
-        .max_by(|(v1, _), (v2, _)| v1.cmp(v2)); // Find the highest version
+    .map_err(|e| AppError::internal("Failed to look up campaigns", e))?;
+    let flags = flags::client_flags(&state.read_pool, app_name, &current_semver, client_id)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up feature flags", e))?;
+    let etag = http_cache::update_check_etag(latest.as_ref(), &changed, &messages, &flags);
 
-    if let Some((v, release)) = latest_update {
-        println!("Update available: {} -> {}", current_version, v);
-        // Return 200 with update info
-        let response = UpdateResponse {
+    // Only the highest version matters: if it isn't newer, nothing is.
+    let Some(release) = latest.filter(is_newer) else {
+        println!(
+            "No update available for {} {} {} {}",
+            app_name, target, arch, current_version
+        );
+        return Ok(UpdateAnswer {
+            update: None,
+            messages,
+            flags,
+            etag,
+            by_country,
+        });
+    };
+    println!(
+        "Update available: {} -> {}",
+        current_version, release.version
+    );
+    Ok(UpdateAnswer {
+        update: Some(UpdateResponse {
             version: release.version,
             url: release.url,
             signature: release.signature,
             pub_date: release.pub_date,
             notes: release.notes,
-        };
-        return (StatusCode::OK, Json(Some(response)));
-    }
+            messages,
+            flags,
+            variant: variant.map(str::to_string),
+            components: changed,
+        }),
+        messages: Vec::new(),
+        flags: Flags::new(),
+        etag,
+        by_country,
+    })
+}
 
-    println!(
-        "No update available for {} {} {} {}",
-        app_name, target, arch, current_version
-    );
-    // No update available
-    (StatusCode::NO_CONTENT, Json(None))
+/// Campaign messages for a version
+///
+/// What an update check would include, for clients told there is no update.
+#[utoipa::path(
+    get,
+    path = "/messages/{app_name}/{target}/{current_version}",
+    security(()),
+    params(
+        ("app_name" = SupportedApp, Path, description = "Application name"),
+        ("target" = SupportedTarget, Path, description = "Target OS"),
+        ("current_version" = String, Path, description = "Current version of the application")
+    ),
+    responses(
+        (status = 200, description = "Messages of the campaigns targeting this version", body = Vec<CampaignMessage>),
+        (status = 400, description = "Invalid version", body = ErrorBody)
+    )
+)]
+pub async fn get_campaign_messages(
+    Path((app_name, target, current_version)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+) -> AppResult<Json<Vec<CampaignMessage>>> {
+    let scheme = versioning::scheme_for(&state, &app_name).await?;
+    let current = versioning::parse_field(scheme, "current_version", &current_version)?.to_semver();
+    let messages =
+        campaigns::matching_messages(&state.read_pool, &app_name, &target, &current, Utc::now())
+            .await
+            .map_err(|e| AppError::internal("Failed to look up campaigns", e))?;
+    Ok(Json(messages))
 }
 
 /// Upload a new release
 #[utoipa::path(
     post,
     path = "/upload",
+    params(OverrideQuery),
     request_body(content = UploadReleaseForm, content_type = "multipart/form-data"),
     responses(
         (status = 201, description = "Release created successfully", body = String),
-        (status = 400, description = "Bad request"),
-        (status = 409, description = "Conflict - Asset already exists"),
-        (status = 500, description = "Internal server error")
+        (status = 202, description = "Staged until the app's required checks pass", body = StagedRelease),
+        (status = 400, description = "Bad request", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization, or a freeze override without the operator token", body = ErrorBody),
+        (status = 409, description = "Asset already exists, publishing is frozen, or a variant B for an app whose uploads are staged", body = ErrorBody),
+        (status = 413, description = "The upload doesn't fit the storage quota", body = ErrorBody),
+        (status = 429, description = "The release or daily upload quota is used up", body = ErrorBody),
+        (status = 500, description = "Internal server error", body = ErrorBody)
     )
 )]
 pub async fn upload_release(
     State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(freeze): Query<OverrideQuery>,
     mut multipart: Multipart,
-) -> impl IntoResponse {
+) -> AppResult<Response> {
     let mut app_name = String::new();
     let mut version = String::new();
     let mut target = String::new();
     let mut arch = String::new();
     let mut notes = String::new();
     let mut signature = String::new();
+    let mut pub_date_field = String::new();
+    let mut channel = String::new();
+    let mut rings_field = String::new();
+    let mut critical_field = String::new();
+    let mut variant_field = String::new();
+    let mut split_field = String::new();
+    let mut host_version_field = String::new();
     let mut file_data: Vec<u8> = Vec::new();
     let mut file_name = String::new();
 
@@ -121,13 +518,8 @@ pub async fn upload_release(
 
     // 1. Extract fields and file from multipart
     while let Some(res) = multipart.next_field().await.transpose() {
-        let field = match res {
-            Ok(f) => f,
-            Err(e) => {
-                println!("Error processing multipart field: {:?}", e);
-                continue;
-            }
-        };
+        let field =
+            res.map_err(|e| AppError::bad_request(format!("Malformed multipart body: {}", e)))?;
 
         let name = field.name().unwrap_or_default().to_string();
         match name.as_str() {
@@ -137,6 +529,13 @@ pub async fn upload_release(
             "arch" => arch = field.text().await.unwrap_or_default(),
             "notes" => notes = field.text().await.unwrap_or_default(),
             "signature" => signature = field.text().await.unwrap_or_default(),
+            "pub_date" => pub_date_field = field.text().await.unwrap_or_default(),
+            "channel" => channel = field.text().await.unwrap_or_default(),
+            "rings" => rings_field = field.text().await.unwrap_or_default(),
+            "critical" => critical_field = field.text().await.unwrap_or_default(),
+            "variant" => variant_field = field.text().await.unwrap_or_default(),
+            "split_percent" => split_field = field.text().await.unwrap_or_default(),
+            "host_version" => host_version_field = field.text().await.unwrap_or_default(),
             "file" => {
                 file_name = field.file_name().unwrap_or("installer").to_string();
                 let content_type = field.content_type().unwrap_or("unknown");
@@ -145,246 +544,791 @@ pub async fn upload_release(
                     file_name, content_type
                 );
 
-                match field.bytes().await {
-                    Ok(bytes) => {
-                        println!("Received file: {}, size: {} bytes", file_name, bytes.len());
-                        file_data = bytes.to_vec();
-                    }
-                    Err(e) => {
-                        println!("Error reading file bytes: {:?}", e);
-                        return (
-                            StatusCode::BAD_REQUEST,
-                            format!("Failed to read file: {}", e),
-                        )
-                            .into_response();
-                    }
-                }
+                let bytes = field
+                    .bytes()
+                    .await
+                    .map_err(|e| AppError::bad_request(format!("Failed to read file: {}", e)))?;
+                println!("Received file: {}, size: {} bytes", file_name, bytes.len());
+                file_data = bytes.to_vec();
             }
             _ => (),
         }
     }
 
     if file_data.is_empty() {
-        println!("Warning: No file data received or file is empty!");
-        return (StatusCode::BAD_REQUEST, "No file uploaded or file is empty").into_response();
+        return Err(AppError::bad_request("No file uploaded or file is empty"));
+    }
+    for (field, value) in [
+        ("app_name", &app_name),
+        ("version", &version),
+        ("target", &target),
+        ("arch", &arch),
+    ] {
+        if value.trim().is_empty() {
+            return Err(AppError::bad_request(format!("{} is required", field)));
+        }
     }
 
+    let pub_date = if pub_date_field.trim().is_empty() {
+        Utc::now()
+    } else {
+        DateTime::parse_from_rfc3339(pub_date_field.trim())
+            .map_err(|e| {
+                AppError::bad_request(format!("pub_date must be an RFC3339 timestamp: {}", e))
+            })?
+            .with_timezone(&Utc)
+    };
+
+    let channel = match channel.trim() {
+        "" => DEFAULT_CHANNEL.to_string(),
+        c if is_valid_channel(c) => c.to_string(),
+        _ => return Err(invalid_channel()),
+    };
+
+    let ring_schedule = if rings_field.trim().is_empty() {
+        None
+    } else {
+        let schedule: RingSchedule = serde_json::from_str(rings_field.trim()).map_err(|e| {
+            AppError::bad_request(format!(
+                "rings must be a JSON object of ring to RFC3339 time: {}",
+                e
+            ))
+        })?;
+        rings::validate_schedule(&schedule)?;
+        (!schedule.is_empty()).then_some(sqlx::types::Json(schedule))
+    };
+
+    let critical = match critical_field.trim() {
+        "" | "false" | "0" => false,
+        "true" | "1" => true,
+        _ => return Err(AppError::bad_request("critical must be true or false")),
+    };
+
+    // Variant B goes with an existing release; its split defaults to half.
+    let variant_split = match variant_field.trim() {
+        "" | "a" | "A" => None,
+        "b" | "B" => {
+            let split = match split_field.trim() {
+                "" => variants::DEFAULT_SPLIT_PERCENT,
+                s => s
+                    .parse()
+                    .map_err(|_| AppError::bad_request("split_percent must be a number"))?,
+            };
+            variants::validate_split(split)?;
+            Some(split)
+        }
+        _ => return Err(AppError::bad_request("variant must be a or b")),
+    };
+
     println!(
-        "Extracted fields: app_name={}, version={}, target={}, arch={}",
-        app_name, version, target, arch
+        "Extracted fields: app_name={}, version={}, target={}, arch={}, channel={}",
+        app_name, version, target, arch, channel
     );
 
-    // 2. GitHub Integration (Octocrab)
-    println!("Initializing GitHub client...");
-    let token = std::env::var("GITHUB_TOKEN").expect("GITHUB_TOKEN must be set");
-    let octo = octocrab::Octocrab::builder()
-        .personal_token(token)
-        .build()
-        .unwrap();
-    let owner = std::env::var("GITHUB_OWNER").unwrap_or_else(|_| "Edustart-Tech".into());
-    let repo = std::env::var("GITHUB_REPO").unwrap_or_else(|_| "App-Release-Manager".into());
-    let tag = format!("{}-v{}", app_name, version);
-
-    println!("Checking if release tag {} exists...", tag);
-    let release = match octo.repos(&owner, &repo).releases().get_by_tag(&tag).await {
-        Ok(r) => {
-            println!("Tag {} exists. Checking for asset conflict...", tag);
-            // Check if asset exists
-            if r.assets.iter().any(|a| a.name == file_name) {
-                println!(
-                    "Conflict: Asset {} already exists in release {}",
-                    file_name, tag
-                );
-                return (StatusCode::CONFLICT, "Asset already exists in this release")
-                    .into_response();
-            }
-            println!("Release {} ready for upload.", tag);
-            r
-        }
-        Err(_) => {
-            println!("Release not found, creating new release for tag {}...", tag);
-            match octo
-                .repos(&owner, &repo)
-                .releases()
-                .create(&tag)
-                .name(&tag)
-                .body(&notes)
-                .send()
+    // Before anything is stored, so a name another organization owns is
+    // refused without touching GitHub.
+    auth::claim_app(&state.pool, principal, &app_name).await?;
+    let scheme = versioning::scheme_for(&state, &app_name).await?;
+    versioning::parse_field(scheme, "version", &version)?;
+    freezes::check_publish(&state.pool, principal, &app_name, &freeze).await?;
+    let host_requirement =
+        plugins::parse_host_requirement(&state.pool, &app_name, &host_version_field).await?;
+
+    file_data = authenticode::prepare(&state, &target, &file_name, file_data).await?;
+    if state.config.normalize_artifacts {
+        file_data = normalize::normalize(&file_name, file_data)
+            .map_err(|e| AppError::bad_request(format!("Cannot normalize {}: {}", file_name, e)))?;
+        file_name = normalize::canonical_file_name(&app_name, &version, &target, &arch, &file_name);
+    }
+
+    let sha256 = artifacts::sha256_hex(&file_data);
+    let size = file_data.len() as i64;
+    quotas::check_upload(&state, &app_name, &sha256, size, variant_split.is_none()).await?;
+
+    if let Some(split_percent) = variant_split {
+        // A variant is served as soon as it is stored, with no way through
+        // the checks or notarization a staged upload waits for.
+        if notarization::prepare(&state, &target, &file_name, &file_data, &signature)?.is_some()
+            || gates::has_gates(&state.pool, &app_name)
                 .await
-            {
-                Ok(r) => {
-                    println!("GitHub release created successfully: id={}", r.id);
-                    r
-                }
-                Err(e) => {
-                    println!("Failed to create GitHub release: {:?}", e);
-                    return (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("GH Release Fail: {:?}", e),
-                    )
-                        .into_response();
-                }
-            }
+                .map_err(|e| AppError::internal("Failed to look up check gates", e))?
+        {
+            return Err(AppError::conflict(format!(
+                "Uploads of {} are staged for checks or notarization, which variant B can't go through",
+                app_name
+            )));
         }
-    };
+        let release = sqlx::query_as::<_, Release>(&format!(
+            "SELECT {} FROM releases WHERE app_name = ? AND target = ? AND arch = ? AND version = ? AND channel = ?",
+            RELEASE_COLUMNS
+        ))
+        .bind(&app_name)
+        .bind(&target)
+        .bind(&arch)
+        .bind(&version)
+        .bind(&channel)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up the release", e))?
+        .ok_or_else(|| {
+            AppError::not_found(format!(
+                "Upload variant A of {} {} before variant B",
+                app_name, version
+            ))
+        })?;
+        // Checked again when saving; this only spares GitHub a doomed upload.
+        if variants::find_variant(&state.pool, release.id)
+            .await
+            .map_err(|e| AppError::internal("Failed to look up the variant", e))?
+            .is_some()
+        {
+            return Err(AppError::conflict(format!(
+                "Release {} already has a variant B; delete it first",
+                release.id
+            )));
+        }
+        let artifact = store_artifact(
+            &state,
+            &app_name,
+            sha256,
+            &release_tag(&app_name, &version),
+            &notes,
+            &file_name,
+            file_data,
+        )
+        .await?;
+        let variant =
+            variants::add_variant(&state, &release, artifact, &signature, split_percent).await?;
+        println!("Variant B of release {} uploaded.", release.id);
+        return Ok((StatusCode::CREATED, Json(variant.url)).into_response());
+    }
 
-    println!("Uploading asset to GitHub release...");
+    let submission = notarization::prepare(&state, &target, &file_name, &file_data, &signature)?;
+    let (sha256, size, download_url, github_asset_id) = store_artifact(
+        &state,
+        &app_name,
+        sha256,
+        &release_tag(&app_name, &version),
+        &notes,
+        &file_name,
+        file_data,
+    )
+    .await?;
 
-    // Upload the Asset
-    let asset = match octo
-        .repos(&owner, &repo)
-        .releases()
-        .upload_asset(*release.id, &file_name, file_data.into())
-        .send()
-        .await
+    if submission.is_some()
+        || gates::has_gates(&state.pool, &app_name)
+            .await
+            .map_err(|e| AppError::internal("Failed to look up check gates", e))?
     {
-        Ok(a) => {
-            println!(
-                "Asset uploaded successfully: url={}",
-                a.browser_download_url
-            );
-            a
-        }
-        Err(e) => {
-            println!("Failed to upload asset: {:?}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("GH Upload Fail: {:?}", e),
-            )
-                .into_response();
+        let release = Release {
+            id: 0,
+            app_name: app_name.clone(),
+            target,
+            arch,
+            version,
+            url: download_url,
+            signature,
+            pub_date,
+            notes,
+            sha256: Some(sha256),
+            channel,
+            yanked: false,
+            ring_schedule,
+            critical,
+            notarization_id: None,
+            archived: false,
+        };
+        let builtin = state
+            .notary
+            .iter()
+            .filter(|_| submission.is_some())
+            .map(|notary| notary.check(&app_name))
+            .collect();
+        let staged = gates::stage(
+            &state,
+            release,
+            size,
+            github_asset_id,
+            builtin,
+            host_requirement.as_ref(),
+        )
+        .await?;
+        if let Some(submission) = submission {
+            notarization::submit(&state, staged.clone(), submission);
         }
-    };
-
-    let download_url = asset.browser_download_url.to_string();
+        return Ok((StatusCode::ACCEPTED, Json(staged)).into_response());
+    }
 
     // 4. Save to Database
     println!("Saving release to local database...");
-    let pub_date = chrono::Utc::now().to_rfc3339();
-    sqlx::query(
-        "INSERT OR IGNORE INTO releases (app_name, target, arch, version, url, signature, pub_date, notes) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
-    )
-    .bind(&app_name).bind(&target).bind(&arch).bind(&version)
-    .bind(&download_url).bind(&signature).bind(&pub_date).bind(&notes)
-    .execute(&state.pool).await.unwrap();
+    let saved: Result<Result<Release, AppError>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        if let Some(refused) =
+            quotas::record_upload(&mut tx, &state.config, &app_name, &sha256, size, true).await?
+        {
+            return Ok(Err(refused));
+        }
+        artifacts::retain(&mut tx, &app_name, &sha256, &download_url, size, github_asset_id).await?;
+        let release = sqlx::query_as::<_, Release>(&format!(
+            "INSERT INTO releases (app_name, target, arch, version, url, signature, pub_date, notes, sha256, channel, ring_schedule, critical) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING {}",
+            RELEASE_COLUMNS
+        ))
+        .bind(&app_name).bind(&target).bind(&arch).bind(&version)
+        .bind(&download_url).bind(&signature).bind(pub_date).bind(&notes).bind(&sha256)
+        .bind(&channel).bind(&ring_schedule).bind(critical)
+        .fetch_one(&mut *tx).await?;
+        if let Some(req) = &host_requirement {
+            plugins::set_host_requirement(&mut tx, &app_name, &version, req).await?;
+        }
+        tx.commit().await?;
+        Ok(Ok(release))
+    }
+    .await;
+    let release = saved.map_err(|e| AppError::internal("Failed to save release", e))??;
+
+    state
+        .cache
+        .invalidate(&cache::latest_key(&app_name, &target, &arch, &channel))
+        .await;
+    state
+        .events
+        .emit(ReleaseEvent::new(ReleaseEventKind::Published, release))
+        .await;
 
     println!("Release process completed successfully.");
-    (StatusCode::CREATED, Json(download_url)).into_response()
+    Ok((StatusCode::CREATED, Json(download_url)).into_response())
+}
+
+/// An uploaded file, once stored: its SHA-256, size, download URL and
+/// GitHub asset.
+pub type StoredArtifact = (String, i64, String, Option<i64>);
+
+/// The GitHub release tag holding the files of `version` of `name`.
+pub fn release_tag(name: &str, version: &str) -> String {
+    format!("{}-v{}", name, version)
+}
+
+/// Reuses identical bytes that `app_name`'s organization already stores,
+/// otherwise publishes the file, whose SHA-256 is `sha256`, to the GitHub
+/// release `tag`. The caller still has to [`artifacts::retain`] it.
+pub async fn store_artifact(
+    state: &AppState,
+    app_name: &str,
+    sha256: String,
+    tag: &str,
+    notes: &str,
+    file_name: &str,
+    file_data: Vec<u8>,
+) -> AppResult<StoredArtifact> {
+    let size = file_data.len() as i64;
+
+    let existing = artifacts::find(&state.pool, app_name, &sha256)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up artifact", e))?;
+    // Archived bytes are published again rather than served from the
+    // private archive bucket.
+    if let Some(artifact) = existing
+        && !state.archive.as_ref().is_some_and(|a| a.holds(&artifact))
+    {
+        println!(
+            "Artifact {} already stored, reusing {}",
+            sha256, artifact.url
+        );
+        return Ok((sha256, size, artifact.url, artifact.github_asset_id));
+    }
+
+    let github = GitHub::from_config(&state.config)
+        .map_err(|e| AppError::internal("Release storage is not configured", e))?;
+    match github
+        .publish_asset(&state.github_lookups, tag, notes, file_name, file_data)
+        .await
+    {
+        Ok(asset) => Ok((
+            sha256,
+            size,
+            asset.browser_download_url.to_string(),
+            Some(*asset.id as i64),
+        )),
+        Err(PublishError::Conflict) => {
+            Err(AppError::conflict("Asset already exists in this release"))
+        }
+        Err(PublishError::Github(e)) => Err(AppError::internal("Failed to store the artifact", e)),
+    }
+}
+
+/// Deletes the stored bytes of an artifact whose last reference is gone.
+/// The database is already consistent by then, so a failure only leaves an
+/// orphaned asset.
+pub async fn delete_orphaned(state: &AppState, orphaned: Option<Artifact>) {
+    if let (Some(archive), Some(artifact)) = (&state.archive, &orphaned) {
+        archive.delete_orphaned(artifact).await;
+    }
+    if let Some(Artifact {
+        sha256,
+        github_asset_id: Some(asset_id),
+        ..
+    }) = orphaned
+    {
+        println!(
+            "Artifact {} no longer referenced, deleting asset {}",
+            sha256, asset_id
+        );
+        match GitHub::from_config(&state.config) {
+            Ok(github) => {
+                if let Err(e) = github
+                    .delete_asset(&state.github_lookups, asset_id as u64)
+                    .await
+                {
+                    println!("{}", e);
+                }
+            }
+            Err(e) => println!("Cannot delete asset {}: {}", asset_id, e),
+        }
+    }
+}
+
+/// The target and arch a request for the latest release is about, with
+/// `auto` segments filled in from the User-Agent; see [`platforms`]. `None`
+/// when no build of the app suits the client.
+async fn requested_platform(
+    state: &AppState,
+    headers: &HeaderMap,
+    app_name: &str,
+    (target, arch): (&str, &str),
+    channel: &str,
+) -> AppResult<Option<(String, String)>> {
+    if !platforms::is_inferred(target, arch) {
+        return Ok(Some((target.to_string(), arch.to_string())));
+    }
+    let platform = platforms::resolve(&state.read_pool, headers, app_name, (target, arch), channel)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up the app's builds", e))?;
+    println!(
+        "Inferred platform for {} from the User-Agent: {}",
+        app_name,
+        platform
+            .as_ref()
+            .map_or("none".to_string(), |(t, a)| format!("{}/{}", t, a))
+    );
+    Ok(platform)
+}
+
+/// Answers that depend on the license key or the User-Agent must say so to
+/// shared caches.
+fn vary_on_client(response: Response, inferred: bool) -> Response {
+    let response = http_cache::vary(response, licenses::LICENSE_KEY_HEADER);
+    if inferred {
+        return http_cache::vary(response, header::USER_AGENT);
+    }
+    response
 }
 
 /// Get the latest version
+///
+/// `auto` as the target or arch picks the build matching the User-Agent.
 #[utoipa::path(
-    get,
+    method(get, head),
     path = "/latest/{app_name}/{target}/{arch}",
+    security(()),
     params(
         ("app_name" = SupportedApp, Path, description = "Application name"),
-        ("target" = SupportedTarget, Path, description = "Target OS"),
-        ("arch" = String, Path, description = "Architecture")
+        ("target" = SupportedTarget, Path, description = "Target OS, or `auto`"),
+        ("arch" = String, Path, description = "Architecture, or `auto`"),
+        LatestQuery
     ),
     responses(
         (status = 200, description = "Latest version found", body = UpdateResponse),
-        (status = 204, description = "No version found")
+        (status = 204, description = "No version found"),
+        (status = 304, description = "Latest release unchanged since the ETag in If-None-Match"),
+        (status = 402, description = "The license key has expired (`license_expired`)", body = ErrorBody),
+        (status = 403, description = "The app requires a license key and none or an invalid one was sent (`license_required`, `license_invalid`)", body = ErrorBody)
     )
 )]
 // Handler to get the latest version (without update check logic)
 pub async fn get_latest_version(
     Path((app_name, target, arch)): Path<(String, String, String)>,
+    Query(query): Query<LatestQuery>,
     State(state): State<AppState>,
-) -> impl IntoResponse {
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let channel = query.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
     println!(
-        "Received latest version check: app_name={}, target={}, arch={}",
-        app_name, target, arch
+        "Received latest version check: app_name={}, target={}, arch={}, channel={}",
+        app_name, target, arch, channel
     );
 
-    // Fetch all releases for this app/target/arch
-    let releases = sqlx::query_as::<_, Release>(
-        "SELECT id, app_name, target, arch, version, url, signature, pub_date, notes FROM releases WHERE app_name = ? AND target = ? AND arch = ?"
-    )
-    .bind(&app_name)
-    .bind(&target)
-    .bind(&arch)
-    .fetch_all(&state.pool)
-    .await
-    .unwrap_or_else(|_| vec![]);
-
-    // Find the latest version
-    let latest_release = releases
-        .into_iter()
-        .filter_map(|r| {
-            let v = Version::parse(&r.version).ok()?;
-            Some((v, r))
-        })
-        .max_by(|(v1, _), (v2, _)| v1.cmp(v2));
+    let inferred = platforms::is_inferred(&target, &arch);
+    let latest_release =
+        match requested_platform(&state, &headers, &app_name, (&target, &arch), channel).await? {
+            Some((target, arch)) => {
+                let key = licenses::request_key(&headers, query.license_key.as_deref());
+                licenses::check_download(&state, key, (&app_name, &target, &arch, channel)).await?;
+                find_latest_release(&state, &app_name, &target, &arch, channel)
+                    .await
+                    .map_err(|e| AppError::internal("Failed to look up the latest release", e))?
+            }
+            None => None,
+        };
+    let fields = response_fields::load(&state, &app_name).await?;
+    let etag = http_cache::with_response_fields(
+        http_cache::release_etag(latest_release.as_ref()),
+        fields.as_ref(),
+    );
+    if http_cache::not_modified(&headers, &etag) {
+        return Ok(vary_on_client(
+            (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response(),
+            inferred,
+        ));
+    }
 
-    if let Some((_, release)) = latest_release {
+    if let Some(release) = latest_release {
         let response = UpdateResponse {
             version: release.version,
             url: release.url,
             signature: release.signature,
             pub_date: release.pub_date,
             notes: release.notes,
+            messages: Vec::new(),
+            flags: Flags::new(),
+            variant: None,
+            components: Vec::new(),
         };
-        return (StatusCode::OK, Json(Some(response)));
+        let body = response_fields::render(response, fields.as_ref())?;
+        return Ok(vary_on_client(
+            (StatusCode::OK, [(header::ETAG, etag)], body).into_response(),
+            inferred,
+        ));
     }
 
-    (StatusCode::NO_CONTENT, Json(None))
+    Ok(vary_on_client(
+        (StatusCode::NO_CONTENT, [(header::ETAG, etag)]).into_response(),
+        inferred,
+    ))
 }
 
 /// Download the latest release
+///
+/// `HEAD` answers with the same status and headers and no body, so probes
+/// can read the ETag and asset size without following the redirect. `auto`
+/// as the target or arch picks the build matching the User-Agent.
 #[utoipa::path(
-    get,
+    method(get, head),
     path = "/download/latest/{app_name}/{target}/{arch}",
+    security(()),
     params(
         ("app_name" = SupportedApp, Path, description = "Application name"),
-        ("target" = SupportedTarget, Path, description = "Target OS"),
-        ("arch" = String, Path, description = "Architecture")
+        ("target" = SupportedTarget, Path, description = "Target OS, or `auto`"),
+        ("arch" = String, Path, description = "Architecture, or `auto`"),
+        LatestQuery
     ),
     responses(
-        (status = 307, description = "Redirect to download URL"),
-        (status = 404, description = "No release found")
+        (status = 307, description = "Redirect to download URL", headers(
+            ("ETag" = String, description = "Tag of the latest release"),
+            ("X-Asset-Size" = i64, description = "Size of the artifact in bytes, when known")
+        )),
+        (status = 304, description = "Latest release unchanged since the ETag in If-None-Match"),
+        (status = 402, description = "The license key has expired (`license_expired`)", body = ErrorBody),
+        (status = 403, description = "The app requires a license key and none or an invalid one was sent (`license_required`, `license_invalid`)", body = ErrorBody),
+        (status = 404, description = "No release found", body = ErrorBody)
     )
 )]
 // Handler to download the latest release (redirect)
 pub async fn download_latest_release(
     Path((app_name, target, arch)): Path<(String, String, String)>,
+    Query(query): Query<LatestQuery>,
     State(state): State<AppState>,
-) -> impl IntoResponse {
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let channel = query.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
     println!(
-        "Received latest download request: app_name={}, target={}, arch={}",
-        app_name, target, arch
+        "Received latest download request: app_name={}, target={}, arch={}, channel={}",
+        app_name, target, arch, channel
     );
+    let key = licenses::request_key(&headers, query.license_key.as_deref());
+    redirect_to_latest(&state, &headers, &app_name, (&target, &arch), channel, key).await
+}
 
-    // Fetch all releases for this app/target/arch
-    let releases = sqlx::query_as::<_, Release>(
-        "SELECT id, app_name, target, arch, version, url, signature, pub_date, notes FROM releases WHERE app_name = ? AND target = ? AND arch = ?"
+/// Download an app
+///
+/// One link for every platform, for download pages: the build is picked by
+/// the User-Agent, as for `/download/latest/{app_name}/auto/auto`.
+#[utoipa::path(
+    method(get, head),
+    path = "/download/{app_name}",
+    security(()),
+    params(
+        ("app_name" = SupportedApp, Path, description = "Application name"),
+        LatestQuery
+    ),
+    responses(
+        (status = 307, description = "Redirect to the download URL of the build for the client's platform", headers(
+            ("ETag" = String, description = "Tag of the latest release"),
+            ("X-Asset-Size" = i64, description = "Size of the artifact in bytes, when known"),
+            ("Vary" = String, description = "`X-License-Key`, `User-Agent`")
+        )),
+        (status = 304, description = "Latest release unchanged since the ETag in If-None-Match"),
+        (status = 402, description = "The license key has expired (`license_expired`)", body = ErrorBody),
+        (status = 403, description = "The app requires a license key and none or an invalid one was sent (`license_required`, `license_invalid`)", body = ErrorBody),
+        (status = 404, description = "No release for the client's platform", body = ErrorBody)
     )
+)]
+pub async fn download_app(
+    Path(app_name): Path<String>,
+    Query(query): Query<LatestQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let channel = query.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
+    println!(
+        "Received download request: app_name={}, user_agent={}, channel={}",
+        app_name,
+        headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-"),
+        channel
+    );
+    redirect_to_latest(
+        &state,
+        &headers,
+        &app_name,
+        (platforms::AUTO, platforms::AUTO),
+        channel,
+        licenses::request_key(&headers, query.license_key.as_deref()),
+    )
+    .await
+}
+
+async fn redirect_to_latest(
+    state: &AppState,
+    headers: &HeaderMap,
+    app_name: &str,
+    (target, arch): (&str, &str),
+    channel: &str,
+    license_key: Option<&str>,
+) -> AppResult<Response> {
+    let inferred = platforms::is_inferred(target, arch);
+    let Some((target, arch)) =
+        requested_platform(state, headers, app_name, (target, arch), channel).await?
+    else {
+        // Another User-Agent may well get a build, so this 404 varies too.
+        return Ok(vary_on_client(
+            AppError::not_found("No release found for this platform; pick a target and arch")
+                .into_response(),
+            inferred,
+        ));
+    };
+    licenses::check_download(state, license_key, (app_name, &target, &arch, channel)).await?;
+    let latest_release = find_latest_release(state, app_name, &target, &arch, channel)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up the latest release", e))?
+        .ok_or_else(|| AppError::not_found("No release found"))?;
+    let etag = http_cache::release_etag(Some(&latest_release));
+    if http_cache::not_modified(headers, &etag) {
+        return Ok(vary_on_client(
+            (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response(),
+            inferred,
+        ));
+    }
+
+    // The redirect itself has an empty body, so the artifact's size goes in
+    // its own header rather than Content-Length. Releases uploaded before
+    // deduplication have no artifact row and no known size.
+    let size = match &latest_release.sha256 {
+        Some(sha256) => artifacts::find(&state.read_pool, &latest_release.app_name, sha256)
+            .await
+            .map_err(|e| AppError::internal("Failed to look up artifact", e))?
+            .map(|a| a.size),
+        None => None,
+    };
+
+    println!("Redirecting to: {}", latest_release.url);
+    let mut response = axum::response::Redirect::temporary(&latest_release.url).into_response();
+    let response_headers = response.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        response_headers.insert(header::ETAG, etag);
+    }
+    if let Some(size) = size {
+        response_headers.insert(http_cache::ASSET_SIZE_HEADER, HeaderValue::from(size));
+    }
+    Ok(vary_on_client(response, inferred))
+}
+
+/// Get an app's metadata
+#[utoipa::path(
+    get,
+    path = "/metadata/{app_name}",
+    security(()),
+    params(
+        ("app_name" = SupportedApp, Path, description = "Application name")
+    ),
+    responses(
+        (status = 200, description = "Display name, icon and links of the app", body = AppMetadata),
+        (status = 404, description = "Unknown app", body = ErrorBody)
+    )
+)]
+pub async fn get_app_metadata(
+    Path(app_name): Path<String>,
+    State(state): State<AppState>,
+) -> AppResult<Json<AppMetadata>> {
+    let metadata = sqlx::query_as::<_, AppMetadata>(&format!(
+        "SELECT {} FROM apps WHERE name = ?",
+        APP_METADATA_COLUMNS
+    ))
     .bind(&app_name)
-    .bind(&target)
-    .bind(&arch)
-    .fetch_all(&state.pool)
+    .fetch_optional(&state.read_pool)
     .await
-    .unwrap_or_else(|_| vec![]);
+    .map_err(|e| AppError::internal("Failed to load app metadata", e))?
+    .ok_or_else(|| AppError::not_found("App not found"))?;
 
-    // Find the latest version
-    let latest_release = releases
-        .into_iter()
-        .filter_map(|r| {
-            let v = Version::parse(&r.version).ok()?;
-            Some((v, r))
-        })
-        .max_by(|(v1, _), (v2, _)| v1.cmp(v2));
+    Ok(Json(metadata))
+}
+
+/// Export the latest release for an MDM
+///
+/// `intune` describes the latest Windows release as an Intune Win32 app,
+/// `jamf` the latest macOS release as a Jamf Pro package.
+#[utoipa::path(
+    get,
+    path = "/mdm/{format}/{app_name}/{arch}",
+    security(()),
+    params(
+        ("format" = MdmFormat, Path, description = "MDM to describe the release for"),
+        ("app_name" = SupportedApp, Path, description = "Application name"),
+        ("arch" = String, Path, description = "Architecture"),
+        LatestQuery
+    ),
+    responses(
+        (status = 200, description = "Deployment descriptor, as an attachment", body = Object),
+        (status = 304, description = "Latest release unchanged since the ETag in If-None-Match"),
+        (status = 402, description = "The license key has expired (`license_expired`)", body = ErrorBody),
+        (status = 403, description = "The app requires a license key and none or an invalid one was sent (`license_required`, `license_invalid`)", body = ErrorBody),
+        (status = 404, description = "Unknown format, or no release found", body = ErrorBody)
+    )
+)]
+pub async fn export_mdm_descriptor(
+    Path((format, app_name, arch)): Path<(String, String, String)>,
+    Query(query): Query<LatestQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let format = MdmFormat::parse(&format)
+        .ok_or_else(|| AppError::not_found("Unknown MDM format; use intune or jamf"))?;
+    let channel = query.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
+    println!(
+        "Received MDM export: format={}, app_name={}, arch={}, channel={}",
+        format.name(),
+        app_name,
+        arch,
+        channel
+    );
+
+    let key = licenses::request_key(&headers, query.license_key.as_deref());
+    licenses::check_download(&state, key, (&app_name, format.target(), &arch, channel)).await?;
+    let release = find_latest_release(&state, &app_name, format.target(), &arch, channel)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up the latest release", e))?
+        .ok_or_else(|| AppError::not_found("No release found"))?;
+    let etag = http_cache::release_etag(Some(&release));
+    if http_cache::not_modified(&headers, &etag) {
+        return Ok(vary_on_client(
+            (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response(),
+            false,
+        ));
+    }
 
-    if let Some((_, release)) = latest_release {
-        println!("Redirecting to: {}", release.url);
-        return axum::response::Redirect::temporary(&release.url).into_response();
+    let descriptor = match format {
+        MdmFormat::Intune => mdm::intune(&release, &state.config.mdm_publisher),
+        MdmFormat::Jamf => mdm::jamf(&release),
+    };
+    let disposition = format!(
+        "attachment; filename=\"{}-{}-{}.json\"",
+        release.app_name,
+        release.version,
+        format.name()
+    );
+    Ok(vary_on_client(
+        (
+            StatusCode::OK,
+            [
+                (header::ETAG, etag),
+                (header::CONTENT_DISPOSITION, disposition),
+            ],
+            Json(descriptor),
+        )
+            .into_response(),
+        false,
+    ))
+}
+
+/// Atom feed of recent releases
+#[utoipa::path(
+    get,
+    path = "/feed/{app_name}.atom",
+    security(()),
+    params(
+        ("app_name" = SupportedApp, Path, description = "Application name"),
+        ChannelQuery
+    ),
+    responses(
+        (status = 200, description = "Atom feed, one entry per version", body = String, content_type = "application/atom+xml"),
+        (status = 404, description = "Unknown feed, or the app requires a license key", body = ErrorBody),
+        (status = 500, description = "Internal server error", body = ErrorBody)
+    )
+)]
+pub async fn release_feed(
+    Path(feed_name): Path<String>,
+    Query(query): Query<ChannelQuery>,
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let Some(app_name) = feed_name.strip_suffix(".atom") else {
+        return Err(AppError::not_found("Feed not found"));
+    };
+    let channel = query.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
+    println!(
+        "Received feed request: app_name={}, channel={}",
+        app_name, channel
+    );
+    // Feed readers can't send a license key, and entries link to every
+    // platform's download.
+    if licenses::requires_license(&state, app_name).await? {
+        return Err(AppError::not_found("Feed not found"));
     }
 
-    (StatusCode::NOT_FOUND, "No release found").into_response()
+    let releases = sqlx::query_as::<_, Release>(&format!(
+        "SELECT {} FROM releases WHERE app_name = ? AND channel = ? AND yanked = 0 AND archived = 0 ORDER BY pub_date DESC LIMIT ?",
+        RELEASE_COLUMNS
+    ))
+    .bind(app_name)
+    .bind(channel)
+    // Enough rows to fill every entry even when each version ships several platforms.
+    .bind((feed::MAX_ENTRIES * 10) as i64)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load releases", e))?;
+
+    // The feed is served under both `/v1` and the legacy path, so link to
+    // whichever one was requested.
+    let self_url = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(|host| format!("http://{}{}", host, uri));
+
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+        feed::render(app_name, channel, self_url.as_deref(), &releases),
+    )
+        .into_response())
 }
 
 /// Root endpoint
 #[utoipa::path(
     get,
     path = "/",
+    security(()),
     responses(
         (status = 200, description = "Service is running", body = String)
     )
@@ -398,38 +1342,795 @@ pub async fn root() -> &'static str {
 #[utoipa::path(
     get,
     path = "/releases",
+    params(ReleaseFilter),
     responses(
         (status = 200, description = "List of all releases", body = Vec<Release>)
     )
 )]
-pub async fn get_releases(State(state): State<AppState>) -> impl IntoResponse {
-    let releases = sqlx::query_as::<_, Release>(
-        "SELECT id, app_name, target, arch, version, url, signature, pub_date, notes FROM releases ORDER BY pub_date DESC"
-    )
-    .fetch_all(&state.pool)
-    .await
-    .unwrap_or_else(|_| vec![]);
+pub async fn get_releases(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<ReleaseFilter>,
+) -> AppResult<Response> {
+    let releases = visible_releases(&state, principal, &filter)
+        .await
+        .map_err(|e| AppError::internal("Failed to load releases", e))?;
 
     let mut buf = Vec::new();
     let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
     let mut ser = serde_json::Serializer::with_formatter(&mut buf, formatter);
-    match serde::Serialize::serialize(&releases, &mut ser) {
-        Ok(_) => {
-            let json_string = String::from_utf8(buf).unwrap_or_default();
-            (
-                StatusCode::OK,
-                [("content-type", "application/json")],
-                json_string,
-            )
-                .into_response()
+    serde::Serialize::serialize(&releases, &mut ser)
+        .map_err(|e| AppError::internal("Failed to serialize releases", e))?;
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/json")],
+        buf,
+    )
+        .into_response())
+}
+
+/// Releases of `principal`'s organization matching `filter`, newest first.
+pub async fn visible_releases(
+    state: &AppState,
+    principal: Principal,
+    filter: &ReleaseFilter,
+) -> Result<Vec<Release>, sqlx::Error> {
+    sqlx::query_as::<_, Release>(&format!(
+        "SELECT {} FROM releases WHERE (?1 IS NULL OR pub_date > ?1) AND (?2 IS NULL OR pub_date < ?2) AND {} ORDER BY pub_date DESC",
+        RELEASE_COLUMNS,
+        app_scope(3)
+    ))
+    .bind(filter.published_after)
+    .bind(filter.published_before)
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+}
+
+/// Delete a release
+#[utoipa::path(
+    delete,
+    path = "/releases/{id}",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    responses(
+        (status = 204, description = "Release deleted"),
+        (status = 404, description = "Release not found", body = ErrorBody),
+        (status = 500, description = "Internal server error", body = ErrorBody)
+    )
+)]
+pub async fn delete_release(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    println!("Received delete request for release {}", id);
+
+    let deleted: Result<Option<(Release, Vec<Artifact>)>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        let Some(release) = sqlx::query_as::<_, Release>(&format!(
+            "DELETE FROM releases WHERE id = ?1 AND {} RETURNING {}",
+            app_scope(2),
+            RELEASE_COLUMNS
+        ))
+        .bind(id)
+        .bind(principal.org_id())
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let mut orphaned = Vec::new();
+        if let Some(sha256) = &release.sha256 {
+            orphaned.extend(artifacts::release(&mut tx, &release.app_name, sha256).await?);
         }
-        Err(e) => {
-            println!("Error serializing releases: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to serialize releases",
-            )
-                .into_response()
+        if let Some(sha256) = variants::remove_variant(&mut tx, release.id).await? {
+            orphaned.extend(artifacts::release(&mut tx, &release.app_name, &sha256).await?);
+        }
+        for sha256 in components::remove_components(&mut tx, release.id).await? {
+            orphaned.extend(artifacts::release(&mut tx, &release.app_name, &sha256).await?);
         }
+        for sha256 in deltas::remove_deltas(&mut tx, release.id).await? {
+            orphaned.extend(artifacts::release(&mut tx, &release.app_name, &sha256).await?);
+        }
+        for sha256 in attachments::remove_attachments(&mut tx, release.id).await? {
+            orphaned.extend(artifacts::release(&mut tx, &release.app_name, &sha256).await?);
+        }
+        sqlx::query("DELETE FROM install_reports WHERE release_id = ?")
+            .bind(release.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM release_promotions WHERE release_id = ?")
+            .bind(release.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM release_bundles WHERE release_id = ?")
+            .bind(release.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM release_rules WHERE release_id = ?")
+            .bind(release.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some((release, orphaned)))
+    }
+    .await;
+
+    let (release, orphaned) = deleted
+        .map_err(|e| AppError::internal("Failed to delete release", e))?
+        .ok_or_else(|| AppError::not_found("Release not found"))?;
+
+    state
+        .cache
+        .invalidate(&cache::latest_key(
+            &release.app_name,
+            &release.target,
+            &release.arch,
+            &release.channel,
+        ))
+        .await;
+
+    // Last reference gone: remove the stored bytes too.
+    for artifact in orphaned {
+        delete_orphaned(&state, Some(artifact)).await;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Yank a release
+#[utoipa::path(
+    post,
+    path = "/releases/{id}/yank",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    responses(
+        (status = 200, description = "Release yanked; clients are no longer offered it", body = Release),
+        (status = 404, description = "Release not found", body = ErrorBody),
+        (status = 500, description = "Internal server error", body = ErrorBody)
+    )
+)]
+pub async fn yank_release(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Release>> {
+    println!("Received yank request for release {}", id);
+
+    let release = sqlx::query_as::<_, Release>(&format!(
+        "UPDATE releases SET yanked = 1 WHERE id = ?1 AND {} RETURNING {}",
+        app_scope(2),
+        RELEASE_COLUMNS
+    ))
+    .bind(id)
+    .bind(principal.org_id())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to yank release", e))?
+    .ok_or_else(|| AppError::not_found("Release not found"))?;
+
+    state
+        .cache
+        .invalidate(&cache::latest_key(
+            &release.app_name,
+            &release.target,
+            &release.arch,
+            &release.channel,
+        ))
+        .await;
+    state
+        .events
+        .emit(ReleaseEvent::new(ReleaseEventKind::Yanked, release.clone()))
+        .await;
+
+    Ok(Json(release))
+}
+
+/// Promote a release to another channel
+#[utoipa::path(
+    post,
+    path = "/releases/{id}/promote",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    params(OverrideQuery),
+    request_body = PromoteRequest,
+    responses(
+        (status = 200, description = "Release moved to the requested channel", body = Release),
+        (status = 400, description = "Invalid channel name", body = ErrorBody),
+        (status = 403, description = "Freeze override without the operator token", body = ErrorBody),
+        (status = 404, description = "Release not found", body = ErrorBody),
+        (status = 409, description = "Publishing is frozen", body = ErrorBody),
+        (status = 500, description = "Internal server error", body = ErrorBody)
+    )
+)]
+pub async fn promote_release(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(freeze): Query<OverrideQuery>,
+    Json(request): Json<PromoteRequest>,
+) -> AppResult<Json<Release>> {
+    println!(
+        "Received promote request for release {} to {}",
+        id, request.channel
+    );
+
+    if !is_valid_channel(&request.channel) {
+        return Err(invalid_channel());
+    }
+
+    let app_name = sqlx::query_scalar::<_, String>(&format!(
+        "SELECT app_name FROM releases WHERE id = ?1 AND {}",
+        app_scope(2)
+    ))
+    .bind(id)
+    .bind(principal.org_id())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to promote release", e))?
+    .ok_or_else(|| AppError::not_found("Release not found"))?;
+    freezes::check_publish(&state.pool, principal, &app_name, &freeze).await?;
+
+    let promoted: Result<Option<(String, Release)>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        let Some(previous) = sqlx::query_scalar::<_, String>(&format!(
+            "SELECT channel FROM releases WHERE id = ?1 AND {}",
+            app_scope(2)
+        ))
+        .bind(id)
+        .bind(principal.org_id())
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let release = sqlx::query_as::<_, Release>(&format!(
+            "UPDATE releases SET channel = ? WHERE id = ? RETURNING {}",
+            RELEASE_COLUMNS
+        ))
+        .bind(&request.channel)
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some((previous, release)))
+    }
+    .await;
+
+    let (previous_channel, release) = promoted
+        .map_err(|e| AppError::internal("Failed to promote release", e))?
+        .ok_or_else(|| AppError::not_found("Release not found"))?;
+
+    for channel in [&previous_channel, &release.channel] {
+        state
+            .cache
+            .invalidate(&cache::latest_key(
+                &release.app_name,
+                &release.target,
+                &release.arch,
+                channel,
+            ))
+            .await;
+    }
+
+    if previous_channel != release.channel {
+        let mut event = ReleaseEvent::new(ReleaseEventKind::Promoted, release.clone());
+        event.previous_channel = Some(previous_channel);
+        state.events.emit(event).await;
+    }
+
+    Ok(Json(release))
+}
+
+/// Stream release activity
+#[utoipa::path(
+    get,
+    path = "/events",
+    params(EventFilter),
+    responses(
+        (status = 200, description = "Server-sent events named `published`, `yanked`, `promoted` or `held`, each carrying a ReleaseEvent", body = crate::events::ReleaseEvent, content_type = "text/event-stream")
+    )
+)]
+pub async fn stream_events(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<EventFilter>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    println!(
+        "Event stream opened (app_name={})",
+        filter.app_name.as_deref().unwrap_or("*")
+    );
+
+    let pool = state.read_pool.clone();
+    let stream = BroadcastStream::new(state.events.subscribe())
+        .then(move |received| {
+            let pool = pool.clone();
+            let app_name = filter.app_name.clone();
+            async move {
+                // A lagging client silently skips the events it missed.
+                let event = received.ok()?;
+                if app_name.is_some_and(|app| app != event.release.app_name) {
+                    return None;
+                }
+                if let Some(org_id) = principal.org_id() {
+                    let owner: Option<i64> =
+                        sqlx::query_scalar("SELECT org_id FROM apps WHERE name = ?")
+                            .bind(&event.release.app_name)
+                            .fetch_optional(&pool)
+                            .await
+                            .ok()
+                            .flatten();
+                    if owner != Some(org_id) {
+                        return None;
+                    }
+                }
+                let sse = Event::default()
+                    .event(event.kind.as_str())
+                    .json_data(&event)
+                    .ok()?;
+                Some(Ok(sse))
+            }
+        })
+        .filter_map(|sse| sse);
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+pub fn invalid_channel() -> AppError {
+    AppError::bad_request("channel must be a lowercase slug (letters, digits, '-')")
+}
+
+pub fn validate_http_url(raw: &str) -> AppResult<()> {
+    match url::Url::parse(raw) {
+        Ok(u) if matches!(u.scheme(), "http" | "https") && u.host().is_some() => Ok(()),
+        Ok(_) => Err(AppError::bad_request("url must be an absolute http(s) URL")),
+        Err(e) => Err(AppError::bad_request(format!("Invalid url: {}", e))),
+    }
+}
+
+/// Create a webhook subscription
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    request_body = WebhookRequest,
+    responses(
+        (status = 201, description = "Webhook created", body = Webhook),
+        (status = 400, description = "Invalid URL or missing secret", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody),
+        (status = 500, description = "Internal server error", body = ErrorBody)
+    )
+)]
+pub async fn create_webhook(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<WebhookRequest>,
+) -> AppResult<(StatusCode, Json<Webhook>)> {
+    validate_http_url(&request.url)?;
+    let Some(secret) = request.secret.filter(|s| !s.is_empty()) else {
+        return Err(AppError::bad_request("secret is required"));
+    };
+    if let Some(app_name) = &request.app_name {
+        auth::claim_app(&state.pool, principal, app_name).await?;
+    }
+
+    let row = sqlx::query_as::<_, WebhookRow>(&format!(
+        "INSERT INTO webhooks (org_id, url, secret, events, app_name, active, created_at) VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING {}",
+        WEBHOOK_COLUMNS
+    ))
+    .bind(principal.org_id())
+    .bind(&request.url)
+    .bind(&secret)
+    .bind(webhooks::encode_events(&request.events))
+    .bind(&request.app_name)
+    .bind(request.active)
+    .bind(Utc::now())
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to create webhook", e))?;
+
+    println!("Created webhook {} for {}", row.id, row.url);
+    Ok((StatusCode::CREATED, Json(Webhook::from(row))))
+}
+
+/// List webhook subscriptions
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    responses(
+        (status = 200, description = "All webhook subscriptions", body = Vec<Webhook>)
+    )
+)]
+pub async fn list_webhooks(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<Webhook>>> {
+    let hooks = sqlx::query_as::<_, WebhookRow>(&format!(
+        "SELECT {} FROM webhooks WHERE {} ORDER BY id",
+        WEBHOOK_COLUMNS,
+        org_scope(1)
+    ))
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load webhooks", e))?;
+
+    Ok(Json(hooks.into_iter().map(Webhook::from).collect()))
+}
+
+/// Get a webhook subscription
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}",
+    params(
+        ("id" = i64, Path, description = "Webhook ID")
+    ),
+    responses(
+        (status = 200, description = "Webhook subscription", body = Webhook),
+        (status = 404, description = "Webhook not found", body = ErrorBody)
+    )
+)]
+pub async fn get_webhook(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Webhook>> {
+    Ok(Json(Webhook::from(
+        visible_webhook(&state, principal, id).await?,
+    )))
+}
+
+async fn visible_webhook(state: &AppState, principal: Principal, id: i64) -> AppResult<WebhookRow> {
+    sqlx::query_as::<_, WebhookRow>(&format!(
+        "SELECT {} FROM webhooks WHERE id = ?1 AND {}",
+        WEBHOOK_COLUMNS,
+        org_scope(2)
+    ))
+    .bind(id)
+    .bind(principal.org_id())
+    .fetch_optional(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load webhook", e))?
+    .ok_or_else(|| AppError::not_found("Webhook not found"))
+}
+
+/// Replace a webhook subscription
+#[utoipa::path(
+    put,
+    path = "/webhooks/{id}",
+    params(
+        ("id" = i64, Path, description = "Webhook ID")
+    ),
+    request_body = WebhookRequest,
+    responses(
+        (status = 200, description = "Webhook updated", body = Webhook),
+        (status = 400, description = "Invalid URL", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody),
+        (status = 404, description = "Webhook not found", body = ErrorBody),
+        (status = 500, description = "Internal server error", body = ErrorBody)
+    )
+)]
+pub async fn update_webhook(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<WebhookRequest>,
+) -> AppResult<Json<Webhook>> {
+    validate_http_url(&request.url)?;
+    if let Some(app_name) = &request.app_name {
+        auth::claim_app(&state.pool, principal, app_name).await?;
+    }
+
+    let row = sqlx::query_as::<_, WebhookRow>(&format!(
+        "UPDATE webhooks SET url = ?1, secret = coalesce(?2, secret), events = ?3, app_name = ?4, active = ?5 WHERE id = ?6 AND {} RETURNING {}",
+        org_scope(7),
+        WEBHOOK_COLUMNS
+    ))
+    .bind(&request.url)
+    .bind(request.secret.filter(|s| !s.is_empty()))
+    .bind(webhooks::encode_events(&request.events))
+    .bind(&request.app_name)
+    .bind(request.active)
+    .bind(id)
+    .bind(principal.org_id())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to update webhook", e))?
+    .ok_or_else(|| AppError::not_found("Webhook not found"))?;
+
+    Ok(Json(Webhook::from(row)))
+}
+
+/// Delete a webhook subscription and its delivery log
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{id}",
+    params(
+        ("id" = i64, Path, description = "Webhook ID")
+    ),
+    responses(
+        (status = 204, description = "Webhook deleted"),
+        (status = 404, description = "Webhook not found", body = ErrorBody),
+        (status = 500, description = "Internal server error", body = ErrorBody)
+    )
+)]
+pub async fn delete_webhook(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let deleted = sqlx::query(&format!(
+        "DELETE FROM webhooks WHERE id = ?1 AND {}",
+        org_scope(2)
+    ))
+    .bind(id)
+    .bind(principal.org_id())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to delete webhook", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("Webhook not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List deliveries for a webhook
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}/deliveries",
+    params(
+        ("id" = i64, Path, description = "Webhook ID"),
+        DeliveryFilter
+    ),
+    responses(
+        (status = 200, description = "Deliveries, newest first", body = Vec<WebhookDelivery>),
+        (status = 404, description = "Webhook not found", body = ErrorBody)
+    )
+)]
+pub async fn list_webhook_deliveries(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<DeliveryFilter>,
+) -> AppResult<Json<Vec<WebhookDelivery>>> {
+    visible_webhook(&state, principal, id).await?;
+    let deliveries = sqlx::query_as::<_, WebhookDelivery>(&format!(
+        "SELECT {} FROM webhook_deliveries WHERE webhook_id = ?1 AND (?2 IS NULL OR status = ?2) ORDER BY id DESC LIMIT ?3",
+        DELIVERY_COLUMNS
+    ))
+    .bind(id)
+    .bind(&filter.status)
+    .bind(filter.limit.unwrap_or(50).clamp(1, 500))
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load deliveries", e))?;
+
+    Ok(Json(deliveries))
+}
+
+fn is_plausible_email(email: &str) -> bool {
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && domain.contains('.')
+        && email.len() <= 254
+        && !email
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '<' | '>' | ',' | ';'))
+}
+
+/// Subscribe an email address to releases
+#[utoipa::path(
+    post,
+    path = "/subscriptions",
+    request_body = SubscriptionRequest,
+    responses(
+        (status = 201, description = "Subscription created", body = Subscription),
+        (status = 200, description = "Already subscribed", body = Subscription),
+        (status = 400, description = "Invalid email or channel", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody),
+        (status = 500, description = "Internal server error", body = ErrorBody)
+    )
+)]
+pub async fn create_subscription(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<SubscriptionRequest>,
+) -> AppResult<(StatusCode, Json<Subscription>)> {
+    let email = request.email.trim().to_lowercase();
+    if !is_plausible_email(&email) {
+        return Err(AppError::bad_request("Invalid email address"));
+    }
+    let channel = request
+        .channel
+        .unwrap_or_else(|| DEFAULT_CHANNEL.to_string());
+    if !is_valid_channel(&channel) {
+        return Err(invalid_channel());
+    }
+    auth::claim_app(&state.pool, principal, &request.app_name).await?;
+
+    let inserted = sqlx::query_as::<_, Subscription>(&format!(
+        "INSERT INTO email_subscriptions (email, app_name, channel, token, created_at) VALUES (?, ?, ?, ?, ?)
+         ON CONFLICT (email, app_name, channel) DO NOTHING RETURNING {}",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(&email)
+    .bind(&request.app_name)
+    .bind(&channel)
+    .bind(auth::random_token()?)
+    .bind(Utc::now())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to subscribe", e))?;
+
+    if let Some(sub) = inserted {
+        println!(
+            "Subscribed {} to {} ({})",
+            sub.email, sub.app_name, sub.channel
+        );
+        return Ok((StatusCode::CREATED, Json(sub)));
     }
+
+    let existing = sqlx::query_as::<_, Subscription>(&format!(
+        "SELECT {} FROM email_subscriptions WHERE email = ? AND app_name = ? AND channel = ?",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(&email)
+    .bind(&request.app_name)
+    .bind(&channel)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to subscribe", e))?;
+    Ok((StatusCode::OK, Json(existing)))
+}
+
+/// List email subscriptions
+#[utoipa::path(
+    get,
+    path = "/subscriptions",
+    params(SubscriptionFilter),
+    responses(
+        (status = 200, description = "Subscriptions", body = Vec<Subscription>)
+    )
+)]
+pub async fn list_subscriptions(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(filter): Query<SubscriptionFilter>,
+) -> AppResult<Json<Vec<Subscription>>> {
+    let subs = sqlx::query_as::<_, Subscription>(&format!(
+        "SELECT {} FROM email_subscriptions WHERE (?1 IS NULL OR app_name = ?1) AND {} ORDER BY app_name, channel, email",
+        SUBSCRIPTION_COLUMNS,
+        app_scope(2)
+    ))
+    .bind(&filter.app_name)
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load subscriptions", e))?;
+
+    Ok(Json(subs))
+}
+
+/// Remove an email subscription
+#[utoipa::path(
+    delete,
+    path = "/subscriptions/{id}",
+    params(
+        ("id" = i64, Path, description = "Subscription ID")
+    ),
+    responses(
+        (status = 204, description = "Subscription removed"),
+        (status = 404, description = "Subscription not found", body = ErrorBody),
+        (status = 500, description = "Internal server error", body = ErrorBody)
+    )
+)]
+pub async fn delete_subscription(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let deleted = sqlx::query(&format!(
+        "DELETE FROM email_subscriptions WHERE id = ?1 AND {}",
+        app_scope(2)
+    ))
+    .bind(id)
+    .bind(principal.org_id())
+    .execute(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to delete subscription", e))?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::not_found("Subscription not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Unsubscribe confirmation page
+#[utoipa::path(
+    get,
+    path = "/unsubscribe/{token}",
+    security(()),
+    params(
+        ("token" = String, Path, description = "Unsubscribe token from the email")
+    ),
+    responses(
+        (status = 200, description = "Confirmation form", body = String, content_type = "text/html"),
+        (status = 404, description = "Unknown or already used token", body = ErrorBody)
+    )
+)]
+pub async fn unsubscribe_page(
+    Path(token): Path<String>,
+    State(state): State<AppState>,
+) -> AppResult<Response> {
+    // Link scanners and mail previews follow GET links, so GET only shows a
+    // form and the unsubscribe itself happens on POST.
+    let sub = sqlx::query_as::<_, Subscription>(&format!(
+        "SELECT {} FROM email_subscriptions WHERE token = ?",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(&token)
+    .fetch_optional(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load subscription", e))?
+    .ok_or_else(|| AppError::not_found("This unsubscribe link is no longer valid"))?;
+
+    let escape = |s: &str| {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+    let html = format!(
+        "<!doctype html><html><body><p>Stop emailing {} about new {} releases ({})?</p>\
+         <form method=\"post\"><button type=\"submit\">Unsubscribe</button></form></body></html>",
+        escape(&sub.email),
+        escape(&sub.app_name),
+        escape(&sub.channel)
+    );
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+        html,
+    )
+        .into_response())
+}
+
+/// Unsubscribe (also the RFC 8058 one-click target)
+#[utoipa::path(
+    post,
+    path = "/unsubscribe/{token}",
+    security(()),
+    params(
+        ("token" = String, Path, description = "Unsubscribe token from the email")
+    ),
+    responses(
+        (status = 200, description = "Unsubscribed", body = String),
+        (status = 404, description = "Unknown or already used token", body = ErrorBody)
+    )
+)]
+pub async fn unsubscribe(
+    Path(token): Path<String>,
+    State(state): State<AppState>,
+) -> AppResult<String> {
+    let sub = sqlx::query_as::<_, Subscription>(&format!(
+        "DELETE FROM email_subscriptions WHERE token = ? RETURNING {}",
+        SUBSCRIPTION_COLUMNS
+    ))
+    .bind(&token)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to unsubscribe", e))?
+    .ok_or_else(|| AppError::not_found("This unsubscribe link is no longer valid"))?;
+
+    println!(
+        "Unsubscribed {} from {} ({})",
+        sub.email, sub.app_name, sub.channel
+    );
+    Ok(format!(
+        "You will no longer receive emails about {} releases.",
+        sub.app_name
+    ))
 }
@@ -0,0 +1,469 @@
+//! Targeting rules.
+//!
+//! A release can carry an ordered list of rules, each a set of conditions
+//! over the checking client (its version, OS version, channel, hash bucket,
+//! country and custom `param.<name>` query parameters) and an action. On
+//! every update check offering the release, the first rule whose conditions
+//! all hold decides: `allow` offers it, `deny` offers nothing, and
+//! `serve_version` offers another version of the same app and platform
+//! instead. Without a matching rule the release is offered as usual.
+//!
+//! Rules apply after rings and deferral have picked the release, so they
+//! narrow a rollout rather than replace it. Devices targeted with a version
+//! skip them, like they skip rings. When the country comes from
+//! `COUNTRY_HEADER`, answers for a release whose rules look at it vary on
+//! that header, so a CDN doesn't replay one country's answer to another.
+
+use std::collections::BTreeMap;
+
+use axum::{
+    Extension,
+    extract::{Path, State},
+    http::HeaderMap,
+    response::Json,
+};
+use semver::{Version, VersionReq};
+use sqlx::{Pool, Sqlite};
+
+use crate::auth::Principal;
+use crate::devices;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::flags;
+use crate::schema::{
+    AppState, Release, RuleAction, TargetingRule, TargetingRulesRequest, UpdateCheckQuery,
+    is_valid_channel,
+};
+use crate::variants::scoped_release;
+use crate::versioning;
+
+/// Most rules one release can carry.
+const MAX_RULES: usize = 64;
+
+/// Query parameters starting with this are custom parameters.
+pub const PARAM_PREFIX: &str = "param.";
+
+/// What an update check tells about the client.
+#[derive(Debug)]
+pub struct RuleContext {
+    /// The current version, as semver; see [`crate::versioning`].
+    pub version: Version,
+    pub os_version: Option<Version>,
+    pub channel: String,
+    /// 0-99, from the client's ID.
+    pub bucket: Option<u8>,
+    /// Uppercase ISO 3166 code.
+    pub country: Option<String>,
+    pub params: BTreeMap<String, String>,
+}
+
+impl RuleContext {
+    /// Collects the context of an update check. The country comes from the
+    /// configured proxy header when there is one, else from the query.
+    pub fn new(
+        state: &AppState,
+        headers: &HeaderMap,
+        (app_name, channel): (&str, &str),
+        version: Version,
+        check: &UpdateCheckQuery,
+        query: &BTreeMap<String, String>,
+    ) -> Self {
+        let country = match &state.config.country_header {
+            Some(name) => headers.get(name.as_str()).and_then(|v| v.to_str().ok()),
+            None => check.country.as_deref(),
+        };
+        let client_id = check.client_id.as_deref().or(check.device_id.as_deref());
+        RuleContext {
+            version,
+            os_version: check.os_version.as_deref().and_then(parse_os_version),
+            channel: channel.to_string(),
+            // Salted with the app only, not the release, so a client stays
+            // in the same slice of the fleet across versions.
+            bucket: client_id.map(|id| flags::bucket(app_name, id)),
+            country: country
+                .map(str::trim)
+                .filter(|c| !c.is_empty())
+                .map(str::to_ascii_uppercase),
+            params: query
+                .iter()
+                .filter_map(|(k, v)| Some((k.strip_prefix(PARAM_PREFIX)?.to_string(), v.clone())))
+                .collect(),
+        }
+    }
+}
+
+/// OS versions are rarely semver: `14.5` and `10.0.22631.3880` are read as
+/// `14.5.0` and `10.0.22631`.
+fn parse_os_version(raw: &str) -> Option<Version> {
+    let mut parts = raw.trim().split('.').map(|p| p.parse::<u64>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().unwrap_or(Some(0))?;
+    let patch = parts.next().unwrap_or(Some(0))?;
+    Some(Version::new(major, minor, patch))
+}
+
+fn requirement_matches(req: &Option<String>, version: Option<&Version>) -> bool {
+    match req {
+        None => true,
+        // Requirements were validated when the rules were set.
+        Some(req) => {
+            version.is_some_and(|v| VersionReq::parse(req).is_ok_and(|req| req.matches(v)))
+        }
+    }
+}
+
+fn matches(rule: &TargetingRule, context: &RuleContext) -> bool {
+    let when = &rule.when;
+    requirement_matches(&when.version, Some(&context.version))
+        && requirement_matches(&when.os_version, context.os_version.as_ref())
+        && when
+            .channels
+            .as_ref()
+            .is_none_or(|channels| channels.contains(&context.channel))
+        && when
+            .buckets
+            .is_none_or(|[from, to]| context.bucket.is_some_and(|b| (from..to).contains(&b)))
+        && when.countries.as_ref().is_none_or(|countries| {
+            context
+                .country
+                .as_ref()
+                .is_some_and(|c| countries.contains(c))
+        })
+        && when.params.as_ref().is_none_or(|params| {
+            params
+                .iter()
+                .all(|(name, value)| context.params.get(name) == Some(value))
+        })
+}
+
+/// The first of `rules` matching `context`, with its position.
+pub fn evaluate<'a>(
+    rules: &'a [TargetingRule],
+    context: &RuleContext,
+) -> Option<(usize, &'a TargetingRule)> {
+    rules
+        .iter()
+        .enumerate()
+        .find(|(_, rule)| matches(rule, context))
+}
+
+async fn load(pool: &Pool<Sqlite>, release_id: i64) -> Result<Vec<TargetingRule>, sqlx::Error> {
+    let rules: Vec<sqlx::types::Json<TargetingRule>> =
+        sqlx::query_scalar("SELECT rule FROM release_rules WHERE release_id = ? ORDER BY position")
+            .bind(release_id)
+            .fetch_all(pool)
+            .await?;
+    Ok(rules.into_iter().map(|r| r.0).collect())
+}
+
+/// Runs the rules of `release` for an update check: the release itself,
+/// another version of it, or nothing. Also says whether the rules look at
+/// the country, as the answer then depends on the country header.
+pub async fn apply(
+    state: &AppState,
+    release: Option<Release>,
+    context: &RuleContext,
+) -> AppResult<(Option<Release>, bool)> {
+    let Some(release) = release else {
+        return Ok((None, false));
+    };
+    let rules = load(&state.read_pool, release.id)
+        .await
+        .map_err(|e| AppError::internal("Failed to load targeting rules", e))?;
+    let by_country = rules.iter().any(|rule| rule.when.countries.is_some());
+    let Some((position, rule)) = evaluate(&rules, context) else {
+        return Ok((Some(release), by_country));
+    };
+    println!(
+        "Rule {} of release {} matched: {:?}",
+        position, release.id, rule.action
+    );
+    let served = match (rule.action, &rule.version) {
+        (RuleAction::Allow, _) => Some(release),
+        (RuleAction::Deny, _) => None,
+        (RuleAction::ServeVersion, Some(version)) => devices::targeted_release(
+            &state.read_pool,
+            (
+                &release.app_name,
+                &release.target,
+                &release.arch,
+                &release.channel,
+            ),
+            version,
+        )
+        .await
+        .map_err(|e| AppError::internal("Failed to look up the version a rule serves", e))?,
+        (RuleAction::ServeVersion, None) => Some(release),
+    };
+    Ok((served, by_country))
+}
+
+fn validate_requirement(index: usize, field: &str, req: &Option<String>) -> AppResult<()> {
+    if let Some(req) = req
+        && let Err(e) = VersionReq::parse(req)
+    {
+        return Err(AppError::bad_request(format!(
+            "Rule {}: {} '{}' is not a semver requirement: {}",
+            index, field, req, e
+        )));
+    }
+    Ok(())
+}
+
+async fn validate(
+    state: &AppState,
+    release: &Release,
+    rules: &mut [TargetingRule],
+) -> AppResult<()> {
+    if rules.len() > MAX_RULES {
+        return Err(AppError::bad_request(format!(
+            "A release can carry at most {} rules",
+            MAX_RULES
+        )));
+    }
+    let scheme = versioning::scheme_for(state, &release.app_name).await?;
+    for (index, rule) in rules.iter_mut().enumerate() {
+        let when = &mut rule.when;
+        validate_requirement(index, "version", &when.version)?;
+        validate_requirement(index, "os_version", &when.os_version)?;
+        if let Some(channels) = &when.channels
+            && let Some(channel) = channels.iter().find(|c| !is_valid_channel(c))
+        {
+            return Err(AppError::bad_request(format!(
+                "Rule {}: '{}' is not a valid channel",
+                index, channel
+            )));
+        }
+        if let Some([from, to]) = when.buckets
+            && (from >= to || to > 100)
+        {
+            return Err(AppError::bad_request(format!(
+                "Rule {}: buckets must be [from, to) with from < to <= 100",
+                index
+            )));
+        }
+        if let Some(countries) = &mut when.countries {
+            for country in countries.iter_mut() {
+                if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                    return Err(AppError::bad_request(format!(
+                        "Rule {}: '{}' is not an ISO 3166 country code",
+                        index, country
+                    )));
+                }
+                *country = country.to_ascii_uppercase();
+            }
+        }
+        if let Some(params) = &when.params
+            && params.keys().any(|name| name.is_empty())
+        {
+            return Err(AppError::bad_request(format!(
+                "Rule {}: custom parameter names can't be empty",
+                index
+            )));
+        }
+
+        match (rule.action, rule.version.as_deref().map(str::trim)) {
+            (RuleAction::ServeVersion, Some(version)) if !version.is_empty() => {
+                versioning::parse_field(scheme, "version", version)?;
+                let served = devices::targeted_release(
+                    &state.pool,
+                    (
+                        &release.app_name,
+                        &release.target,
+                        &release.arch,
+                        &release.channel,
+                    ),
+                    version,
+                )
+                .await
+                .map_err(|e| AppError::internal("Failed to look up the release", e))?;
+                if served.is_none() {
+                    return Err(AppError::bad_request(format!(
+                        "Rule {}: {} has no release {} for {}/{}",
+                        index, release.app_name, version, release.target, release.arch
+                    )));
+                }
+                rule.version = Some(version.to_string());
+            }
+            (RuleAction::ServeVersion, _) => {
+                return Err(AppError::bad_request(format!(
+                    "Rule {}: serve_version needs a version",
+                    index
+                )));
+            }
+            (_, Some(_)) => {
+                return Err(AppError::bad_request(format!(
+                    "Rule {}: only serve_version takes a version",
+                    index
+                )));
+            }
+            (_, None) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Targeting rules of a release
+#[utoipa::path(
+    get,
+    path = "/releases/{id}/rules",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    responses(
+        (status = 200, description = "The release's rules, in evaluation order", body = Vec<TargetingRule>),
+        (status = 404, description = "Release not found", body = ErrorBody)
+    )
+)]
+pub async fn get_release_rules(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<TargetingRule>>> {
+    let release = scoped_release(&state, principal, id).await?;
+    let rules = load(&state.read_pool, release.id)
+        .await
+        .map_err(|e| AppError::internal("Failed to load targeting rules", e))?;
+    Ok(Json(rules))
+}
+
+/// Set the targeting rules of a release
+#[utoipa::path(
+    put,
+    path = "/releases/{id}/rules",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    request_body = TargetingRulesRequest,
+    responses(
+        (status = 200, description = "Rules saved", body = Vec<TargetingRule>),
+        (status = 400, description = "Invalid condition, or a version to serve that wasn't released", body = ErrorBody),
+        (status = 404, description = "Release not found", body = ErrorBody)
+    )
+)]
+pub async fn set_release_rules(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<TargetingRulesRequest>,
+) -> AppResult<Json<Vec<TargetingRule>>> {
+    let mut rules = request.rules;
+    let release = scoped_release(&state, principal, id).await?;
+    validate(&state, &release, &mut rules).await?;
+
+    let saved: Result<(), sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        sqlx::query("DELETE FROM release_rules WHERE release_id = ?")
+            .bind(release.id)
+            .execute(&mut *tx)
+            .await?;
+        for (position, rule) in rules.iter().enumerate() {
+            sqlx::query("INSERT INTO release_rules (release_id, position, rule) VALUES (?, ?, ?)")
+                .bind(release.id)
+                .bind(position as i64)
+                .bind(sqlx::types::Json(rule))
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+    .await;
+    saved.map_err(|e| AppError::internal("Failed to save targeting rules", e))?;
+
+    println!(
+        "Release {} now carries {} targeting rules",
+        release.id,
+        rules.len()
+    );
+    Ok(Json(rules))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(json: serde_json::Value) -> TargetingRule {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn context() -> RuleContext {
+        RuleContext {
+            version: Version::new(1, 4, 0),
+            os_version: parse_os_version("10.0.22631.3880"),
+            channel: "stable".to_string(),
+            bucket: Some(42),
+            country: Some("NL".to_string()),
+            params: BTreeMap::from([("school_type".to_string(), "primary".to_string())]),
+        }
+    }
+
+    fn position(rules: &[TargetingRule], context: &RuleContext) -> Option<usize> {
+        evaluate(rules, context).map(|(position, _)| position)
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = [
+            rule(serde_json::json!({"when": {"channels": ["beta"]}, "action": "deny"})),
+            rule(serde_json::json!({"when": {"version": ">=1.2.0, <2.0.0"}, "action": "allow"})),
+            rule(serde_json::json!({"action": "deny"})),
+        ];
+        assert_eq!(position(&rules, &context()), Some(1));
+    }
+
+    #[test]
+    fn no_rule_matches() {
+        let rules = [rule(
+            serde_json::json!({"when": {"version": "<1.0.0"}, "action": "deny"}),
+        )];
+        assert_eq!(position(&rules, &context()), None);
+    }
+
+    #[test]
+    fn every_condition_must_hold() {
+        let all = rule(serde_json::json!({
+            "when": {
+                "os_version": ">=10.0.22000",
+                "channels": ["stable"],
+                "buckets": [40, 50],
+                "countries": ["NL", "BE"],
+                "params": {"school_type": "primary"}
+            },
+            "action": "deny"
+        }));
+        assert!(matches(&all, &context()));
+
+        let mut elsewhere = context();
+        elsewhere.country = Some("DE".to_string());
+        assert!(!matches(&all, &elsewhere));
+
+        let mut other_bucket = context();
+        other_bucket.bucket = Some(50);
+        assert!(!matches(&all, &other_bucket));
+    }
+
+    #[test]
+    fn missing_client_facts_dont_match() {
+        let rules = [
+            rule(serde_json::json!({"when": {"os_version": ">=10.0.0"}, "action": "deny"})),
+            rule(serde_json::json!({"when": {"buckets": [0, 100]}, "action": "deny"})),
+            rule(serde_json::json!({"when": {"countries": ["NL"]}, "action": "deny"})),
+        ];
+        let anonymous = RuleContext {
+            os_version: None,
+            bucket: None,
+            country: None,
+            ..context()
+        };
+        assert_eq!(position(&rules, &anonymous), None);
+    }
+
+    #[test]
+    fn reads_os_versions_loosely() {
+        assert_eq!(parse_os_version("14.5"), Some(Version::new(14, 5, 0)));
+        assert_eq!(
+            parse_os_version("10.0.22631.3880"),
+            Some(Version::new(10, 0, 22631))
+        );
+        assert_eq!(parse_os_version("Sonoma"), None);
+    }
+}
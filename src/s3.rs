@@ -0,0 +1,230 @@
+//! Minimal S3 client: SigV4-signed object PUT and DELETE, and presigned
+//! GETs. Used for notarization uploads and the release archive bucket.
+
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::http::{Method, Request, header};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use sha2::Sha256;
+
+use crate::artifacts;
+use crate::http_client::HttpClient;
+
+/// Characters SigV4 wants encoded in query strings.
+const QUERY: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Characters S3 wants encoded in object keys.
+pub const KEY: &AsciiSet = &QUERY.remove(b'/');
+
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// For temporary credentials.
+    pub session_token: Option<String>,
+}
+
+/// A bucket, addressed by its virtual-hosted URL, e.g.
+/// `https://releases-archive.s3.eu-central-1.amazonaws.com`.
+pub struct Bucket {
+    pub url: String,
+    pub region: String,
+    pub credentials: Credentials,
+}
+
+/// The host and path a signature covers.
+fn host_and_path(url: &str) -> Result<(String, String), String> {
+    let parsed = url::Url::parse(url).map_err(|e| format!("Invalid S3 URL {}: {}", url, e))?;
+    let host = match (parsed.host_str(), parsed.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return Err(format!("S3 URL {} has no host", url)),
+    };
+    Ok((host, parsed.path().to_string()))
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl Bucket {
+    /// URL of the object at `key`.
+    pub fn object_url(&self, key: &str) -> String {
+        format!(
+            "{}/{}",
+            self.url.trim_end_matches('/'),
+            utf8_percent_encode(key, KEY)
+        )
+    }
+
+    /// The object key of a URL from [`Bucket::object_url`], if it is in
+    /// this bucket.
+    pub fn key_of(&self, url: &str) -> Option<String> {
+        let encoded = url.strip_prefix(&format!("{}/", self.url.trim_end_matches('/')))?;
+        percent_encoding::percent_decode_str(encoded)
+            .decode_utf8()
+            .ok()
+            .map(|k| k.into_owned())
+    }
+
+    fn scope(&self, date: &str) -> String {
+        format!("{}/{}/s3/aws4_request", date, self.region)
+    }
+
+    fn signature(&self, now: DateTime<Utc>, canonical_request: &str) -> String {
+        let date = now.format("%Y%m%d").to_string();
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            now.format("%Y%m%dT%H%M%SZ"),
+            self.scope(&date),
+            artifacts::sha256_hex(canonical_request.as_bytes())
+        );
+        let signing_key = [date.as_str(), self.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.credentials.secret_access_key).into_bytes(),
+                |key, part| hmac_sha256(&key, part),
+            );
+        hex::encode(hmac_sha256(&signing_key, &string_to_sign))
+    }
+
+    /// Sends a signed request for the object at `url`. `headers` are
+    /// `x-amz-*` headers to sign along, in lowercase.
+    async fn send(
+        &self,
+        client: &HttpClient,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        data: Vec<u8>,
+    ) -> Result<(), String> {
+        let (host, path) = host_and_path(url)?;
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = artifacts::sha256_hex(&data);
+
+        let mut signed: Vec<(&str, &str)> = vec![
+            ("host", &host),
+            ("x-amz-content-sha256", &payload_hash),
+            ("x-amz-date", &amz_date),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            signed.push(("x-amz-security-token", token));
+        }
+        signed.extend_from_slice(headers);
+        signed.sort();
+        let canonical_headers: String = signed
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let signed_headers = signed
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method, path, canonical_headers, signed_headers, payload_hash
+        );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.credentials.access_key_id,
+            self.scope(&now.format("%Y%m%d").to_string()),
+            signed_headers,
+            self.signature(now, &canonical_request)
+        );
+
+        let mut request = Request::builder()
+            .method(method.clone())
+            .uri(url)
+            .header(header::AUTHORIZATION, authorization);
+        for (name, value) in &signed {
+            request = request.header(*name, *value);
+        }
+        let request = request
+            .body(Full::new(Bytes::from(data)))
+            .map_err(|e| format!("Invalid request to {}: {}", url, e))?;
+        let response = client.send(request, 4096).await?;
+        if !response.status.is_success() {
+            return Err(format!(
+                "{} of {} answered {}: {}",
+                method,
+                url,
+                response.status,
+                response.body.chars().take(300).collect::<String>()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Uploads `data` as `key` and returns the object's URL.
+    pub async fn put(
+        &self,
+        client: &HttpClient,
+        key: &str,
+        headers: &[(&str, &str)],
+        data: Vec<u8>,
+    ) -> Result<String, String> {
+        let url = self.object_url(key);
+        self.send(client, Method::PUT, &url, headers, data).await?;
+        Ok(url)
+    }
+
+    pub async fn delete(&self, client: &HttpClient, key: &str) -> Result<(), String> {
+        self.send(
+            client,
+            Method::DELETE,
+            &self.object_url(key),
+            &[],
+            Vec::new(),
+        )
+        .await
+    }
+
+    /// A URL anyone can GET the object at `key` with for `expires`.
+    pub fn presign_get(&self, key: &str, expires: Duration) -> Result<String, String> {
+        let url = self.object_url(key);
+        let (host, path) = host_and_path(&url)?;
+        let now = Utc::now();
+        let credential = format!(
+            "{}/{}",
+            self.credentials.access_key_id,
+            self.scope(&now.format("%Y%m%d").to_string())
+        );
+        let mut query = vec![
+            ("X-Amz-Algorithm", "AWS4-HMAC-SHA256".to_string()),
+            ("X-Amz-Credential", credential),
+            ("X-Amz-Date", now.format("%Y%m%dT%H%M%SZ").to_string()),
+            ("X-Amz-Expires", expires.as_secs().to_string()),
+            ("X-Amz-SignedHeaders", "host".to_string()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            query.push(("X-Amz-Security-Token", token.clone()));
+        }
+        query.sort();
+        let canonical_query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, utf8_percent_encode(value, QUERY)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let canonical_request = format!(
+            "GET\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+            path, canonical_query, host
+        );
+        Ok(format!(
+            "{}?{}&X-Amz-Signature={}",
+            url,
+            canonical_query,
+            self.signature(now, &canonical_request)
+        ))
+    }
+}
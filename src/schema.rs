@@ -1,9 +1,48 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Sqlite, prelude::FromRow};
+use sqlx::{Pool, Sqlite, prelude::FromRow, types::Json};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::archive::Archive;
+use crate::authenticode::Signer;
+use crate::cache::ReleaseCache;
+use crate::config::Config;
+use crate::entitlements::EntitlementCache;
+use crate::events::{EventBus, ReleaseEventKind};
+use crate::flags::Flags;
+use crate::github::ReleaseLookups;
+use crate::http_client::HttpClient;
+use crate::load_shedding::LoadShedder;
+use crate::notarization::Notary;
+use crate::rate_limit::RateLimiter;
+use crate::response_fields::ResponseFields;
+use crate::rings::RingSchedule;
 
 #[derive(Clone)]
 pub struct AppState {
+    /// Primary database; all writes go here.
     pub pool: Pool<Sqlite>,
+    /// Read-only pool for update checks and listings (a replica when configured).
+    pub read_pool: Pool<Sqlite>,
+    pub config: Arc<Config>,
+    pub cache: Arc<ReleaseCache>,
+    pub events: Arc<EventBus>,
+    /// Outbound calls made while answering a request, e.g. license checks.
+    pub http: HttpClient,
+    pub entitlements: Arc<EntitlementCache>,
+    /// Which GitHub releases and assets exist, remembered across uploads.
+    pub github_lookups: Arc<ReleaseLookups>,
+    /// Authenticode signing of Windows uploads, when configured.
+    pub signer: Option<Arc<Signer>>,
+    /// Apple notarization of darwin uploads, when configured.
+    pub notary: Option<Arc<Notary>>,
+    /// Cold storage for old releases, when configured.
+    pub archive: Option<Arc<Archive>>,
+    /// Admission of client traffic under load.
+    pub load: Arc<LoadShedder>,
+    /// Per-client request counters of the client routes.
+    pub rate_limiter: Arc<RateLimiter>,
 }
 
 #[derive(Debug, Serialize, Deserialize, utoipa::ToSchema)]
@@ -20,7 +59,22 @@ pub enum SupportedTarget {
     Windows,
 }
 
-#[derive(Debug, Serialize, FromRow, utoipa::ToSchema)]
+/// Column list matching [`Release`], for `SELECT`/`RETURNING` clauses.
+pub const RELEASE_COLUMNS: &str = "id, app_name, target, arch, version, url, signature, pub_date, notes, sha256, channel, yanked, ring_schedule, critical, notarization_id, archived";
+
+/// Channel clients follow when they don't ask for one.
+pub const DEFAULT_CHANNEL: &str = "stable";
+
+/// Channel names are short lowercase slugs such as `stable`, `beta` or `canary`.
+pub fn is_valid_channel(channel: &str) -> bool {
+    !channel.is_empty()
+        && channel.len() <= 32
+        && channel
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, utoipa::ToSchema)]
 pub struct Release {
     pub id: i64,
     pub app_name: String,
@@ -29,19 +83,70 @@ pub struct Release {
     pub version: String,
     pub url: String,
     pub signature: String,
-    pub pub_date: String,
+    pub pub_date: DateTime<Utc>,
     pub notes: String,
+    /// SHA-256 of the artifact; absent for releases uploaded before deduplication.
+    pub sha256: Option<String>,
+    /// Release channel, e.g. `stable` or `beta`.
+    pub channel: String,
+    /// Yanked releases stay listed but are never offered to clients.
+    pub yanked: bool,
+    /// When each deployment ring may install this release; absent means all
+    /// rings at once.
+    #[schema(value_type = Option<Object>, example = json!({"canary": "2024-01-01T12:00:00Z", "broad": "2024-01-08T12:00:00Z"}))]
+    pub ring_schedule: Option<Json<RingSchedule>>,
+    /// Critical releases are offered even during blackout windows.
+    pub critical: bool,
+    /// Apple notarization submission that accepted the artifact.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notarization_id: Option<String>,
+    /// The artifact was moved to the archive bucket, so `url` is private
+    /// and clients are no longer offered it; see [`crate::archive`].
+    pub archived: bool,
 }
 
-#[derive(Debug, Serialize, utoipa::ToSchema)]
+/// A stored binary, shared by every upload of one organization with the same
+/// SHA-256.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct Artifact {
+    pub org_id: i64,
+    pub sha256: String,
+    pub url: String,
+    pub size: i64,
+    pub github_asset_id: Option<i64>,
+    pub ref_count: i64,
+}
+
+pub const ARTIFACT_COLUMNS: &str = "org_id, sha256, url, size, github_asset_id, ref_count";
+
+#[derive(Debug, Serialize, Deserialize, utoipa::ToSchema)]
 pub struct UpdateResponse {
     pub version: String,
     pub url: String,
     pub signature: String,
-    pub pub_date: String,
+    pub pub_date: DateTime<Utc>,
     pub notes: String,
+    /// Campaign messages for the version checking in; left out when none
+    /// match.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<CampaignMessage>,
+    /// Feature flags for this client; left out when none apply.
+    #[serde(default, skip_serializing_if = "Flags::is_empty")]
+    #[schema(value_type = Object, example = json!({"new_gradebook": true}))]
+    pub flags: Flags,
+    /// `a` or `b` when the release is being A/B tested; clients send it back
+    /// in their install report.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "b")]
+    pub variant: Option<String>,
+    /// Components of the release that differ from the client's manifest;
+    /// left out for releases without components.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<ComponentUpdate>,
 }
 
+// Only used to document the multipart body in the OpenAPI spec.
+#[allow(dead_code)]
 #[derive(Debug, utoipa::ToSchema)]
 pub struct UploadReleaseForm {
     #[schema(example = "classprime")]
@@ -56,6 +161,1707 @@ pub struct UploadReleaseForm {
     pub notes: String,
     #[schema(example = "signature")]
     pub signature: String,
+    /// Defaults to the time of upload.
+    #[schema(example = "2024-01-01T12:00:00Z")]
+    pub pub_date: Option<DateTime<Utc>>,
+    /// Defaults to `stable`.
+    #[schema(example = "beta")]
+    pub channel: Option<String>,
+    /// JSON object of ring (`canary`, `early`, `broad`) to the RFC3339 time
+    /// it may install this release. Omit to release to every ring at once.
+    #[schema(example = r#"{"canary": "2024-01-01T12:00:00Z", "broad": "2024-01-08T12:00:00Z"}"#)]
+    pub rings: Option<String>,
+    /// `true` to offer the release during blackout windows too.
+    pub critical: Option<bool>,
+    /// `b` to add the file as variant B of the already uploaded release of
+    /// this version; every other field but `signature` is only used to find
+    /// that release.
+    #[schema(example = "b")]
+    pub variant: Option<String>,
+    /// Share of clients that get variant B; defaults to 50.
+    #[schema(example = 10)]
+    pub split_percent: Option<i64>,
+    /// For plugins: the host app versions this version runs in, as a semver
+    /// requirement.
+    #[schema(example = ">=2.0.0, <3.0.0")]
+    pub host_version: Option<String>,
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReleaseFilter {
+    /// Only releases published strictly after this RFC3339 timestamp
+    #[param(example = "2024-01-01T00:00:00Z")]
+    pub published_after: Option<DateTime<Utc>>,
+    /// Only releases published strictly before this RFC3339 timestamp
+    #[param(example = "2025-01-01T00:00:00Z")]
+    pub published_before: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LookupQuery {
+    /// SHA-256 of the file, hex-encoded
+    #[param(example = "be9b00f5e67c5a3b74b1d29a4c8b37f170a9a53c51a5497395080a0dd5239fe8")]
+    pub sha256: Option<String>,
+    /// Signature the file was published with
+    pub signature: Option<String>,
+}
+
+/// Something published with the artifact a lookup asked about.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct ArtifactMatch {
+    /// `release`, `variant`, `component`, `delta`, `staged`, `bundle` or
+    /// `web_bundle`.
+    #[schema(example = "release")]
+    pub kind: String,
+    #[schema(example = "ggp-updater")]
+    pub app_name: String,
+    /// Version of the release, or of the bundle.
+    #[schema(example = "1.2.0")]
+    pub version: String,
+    #[schema(example = "windows")]
+    pub target: Option<String>,
+    #[schema(example = "x86_64")]
+    pub arch: Option<String>,
+    pub channel: Option<String>,
+    /// The release it belongs to, for everything but bundles and web
+    /// bundles (and staged releases not yet published).
+    pub release_id: Option<i64>,
+    /// Component or bundle name and version, or the version a delta patches
+    /// from.
+    #[schema(example = "renderer 2.1.0")]
+    pub detail: Option<String>,
+    /// Yanked, rolled back, or a staged release that failed its checks.
+    pub withdrawn: bool,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ChannelQuery {
+    /// Release channel to follow (defaults to `stable`)
+    #[param(example = "beta")]
+    pub channel: Option<String>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LatestQuery {
+    /// Release channel to follow (defaults to `stable`)
+    #[param(example = "beta")]
+    pub channel: Option<String>,
+    /// License key, for apps that require one; the `X-License-Key` header
+    /// is preferred, as it stays out of access logs.
+    pub license_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UpdateCheckQuery {
+    /// Release channel to follow (defaults to `stable`)
+    #[param(example = "beta")]
+    pub channel: Option<String>,
+    /// School or district the installation belongs to; decides its
+    /// deployment ring. Without it the client follows `broad`.
+    #[param(example = "district-42")]
+    pub customer_id: Option<String>,
+    /// License key, for apps that require one; the `X-License-Key` header
+    /// is preferred, as it stays out of access logs.
+    pub license_key: Option<String>,
+    /// Managed device checking in; registered devices record the version
+    /// they report, and may be targeted with a specific version.
+    #[param(example = "LAPTOP-0042")]
+    pub device_id: Option<String>,
+    /// Stable per-installation identifier that buckets the client into
+    /// percentage rollouts; `device_id` is used when absent.
+    pub client_id: Option<String>,
+    /// Installed components as comma-separated `name=version` pairs; only
+    /// components that differ are offered. Without it, every component of
+    /// the release is.
+    #[param(example = "core=1.4.0,renderer=2.0.1")]
+    pub components: Option<String>,
+    /// Version of the operating system, for targeting rules.
+    #[param(example = "10.0.22631")]
+    pub os_version: Option<String>,
+    /// ISO 3166 country code, for targeting rules; ignored when the server
+    /// reads the country from a proxy header.
+    #[param(example = "NL")]
+    pub country: Option<String>,
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct RingScheduleRequest {
+    /// Ring to RFC3339 time; `null` or `{}` releases to every ring at once.
+    #[schema(value_type = Option<Object>, example = json!({"canary": "2024-01-01T12:00:00Z", "early": "2024-01-03T12:00:00Z", "broad": "2024-01-08T12:00:00Z"}))]
+    pub rings: Option<RingSchedule>,
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct PromoteRequest {
+    /// Channel to move the release to
+    #[schema(example = "stable")]
+    pub channel: String,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventFilter {
+    /// Only stream events for this application
+    #[param(example = "classprime")]
+    pub app_name: Option<String>,
+}
+
+/// A webhook subscription. The signing secret is write-only.
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct Webhook {
+    pub id: i64,
+    /// Owning organization; absent for instance-wide hooks created with the
+    /// operator token, which receive every organization's events.
+    pub org_id: Option<i64>,
+    pub url: String,
+    /// Events delivered to this hook; empty means every event.
+    pub events: Vec<ReleaseEventKind>,
+    /// Only deliver events for this application.
+    pub app_name: Option<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct WebhookRequest {
+    #[schema(example = "https://example.com/hooks/releases")]
+    pub url: String,
+    /// Key for the HMAC-SHA256 payload signature. Required on create; kept
+    /// unchanged when omitted on update.
+    #[schema(example = "s3cr3t")]
+    pub secret: Option<String>,
+    /// Events to deliver; empty or omitted means every event.
+    #[serde(default)]
+    pub events: Vec<ReleaseEventKind>,
+    /// Only deliver events for this application.
+    #[schema(example = "classprime")]
+    pub app_name: Option<String>,
+    #[serde(default = "default_true")]
+    pub active: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// One queued webhook delivery and the outcome of its latest attempt.
+#[derive(Debug, Serialize, FromRow, utoipa::ToSchema)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event: String,
+    /// JSON body sent to the endpoint.
+    pub payload: String,
+    /// `pending`, `succeeded` or `failed`.
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub last_status_code: Option<i64>,
+    pub last_error: Option<String>,
+    /// Start of the endpoint's last response body.
+    pub last_response: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeliveryFilter {
+    /// Only deliveries in this state (`pending`, `succeeded`, `failed`)
+    pub status: Option<String>,
+    /// Maximum number of deliveries to return, newest first (default 50)
+    pub limit: Option<i64>,
+}
+
+/// An email subscription to new releases of an app on one channel.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct Subscription {
+    pub id: i64,
+    pub email: String,
+    pub app_name: String,
+    pub channel: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`Subscription`]; the unsubscribe token stays private.
+pub const SUBSCRIPTION_COLUMNS: &str = "id, email, app_name, channel, created_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct SubscriptionRequest {
+    #[schema(example = "it-admin@school.example")]
+    pub email: String,
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    /// Defaults to `stable`.
+    #[schema(example = "stable")]
+    pub channel: Option<String>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SubscriptionFilter {
+    /// Only subscriptions to this application
+    pub app_name: Option<String>,
+}
+
+/// A tenant: a partner company whose apps, tokens, webhooks and subscriptions
+/// are kept apart from every other organization's.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct Organization {
+    pub id: i64,
+    #[schema(example = "edustart")]
+    pub slug: String,
+    #[schema(example = "Edustart Tech")]
+    pub name: String,
+    /// Non-critical releases reach this organization's clients this many
+    /// days after their publish date, unless their customer has a policy
+    /// of its own.
+    pub defer_days: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`Organization`].
+pub const ORGANIZATION_COLUMNS: &str = "id, slug, name, defer_days, created_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct OrganizationRequest {
+    /// Lowercase letters, digits and `-`.
+    #[schema(example = "edustart")]
+    pub slug: String,
+    #[schema(example = "Edustart Tech")]
+    pub name: String,
+}
+
+/// An application name and the organization that owns it. Names are global
+/// because update checks don't carry credentials.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct App {
+    #[schema(example = "classprime")]
+    pub name: String,
+    pub org_id: i64,
+    /// Update checks must present a valid license key.
+    pub require_license: bool,
+    /// Endpoint that validates license keys; the `licenses` table is used
+    /// when absent.
+    pub license_url: Option<String>,
+    /// Endpoint consulted before an update is served; see
+    /// [`crate::entitlements`]. Its signing secret is write-only.
+    pub entitlement_url: Option<String>,
+    /// Serve updates when the entitlement hook can't be reached.
+    pub entitlement_fail_open: bool,
+    /// How long hook answers are reused, unless an answer says otherwise.
+    pub entitlement_cache_secs: i64,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub metadata: AppMetadataFields,
+    /// For plugins, the app that loads them; see [`crate::plugins`].
+    pub host_app: Option<String>,
+    /// How release versions are parsed and compared; see
+    /// [`crate::versioning`].
+    #[schema(example = "semver")]
+    pub version_scheme: String,
+    /// Releases more than this many versions behind the newest of their
+    /// target, arch and channel are archived; absent keeps everything.
+    pub archive_keep_versions: Option<i64>,
+    /// Names update checks and `/latest` give their fields instead of the
+    /// usual ones, for clients expecting others; see
+    /// [`crate::response_fields`].
+    #[schema(value_type = Option<Object>, example = json!({"url": "download_url", "notes": "changelog"}))]
+    pub response_fields: Option<Json<ResponseFields>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`App`].
+pub const APP_COLUMNS: &str = "name, org_id, require_license, license_url, entitlement_url, entitlement_fail_open, entitlement_cache_secs, display_name, icon_url, homepage_url, support_url, description, host_app, version_scheme, archive_keep_versions, response_fields, created_at";
+
+/// Branding and links of an app, shown by the download page and the
+/// in-app update dialog.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct AppMetadataFields {
+    #[schema(example = "ClassPrime")]
+    pub display_name: Option<String>,
+    #[schema(example = "https://edustart.example/classprime.png")]
+    pub icon_url: Option<String>,
+    #[schema(example = "https://edustart.example/classprime")]
+    pub homepage_url: Option<String>,
+    #[schema(example = "https://edustart.example/support")]
+    pub support_url: Option<String>,
+    #[schema(example = "Classroom management for teachers.")]
+    pub description: Option<String>,
+}
+
+/// Public metadata of an app.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct AppMetadata {
+    #[schema(example = "classprime")]
+    pub name: String,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub metadata: AppMetadataFields,
+}
+
+/// Columns matching [`AppMetadata`].
+pub const APP_METADATA_COLUMNS: &str =
+    "name, display_name, icon_url, homepage_url, support_url, description";
+
+/// Replaces an app's metadata; fields left out are cleared.
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct AppMetadataRequest {
+    #[schema(example = "ClassPrime")]
+    pub display_name: Option<String>,
+    #[schema(example = "https://edustart.example/classprime.png")]
+    pub icon_url: Option<String>,
+    #[schema(example = "https://edustart.example/classprime")]
+    pub homepage_url: Option<String>,
+    #[schema(example = "https://edustart.example/support")]
+    pub support_url: Option<String>,
+    #[schema(example = "Classroom management for teachers.")]
+    pub description: Option<String>,
+}
+
+/// An organization's API token. Only its hash is stored.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct ApiToken {
+    pub id: i64,
+    pub org_id: i64,
+    #[schema(example = "ci")]
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// Columns matching [`ApiToken`]; the hash stays private.
+pub const API_TOKEN_COLUMNS: &str = "id, org_id, name, created_at, last_used_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct ApiTokenRequest {
+    /// What the token is for, e.g. the CI pipeline using it.
+    #[schema(example = "ci")]
+    pub name: String,
+}
+
+/// A newly issued token. The secret is shown this once.
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct IssuedApiToken {
+    #[serde(flatten)]
+    pub token: ApiToken,
+    /// Send as `Authorization: Bearer <token>`.
+    #[schema(example = "arm_5f2b...")]
+    pub secret: String,
+}
+
+/// A customer's deployment ring within an organization.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct CustomerRing {
+    pub org_id: i64,
+    /// Identifier the app sends as `customer_id`.
+    #[schema(example = "district-42")]
+    pub customer_id: String,
+    #[schema(example = "canary")]
+    pub ring: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A customer's own deferral, overriding its organization's.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct CustomerUpdatePolicy {
+    pub org_id: i64,
+    /// Identifier the app sends as `customer_id`.
+    #[schema(example = "district-42")]
+    pub customer_id: String,
+    /// Non-critical releases reach this customer's clients this many days
+    /// after their publish date.
+    pub defer_days: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Columns matching [`CustomerUpdatePolicy`].
+pub const CUSTOMER_UPDATE_POLICY_COLUMNS: &str = "org_id, customer_id, defer_days, updated_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct CustomerRingRequest {
+    /// `canary`, `early` or `broad`.
+    #[schema(example = "canary")]
+    pub ring: String,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CustomerFilter {
+    /// Only customers in this ring
+    pub ring: Option<String>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OrgQuery {
+    /// Organization to act for; only the operator token may pick one
+    /// (defaults to the default organization)
+    pub org_id: Option<i64>,
+}
+
+/// A period during which an app's clients aren't offered updates, such as
+/// exam weeks. Critical releases still go out.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct Blackout {
+    pub id: i64,
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    pub starts_at: DateTime<Utc>,
+    /// Exclusive.
+    pub ends_at: DateTime<Utc>,
+    /// UTC offset the window was given in.
+    #[schema(example = "+02:00")]
+    pub timezone: String,
+    #[schema(example = "Final exams")]
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`Blackout`].
+pub const BLACKOUT_COLUMNS: &str = "id, app_name, starts_at, ends_at, timezone, reason, created_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct BlackoutRequest {
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    /// Local start time in `timezone`.
+    #[schema(value_type = String, example = "2024-06-03T00:00:00")]
+    pub starts_at: NaiveDateTime,
+    /// Local end time in `timezone` (exclusive).
+    #[schema(value_type = String, example = "2024-06-15T00:00:00")]
+    pub ends_at: NaiveDateTime,
+    /// UTC offset such as `+02:00` or `-05:00`; defaults to UTC.
+    #[schema(example = "+02:00")]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    #[schema(example = "Final exams")]
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BlackoutFilter {
+    /// Only windows for this application
+    pub app_name: Option<String>,
+    /// Also list windows that have already ended
+    #[serde(default)]
+    pub include_past: bool,
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct CriticalRequest {
+    pub critical: bool,
+}
+
+/// A window during which uploads and promotions need an operator override,
+/// e.g. the Friday before a school holiday.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct PublishFreeze {
+    pub id: i64,
+    pub org_id: i64,
+    /// App the freeze applies to; absent for every app of the organization.
+    #[schema(example = "classprime")]
+    pub app_name: Option<String>,
+    pub starts_at: DateTime<Utc>,
+    /// Exclusive.
+    pub ends_at: DateTime<Utc>,
+    /// UTC offset the window was given in.
+    #[schema(example = "+02:00")]
+    pub timezone: String,
+    #[schema(example = "Winter holidays")]
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`PublishFreeze`].
+pub const PUBLISH_FREEZE_COLUMNS: &str =
+    "id, org_id, app_name, starts_at, ends_at, timezone, reason, created_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct PublishFreezeRequest {
+    /// Freeze a single app; leave out to freeze every app of the organization.
+    #[schema(example = "classprime")]
+    pub app_name: Option<String>,
+    /// Local start time in `timezone`.
+    #[schema(value_type = String, example = "2024-12-20T00:00:00")]
+    pub starts_at: NaiveDateTime,
+    /// Local end time in `timezone` (exclusive).
+    #[schema(value_type = String, example = "2025-01-06T00:00:00")]
+    pub ends_at: NaiveDateTime,
+    /// UTC offset such as `+02:00` or `-05:00`; defaults to UTC.
+    #[schema(example = "+01:00")]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    #[schema(example = "Winter holidays")]
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FreezeFilter {
+    /// Also list freezes that have already ended
+    #[serde(default)]
+    pub include_past: bool,
+}
+
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OverrideQuery {
+    /// Publish despite an active freeze; only the operator token may
+    #[serde(default, rename = "override")]
+    #[param(rename = "override")]
+    pub override_freeze: bool,
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct UpdatePolicyRequest {
+    /// Days to hold back non-critical releases after their publish date;
+    /// 0 offers them right away.
+    #[schema(example = 7, minimum = 0, maximum = 365)]
+    pub defer_days: i64,
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct LicensePolicyRequest {
+    /// Refuse updates to clients without a valid license key.
+    pub require_license: bool,
+    /// Validate keys against this endpoint instead of the `licenses` table.
+    /// It receives the check's context and answers
+    /// `{"valid": bool, "expires_at": RFC3339 | null}`.
+    #[schema(example = "https://billing.example.com/licenses/validate")]
+    pub license_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionScheme {
+    /// `1.4.2`, `2.0.0-beta.1`.
+    #[default]
+    Semver,
+    /// A year followed by up to three numbers, such as `2024.06.1`.
+    Calver,
+    /// A plain build number, such as `4512`.
+    Numeric,
+}
+
+impl VersionScheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VersionScheme::Semver => "semver",
+            VersionScheme::Calver => "calver",
+            VersionScheme::Numeric => "numeric",
+        }
+    }
+
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "semver" => Some(VersionScheme::Semver),
+            "calver" => Some(VersionScheme::Calver),
+            "numeric" => Some(VersionScheme::Numeric),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct ArchivePolicyRequest {
+    /// Versions to keep in regular storage per target, arch and channel;
+    /// null stops archiving the app.
+    #[schema(example = 10, minimum = 1)]
+    pub keep_versions: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct ResponseFieldsRequest {
+    /// Field to the name it goes by; null or `{}` restores the usual names.
+    #[schema(value_type = Option<Object>, example = json!({"url": "download_url", "notes": "changelog"}))]
+    pub fields: Option<ResponseFields>,
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct VersionSchemeRequest {
+    pub version_scheme: VersionScheme,
+}
+
+/// A license key for an app. Only a hash of the key is stored.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct License {
+    pub id: i64,
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    /// Last characters of the key, to tell keys apart.
+    #[schema(example = "9f3a")]
+    pub key_hint: String,
+    /// School or district the license was sold to.
+    #[schema(example = "district-42")]
+    pub customer_id: Option<String>,
+    /// Absent for licenses that never expire.
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`License`]; the key hash stays private.
+pub const LICENSE_COLUMNS: &str = "id, app_name, key_hint, customer_id, expires_at, created_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct LicenseRequest {
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    /// Key to register, e.g. one already sold through billing; a random
+    /// key is generated when absent.
+    pub key: Option<String>,
+    #[schema(example = "district-42")]
+    pub customer_id: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct LicenseUpdateRequest {
+    /// New expiry; `null` for a license that never expires.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A newly registered license. The key is shown this once.
+#[derive(Debug, Serialize, utoipa::ToSchema)]
+pub struct IssuedLicense {
+    #[serde(flatten)]
+    pub license: License,
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LicenseFilter {
+    /// Only licenses for this application
+    pub app_name: Option<String>,
+    /// Only licenses sold to this customer
+    pub customer_id: Option<String>,
+}
+
+/// What a client told us in an update check, as passed to external checks.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateCheckContext {
+    pub app_name: String,
+    pub target: String,
+    pub arch: String,
+    /// Absent for downloads, which don't say what is installed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_version: Option<String>,
+    pub channel: String,
+    pub customer_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct EntitlementHookRequest {
+    /// Endpoint to consult; `null` removes the hook.
+    #[schema(example = "https://billing.example.com/entitlements")]
+    pub url: Option<String>,
+    /// Signs hook requests like webhook deliveries (`X-Updater-Signature-256`).
+    pub secret: Option<String>,
+    /// Serve updates when the hook fails instead of answering 503.
+    #[serde(default)]
+    pub fail_open: bool,
+    /// How long to reuse an answer (default 60, 0 to always ask).
+    #[schema(example = 60)]
+    pub cache_secs: Option<i64>,
+}
+
+/// A managed device running an app, as registered by an admin. Its version
+/// and platform are refreshed whenever it checks for updates with its
+/// `device_id`.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct Device {
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    #[schema(example = "LAPTOP-0042")]
+    pub device_id: String,
+    /// School or district, as sent in update checks' `customer_id`.
+    #[schema(example = "district-42")]
+    pub customer_id: Option<String>,
+    /// Group that can be targeted as a whole, e.g. a classroom cart.
+    #[schema(example = "cart-3")]
+    pub group_name: Option<String>,
+    #[schema(example = "windows")]
+    pub target: Option<String>,
+    #[schema(example = "x86_64")]
+    pub arch: Option<String>,
+    /// Version reported on the last check-in.
+    #[schema(example = "1.0.0")]
+    pub current_version: Option<String>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub registered_at: DateTime<Utc>,
+}
+
+/// Columns matching [`Device`].
+pub const DEVICE_COLUMNS: &str = "app_name, device_id, customer_id, group_name, target, arch, current_version, last_seen_at, registered_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct DeviceRequest {
+    #[schema(example = "district-42")]
+    pub customer_id: Option<String>,
+    #[schema(example = "cart-3")]
+    pub group_name: Option<String>,
+    /// Platform, until the device reports its own on check-in.
+    #[schema(example = "windows")]
+    pub target: Option<String>,
+    #[schema(example = "x86_64")]
+    pub arch: Option<String>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeviceFilter {
+    pub app_name: Option<String>,
+    pub customer_id: Option<String>,
+    pub group_name: Option<String>,
+    /// Only devices reporting this version
+    pub current_version: Option<String>,
+}
+
+/// Pins a device, or every device in a group, to a version. Targeted
+/// devices are offered that version instead of the channel's latest, and
+/// nothing newer.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct DeviceTarget {
+    pub id: i64,
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    /// Set when a single device is targeted.
+    pub device_id: Option<String>,
+    /// Set when a group is targeted.
+    #[schema(example = "cart-3")]
+    pub group_name: Option<String>,
+    #[schema(example = "1.2.0")]
+    pub version: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`DeviceTarget`].
+pub const DEVICE_TARGET_COLUMNS: &str = "id, app_name, device_id, group_name, version, created_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct DeviceTargetRequest {
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    /// Target one device; give either this or `group_name`.
+    pub device_id: Option<String>,
+    #[schema(example = "cart-3")]
+    pub group_name: Option<String>,
+    #[schema(example = "1.2.0")]
+    pub version: String,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeviceTargetFilter {
+    pub app_name: Option<String>,
+}
+
+/// A message shown inside the app to users whose version, platform and
+/// check time match.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct Campaign {
+    pub id: i64,
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    #[schema(example = "Time to update")]
+    pub title: String,
+    #[schema(example = "Version 1.0 stops working in June; update to keep your classes in sync.")]
+    pub body: String,
+    /// Link the message points to, such as a blog post.
+    pub link_url: Option<String>,
+    /// Semver requirement on the user's current version; every version when
+    /// absent.
+    #[schema(example = "<1.2.0")]
+    pub version_req: Option<String>,
+    /// Only users on this platform; every platform when absent.
+    #[schema(example = "windows")]
+    pub target: Option<String>,
+    pub starts_at: Option<DateTime<Utc>>,
+    pub ends_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`Campaign`].
+pub const CAMPAIGN_COLUMNS: &str =
+    "id, app_name, title, body, link_url, version_req, target, starts_at, ends_at, created_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct CampaignRequest {
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    #[schema(example = "Time to update")]
+    pub title: String,
+    #[schema(example = "Version 1.0 stops working in June; update to keep your classes in sync.")]
+    pub body: String,
+    pub link_url: Option<String>,
+    #[schema(example = "<1.2.0")]
+    pub version_req: Option<String>,
+    #[schema(example = "windows")]
+    pub target: Option<String>,
+    pub starts_at: Option<DateTime<Utc>>,
+    pub ends_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CampaignFilter {
+    pub app_name: Option<String>,
+    /// Include campaigns that have ended
+    #[serde(default)]
+    pub include_past: bool,
+}
+
+/// What clients are shown of a campaign.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct CampaignMessage {
+    pub id: i64,
+    #[schema(example = "Time to update")]
+    pub title: String,
+    #[schema(example = "Version 1.0 stops working in June; update to keep your classes in sync.")]
+    pub body: String,
+    pub link_url: Option<String>,
+}
+
+/// A remote-config value handed to an app's clients in update check
+/// answers.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct FeatureFlag {
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    #[schema(example = "new_gradebook")]
+    pub key: String,
+    /// Any JSON value.
+    #[schema(value_type = Object, example = json!(true))]
+    pub value: Json<serde_json::Value>,
+    /// Only clients whose version matches get the flag.
+    #[schema(example = ">=1.2.0")]
+    pub version_req: Option<String>,
+    /// Share of clients (by `client_id`) that get the flag.
+    #[schema(example = 25)]
+    pub rollout_percent: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Columns matching [`FeatureFlag`].
+pub const FEATURE_FLAG_COLUMNS: &str =
+    "app_name, key, value, version_req, rollout_percent, updated_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct FeatureFlagRequest {
+    #[schema(value_type = Object, example = json!(true))]
+    pub value: serde_json::Value,
+    #[schema(example = ">=1.2.0")]
+    pub version_req: Option<String>,
+    /// Defaults to 100.
+    #[schema(example = 25)]
+    pub rollout_percent: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FeatureFlagFilter {
+    pub app_name: Option<String>,
+}
+
+/// The B variant of a release: another artifact of the same version, served
+/// to `split_percent` of clients. The release row itself is variant A.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct ReleaseVariant {
+    pub release_id: i64,
+    pub url: String,
+    pub signature: String,
+    pub sha256: String,
+    #[schema(example = 10)]
+    pub split_percent: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`ReleaseVariant`].
+pub const RELEASE_VARIANT_COLUMNS: &str =
+    "release_id, url, signature, sha256, split_percent, created_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct VariantSplitRequest {
+    /// Share of clients, by `client_id`, that get variant B.
+    #[schema(example = 10)]
+    pub split_percent: i64,
+}
+
+/// How an update went on a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallOutcome {
+    Installed,
+    Failed,
+    RolledBack,
+}
+
+impl InstallOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InstallOutcome::Installed => "installed",
+            InstallOutcome::Failed => "failed",
+            InstallOutcome::RolledBack => "rolled_back",
+        }
+    }
+}
+
+/// Sent by clients after they try to install an update.
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct InstallReportRequest {
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    #[schema(example = "windows")]
+    pub target: String,
+    #[schema(example = "x86_64")]
+    pub arch: String,
+    /// Version the client tried to install.
+    #[schema(example = "1.2.0")]
+    pub version: String,
+    /// Defaults to `stable`.
+    pub channel: Option<String>,
+    /// Variant the update check answered with, if any.
+    #[schema(example = "b")]
+    pub variant: Option<String>,
+    pub outcome: InstallOutcome,
+    /// Reports with the same client ID and outcome are counted once.
+    pub client_id: Option<String>,
+    /// Error shown to the user, for failures.
+    pub message: Option<String>,
+}
+
+/// Install reports of one variant of a release.
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct VariantMetrics {
+    #[schema(example = "a")]
+    pub variant: String,
+    /// Share of clients the variant is served to.
+    #[schema(example = 90)]
+    pub split_percent: i64,
+    pub installed: i64,
+    pub failed: i64,
+    pub rolled_back: i64,
+    /// Failed and rolled-back installs over all reported ones; 0 without
+    /// reports.
+    pub failure_rate: f64,
+}
+
+/// When releases of an app move on from one channel to the next without
+/// anybody promoting them.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct PromotionPolicy {
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    #[schema(example = "canary")]
+    pub from_channel: String,
+    #[schema(example = "stable")]
+    pub to_channel: String,
+    /// Days a release must have been live on `from_channel`.
+    #[schema(example = 7)]
+    pub min_days: i64,
+    /// Install reports needed before a release is judged.
+    #[schema(example = 20)]
+    pub min_reports: i64,
+    /// Highest share of failed and rolled-back installs allowed.
+    #[schema(example = 0.02)]
+    pub max_failure_rate: f64,
+    /// Most rolled-back installs allowed.
+    #[schema(example = 3)]
+    pub max_rollbacks: i64,
+    /// Disabled policies keep their settings but promote nothing.
+    pub enabled: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Columns matching [`PromotionPolicy`].
+pub const PROMOTION_POLICY_COLUMNS: &str = "app_name, from_channel, to_channel, min_days, min_reports, max_failure_rate, max_rollbacks, enabled, updated_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct PromotionPolicyRequest {
+    /// Defaults to `canary`.
+    pub from_channel: Option<String>,
+    /// Defaults to `stable`.
+    pub to_channel: Option<String>,
+    #[schema(example = 7)]
+    pub min_days: i64,
+    /// Defaults to 0: releases nobody reported on are promoted too.
+    pub min_reports: Option<i64>,
+    #[schema(example = 0.02)]
+    pub max_failure_rate: f64,
+    #[schema(example = 3)]
+    pub max_rollbacks: i64,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PromotionState {
+    /// Moved to the policy's `to_channel`.
+    Promoted,
+    /// Breached the policy; stays put until promoted or yanked by hand.
+    Held,
+}
+
+impl PromotionState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PromotionState::Promoted => "promoted",
+            PromotionState::Held => "held",
+        }
+    }
+}
+
+/// What the promotion policy decided for a release.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct ReleasePromotion {
+    pub release_id: i64,
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    #[schema(example = "1.2.0")]
+    pub version: String,
+    #[schema(example = "held")]
+    pub state: String,
+    #[schema(example = "canary")]
+    pub from_channel: String,
+    #[schema(example = "stable")]
+    pub to_channel: String,
+    /// The numbers the decision was based on.
+    #[schema(example = "failure rate 6.5% over 2.0% (3 of 46 installs)")]
+    pub reason: String,
+    pub decided_at: DateTime<Utc>,
+}
+
+/// Columns matching [`ReleasePromotion`].
+pub const RELEASE_PROMOTION_COLUMNS: &str =
+    "release_id, app_name, version, state, from_channel, to_channel, reason, decided_at";
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PromotionFilter {
+    pub app_name: Option<String>,
+    /// `promoted` or `held`.
+    pub state: Option<PromotionState>,
+}
+
+/// Limits on what an app or organization keeps and uploads. Absent limits
+/// don't apply.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, FromRow, utoipa::ToSchema)]
+pub struct QuotaLimits {
+    /// Releases kept, yanked ones included.
+    #[schema(example = 200)]
+    pub max_releases: Option<i64>,
+    /// Bytes of distinct artifacts the releases use.
+    #[schema(example = 10737418240i64)]
+    pub max_storage_bytes: Option<i64>,
+    /// Uploads in any 24 hours.
+    #[schema(example = 50)]
+    pub max_uploads_per_day: Option<i64>,
+}
+
+/// Columns matching [`QuotaLimits`].
+pub const QUOTA_LIMIT_COLUMNS: &str = "max_releases, max_storage_bytes, max_uploads_per_day";
+
+/// What an app or organization uses, next to the limits that apply to it.
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct QuotaUsage {
+    pub releases: i64,
+    pub storage_bytes: i64,
+    /// Uploads in the last 24 hours.
+    pub uploads_last_day: i64,
+    #[serde(flatten)]
+    pub limits: QuotaLimits,
+}
+
+/// A check an app's releases must pass before they are published.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct CheckGate {
+    #[schema(example = "classprime")]
+    pub app_name: String,
+    #[schema(example = "smoke-tests")]
+    pub name: String,
+    /// Receives a POST for every staged release, with the URL to report
+    /// the check's result to.
+    #[schema(example = "https://ci.example.com/hooks/smoke-tests")]
+    pub trigger_url: Option<String>,
+    /// Minutes the check gets to report before it counts as failed.
+    #[schema(example = 60)]
+    pub timeout_mins: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`CheckGate`].
+pub const CHECK_GATE_COLUMNS: &str = "app_name, name, trigger_url, timeout_mins, created_at";
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct CheckGateRequest {
+    /// Gets a POST with the staged release and the callback URL to report
+    /// to. Leave out when the uploader runs the check itself, using the
+    /// callback URLs in the upload response.
+    #[schema(example = "https://ci.example.com/hooks/smoke-tests")]
+    pub trigger_url: Option<String>,
+    /// Defaults to 60.
+    pub timeout_mins: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CheckGateFilter {
+    pub app_name: Option<String>,
+}
+
+/// An upload waiting on its app's checks before it becomes a release.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, utoipa::ToSchema)]
+pub struct StagedRelease {
+    pub id: i64,
+    pub app_name: String,
+    pub target: String,
+    pub arch: String,
+    pub version: String,
+    pub url: String,
+    pub signature: String,
+    pub pub_date: DateTime<Utc>,
+    pub notes: String,
+    pub sha256: String,
+    pub channel: String,
+    #[schema(value_type = Option<Object>)]
+    pub ring_schedule: Option<Json<RingSchedule>>,
+    pub critical: bool,
+    /// `pending`, `published` or `failed`.
+    #[schema(example = "pending")]
+    pub status: String,
+    /// The release it became, once published.
+    pub release_id: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    /// Apple notarization submission, once one accepted the artifact.
+    pub notarization_id: Option<String>,
+    #[sqlx(skip)]
+    pub checks: Vec<CheckRun>,
+}
+
+/// Columns matching [`StagedRelease`].
+pub const STAGED_RELEASE_COLUMNS: &str = "id, app_name, target, arch, version, url, signature, pub_date, notes, sha256, channel, ring_schedule, critical, status, release_id, created_at, completed_at, notarization_id";
+
+/// One check of a staged release.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, utoipa::ToSchema)]
+pub struct CheckRun {
+    pub id: i64,
+    pub staged_release_id: i64,
+    #[schema(example = "smoke-tests")]
+    pub name: String,
+    /// `pending`, `success`, `failure` or `timed_out`.
+    #[schema(example = "success")]
+    pub status: String,
+    pub message: Option<String>,
+    pub details_url: Option<String>,
+    pub deadline: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    /// Where to report the result; only in the upload response, as the
+    /// token in it isn't stored.
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
+}
+
+/// Columns matching [`CheckRun`].
+pub const CHECK_RUN_COLUMNS: &str =
+    "id, staged_release_id, name, status, message, details_url, deadline, completed_at";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckConclusion {
+    Success,
+    Failure,
+}
+
+/// Sent by a check to the callback URL it was triggered with.
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct CheckReportRequest {
+    pub conclusion: CheckConclusion,
+    #[schema(example = "42 of 42 smoke tests passed")]
+    pub message: Option<String>,
+    #[schema(example = "https://ci.example.com/runs/1234")]
+    pub details_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StagedReleaseFilter {
+    pub app_name: Option<String>,
+    /// `pending`, `published` or `failed`.
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PluginUpdateCheckQuery {
+    /// Version of the host app the plugin runs in.
+    #[param(example = "2.4.0")]
+    pub host_version: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct PluginUpdateResponse {
+    pub version: String,
+    pub url: String,
+    pub signature: String,
+    pub pub_date: DateTime<Utc>,
+    pub notes: String,
+    /// Host versions this plugin version runs in; absent when any will do.
+    #[schema(example = ">=2.0.0, <3.0.0")]
+    pub host_version_req: Option<String>,
+}
+
+/// One version of an auxiliary asset, such as an ffmpeg build or an ML
+/// model, that releases of its app can depend on.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct Bundle {
+    pub app_name: String,
+    #[schema(example = "ffmpeg")]
+    pub name: String,
+    #[schema(example = "6.1.0")]
+    pub version: String,
+    /// Empty when the bundle runs on every target.
+    pub target: String,
+    /// Empty when the bundle runs on every architecture.
+    pub arch: String,
+    pub url: String,
+    pub sha256: String,
+    pub size: i64,
+    pub notes: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`Bundle`].
+pub const BUNDLE_COLUMNS: &str =
+    "app_name, name, version, target, arch, url, sha256, size, notes, created_at";
+
+// Only used to document the multipart body in the OpenAPI spec.
+#[allow(dead_code)]
+#[derive(Debug, utoipa::ToSchema)]
+pub struct BundleUploadForm {
+    #[schema(example = "6.1.0")]
+    pub version: String,
+    /// Leave out for bundles that run on every target.
+    #[schema(example = "windows")]
+    pub target: Option<String>,
+    /// Leave out for bundles that run on every architecture.
+    #[schema(example = "x86_64")]
+    pub arch: Option<String>,
+    pub notes: Option<String>,
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+}
+
+/// What a targeting rule does with the release when it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Offer the release.
+    Allow,
+    /// Don't offer it; the client hears there is no update.
+    Deny,
+    /// Offer the rule's `version` of the same app and platform instead.
+    ServeVersion,
+}
+
+/// Conditions over the checking client. Every condition given must hold;
+/// a rule without any matches every check.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConditions {
+    /// Semver requirement on the client's current version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = ">=1.2.0, <2.0.0")]
+    pub version: Option<String>,
+    /// Semver requirement on the `os_version` the client reports; clients
+    /// that report none don't match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = ">=10.0.22000")]
+    pub os_version: Option<String>,
+    /// Channels the check is for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = json!(["beta"]))]
+    pub channels: Option<Vec<String>>,
+    /// Buckets `[from, to)` out of 100 that the client's `client_id` (or
+    /// `device_id`) hashes into. Anonymous clients don't match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = json!([0, 10]))]
+    pub buckets: Option<[u8; 2]>,
+    /// ISO 3166 country codes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = json!(["NL", "BE"]))]
+    pub countries: Option<Vec<String>>,
+    /// Values of custom parameters, sent as `param.<name>` in the query.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>, example = json!({"school_type": "primary"}))]
+    pub params: Option<BTreeMap<String, String>>,
+}
+
+/// A release's targeting rule; see [`crate::rules`].
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TargetingRule {
+    #[serde(default)]
+    pub when: RuleConditions,
+    pub action: RuleAction,
+    /// For `serve_version`, the version to offer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "1.4.2")]
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct TargetingRulesRequest {
+    /// Evaluated in order on every update check offering this release; the
+    /// first matching rule decides, and the release is offered when none
+    /// matches. Replaces the release's earlier rules.
+    pub rules: Vec<TargetingRule>,
+}
+
+#[derive(Debug, Deserialize, utoipa::ToSchema)]
+pub struct BundleRequirementsRequest {
+    /// Bundle name to the semver requirement its version must meet;
+    /// `=` pins one version. Replaces the release's earlier requirements.
+    #[schema(value_type = Object, example = json!({"ffmpeg": "^6.1", "speech-model": "=2.0.3"}))]
+    pub bundles: BTreeMap<String, String>,
+}
+
+/// A bundle a release depends on.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct BundleRequirement {
+    #[schema(example = "ffmpeg")]
+    pub bundle: String,
+    #[schema(example = "^6.1")]
+    pub version_req: String,
+}
+
+/// The bundle version a client should install alongside its app version.
+#[derive(Debug, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct ResolvedBundle {
+    #[schema(example = "ffmpeg")]
+    pub name: String,
+    #[schema(example = "6.1.2")]
+    pub version: String,
+    #[schema(example = "^6.1")]
+    pub version_req: String,
+    pub url: String,
+    pub sha256: String,
+    pub size: i64,
+}
+
+/// A separately versioned part of a release, such as its renderer or
+/// content, that clients can update without the rest.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct ReleaseComponent {
+    pub release_id: i64,
+    #[schema(example = "renderer")]
+    pub name: String,
+    #[schema(example = "2.1.0")]
+    pub version: String,
+    pub url: String,
+    pub signature: String,
+    pub sha256: String,
+    pub size: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`ReleaseComponent`].
+pub const RELEASE_COMPONENT_COLUMNS: &str =
+    "release_id, name, version, url, signature, sha256, size, created_at";
+
+// Only used to document the multipart body in the OpenAPI spec.
+#[allow(dead_code)]
+#[derive(Debug, utoipa::ToSchema)]
+pub struct ComponentUploadForm {
+    #[schema(example = "2.1.0")]
+    pub version: String,
+    #[schema(example = "signature")]
+    pub signature: String,
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+}
+
+/// An image attached to a release, for its notes to show.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct ReleaseAttachment {
+    pub release_id: i64,
+    #[schema(example = "settings-screen.png")]
+    pub name: String,
+    #[schema(example = "image/png")]
+    pub content_type: String,
+    #[serde(skip)]
+    pub storage_url: String,
+    pub sha256: String,
+    pub size: i64,
+    pub created_at: DateTime<Utc>,
+    /// Where this server serves the attachment, for Markdown notes to
+    /// reference.
+    #[sqlx(skip)]
+    #[schema(example = "https://updates.example.com/v1/attachments/42/settings-screen.png")]
+    pub url: String,
+}
+
+/// Columns matching [`ReleaseAttachment`].
+pub const RELEASE_ATTACHMENT_COLUMNS: &str =
+    "release_id, name, content_type, storage_url, sha256, size, created_at";
+
+// Only used to document the multipart body in the OpenAPI spec.
+#[allow(dead_code)]
+#[derive(Debug, utoipa::ToSchema)]
+pub struct AttachmentUploadForm {
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+}
+
+/// A component the client should replace, in an update check answer.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct ComponentUpdate {
+    #[schema(example = "renderer")]
+    pub name: String,
+    #[schema(example = "2.1.0")]
+    pub version: String,
+    pub url: String,
+    pub signature: String,
+    pub sha256: String,
+    pub size: i64,
+}
+
+/// A patch that turns an installation of `from_version` into the release.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct ReleaseDelta {
+    pub release_id: i64,
+    #[schema(example = "1.1.0")]
+    pub from_version: String,
+    pub url: String,
+    pub signature: String,
+    pub sha256: String,
+    pub size: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`ReleaseDelta`].
+pub const RELEASE_DELTA_COLUMNS: &str =
+    "release_id, from_version, url, signature, sha256, size, created_at";
+
+// Only used to document the multipart body in the OpenAPI spec.
+#[allow(dead_code)]
+#[derive(Debug, utoipa::ToSchema)]
+pub struct DeltaUploadForm {
+    /// Version the patch applies to.
+    #[schema(example = "1.1.0")]
+    pub from_version: String,
+    #[schema(example = "signature")]
+    pub signature: String,
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeltaQuery {
+    /// Release channel to follow (defaults to `stable`)
+    #[param(example = "beta")]
+    pub channel: Option<String>,
+    /// Version to patch up to, such as the one an update check offered;
+    /// the channel's latest when absent.
+    #[param(example = "1.2.0")]
+    pub to_version: Option<String>,
+    /// License key, for apps that require one; the `X-License-Key` header
+    /// is preferred, as it stays out of access logs.
+    pub license_key: Option<String>,
+}
+
+/// One patch of a chain.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct DeltaStep {
+    #[schema(example = "1.1.0")]
+    pub from_version: String,
+    #[schema(example = "1.2.0")]
+    pub to_version: String,
+    pub url: String,
+    pub signature: String,
+    pub sha256: String,
+    pub size: i64,
+}
+
+/// The full artifact of a release, to weigh against a patch chain.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct FullDownload {
+    pub url: String,
+    pub signature: String,
+    /// Absent, like the size, for releases uploaded before deduplication.
+    pub sha256: Option<String>,
+    pub size: Option<i64>,
+}
+
+/// How a client can get from its version to the target one.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct DeltaManifest {
+    #[schema(example = "1.0.0")]
+    pub current_version: String,
+    #[schema(example = "1.2.0")]
+    pub to_version: String,
+    pub full: FullDownload,
+    /// Patches to apply in order, the smallest chain in total; empty when
+    /// no chain of patches leads from the current version.
+    pub chain: Vec<DeltaStep>,
+    /// Bytes of the patches in `chain`.
+    pub chain_size: i64,
+}
+
+/// A versioned archive of frontend assets that hybrid apps load instead of
+/// the ones shipped in their installer.
+#[derive(Debug, Clone, Serialize, FromRow, utoipa::ToSchema)]
+pub struct WebBundle {
+    pub id: i64,
+    pub app_name: String,
+    #[schema(example = "1.4.2")]
+    pub version: String,
+    pub channel: String,
+    /// App versions the bundle runs in; absent when any will do.
+    #[schema(example = ">=3.2.0, <4.0.0")]
+    pub app_version_req: Option<String>,
+    pub url: String,
+    pub signature: String,
+    pub sha256: String,
+    pub size: i64,
+    pub notes: String,
+    /// Rolled-back bundles are never offered, and clients running one are
+    /// sent back to the previous bundle.
+    pub rolled_back: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Columns matching [`WebBundle`].
+pub const WEB_BUNDLE_COLUMNS: &str = "id, app_name, version, channel, app_version_req, url, signature, sha256, size, notes, rolled_back, created_at";
+
+// Only used to document the multipart body in the OpenAPI spec.
+#[allow(dead_code)]
+#[derive(Debug, utoipa::ToSchema)]
+pub struct WebBundleUploadForm {
+    #[schema(example = "1.4.2")]
+    pub version: String,
+    /// Defaults to `stable`.
+    pub channel: Option<String>,
+    /// App versions the bundle runs in, as a semver requirement.
+    #[schema(example = ">=3.2.0, <4.0.0")]
+    pub app_version: Option<String>,
+    #[schema(example = "signature")]
+    pub signature: String,
+    pub notes: Option<String>,
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WebBundleCheckQuery {
+    /// Version of the web bundle the client runs; leave out while it runs
+    /// the assets shipped with the app.
+    #[param(example = "1.4.1")]
+    pub bundle_version: Option<String>,
+    /// Release channel to follow (defaults to `stable`)
+    pub channel: Option<String>,
+    /// License key, for apps that require one; the `X-License-Key` header
+    /// is preferred, as it stays out of access logs.
+    pub license_key: Option<String>,
+}
+
+// Only used to document the multipart body in the OpenAPI spec.
+#[allow(dead_code)]
+#[derive(Debug, utoipa::ToSchema)]
+pub struct AuthenticodeForm {
+    /// App the installer belongs to.
+    #[schema(example = "classprime")]
+    pub app_name: String,
     #[schema(value_type = String, format = Binary)]
     pub file: Vec<u8>,
 }
+
+// Only used to document the multipart body in the OpenAPI spec.
+#[allow(dead_code)]
+#[derive(Debug, utoipa::ToSchema)]
+pub struct NormalizeForm {
+    #[schema(example = "my-app")]
+    pub app_name: String,
+    #[schema(example = "1.0.0")]
+    pub version: String,
+    #[schema(example = "darwin")]
+    pub target: String,
+    #[schema(example = "aarch64")]
+    pub arch: String,
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct WebBundleUpdateResponse {
+    pub version: String,
+    pub url: String,
+    pub signature: String,
+    pub sha256: String,
+    pub size: i64,
+    pub notes: String,
+    /// The offered bundle is older than the running one, which was rolled
+    /// back.
+    pub rollback: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    /// The feature the check covers isn't configured.
+    Skip,
+}
+
+/// One line of a self-check.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct SelfCheck {
+    #[schema(example = "github")]
+    pub name: String,
+    pub status: CheckStatus,
+    #[schema(example = "Edustart-Tech/App-Release-Manager is reachable")]
+    pub detail: String,
+}
+
+/// The result of checking the deployment's configuration.
+#[derive(Debug, Clone, Serialize, Deserialize, utoipa::ToSchema)]
+pub struct SelfCheckReport {
+    /// No check failed.
+    pub passed: bool,
+    pub checks: Vec<SelfCheck>,
+}
+
+/// How much client traffic is being served and shed.
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct LoadStats {
+    /// Update checks and other client requests being answered.
+    pub in_flight: usize,
+    /// Client requests waiting for one of those to finish.
+    pub queued: usize,
+    /// 0 when shedding is off.
+    pub max_in_flight: usize,
+    pub max_queue: usize,
+    /// Client requests answered since the server started.
+    pub served_total: u64,
+    /// Client requests turned away with 503 since the server started.
+    pub shed_total: u64,
+    pub served_last_minute: u64,
+    pub shed_last_minute: u64,
+    /// Share of client requests shed in the last minute.
+    #[schema(example = 0.12)]
+    pub shed_rate_last_minute: f64,
+}
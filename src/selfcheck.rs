@@ -0,0 +1,370 @@
+//! Configuration self-check.
+//!
+//! Most misconfiguration (a revoked GitHub token, a signing key that no
+//! longer parses, a full disk) only shows when the request that needs it
+//! fails. `GET /admin/selfcheck` and `arm check-config` exercise each
+//! dependency up front and report a pass/fail line per check instead.
+//! Nothing is sent to webhook endpoints: they are only connected to.
+
+use std::cmp::Ordering;
+use std::path::Path;
+use std::time::Duration;
+
+use axum::{Extension, extract::State, http::StatusCode, response::Json};
+use sqlx::{Pool, Sqlite};
+use tokio::net::TcpStream;
+use url::Url;
+
+use crate::auth::{self, Principal};
+use crate::authenticode::Signer;
+use crate::config::Config;
+use crate::db;
+use crate::error::{AppResult, ErrorBody};
+use crate::github::GitHub;
+use crate::notarization::Notary;
+use crate::redis::RedisClient;
+use crate::schema::{AppState, CheckStatus, SelfCheck, SelfCheckReport};
+
+/// How long a remote service gets to answer a check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn check(name: &str, result: Result<String, String>) -> SelfCheck {
+    let (status, detail) = match result {
+        Ok(detail) => (CheckStatus::Pass, detail),
+        Err(detail) => (CheckStatus::Fail, detail),
+    };
+    SelfCheck {
+        name: name.to_string(),
+        status,
+        detail,
+    }
+}
+
+fn skip(name: &str, detail: &str) -> SelfCheck {
+    SelfCheck {
+        name: name.to_string(),
+        status: CheckStatus::Skip,
+        detail: detail.to_string(),
+    }
+}
+
+async fn check_database(pool: &Pool<Sqlite>) -> Result<String, String> {
+    let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(pool)
+        .await
+        .map_err(|e| format!("Cannot query the database: {}", e))?;
+    match version.cmp(&db::SCHEMA_VERSION) {
+        Ordering::Equal => Ok(format!("Schema version {}", version)),
+        Ordering::Less => Err(format!(
+            "Schema version {} is behind {}; starting the server migrates it",
+            version,
+            db::SCHEMA_VERSION
+        )),
+        Ordering::Greater => Err(format!(
+            "Schema version {} is newer than this build's {}",
+            version,
+            db::SCHEMA_VERSION
+        )),
+    }
+}
+
+async fn check_redis(url: &str) -> Result<String, String> {
+    let client = RedisClient::new(url).map_err(|e| e.to_string())?;
+    match tokio::time::timeout(CHECK_TIMEOUT, client.command(&["PING"])).await {
+        Ok(Ok(_)) => Ok("Answered PING".to_string()),
+        Ok(Err(e)) => Err(format!("PING failed: {}", e)),
+        Err(_) => Err("PING timed out".to_string()),
+    }
+}
+
+async fn check_github(config: &Config) -> Result<String, String> {
+    let github = GitHub::from_config(config)?;
+    tokio::time::timeout(CHECK_TIMEOUT, github.check_access())
+        .await
+        .map_err(|_| "GitHub did not answer in time".to_string())?
+}
+
+/// Whether the program a configured command line starts can be found.
+fn find_program(command: &str) -> Result<String, String> {
+    let program = command
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| "The command is empty".to_string())?;
+    let found = if program.contains(std::path::MAIN_SEPARATOR) {
+        Path::new(program).is_file()
+    } else {
+        std::env::var_os("PATH")
+            .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+    };
+    if found {
+        Ok(format!("{} is installed", program))
+    } else {
+        Err(format!("{} is not installed", program))
+    }
+}
+
+/// The `host:port` a URL points at, without any credentials or path, which
+/// for incoming webhooks hold the secret.
+fn address(raw: &str) -> Result<String, String> {
+    let url = Url::parse(raw).map_err(|e| format!("Invalid URL: {}", e))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| "The URL has no host".to_string())?;
+    let port = url
+        .port_or_known_default()
+        .or(match url.scheme() {
+            "smtps" => Some(465),
+            "smtp" => Some(587),
+            "redis" => Some(6379),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No port for {} URLs", url.scheme()))?;
+    Ok(format!("{}:{}", host, port))
+}
+
+async fn check_reachable(raw: &str, timeout: Duration) -> Result<String, String> {
+    let address = address(raw)?;
+    match tokio::time::timeout(timeout, TcpStream::connect(&address)).await {
+        Ok(Ok(_)) => Ok(format!("{} is reachable", address)),
+        Ok(Err(e)) => Err(format!("Cannot connect to {}: {}", address, e)),
+        Err(_) => Err(format!("Connecting to {} timed out", address)),
+    }
+}
+
+async fn check_authenticode(config: &Config) -> Result<String, String> {
+    Signer::from_config(config).map_err(|e| e.to_string())?;
+    if let Some(command) = &config.authenticode_command {
+        return find_program(command);
+    }
+    match &config.authenticode_service_url {
+        Some(url) => check_reachable(url, CHECK_TIMEOUT).await,
+        None => Err("AUTHENTICODE_COMMAND or AUTHENTICODE_SERVICE_URL must be set".to_string()),
+    }
+}
+
+fn check_notary(config: &Config) -> Result<String, String> {
+    if Notary::from_config(config)
+        .map_err(|e| e.to_string())?
+        .is_none()
+    {
+        return Err(
+            "NOTARY_KEY_ID, NOTARY_ISSUER_ID and NOTARY_PRIVATE_KEY_PATH must all be set"
+                .to_string(),
+        );
+    }
+    let loaded = format!(
+        "Key {} loaded",
+        config.notary_key_id.as_deref().unwrap_or_default()
+    );
+    match &config.notary_staple_command {
+        Some(command) => Ok(format!("{}; {}", loaded, find_program(command)?)),
+        None => Ok(loaded),
+    }
+}
+
+#[cfg(unix)]
+fn free_bytes(dir: &Path) -> Result<u64, String> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stats` is only read once
+    // statvfs has filled it in.
+    let stats = unsafe {
+        if libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+        stats.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_bytes(_dir: &Path) -> Result<u64, String> {
+    Err("Free space can only be checked on Unix".to_string())
+}
+
+async fn check_temp_dir(config: &Config) -> Result<String, String> {
+    let dir = std::env::temp_dir();
+    let token = auth::random_token().map_err(|e| e.to_string())?;
+    let probe = dir.join(format!("updater-selfcheck-{}", token));
+    tokio::fs::write(&probe, b"selfcheck")
+        .await
+        .map_err(|e| format!("Cannot write to {}: {}", dir.display(), e))?;
+    let _ = tokio::fs::remove_file(&probe).await;
+
+    let free_mb = free_bytes(&dir)
+        .map_err(|e| format!("Cannot read free space of {}: {}", dir.display(), e))?
+        / (1024 * 1024);
+    if free_mb < config.selfcheck_min_free_mb {
+        return Err(format!(
+            "{} has {} MB free, below SELFCHECK_MIN_FREE_MB ({} MB)",
+            dir.display(),
+            free_mb,
+            config.selfcheck_min_free_mb
+        ));
+    }
+    Ok(format!(
+        "{} is writable, {} MB free",
+        dir.display(),
+        free_mb
+    ))
+}
+
+async fn webhook_checks(config: &Config, pool: Option<&Pool<Sqlite>>) -> Vec<SelfCheck> {
+    let mut checks = Vec::new();
+    if let Some(pool) = pool {
+        let webhooks: Result<Vec<(i64, String)>, sqlx::Error> =
+            sqlx::query_as("SELECT id, url FROM webhooks WHERE active = 1 ORDER BY id")
+                .fetch_all(pool)
+                .await;
+        match webhooks {
+            Ok(webhooks) => {
+                for (id, url) in webhooks {
+                    let result = check_reachable(&url, config.webhook_timeout).await;
+                    checks.push(check(&format!("webhook {}", id), result));
+                }
+            }
+            Err(e) => checks.push(check(
+                "webhooks",
+                Err(format!("Cannot list webhooks: {}", e)),
+            )),
+        }
+    }
+    for (name, url) in [
+        ("slack_webhook", &config.slack_webhook_url),
+        ("discord_webhook", &config.discord_webhook_url),
+        ("smtp", &config.smtp_url),
+    ] {
+        if let Some(url) = url {
+            let result = check_reachable(url, config.webhook_timeout).await;
+            checks.push(check(name, result));
+        }
+    }
+    if checks.is_empty() {
+        checks.push(skip("webhooks", "No webhooks or notification channels"));
+    }
+    checks
+}
+
+async fn report(config: &Config, pool: Result<&Pool<Sqlite>, String>) -> SelfCheckReport {
+    let mut checks = Vec::new();
+    match &pool {
+        Ok(pool) => checks.push(check("database", check_database(pool).await)),
+        Err(e) => checks.push(check("database", Err(e.clone()))),
+    }
+    checks.push(match &config.redis_url {
+        Some(url) => check("redis", check_redis(url).await),
+        None => skip("redis", "REDIS_URL is not set"),
+    });
+    checks.push(match &config.github_token {
+        Some(_) => check("github", check_github(config).await),
+        None => check(
+            "github",
+            Err("GITHUB_TOKEN is not set; uploads cannot be stored".to_string()),
+        ),
+    });
+    checks.push(
+        if config.authenticode_command.is_none() && config.authenticode_service_url.is_none() {
+            skip("authenticode", "Windows builds are not signed")
+        } else {
+            check("authenticode", check_authenticode(config).await)
+        },
+    );
+    checks.push(
+        if config.notary_key_id.is_none()
+            && config.notary_issuer_id.is_none()
+            && config.notary_private_key_path.is_none()
+        {
+            skip("notarization", "macOS builds are not notarized")
+        } else {
+            check("notarization", check_notary(config))
+        },
+    );
+    checks.extend(webhook_checks(config, pool.ok()).await);
+    checks.push(check("temp_dir", check_temp_dir(config).await));
+
+    SelfCheckReport {
+        passed: checks.iter().all(|c| c.status != CheckStatus::Fail),
+        checks,
+    }
+}
+
+/// Checks a running deployment, whose database is already open.
+pub async fn run(config: &Config, pool: &Pool<Sqlite>) -> SelfCheckReport {
+    report(config, Ok(pool)).await
+}
+
+/// Checks the configuration in the environment without a running server,
+/// opening the database read-only so a mistyped `DATABASE_URL` isn't
+/// created.
+pub async fn run_standalone(config: &Config) -> SelfCheckReport {
+    match db::connect_existing(config).await {
+        Ok(pool) => {
+            let report = run(config, &pool).await;
+            pool.close().await;
+            report
+        }
+        Err(e) => {
+            report(
+                config,
+                Err(format!("Cannot open {}: {}", config.database_url, e)),
+            )
+            .await
+        }
+    }
+}
+
+/// Runs the self-check once in the background at startup and logs what
+/// failed, so a broken deployment says so before the first request does.
+pub fn spawn(state: AppState) {
+    tokio::spawn(async move {
+        let report = run(&state.config, &state.pool).await;
+        for check in &report.checks {
+            if check.status == CheckStatus::Fail {
+                println!("Self-check {} failed: {}", check.name, check.detail);
+            }
+        }
+        if report.passed {
+            println!("Self-check passed");
+        }
+    });
+}
+
+/// Check the deployment's configuration
+///
+/// Verifies the database and its schema version, Redis, the GitHub token,
+/// signing keys, webhook endpoints and the temp dir. Operator token only.
+#[utoipa::path(
+    get,
+    path = "/admin/selfcheck",
+    responses(
+        (status = 200, description = "Every check passed or was skipped", body = SelfCheckReport),
+        (status = 403, description = "Not the operator token", body = ErrorBody),
+        (status = 503, description = "A check failed", body = SelfCheckReport)
+    )
+)]
+pub async fn get_selfcheck(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<(StatusCode, Json<SelfCheckReport>)> {
+    principal.require_operator()?;
+    let report = run(&state.config, &state.pool).await;
+    let status = if report.passed {
+        StatusCode::OK
+    } else {
+        println!(
+            "Self-check failed: {}",
+            report
+                .checks
+                .iter()
+                .filter(|c| c.status == CheckStatus::Fail)
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok((status, Json(report)))
+}
@@ -0,0 +1,307 @@
+//! A/B release variants.
+//!
+//! A release can carry a second artifact of the same version, variant B,
+//! uploaded with `variant=b`, to trial an installer change on part of the
+//! fleet. Clients are split by `client_id` (or `device_id`): the same
+//! client always gets the same variant of a version, on every platform.
+//! Clients that identify as neither get variant A. Update answers name the
+//! variant they serve, and install reports sent back with it make up the
+//! per-variant metrics.
+
+use axum::{
+    Extension,
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::Utc;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+
+use crate::artifacts;
+use crate::auth::{Principal, app_scope};
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::flags;
+use crate::quotas;
+use crate::reports;
+use crate::routes::{StoredArtifact, delete_orphaned};
+use crate::schema::{
+    AppState, RELEASE_COLUMNS, RELEASE_VARIANT_COLUMNS, Release, ReleaseVariant, VariantMetrics,
+    VariantSplitRequest,
+};
+
+pub const VARIANT_A: &str = "a";
+pub const VARIANT_B: &str = "b";
+
+pub const DEFAULT_SPLIT_PERCENT: i64 = 50;
+
+pub fn validate_split(split_percent: i64) -> AppResult<()> {
+    if !(0..=100).contains(&split_percent) {
+        return Err(AppError::bad_request(
+            "split_percent must be between 0 and 100",
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `client_id` gets variant B of `release`.
+fn in_variant_b(release: &Release, client_id: &str, split_percent: i64) -> bool {
+    let salt = format!("{}\0{}", release.app_name, release.version);
+    i64::from(flags::bucket(&salt, client_id)) < split_percent
+}
+
+pub async fn find_variant(
+    pool: &Pool<Sqlite>,
+    release_id: i64,
+) -> Result<Option<ReleaseVariant>, sqlx::Error> {
+    sqlx::query_as::<_, ReleaseVariant>(&format!(
+        "SELECT {} FROM release_variants WHERE release_id = ?",
+        RELEASE_VARIANT_COLUMNS
+    ))
+    .bind(release_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// The artifact of `release` that `client_id` should install, and which
+/// variant that is when the release is A/B tested.
+pub async fn for_client(
+    pool: &Pool<Sqlite>,
+    mut release: Release,
+    client_id: Option<&str>,
+) -> Result<(Release, Option<&'static str>), sqlx::Error> {
+    let Some(variant) = find_variant(pool, release.id).await? else {
+        return Ok((release, None));
+    };
+    if !client_id.is_some_and(|id| in_variant_b(&release, id, variant.split_percent)) {
+        return Ok((release, Some(VARIANT_A)));
+    }
+    release.url = variant.url;
+    release.signature = variant.signature;
+    release.sha256 = Some(variant.sha256);
+    Ok((release, Some(VARIANT_B)))
+}
+
+/// Records `artifact` as variant B of `release`.
+pub async fn add_variant(
+    state: &AppState,
+    release: &Release,
+    (sha256, size, url, github_asset_id): StoredArtifact,
+    signature: &str,
+    split_percent: i64,
+) -> AppResult<ReleaseVariant> {
+    let saved: Result<Result<Option<ReleaseVariant>, AppError>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        if let Some(refused) =
+            quotas::record_upload(&mut tx, &state.config, &release.app_name, &sha256, size, false)
+                .await?
+        {
+            return Ok(Err(refused));
+        }
+        let Some(variant) = sqlx::query_as::<_, ReleaseVariant>(&format!(
+            "INSERT INTO release_variants (release_id, url, signature, sha256, split_percent, created_at) VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT (release_id) DO NOTHING RETURNING {}",
+            RELEASE_VARIANT_COLUMNS
+        ))
+        .bind(release.id)
+        .bind(&url)
+        .bind(signature)
+        .bind(&sha256)
+        .bind(split_percent)
+        .bind(Utc::now())
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(Ok(None));
+        };
+        artifacts::retain(&mut tx, &release.app_name, &sha256, &url, size, github_asset_id).await?;
+        tx.commit().await?;
+        Ok(Ok(Some(variant)))
+    }
+    .await;
+
+    saved
+        .map_err(|e| AppError::internal("Failed to save variant", e))??
+        .ok_or_else(|| {
+            AppError::conflict(format!(
+                "Release {} already has a variant B; delete it first",
+                release.id
+            ))
+        })
+}
+
+/// Removes the variant B of a release, returning its artifact for the
+/// caller to release.
+pub async fn remove_variant(
+    conn: &mut SqliteConnection,
+    release_id: i64,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("DELETE FROM release_variants WHERE release_id = ? RETURNING sha256")
+        .bind(release_id)
+        .fetch_optional(conn)
+        .await
+}
+
+pub async fn scoped_release(state: &AppState, principal: Principal, id: i64) -> AppResult<Release> {
+    sqlx::query_as::<_, Release>(&format!(
+        "SELECT {} FROM releases WHERE id = ?1 AND {}",
+        RELEASE_COLUMNS,
+        app_scope(2)
+    ))
+    .bind(id)
+    .bind(principal.org_id())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load release", e))?
+    .ok_or_else(|| AppError::not_found("Release not found"))
+}
+
+/// Get a release's variant B
+#[utoipa::path(
+    get,
+    path = "/releases/{id}/variant",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    responses(
+        (status = 200, description = "Variant B", body = ReleaseVariant),
+        (status = 404, description = "Release not found or not A/B tested", body = ErrorBody)
+    )
+)]
+pub async fn get_variant(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<ReleaseVariant>> {
+    let release = scoped_release(&state, principal, id).await?;
+    let variant = find_variant(&state.pool, release.id)
+        .await
+        .map_err(|e| AppError::internal("Failed to load variant", e))?
+        .ok_or_else(|| AppError::not_found("Release has no variant B"))?;
+    Ok(Json(variant))
+}
+
+/// Change a release's traffic split
+#[utoipa::path(
+    put,
+    path = "/releases/{id}/variant",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    request_body = VariantSplitRequest,
+    responses(
+        (status = 200, description = "Split saved", body = ReleaseVariant),
+        (status = 400, description = "Invalid split", body = ErrorBody),
+        (status = 404, description = "Release not found or not A/B tested", body = ErrorBody)
+    )
+)]
+pub async fn set_variant_split(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<VariantSplitRequest>,
+) -> AppResult<Json<ReleaseVariant>> {
+    validate_split(request.split_percent)?;
+    let release = scoped_release(&state, principal, id).await?;
+    let variant = sqlx::query_as::<_, ReleaseVariant>(&format!(
+        "UPDATE release_variants SET split_percent = ? WHERE release_id = ? RETURNING {}",
+        RELEASE_VARIANT_COLUMNS
+    ))
+    .bind(request.split_percent)
+    .bind(release.id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to update variant", e))?
+    .ok_or_else(|| AppError::not_found("Release has no variant B"))?;
+
+    println!(
+        "Release {} now serves variant B to {}% of clients",
+        release.id, variant.split_percent
+    );
+    Ok(Json(variant))
+}
+
+/// End an A/B test
+///
+/// Every client gets variant A again. The install reports are kept.
+#[utoipa::path(
+    delete,
+    path = "/releases/{id}/variant",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    responses(
+        (status = 204, description = "Variant B removed"),
+        (status = 404, description = "Release not found or not A/B tested", body = ErrorBody)
+    )
+)]
+pub async fn delete_variant(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let release = scoped_release(&state, principal, id).await?;
+    let removed: Result<Option<Option<crate::schema::Artifact>>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        let Some(sha256) = remove_variant(&mut tx, release.id).await? else {
+            return Ok(None);
+        };
+        let orphaned = artifacts::release(&mut tx, &release.app_name, &sha256).await?;
+        tx.commit().await?;
+        Ok(Some(orphaned))
+    }
+    .await;
+    let orphaned = removed
+        .map_err(|e| AppError::internal("Failed to remove variant", e))?
+        .ok_or_else(|| AppError::not_found("Release has no variant B"))?;
+    delete_orphaned(&state, orphaned).await;
+
+    println!("Removed variant B of release {}", release.id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Per-variant install metrics of a release
+#[utoipa::path(
+    get,
+    path = "/releases/{id}/metrics",
+    params(
+        ("id" = i64, Path, description = "Release ID")
+    ),
+    responses(
+        (status = 200, description = "Install reports by variant; just `a` for releases that aren't A/B tested", body = Vec<VariantMetrics>),
+        (status = 404, description = "Release not found", body = ErrorBody)
+    )
+)]
+pub async fn release_metrics(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<VariantMetrics>>> {
+    let release = scoped_release(&state, principal, id).await?;
+    let variant = find_variant(&state.read_pool, release.id)
+        .await
+        .map_err(|e| AppError::internal("Failed to load variant", e))?;
+    let mut counts = reports::outcome_counts(&state.read_pool, release.id)
+        .await
+        .map_err(|e| AppError::internal("Failed to load install reports", e))?;
+
+    let b_split = variant.as_ref().map(|v| v.split_percent);
+    let mut variants = vec![(VARIANT_A, 100 - b_split.unwrap_or(0))];
+    // Reports for B outlive the variant itself.
+    if b_split.is_some() || counts.contains_key(VARIANT_B) {
+        variants.push((VARIANT_B, b_split.unwrap_or(0)));
+    }
+    let metrics = variants
+        .into_iter()
+        .map(|(name, split_percent)| {
+            let c = counts.remove(name).unwrap_or_default();
+            VariantMetrics {
+                variant: name.to_string(),
+                split_percent,
+                installed: c.installed,
+                failed: c.failed,
+                rolled_back: c.rolled_back,
+                failure_rate: c.failure_rate(),
+            }
+        })
+        .collect();
+    Ok(Json(metrics))
+}
@@ -0,0 +1,238 @@
+//! Per-app version schemes.
+//!
+//! Releases are versioned with semver unless their app says otherwise. Some
+//! internal tools use CalVer (`2024.06.1`) or plain build numbers (`4512`)
+//! instead; an app's scheme decides which versions uploads accept, which
+//! `current_version`s update checks accept, and how versions compare when
+//! the latest release is picked.
+//!
+//! Version requirements (campaigns, feature flags, plugins' host versions
+//! and web bundles' app versions) stay semver ranges. A
+//! CalVer version is matched against them as `year.minor.patch` and a build
+//! number as `number.0.0`, so `>=2024.6.0` or `>=4500.0.0` work as expected.
+
+use axum::{
+    Extension,
+    extract::{Path, State},
+    response::Json,
+};
+use semver::Version;
+use sqlx::{Pool, Sqlite};
+
+use crate::auth::{self, Principal};
+use crate::cache;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::schema::{APP_COLUMNS, App, AppState, VersionScheme, VersionSchemeRequest};
+
+/// A parsed version. Versions only compare meaningfully with versions of
+/// the same scheme, which all releases of an app are.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AppVersion {
+    Semver(Version),
+    /// Components with trailing zeros dropped, so `2024.06` and `2024.6.0`
+    /// are the same version.
+    Calver(Vec<u64>),
+    Numeric(u64),
+}
+
+impl AppVersion {
+    /// The version as semver, for matching version requirements.
+    pub fn to_semver(&self) -> Version {
+        match self {
+            AppVersion::Semver(v) => v.clone(),
+            AppVersion::Calver(parts) => Version::new(
+                parts[0],
+                parts.get(1).copied().unwrap_or(0),
+                parts.get(2).copied().unwrap_or(0),
+            ),
+            AppVersion::Numeric(n) => Version::new(*n, 0, 0),
+        }
+    }
+}
+
+fn parse_number(part: &str) -> Result<u64, String> {
+    if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("'{}' is not a number", part));
+    }
+    part.parse().map_err(|_| format!("{} is too large", part))
+}
+
+fn parse_calver(raw: &str) -> Result<Vec<u64>, String> {
+    let parts: Vec<&str> = raw.split('.').collect();
+    if !(2..=4).contains(&parts.len()) {
+        return Err("expected a year followed by one to three numbers".to_string());
+    }
+    if !matches!(parts[0].len(), 2 | 4) {
+        return Err(format!("'{}' is not a year; use YYYY or YY", parts[0]));
+    }
+    let mut numbers = parts
+        .into_iter()
+        .map(parse_number)
+        .collect::<Result<Vec<u64>, String>>()?;
+    while numbers.len() > 1 && numbers.last() == Some(&0) {
+        numbers.pop();
+    }
+    Ok(numbers)
+}
+
+/// Parses `raw` as a version of `scheme`.
+pub fn parse(scheme: VersionScheme, raw: &str) -> Result<AppVersion, String> {
+    match scheme {
+        VersionScheme::Semver => Version::parse(raw)
+            .map(AppVersion::Semver)
+            .map_err(|e| e.to_string()),
+        VersionScheme::Calver => parse_calver(raw).map(AppVersion::Calver),
+        VersionScheme::Numeric => parse_number(raw).map(AppVersion::Numeric),
+    }
+}
+
+/// Parses a version a client or admin sent, as a 400 naming `field` when
+/// it doesn't fit the scheme.
+pub fn parse_field(scheme: VersionScheme, field: &str, raw: &str) -> AppResult<AppVersion> {
+    parse(scheme, raw).map_err(|e| {
+        AppError::bad_request(format!(
+            "{} '{}' is not a {} version: {}",
+            field,
+            raw,
+            scheme.as_str(),
+            e
+        ))
+    })
+}
+
+/// The version scheme of `app_name`; semver for apps that don't exist yet.
+pub async fn app_scheme(pool: &Pool<Sqlite>, app_name: &str) -> Result<VersionScheme, sqlx::Error> {
+    let scheme: Option<String> =
+        sqlx::query_scalar("SELECT version_scheme FROM apps WHERE name = ?")
+            .bind(app_name)
+            .fetch_optional(pool)
+            .await?;
+    Ok(scheme
+        .as_deref()
+        .and_then(VersionScheme::parse)
+        .unwrap_or_default())
+}
+
+/// Looks up the version scheme of `app_name` for a request.
+pub async fn scheme_for(state: &AppState, app_name: &str) -> AppResult<VersionScheme> {
+    app_scheme(&state.read_pool, app_name)
+        .await
+        .map_err(|e| AppError::internal("Failed to look up the version scheme", e))
+}
+
+/// Set an app's version scheme
+///
+/// Every existing release of the app must have a version of the new scheme.
+#[utoipa::path(
+    put,
+    path = "/apps/{name}/version-scheme",
+    params(
+        ("name" = String, Path, description = "Application name")
+    ),
+    request_body = VersionSchemeRequest,
+    responses(
+        (status = 200, description = "Scheme saved", body = App),
+        (status = 403, description = "The app belongs to another organization", body = ErrorBody),
+        (status = 409, description = "A release's version doesn't fit the scheme", body = ErrorBody)
+    )
+)]
+pub async fn set_version_scheme(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<VersionSchemeRequest>,
+) -> AppResult<Json<App>> {
+    let scheme = request.version_scheme;
+    auth::claim_app(&state.pool, principal, &name).await?;
+
+    let versions: Vec<String> =
+        sqlx::query_scalar("SELECT DISTINCT version FROM releases WHERE app_name = ?")
+            .bind(&name)
+            .fetch_all(&state.pool)
+            .await
+            .map_err(|e| AppError::internal("Failed to look up releases", e))?;
+    for version in &versions {
+        if let Err(e) = parse(scheme, version) {
+            return Err(AppError::conflict(format!(
+                "Release {} of {} is not a {} version: {}",
+                version,
+                name,
+                scheme.as_str(),
+                e
+            )));
+        }
+    }
+
+    let app = sqlx::query_as::<_, App>(&format!(
+        "UPDATE apps SET version_scheme = ? WHERE name = ? RETURNING {}",
+        APP_COLUMNS
+    ))
+    .bind(scheme.as_str())
+    .bind(&name)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to save the version scheme", e))?;
+
+    // Which release is the latest may have changed everywhere.
+    let latest_keys: Vec<(String, String, String)> =
+        sqlx::query_as("SELECT DISTINCT target, arch, channel FROM releases WHERE app_name = ?")
+            .bind(&name)
+            .fetch_all(&state.pool)
+            .await
+            .map_err(|e| AppError::internal("Failed to look up releases", e))?;
+    for (target, arch, channel) in latest_keys {
+        state
+            .cache
+            .invalidate(&cache::latest_key(&name, &target, &arch, &channel))
+            .await;
+    }
+
+    println!("{} now uses {} versions", app.name, app.version_scheme);
+    Ok(Json(app))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calver(raw: &str) -> AppVersion {
+        parse(VersionScheme::Calver, raw).unwrap()
+    }
+
+    #[test]
+    fn calver_orders_by_component() {
+        assert!(calver("2024.6") < calver("2024.10"));
+        assert!(calver("2024.12.3") < calver("2025.1"));
+        assert!(calver("2024.6.1") > calver("2024.6"));
+    }
+
+    #[test]
+    fn calver_ignores_trailing_zeros() {
+        assert_eq!(calver("2024.06"), calver("2024.6.0"));
+        assert_eq!(calver("2024.6.0.0"), AppVersion::Calver(vec![2024, 6]));
+    }
+
+    #[test]
+    fn numeric_orders_as_numbers() {
+        let numeric = |raw| parse(VersionScheme::Numeric, raw).unwrap();
+        assert!(numeric("9") < numeric("10"));
+        assert_eq!(numeric("0042"), AppVersion::Numeric(42));
+    }
+
+    #[test]
+    fn rejects_versions_outside_the_scheme() {
+        for raw in ["2024", "2024.6.1.2.3", "202.6", "2024.x", "2024..1"] {
+            assert!(parse(VersionScheme::Calver, raw).is_err(), "{}", raw);
+        }
+        for raw in ["", "1.2", "-1", "99999999999999999999999"] {
+            assert!(parse(VersionScheme::Numeric, raw).is_err(), "{}", raw);
+        }
+        assert!(parse(VersionScheme::Semver, "1.2").is_err());
+    }
+
+    #[test]
+    fn converts_to_semver_for_requirements() {
+        assert_eq!(calver("2024.6").to_semver(), Version::new(2024, 6, 0));
+        assert_eq!(AppVersion::Numeric(7).to_semver(), Version::new(7, 0, 0));
+    }
+}
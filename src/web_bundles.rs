@@ -0,0 +1,535 @@
+//! Over-the-air web bundles.
+//!
+//! Hybrid apps, such as our Tauri apps, can hot-update their frontend
+//! between full releases by loading a downloaded asset archive instead of
+//! the assets shipped in the installer. Web bundles are versioned on their
+//! own, per channel, and may require a range of app versions, since new UI
+//! assets often depend on new native commands.
+//!
+//! A bad bundle is rolled back rather than deleted: it is no longer offered,
+//! and clients reporting it as their running bundle are offered the newest
+//! remaining one, or told with `205 Reset Content` to go back to the assets
+//! shipped with the app.
+//!
+//! Swapping the assets clients run is a publish, so uploads, roll-backs and
+//! restores are held during a publish freeze like release uploads are.
+//! Checks go through the same gates as update checks: new bundles wait out
+//! blackout windows, and apps requiring a license only hand them to
+//! licensed clients. Moving clients off a rolled-back bundle isn't held.
+
+use axum::{
+    Extension,
+    extract::{Multipart, Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::Utc;
+use semver::{Version, VersionReq};
+
+use crate::artifacts;
+use crate::auth::{self, Principal, app_scope};
+use crate::blackouts;
+use crate::error::{AppError, AppResult, ErrorBody};
+use crate::freezes;
+use crate::http_cache;
+use crate::licenses;
+use crate::quotas;
+use crate::routes::{delete_orphaned, invalid_channel, release_tag, store_artifact};
+use crate::schema::{
+    AppState, Artifact, ChannelQuery, DEFAULT_CHANNEL, OverrideQuery, WEB_BUNDLE_COLUMNS,
+    WebBundle, WebBundleCheckQuery, WebBundleUpdateResponse, WebBundleUploadForm, is_valid_channel,
+};
+use crate::versioning;
+
+/// Upload a web bundle
+#[utoipa::path(
+    post,
+    path = "/apps/{name}/web-bundles",
+    params(
+        ("name" = String, Path, description = "Application name"),
+        OverrideQuery
+    ),
+    request_body(content = WebBundleUploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "Web bundle published", body = WebBundle),
+        (status = 400, description = "Invalid version, channel or app version requirement, or no file", body = ErrorBody),
+        (status = 403, description = "The app belongs to another organization, or only the operator may override the freeze", body = ErrorBody),
+        (status = 409, description = "This version is already on the channel, or publishing is frozen", body = ErrorBody),
+        (status = 413, description = "The upload doesn't fit the storage quota", body = ErrorBody),
+        (status = 429, description = "The daily upload quota is used up", body = ErrorBody)
+    )
+)]
+pub async fn upload_web_bundle(
+    Path(app_name): Path<String>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(freeze): Query<OverrideQuery>,
+    mut multipart: Multipart,
+) -> AppResult<(StatusCode, Json<WebBundle>)> {
+    let mut version = String::new();
+    let mut channel = String::new();
+    let mut app_version = String::new();
+    let mut signature = String::new();
+    let mut notes = String::new();
+    let mut file_name = String::new();
+    let mut file_data: Vec<u8> = Vec::new();
+    while let Some(res) = multipart.next_field().await.transpose() {
+        let field =
+            res.map_err(|e| AppError::bad_request(format!("Malformed multipart body: {}", e)))?;
+        match field.name().unwrap_or_default() {
+            "version" => version = field.text().await.unwrap_or_default(),
+            "channel" => channel = field.text().await.unwrap_or_default(),
+            "app_version" => app_version = field.text().await.unwrap_or_default(),
+            "signature" => signature = field.text().await.unwrap_or_default(),
+            "notes" => notes = field.text().await.unwrap_or_default(),
+            "file" => {
+                file_name = field.file_name().unwrap_or("web-bundle").to_string();
+                file_data = field
+                    .bytes()
+                    .await
+                    .map_err(|e| AppError::bad_request(format!("Failed to read file: {}", e)))?
+                    .to_vec();
+            }
+            _ => (),
+        }
+    }
+    if file_data.is_empty() {
+        return Err(AppError::bad_request("No file uploaded or file is empty"));
+    }
+    let version = version.trim();
+    Version::parse(version).map_err(|e| {
+        AppError::bad_request(format!(
+            "version '{}' is not a semver version: {}",
+            version, e
+        ))
+    })?;
+    let channel = match channel.trim() {
+        "" => DEFAULT_CHANNEL,
+        c if is_valid_channel(c) => c,
+        _ => return Err(invalid_channel()),
+    };
+    let app_version_req = match app_version.trim() {
+        "" => None,
+        raw => Some(
+            VersionReq::parse(raw)
+                .map_err(|e| {
+                    AppError::bad_request(format!(
+                        "app_version '{}' is not a semver requirement: {}",
+                        raw, e
+                    ))
+                })?
+                .to_string(),
+        ),
+    };
+    auth::claim_app(&state.pool, principal, &app_name).await?;
+    freezes::check_publish(&state.pool, principal, &app_name, &freeze).await?;
+
+    // Checked again when saving; this only spares GitHub a doomed upload.
+    let exists: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM web_bundles WHERE app_name = ? AND channel = ? AND version = ?)",
+    )
+    .bind(&app_name)
+    .bind(channel)
+    .bind(version)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to look up the web bundle", e))?;
+    let duplicate = || {
+        AppError::conflict(format!(
+            "Web bundle {} is already on {}; roll it back or delete it first",
+            version, channel
+        ))
+    };
+    if exists {
+        return Err(duplicate());
+    }
+
+    let sha256 = artifacts::sha256_hex(&file_data);
+    let size = file_data.len() as i64;
+    quotas::check_upload(&state, &app_name, &sha256, size, false).await?;
+    let (sha256, size, url, github_asset_id) = store_artifact(
+        &state,
+        &app_name,
+        sha256,
+        &release_tag(&format!("{}-web", app_name), version),
+        &notes,
+        &file_name,
+        file_data,
+    )
+    .await?;
+
+    let saved: Result<Result<Option<WebBundle>, AppError>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        if let Some(refused) =
+            quotas::record_upload(&mut tx, &state.config, &app_name, &sha256, size, false).await?
+        {
+            return Ok(Err(refused));
+        }
+        let Some(bundle) = sqlx::query_as::<_, WebBundle>(&format!(
+            "INSERT INTO web_bundles (app_name, version, channel, app_version_req, url, signature, sha256, size, notes, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT DO NOTHING RETURNING {}",
+            WEB_BUNDLE_COLUMNS
+        ))
+        .bind(&app_name)
+        .bind(version)
+        .bind(channel)
+        .bind(&app_version_req)
+        .bind(&url)
+        .bind(signature.trim())
+        .bind(&sha256)
+        .bind(size)
+        .bind(notes.trim())
+        .bind(Utc::now())
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(Ok(None));
+        };
+        artifacts::retain(&mut tx, &app_name, &sha256, &url, size, github_asset_id).await?;
+        tx.commit().await?;
+        Ok(Ok(Some(bundle)))
+    }
+    .await;
+    let bundle = saved
+        .map_err(|e| AppError::internal("Failed to save web bundle", e))??
+        .ok_or_else(duplicate)?;
+
+    println!(
+        "Published web bundle {} of {} on {}",
+        bundle.version, bundle.app_name, bundle.channel
+    );
+    Ok((StatusCode::CREATED, Json(bundle)))
+}
+
+/// Check for a web bundle update
+///
+/// `205 Reset Content` asks the client to drop its running bundle, which
+/// was rolled back with nothing to replace it, and load the assets shipped
+/// with the app.
+#[utoipa::path(
+    get,
+    path = "/web/{app_name}/{app_version}",
+    security(()),
+    params(
+        ("app_name" = String, Path, description = "Application name"),
+        ("app_version" = String, Path, description = "Version of the installed app"),
+        WebBundleCheckQuery
+    ),
+    responses(
+        (status = 200, description = "A newer bundle, or an older one replacing a rolled-back bundle", body = WebBundleUpdateResponse),
+        (status = 204, description = "The running assets are current, or a new bundle waits for a blackout window to end"),
+        (status = 205, description = "Go back to the assets shipped with the app"),
+        (status = 304, description = "Answer unchanged since the ETag in If-None-Match"),
+        (status = 400, description = "Invalid version", body = ErrorBody),
+        (status = 402, description = "The license key has expired (`license_expired`)", body = ErrorBody),
+        (status = 403, description = "The app requires a license key and none or an invalid one was sent (`license_required`, `license_invalid`)", body = ErrorBody)
+    )
+)]
+pub async fn check_web_bundle(
+    Path((app_name, app_version)): Path<(String, String)>,
+    Query(query): Query<WebBundleCheckQuery>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let channel = query.channel.as_deref().unwrap_or(DEFAULT_CHANNEL);
+    // The app's version follows its scheme, matched against requirements
+    // as semver; bundles are always versioned with semver.
+    let scheme = versioning::scheme_for(&state, &app_name).await?;
+    let app_ver = versioning::parse_field(scheme, "app_version", app_version.trim())?.to_semver();
+    let running = query
+        .bundle_version
+        .as_deref()
+        .filter(|v| !v.trim().is_empty())
+        .map(|raw| {
+            Version::parse(raw.trim()).map_err(|e| {
+                AppError::bad_request(format!(
+                    "bundle_version '{}' is not a semver version: {}",
+                    raw, e
+                ))
+            })
+        })
+        .transpose()?;
+
+    let bundles = sqlx::query_as::<_, WebBundle>(&format!(
+        "SELECT {} FROM web_bundles WHERE app_name = ? AND channel = ?",
+        WEB_BUNDLE_COLUMNS
+    ))
+    .bind(&app_name)
+    .bind(channel)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to look up web bundles", e))?;
+
+    let running_rolled_back = running.as_ref().is_some_and(|r| {
+        bundles
+            .iter()
+            .any(|b| b.rolled_back && Version::parse(&b.version).is_ok_and(|v| v == *r))
+    });
+    let best = bundles
+        .into_iter()
+        .filter(|b| !b.rolled_back)
+        .filter(|b| {
+            // Requirements were validated on upload.
+            b.app_version_req
+                .as_deref()
+                .and_then(|r| VersionReq::parse(r).ok())
+                .is_none_or(|r| r.matches(&app_ver))
+        })
+        .filter_map(|b| Some((Version::parse(&b.version).ok()?, b)))
+        .max_by(|(a, _), (b, _)| a.cmp(b));
+
+    let offer = match (best, &running) {
+        (Some((v, b)), None) => Some((b, v, false)),
+        (Some((v, b)), Some(r)) if v > *r => Some((b, v, false)),
+        (Some((v, b)), Some(r)) if running_rolled_back && v < *r => Some((b, v, true)),
+        _ => None,
+    };
+    // Like a non-critical release, a new bundle waits out a blackout; a
+    // client running a rolled-back one is moved off it regardless.
+    let offer = match offer {
+        Some((bundle, version, false)) if !running_rolled_back => {
+            match blackouts::active_blackout(&state.read_pool, &app_name, Utc::now())
+                .await
+                .map_err(|e| AppError::internal("Failed to check blackout windows", e))?
+            {
+                Some(reason) => {
+                    println!(
+                        "Holding back web bundle {} of {} during blackout: {}",
+                        version, app_name, reason
+                    );
+                    None
+                }
+                None => Some((bundle, version, false)),
+            }
+        }
+        offer => offer,
+    };
+    if offer.is_some() {
+        let key = licenses::request_key(&headers, query.license_key.as_deref());
+        licenses::check_download(&state, key, (&app_name, "", "", channel)).await?;
+    }
+
+    let reset = offer.is_none() && running_rolled_back;
+    let answer = offer.map(|(bundle, version, rollback)| {
+        println!(
+            "Web bundle {} of {}: {} -> {}{}",
+            if rollback { "rollback" } else { "update" },
+            app_name,
+            running
+                .as_ref()
+                .map(|r| r.to_string())
+                .unwrap_or_else(|| "shipped".to_string()),
+            version,
+            if rollback { " (rolled back)" } else { "" }
+        );
+        WebBundleUpdateResponse {
+            version: bundle.version,
+            url: bundle.url,
+            signature: bundle.signature,
+            sha256: bundle.sha256,
+            size: bundle.size,
+            notes: bundle.notes,
+            rollback,
+        }
+    });
+    let etag = http_cache::web_bundle_etag(answer.as_ref(), reset);
+    let vary = |response: Response| http_cache::vary(response, licenses::LICENSE_KEY_HEADER);
+    if http_cache::not_modified(&headers, &etag) {
+        return Ok(vary(
+            (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response(),
+        ));
+    }
+    let response = match answer {
+        Some(answer) => (StatusCode::OK, [(header::ETAG, etag)], Json(answer)).into_response(),
+        None if reset => {
+            println!(
+                "Web bundle {} of {} was rolled back; resetting to the shipped assets",
+                running.map(|r| r.to_string()).unwrap_or_default(),
+                app_name
+            );
+            (StatusCode::RESET_CONTENT, [(header::ETAG, etag)]).into_response()
+        }
+        None => (StatusCode::NO_CONTENT, [(header::ETAG, etag)]).into_response(),
+    };
+    Ok(vary(response))
+}
+
+/// List an app's web bundles
+#[utoipa::path(
+    get,
+    path = "/apps/{name}/web-bundles",
+    params(
+        ("name" = String, Path, description = "Application name"),
+        ChannelQuery
+    ),
+    responses(
+        (status = 200, description = "Web bundles, newest first", body = Vec<WebBundle>)
+    )
+)]
+pub async fn list_web_bundles(
+    Path(app_name): Path<String>,
+    Query(query): Query<ChannelQuery>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<Json<Vec<WebBundle>>> {
+    let bundles = sqlx::query_as::<_, WebBundle>(&format!(
+        "SELECT {} FROM web_bundles WHERE app_name = ?1 AND (?2 IS NULL OR channel = ?2) AND {} ORDER BY id DESC",
+        WEB_BUNDLE_COLUMNS,
+        app_scope(3)
+    ))
+    .bind(&app_name)
+    .bind(&query.channel)
+    .bind(principal.org_id())
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load web bundles", e))?;
+
+    Ok(Json(bundles))
+}
+
+async fn set_rolled_back(
+    state: &AppState,
+    principal: Principal,
+    id: i64,
+    rolled_back: bool,
+    freeze: &OverrideQuery,
+) -> AppResult<WebBundle> {
+    let app_name: String = sqlx::query_scalar(&format!(
+        "SELECT app_name FROM web_bundles WHERE id = ?1 AND {}",
+        app_scope(2)
+    ))
+    .bind(id)
+    .bind(principal.org_id())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to load web bundle", e))?
+    .ok_or_else(|| AppError::not_found("Web bundle not found"))?;
+    freezes::check_publish(&state.pool, principal, &app_name, freeze).await?;
+
+    let bundle = sqlx::query_as::<_, WebBundle>(&format!(
+        "UPDATE web_bundles SET rolled_back = ?1 WHERE id = ?2 AND {} RETURNING {}",
+        app_scope(3),
+        WEB_BUNDLE_COLUMNS
+    ))
+    .bind(rolled_back)
+    .bind(id)
+    .bind(principal.org_id())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| AppError::internal("Failed to update web bundle", e))?
+    .ok_or_else(|| AppError::not_found("Web bundle not found"))?;
+
+    println!(
+        "Web bundle {} of {} on {} {}",
+        bundle.version,
+        bundle.app_name,
+        bundle.channel,
+        if rolled_back {
+            "rolled back"
+        } else {
+            "restored"
+        }
+    );
+    Ok(bundle)
+}
+
+/// Roll back a web bundle
+///
+/// Clients running it are moved to the newest bundle left, or to the
+/// assets shipped with the app.
+#[utoipa::path(
+    post,
+    path = "/web-bundles/{id}/rollback",
+    params(
+        ("id" = i64, Path, description = "Web bundle ID"),
+        OverrideQuery
+    ),
+    responses(
+        (status = 200, description = "Rolled back", body = WebBundle),
+        (status = 403, description = "Only the operator may override the freeze", body = ErrorBody),
+        (status = 404, description = "Web bundle not found", body = ErrorBody),
+        (status = 409, description = "Publishing is frozen", body = ErrorBody)
+    )
+)]
+pub async fn roll_back_web_bundle(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(freeze): Query<OverrideQuery>,
+) -> AppResult<Json<WebBundle>> {
+    set_rolled_back(&state, principal, id, true, &freeze)
+        .await
+        .map(Json)
+}
+
+/// Undo a web bundle rollback
+#[utoipa::path(
+    delete,
+    path = "/web-bundles/{id}/rollback",
+    params(
+        ("id" = i64, Path, description = "Web bundle ID"),
+        OverrideQuery
+    ),
+    responses(
+        (status = 200, description = "Offered again", body = WebBundle),
+        (status = 403, description = "Only the operator may override the freeze", body = ErrorBody),
+        (status = 404, description = "Web bundle not found", body = ErrorBody),
+        (status = 409, description = "Publishing is frozen", body = ErrorBody)
+    )
+)]
+pub async fn restore_web_bundle(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(freeze): Query<OverrideQuery>,
+) -> AppResult<Json<WebBundle>> {
+    set_rolled_back(&state, principal, id, false, &freeze)
+        .await
+        .map(Json)
+}
+
+/// Delete a web bundle
+///
+/// Clients still running it keep it until a newer bundle comes out; roll
+/// it back instead to move them off it.
+#[utoipa::path(
+    delete,
+    path = "/web-bundles/{id}",
+    params(
+        ("id" = i64, Path, description = "Web bundle ID")
+    ),
+    responses(
+        (status = 204, description = "Web bundle deleted"),
+        (status = 404, description = "Web bundle not found", body = ErrorBody)
+    )
+)]
+pub async fn delete_web_bundle(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+) -> AppResult<StatusCode> {
+    let deleted: Result<Option<Option<Artifact>>, sqlx::Error> = async {
+        let mut tx = state.pool.begin().await?;
+        let Some((app_name, sha256)) = sqlx::query_as::<_, (String, String)>(&format!(
+            "DELETE FROM web_bundles WHERE id = ?1 AND {} RETURNING app_name, sha256",
+            app_scope(2)
+        ))
+        .bind(id)
+        .bind(principal.org_id())
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let orphaned = artifacts::release(&mut tx, &app_name, &sha256).await?;
+        tx.commit().await?;
+        Ok(Some(orphaned))
+    }
+    .await;
+    let orphaned = deleted
+        .map_err(|e| AppError::internal("Failed to delete web bundle", e))?
+        .ok_or_else(|| AppError::not_found("Web bundle not found"))?;
+    delete_orphaned(&state, orphaned).await;
+
+    println!("Deleted web bundle {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
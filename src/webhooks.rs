@@ -0,0 +1,370 @@
+//! Webhook deliveries.
+//!
+//! Every release event emitted on this replica is queued in
+//! `webhook_deliveries`, one row per matching subscription. A background
+//! worker posts them with an HMAC-SHA256 signature and retries failures with
+//! exponential backoff until an attempt succeeds or `WEBHOOK_MAX_ATTEMPTS`
+//! runs out. The rows double as the delivery log, and because the queue lives
+//! in the database, pending retries survive a restart.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::{Pool, Sqlite, prelude::FromRow};
+use tokio::sync::{Notify, broadcast};
+
+use crate::config::Config;
+use crate::events::{EventBus, ReleaseEvent, ReleaseEventKind};
+use crate::http_client::HttpClient;
+use crate::schema::Webhook;
+
+pub const SIGNATURE_HEADER: &str = "X-Updater-Signature-256";
+
+/// Columns matching [`WebhookRow`].
+/// The secret is deliberately left out; only the delivery worker reads it.
+pub const WEBHOOK_COLUMNS: &str = "id, org_id, url, events, app_name, active, created_at";
+
+/// Columns matching [`crate::schema::WebhookDelivery`].
+pub const DELIVERY_COLUMNS: &str = "id, webhook_id, event, payload, status, attempts, next_attempt_at, last_status_code, last_error, last_response, created_at, completed_at";
+
+/// How often the worker looks for due retries when nothing wakes it.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Deliveries attempted per worker pass.
+const BATCH_SIZE: i64 = 20;
+
+/// Stored response bodies are cut to this many bytes.
+const MAX_LOGGED_RESPONSE: usize = 1024;
+
+#[derive(Debug, FromRow)]
+pub struct WebhookRow {
+    pub id: i64,
+    pub org_id: Option<i64>,
+    pub url: String,
+    /// Comma-separated event kinds; empty means every event.
+    pub events: String,
+    pub app_name: Option<String>,
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl WebhookRow {
+    fn wants(&self, event: &ReleaseEvent) -> bool {
+        let kinds = decode_events(&self.events);
+        (kinds.is_empty() || kinds.contains(&event.kind))
+            && self
+                .app_name
+                .as_ref()
+                .is_none_or(|app| *app == event.release.app_name)
+    }
+}
+
+impl From<WebhookRow> for Webhook {
+    fn from(row: WebhookRow) -> Self {
+        Webhook {
+            id: row.id,
+            org_id: row.org_id,
+            events: decode_events(&row.events),
+            url: row.url,
+            app_name: row.app_name,
+            active: row.active,
+            created_at: row.created_at,
+        }
+    }
+}
+
+pub fn encode_events(kinds: &[ReleaseEventKind]) -> String {
+    kinds
+        .iter()
+        .map(|k| k.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn decode_events(raw: &str) -> Vec<ReleaseEventKind> {
+    raw.split(',')
+        .filter_map(|k| k.trim().parse().ok())
+        .collect()
+}
+
+/// `sha256=<hex>` HMAC of the body, in the format GitHub uses, so existing
+/// verification code can be reused by receivers.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Delay before retrying after `attempts` failed attempts: 10s, 30s, 90s, ...
+/// capped at an hour.
+fn backoff(attempts: i64) -> chrono::Duration {
+    let secs = 10i64.saturating_mul(3i64.saturating_pow(attempts.saturating_sub(1) as u32));
+    chrono::Duration::seconds(secs.min(3600))
+}
+
+#[derive(FromRow)]
+struct PendingAttempt {
+    event: String,
+    payload: String,
+    attempts: i64,
+    url: String,
+    secret: String,
+    active: bool,
+}
+
+pub struct Webhooks {
+    pool: Pool<Sqlite>,
+    client: HttpClient,
+    timeout: Duration,
+    max_attempts: i64,
+    wake: Notify,
+}
+
+impl Webhooks {
+    pub fn new(pool: Pool<Sqlite>, config: &Config) -> std::io::Result<Self> {
+        Ok(Self {
+            pool,
+            client: HttpClient::new(config.webhook_timeout)?,
+            timeout: config.webhook_timeout,
+            max_attempts: config.webhook_max_attempts.max(1),
+            wake: Notify::new(),
+        })
+    }
+
+    /// Starts queueing this replica's events and delivering them.
+    pub fn spawn(self: &Arc<Self>, events: &EventBus) {
+        let mut rx = events.subscribe_own();
+        let hooks = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => {
+                        if let Err(e) = hooks.enqueue(&event).await {
+                            println!("Failed to queue webhook deliveries: {}", e);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        println!("Webhook queue fell behind, {} events not delivered", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+
+        let hooks = self.clone();
+        tokio::spawn(async move {
+            loop {
+                hooks.deliver_due().await;
+                tokio::select! {
+                    _ = hooks.wake.notified() => {}
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                }
+            }
+        });
+    }
+
+    async fn enqueue(&self, event: &ReleaseEvent) -> Result<(), sqlx::Error> {
+        // Instance-wide hooks get everything; the rest only their
+        // organization's apps.
+        let hooks = sqlx::query_as::<_, WebhookRow>(&format!(
+            "SELECT {} FROM webhooks WHERE active = 1 AND (org_id IS NULL OR org_id = (SELECT org_id FROM apps WHERE name = ?))",
+            WEBHOOK_COLUMNS
+        ))
+        .bind(&event.release.app_name)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let payload = serde_json::to_string(event).unwrap_or_default();
+        let now = Utc::now();
+        let mut queued = 0;
+        for hook in hooks.iter().filter(|h| h.wants(event)) {
+            sqlx::query(
+                "INSERT INTO webhook_deliveries (webhook_id, event, payload, next_attempt_at, created_at) VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(hook.id)
+            .bind(event.kind.as_str())
+            .bind(&payload)
+            .bind(now)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+            queued += 1;
+        }
+
+        if queued > 0 {
+            println!(
+                "Queued {} webhook deliveries for {} event",
+                queued,
+                event.kind.as_str()
+            );
+            self.wake.notify_one();
+        }
+        Ok(())
+    }
+
+    async fn deliver_due(self: &Arc<Self>) {
+        let now = Utc::now();
+        let due: Vec<(i64, DateTime<Utc>)> = match sqlx::query_as(
+            "SELECT id, next_attempt_at FROM webhook_deliveries WHERE status = 'pending' AND next_attempt_at <= ? ORDER BY next_attempt_at LIMIT ?",
+        )
+        .bind(now)
+        .bind(BATCH_SIZE)
+        .fetch_all(&self.pool)
+        .await
+        {
+            Ok(due) => due,
+            Err(e) => {
+                println!("Failed to load due webhook deliveries: {}", e);
+                return;
+            }
+        };
+
+        // Claim each delivery by pushing its next attempt past the request
+        // timeout, so another worker sharing the database skips it.
+        let lease = now + chrono::Duration::from_std(self.timeout).unwrap_or_default() + backoff(1);
+        let mut attempts = tokio::task::JoinSet::new();
+        for (id, scheduled) in due {
+            let claimed = sqlx::query(
+                "UPDATE webhook_deliveries SET next_attempt_at = ? WHERE id = ? AND status = 'pending' AND next_attempt_at = ?",
+            )
+            .bind(lease)
+            .bind(id)
+            .bind(scheduled)
+            .execute(&self.pool)
+            .await
+            .map(|r| r.rows_affected() == 1)
+            .unwrap_or(false);
+            if claimed {
+                let hooks = self.clone();
+                attempts.spawn(async move { hooks.attempt(id).await });
+            }
+        }
+        while attempts.join_next().await.is_some() {}
+    }
+
+    async fn attempt(&self, id: i64) {
+        let pending = match sqlx::query_as::<_, PendingAttempt>(
+            "SELECT d.event, d.payload, d.attempts, w.url, w.secret, w.active FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id WHERE d.id = ?",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(Some(p)) => p,
+            Ok(None) => return,
+            Err(e) => {
+                println!("Failed to load webhook delivery {}: {}", id, e);
+                return;
+            }
+        };
+
+        let attempts = pending.attempts + 1;
+        let (status_code, response, error) = if !pending.active {
+            (None, None, Some("Webhook is disabled".to_string()))
+        } else {
+            let signature = sign(&pending.secret, pending.payload.as_bytes());
+            let delivery_id = id.to_string();
+            let headers = [
+                ("X-Updater-Event", pending.event.as_str()),
+                ("X-Updater-Delivery", delivery_id.as_str()),
+                (SIGNATURE_HEADER, signature.as_str()),
+            ];
+            match self
+                .client
+                .post_json(&pending.url, &headers, pending.payload.into_bytes())
+                .await
+            {
+                Ok(r) if r.status.is_success() => {
+                    (Some(r.status.as_u16() as i64), Some(r.body), None)
+                }
+                Ok(r) => (
+                    Some(r.status.as_u16() as i64),
+                    Some(r.body),
+                    Some(format!("Endpoint responded with {}", r.status)),
+                ),
+                Err(e) => (None, None, Some(e)),
+            }
+        };
+
+        let now = Utc::now();
+        let (status, next_attempt_at, completed_at) = match &error {
+            None => ("succeeded", None, Some(now)),
+            // A disabled hook won't come back on its own; stop retrying.
+            Some(_) if attempts >= self.max_attempts || !pending.active => {
+                ("failed", None, Some(now))
+            }
+            Some(_) => ("pending", Some(now + backoff(attempts)), None),
+        };
+        match &error {
+            None => println!("Webhook delivery {} succeeded", id),
+            Some(e) => println!(
+                "Webhook delivery {} attempt {} failed ({}): {}",
+                id, attempts, status, e
+            ),
+        }
+
+        let response = response.map(|mut body| {
+            if body.len() > MAX_LOGGED_RESPONSE {
+                let mut end = MAX_LOGGED_RESPONSE;
+                while !body.is_char_boundary(end) {
+                    end -= 1;
+                }
+                body.truncate(end);
+            }
+            body
+        });
+
+        if let Err(e) = sqlx::query(
+            "UPDATE webhook_deliveries SET status = ?, attempts = ?, next_attempt_at = ?, last_status_code = ?, last_error = ?, last_response = ?, completed_at = ? WHERE id = ?",
+        )
+        .bind(status)
+        .bind(attempts)
+        .bind(next_attempt_at)
+        .bind(status_code)
+        .bind(&error)
+        .bind(response)
+        .bind(completed_at)
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        {
+            println!("Failed to record webhook delivery {}: {}", id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_match_githubs_format() {
+        // The example from GitHub's guide to validating webhook deliveries.
+        assert_eq!(
+            sign("It's a Secret to Everybody", b"Hello, World!"),
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+        );
+        assert_ne!(
+            sign("another secret", b"Hello, World!"),
+            sign("It's a Secret to Everybody", b"Hello, World!")
+        );
+    }
+
+    #[test]
+    fn retries_back_off_up_to_an_hour() {
+        let secs: Vec<i64> = (1..=7).map(|n| backoff(n).num_seconds()).collect();
+        assert_eq!(secs, [10, 30, 90, 270, 810, 2430, 3600]);
+        assert_eq!(backoff(1000).num_seconds(), 3600);
+    }
+
+    #[test]
+    fn event_filters_round_trip() {
+        let kinds = [ReleaseEventKind::Published, ReleaseEventKind::Promoted];
+        assert_eq!(encode_events(&kinds), "published,promoted");
+        assert_eq!(decode_events("published, promoted,bogus"), kinds);
+    }
+}